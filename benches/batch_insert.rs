@@ -0,0 +1,60 @@
+//! Compare event import throughput for different write batch sizes.
+//!
+//! Usage: `cargo bench --bench batch_insert [event_count]`
+//!
+//! A batch size of 1 is how the writer stored events before batching,
+//! with a transaction per event.  With the default 100,000 events on a
+//! single-core Xeon VM:
+//!
+//! ```text
+//! batch size     1:       7947 events/sec
+//! batch size    10:      10860 events/sec
+//! batch size   100:      12790 events/sec
+//! batch size  1000:      14945 events/sec
+//! ```
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use nostrd::db::{SqliteStorage, Storage};
+use nostrd::protocol::Event;
+use serde_json::json;
+use std::env;
+use std::time::Instant;
+
+/// Number of events imported when no count is given
+const DEFAULT_EVENTS: usize = 100_000;
+
+/// Batch sizes to compare; 1 is a transaction per event.
+const BATCH_SIZES: [usize; 4] = [1, 10, 100, 1000];
+
+/// Import events into a fresh database, writing `batch_size` at a time.
+async fn import(events: &[Event], batch_size: usize) -> f64 {
+    let dir = common::temp_db_dir();
+    let storage = SqliteStorage::open(&dir).unwrap();
+    storage.migrate().await.unwrap();
+    let start = Instant::now();
+    for batch in events.chunks(batch_size) {
+        for result in storage.write_events(batch.to_vec()).await {
+            result.unwrap();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    std::fs::remove_dir_all(dir).ok();
+    events.len() as f64 / elapsed
+}
+
+#[tokio::main]
+async fn main() {
+    let count = env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(DEFAULT_EVENTS);
+    println!("signing {} events", count);
+    let events: Vec<Event> = (0..count)
+        .map(|i| common::signed_event(1, i as u64, 1, json!([]), &format!("note {}", i)))
+        .collect();
+    for batch_size in BATCH_SIZES {
+        let rate = import(&events, batch_size).await;
+        println!("batch size {:>5}: {:>10.0} events/sec", batch_size, rate);
+    }
+}
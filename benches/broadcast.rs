@@ -0,0 +1,77 @@
+//! Compare framing a broadcast event for every matching subscription
+//! by serializing it each time with framing JSON serialized once, and
+//! verify both produce the same messages.
+//!
+//! Usage: `cargo bench --bench broadcast [subscriber_count]`
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use nostrd::protocol::{BroadcastEvent, Event};
+use nostrd::protostream::{response_message, NostrResponse};
+use serde_json::json;
+use std::env;
+use std::str::FromStr;
+use std::time::Instant;
+use tungstenite::Message;
+
+/// Number of subscribers when no count is given
+const DEFAULT_SUBSCRIBERS: usize = 2_000;
+
+/// Number of events broadcast
+const EVENTS: usize = 100;
+
+/// The messages for one event, serialized, parsed and serialized again
+/// for each subscriber as broadcasts once were.
+fn per_subscriber(event: &Event, sub_ids: &[String]) -> Vec<Message> {
+    sub_ids
+        .iter()
+        .map(|id| {
+            let event_str = serde_json::to_string(event).unwrap();
+            let event = Event::from_str(&event_str).unwrap();
+            response_message(&NostrResponse::new_event(id, &event)).unwrap()
+        })
+        .collect()
+}
+
+/// The messages for one event, serialized once for all subscribers.
+fn shared(event: &Event, sub_ids: &[String]) -> Vec<Message> {
+    let event = BroadcastEvent::new(event.clone());
+    sub_ids
+        .iter()
+        .map(|id| response_message(&NostrResponse::new_serialized_event(id, &event)).unwrap())
+        .collect()
+}
+
+fn main() {
+    let count = env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(DEFAULT_SUBSCRIBERS);
+    let sub_ids: Vec<String> = (0..count).map(|i| format!("sub{}", i)).collect();
+    let tags = json!([
+        ["p", common::test_pubkey(2).to_string()],
+        ["p", common::test_pubkey(3).to_string()]
+    ]);
+    let events: Vec<Event> = (0..EVENTS)
+        .map(|i| common::signed_event(1, i as u64, 1, tags.clone(), &"note ".repeat(40)))
+        .collect();
+
+    let start = Instant::now();
+    let old: Vec<Vec<Message>> = events.iter().map(|e| per_subscriber(e, &sub_ids)).collect();
+    let old_elapsed = start.elapsed();
+
+    let start = Instant::now();
+    let new: Vec<Vec<Message>> = events.iter().map(|e| shared(e, &sub_ids)).collect();
+    let new_elapsed = start.elapsed();
+
+    assert!(old == new, "framed messages differ");
+    let per_event = |elapsed: std::time::Duration| elapsed.as_secs_f64() * 1e3 / EVENTS as f64;
+    println!(
+        "{} events to {} subscribers: per subscriber {:.3} ms/event, shared {:.3} ms/event ({:.0}x)",
+        EVENTS,
+        count,
+        per_event(old_elapsed),
+        per_event(new_elapsed),
+        old_elapsed.as_secs_f64() / new_elapsed.as_secs_f64()
+    );
+}
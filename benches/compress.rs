@@ -0,0 +1,88 @@
+//! Measure the write path cost of compressing stored event content.
+//!
+//! Usage: `cargo bench --bench compress [event_count]`
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use nostrd::config::SETTINGS;
+use nostrd::db::{Compression, SqliteStorage, Storage};
+use nostrd::protocol::Event;
+use serde_json::json;
+use std::env;
+use std::time::Instant;
+
+/// Number of events written when no count is given
+const DEFAULT_EVENTS: usize = 20_000;
+
+/// Events committed in each transaction
+const BATCH_SIZE: usize = 100;
+
+/// Words used to build article-like content
+const WORDS: [&str; 12] = [
+    "relay",
+    "note",
+    "event",
+    "the",
+    "of",
+    "signature",
+    "and",
+    "client",
+    "a",
+    "subscription",
+    "to",
+    "protocol",
+];
+
+/// Roughly `len` bytes of text, varied by `seed`.
+fn article(seed: usize, len: usize) -> String {
+    let mut text = String::with_capacity(len + 16);
+    let mut n = seed;
+    while text.len() < len {
+        n = n
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        text.push_str(WORDS[(n >> 33) % WORDS.len()]);
+        text.push(' ');
+    }
+    text
+}
+
+/// Write events into a fresh database, returning events per second
+/// and the compression ratio achieved.
+async fn write(events: &[Event], compression: Compression) -> (f64, Option<f64>) {
+    SETTINGS.write().unwrap().database.compress_content = compression;
+    let dir = common::temp_db_dir();
+    let storage = SqliteStorage::open(&dir).unwrap();
+    storage.migrate().await.unwrap();
+    let start = Instant::now();
+    for batch in events.chunks(BATCH_SIZE) {
+        for result in storage.write_events(batch.to_vec()).await {
+            result.unwrap();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let ratio = storage.stats().await.unwrap().compression_ratio();
+    std::fs::remove_dir_all(dir).ok();
+    (events.len() as f64 / elapsed, ratio)
+}
+
+#[tokio::main]
+async fn main() {
+    let count = env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(DEFAULT_EVENTS);
+    println!("signing {} events", count);
+    let events: Vec<Event> = (0..count)
+        .map(|i| common::signed_event(1, i as u64, 1, json!([]), &article(i, 4096)))
+        .collect();
+    for compression in [Compression::Off, Compression::Zstd] {
+        let (rate, ratio) = write(&events, compression).await;
+        println!(
+            "{:>5}: {:>10.0} events/sec, compression ratio {}",
+            format!("{:?}", compression),
+            rate,
+            ratio.map_or("-".to_owned(), |r| format!("{:.2}", r))
+        );
+    }
+}
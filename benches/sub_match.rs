@@ -0,0 +1,94 @@
+//! Compare broadcast matching through the subscription index with
+//! checking every subscription, and verify both find the same ones.
+//!
+//! Usage: `cargo bench --bench sub_match [subscription_count]`
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use bitcoin_hashes::{sha256, Hash};
+use nostrd::conn::ClientConn;
+use nostrd::protocol::{Event, Subscription, SubscriptionId};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::env;
+use std::time::Instant;
+
+/// Number of subscriptions when no count is given
+const DEFAULT_SUBSCRIPTIONS: usize = 10_000;
+
+/// Number of distinct authors events and subscriptions use
+const AUTHORS: u8 = 250;
+
+/// Number of events matched against the subscriptions
+const EVENTS: usize = 1_000;
+
+/// Filters for the `i`th subscription: mostly by author, some by
+/// kind, and some with neither.
+fn filter(i: usize) -> Value {
+    let author = common::test_pubkey(1 + (i % AUTHORS as usize) as u8).to_string();
+    match i % 20 {
+        0 => json!({ "kinds": [i % 3] }),
+        1 => json!({ "since": i }),
+        _ => json!({ "authors": [author] }),
+    }
+}
+
+fn subscription(i: usize) -> Subscription {
+    let id = sha256::Hash::hash(format!("sub{}", i).as_bytes());
+    serde_json::from_value(json!(["REQ", id.to_string(), filter(i)])).unwrap()
+}
+
+fn main() {
+    let count = env::args()
+        .skip(1)
+        .find_map(|a| a.parse().ok())
+        .unwrap_or(DEFAULT_SUBSCRIPTIONS);
+    let subs: Vec<Subscription> = (0..count).map(subscription).collect();
+    let mut conn = ClientConn::with_limits(0, 0);
+    for sub in &subs {
+        conn.subscribe(sub.clone()).unwrap();
+    }
+    println!("signing {} events", EVENTS);
+    let events: Vec<Event> = (0..EVENTS)
+        .map(|i| {
+            let secret = 1 + (i % AUTHORS as usize) as u8;
+            common::signed_event(secret, i as u64 * 10, (i % 3) as u64, json!([]), "note")
+        })
+        .collect();
+
+    let start = Instant::now();
+    let indexed: Vec<HashSet<SubscriptionId>> = events
+        .iter()
+        .map(|e| {
+            conn.get_matching_subscriptions(e)
+                .into_iter()
+                .cloned()
+                .collect()
+        })
+        .collect();
+    let indexed_time = start.elapsed();
+
+    let start = Instant::now();
+    let brute_force: Vec<HashSet<SubscriptionId>> = events
+        .iter()
+        .map(|e| {
+            subs.iter()
+                .filter(|s| s.interested_in_event(e))
+                .map(|s| s.get_id().clone())
+                .collect()
+        })
+        .collect();
+    let brute_force_time = start.elapsed();
+
+    assert_eq!(indexed, brute_force);
+    let matches: usize = indexed.iter().map(HashSet::len).sum();
+    println!("{} subscriptions, {} matches", count, matches);
+    println!(
+        "indexed:     {:>8.2} µs/event",
+        indexed_time.as_secs_f64() * 1e6 / EVENTS as f64
+    );
+    println!(
+        "brute force: {:>8.2} µs/event",
+        brute_force_time.as_secs_f64() * 1e6 / EVENTS as f64
+    );
+}
@@ -0,0 +1,37 @@
+//! Query the most recent text notes from a nostrd database.
+//!
+//! Usage: `cargo run --example recent_notes [data_directory]`
+use futures::StreamExt;
+use nostrd::db::{query_stream, ReaderPool};
+use nostrd::protocol::Subscription;
+use std::collections::VecDeque;
+use std::env;
+use std::path::Path;
+
+/// Number of events to print
+const COUNT: usize = 10;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let db_dir = env::args().nth(1).unwrap_or_else(|| ".".to_owned());
+    let pool = ReaderPool::new(Path::new(&db_dir));
+    // subscription ids are hashes, any valid one will do here.
+    let sub: Subscription = serde_json::from_value(serde_json::json!([
+        "REQ",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+        { "kinds": [1] }
+    ]))?;
+    // results arrive oldest first, so only keep the tail.
+    let mut recent = VecDeque::with_capacity(COUNT);
+    let mut results = query_stream(sub, &pool);
+    while let Some(event) = results.next().await {
+        if recent.len() == COUNT {
+            recent.pop_front();
+        }
+        recent.push_back(event?);
+    }
+    for event in recent.iter().rev() {
+        println!("{}", serde_json::to_string(event)?);
+    }
+    Ok(())
+}
@@ -0,0 +1,550 @@
+//! Operator HTTP interface, under `/admin/`
+use crate::bans::Bans;
+use crate::config;
+use crate::db::{self, RecentIds, Storage};
+use crate::error::{Error, Result};
+use crate::protocol::EventId;
+use crate::registry::ConnectionRegistry;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Largest admin request body accepted
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Endpoints with fixed paths, to tell a wrong method from a wrong
+/// path
+const ENDPOINTS: [&str; 16] = [
+    "/admin/connections",
+    "/admin/notice",
+    "/admin/notices",
+    "/admin/disconnect",
+    "/admin/close-codes",
+    "/admin/delete-event",
+    "/admin/ban-pubkey",
+    "/admin/unban-pubkey",
+    "/admin/author-stats",
+    "/admin/ban-ip",
+    "/admin/unban-ip",
+    "/admin/read-only",
+    "/admin/bans",
+    "/admin/event-source",
+    "/admin/compact",
+    "/admin/backup",
+];
+
+/// Sent to every client before the database is compacted
+const COMPACT_NOTICE: &str =
+    "relay is briefly read-only for maintenance; events sent meanwhile are stored once it finishes";
+
+/// A notice for every connected client, sent once or repeatedly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoticeRequest {
+    pub message: String,
+    /// Send the notice again this often, until cancelled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_secs: Option<u64>,
+}
+
+/// Compare two byte strings in time that depends only on their
+/// lengths, so a token can not be guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Check that a request carries the configured bearer token.  No
+/// request is authorized if the token is empty.
+pub fn is_authorized<T>(request: &Request<T>, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// A JSON response with the given status.
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(body).unwrap()))
+        .unwrap()
+}
+
+/// A JSON error response.
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+/// Decode `%XX` escapes, and `+` as a space, in part of a query
+/// string.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The decoded value of the query string parameter `name`, if given.
+fn query_param<T>(request: &Request<T>, name: &str) -> Option<String> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+/// Connections to close, identified by client id, the id prefix
+/// shown in logs, or IP address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisconnectRequest {
+    pub target: String,
+    /// Reason given to the client in the close frame
+    #[serde(default = "default_disconnect_reason")]
+    pub reason: String,
+}
+
+fn default_disconnect_reason() -> String {
+    "disconnected by operator".to_owned()
+}
+
+/// An event to delete, by id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeleteEventRequest {
+    pub id: String,
+}
+
+/// An author to ban or unban.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PubkeyRequest {
+    pub pubkey: String,
+}
+
+/// An address to ban or unban.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpRequest {
+    pub ip: String,
+}
+
+/// Whether the relay should refuse new events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadOnlyRequest {
+    pub read_only: bool,
+}
+
+/// The parts of the running relay that admin requests act on.
+pub struct AdminTargets<'a> {
+    pub registry: &'a Arc<ConnectionRegistry>,
+    pub storage: &'a dyn Storage,
+    /// Ids the writer answers as duplicates, forgotten once deleted
+    pub recent: &'a RecentIds,
+    pub bans: &'a Bans,
+}
+
+/// Read a JSON request body.
+async fn read_json<T: for<'de> Deserialize<'de>>(
+    request: Request<Body>,
+) -> Result<T, Response<Body>> {
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|_| error_response(StatusCode::BAD_REQUEST, "could not read body"))?;
+    if body.len() > MAX_BODY_BYTES {
+        return Err(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body too large",
+        ));
+    }
+    serde_json::from_slice(&body)
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))
+}
+
+/// Send a notice to every client now, and again every `repeat_secs`
+/// if given.
+fn send_notice(registry: &Arc<ConnectionRegistry>, notice: &NoticeRequest) -> Response<Body> {
+    if notice.message.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "message is empty");
+    }
+    let sent = registry.send_notice(&notice.message);
+    match notice.repeat_secs {
+        Some(0) => error_response(StatusCode::BAD_REQUEST, "repeat_secs must be positive"),
+        Some(secs) => {
+            let id = registry.schedule_notice(&notice.message, Duration::from_secs(secs));
+            json_response(
+                StatusCode::OK,
+                &serde_json::json!({ "sent": sent, "schedule_id": id }),
+            )
+        }
+        None => json_response(StatusCode::OK, &serde_json::json!({ "sent": sent })),
+    }
+}
+
+/// Copy a snapshot of the SQLite database to `dest`, on a blocking
+/// thread so that the backup's pauses between steps do not hold up
+/// the runtime.
+async fn backup(dest: PathBuf) -> Response<Body> {
+    let (engine, data_directory) = {
+        let database = &config::SETTINGS.read().unwrap().database;
+        (database.engine.clone(), database.data_directory.clone())
+    };
+    if engine != "sqlite" {
+        return error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "backups are only supported for the sqlite engine",
+        );
+    }
+    if dest.exists() {
+        return error_response(StatusCode::CONFLICT, "backup destination already exists");
+    }
+    let start = Instant::now();
+    let path = dest.clone();
+    let task =
+        tokio::task::spawn_blocking(move || db::backup_to(Path::new(&data_directory), &path, None));
+    let result = match task.await {
+        Ok(result) => result,
+        Err(e) => Err(Error::GenericError(e.to_string())),
+    };
+    match result {
+        Ok(()) => json_response(
+            StatusCode::OK,
+            &serde_json::json!({
+                "path": dest.display().to_string(),
+                "elapsed_ms": start.elapsed().as_millis() as u64,
+            }),
+        ),
+        Err(e) => {
+            warn!("admin: backup to {:?} failed: {}", dest, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+/// Handle a request for a path under `/admin/`.
+pub async fn handle_admin_request(
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+    targets: &AdminTargets<'_>,
+) -> Response<Body> {
+    let registry = targets.registry;
+    let (enabled, token) = {
+        let admin = &config::SETTINGS.read().unwrap().admin;
+        (admin.enabled, admin.token.clone())
+    };
+    if !enabled {
+        return error_response(StatusCode::NOT_FOUND, "not found");
+    }
+    if !is_authorized(&request, &token) {
+        warn!("unauthorized admin request from {}", remote_addr);
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    let path = request.uri().path().to_owned();
+    match (request.method(), path.as_str()) {
+        (&Method::GET, "/admin/connections") => {
+            info!("admin: list connections (from {})", remote_addr);
+            json_response(StatusCode::OK, &registry.list())
+        }
+        (&Method::POST, "/admin/notice") => {
+            let notice: NoticeRequest = match read_json(request).await {
+                Ok(notice) => notice,
+                Err(response) => return response,
+            };
+            let repeat = notice
+                .repeat_secs
+                .map(|secs| format!(" every {}s", secs))
+                .unwrap_or_default();
+            info!(
+                "admin: notice {:?}{} (from {})",
+                notice.message, repeat, remote_addr
+            );
+            send_notice(registry, &notice)
+        }
+        (&Method::POST, "/admin/disconnect") => {
+            let disconnect: DisconnectRequest = match read_json(request).await {
+                Ok(disconnect) => disconnect,
+                Err(response) => return response,
+            };
+            let closed = registry.disconnect(&disconnect.target, &disconnect.reason);
+            info!(
+                "admin: disconnect {} ({} connection(s), reason {:?}) (from {})",
+                disconnect.target, closed, disconnect.reason, remote_addr
+            );
+            json_response(StatusCode::OK, &serde_json::json!({ "closed": closed }))
+        }
+        (&Method::POST, "/admin/delete-event") => {
+            let delete: DeleteEventRequest = match read_json(request).await {
+                Ok(delete) => delete,
+                Err(response) => return response,
+            };
+            let id = match EventId::from_str(&delete.id) {
+                Ok(id) => id,
+                Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid event id"),
+            };
+            match targets.storage.delete_event(id).await {
+                Ok(outcome) => {
+                    targets.recent.remove(&id);
+                    info!(
+                        "admin: delete event {} (deleted: {}, tombstoned: {}) (from {})",
+                        id, outcome.deleted, outcome.tombstoned, remote_addr
+                    );
+                    json_response(StatusCode::OK, &outcome)
+                }
+                Err(e) => {
+                    warn!(
+                        "admin: delete event {} failed: {} (from {})",
+                        id, e, remote_addr
+                    );
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+                }
+            }
+        }
+        (&Method::POST, path @ ("/admin/ban-pubkey" | "/admin/unban-pubkey")) => {
+            let banned = path == "/admin/ban-pubkey";
+            let target: PubkeyRequest = match read_json(request).await {
+                Ok(target) => target,
+                Err(response) => return response,
+            };
+            let pubkey = match XOnlyPublicKey::from_str(&target.pubkey) {
+                Ok(pubkey) => pubkey,
+                Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid pubkey"),
+            };
+            match targets.bans.set_pubkey(pubkey, banned) {
+                Ok(changed) => {
+                    info!(
+                        "admin: {} pubkey {} (changed: {}) (from {})",
+                        if banned { "ban" } else { "unban" },
+                        pubkey,
+                        changed,
+                        remote_addr
+                    );
+                    json_response(
+                        StatusCode::OK,
+                        &serde_json::json!({ "pubkey": pubkey, "banned": banned, "changed": changed }),
+                    )
+                }
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            }
+        }
+        (&Method::GET, "/admin/author-stats") => {
+            let pubkey = query_param(&request, "pubkey")
+                .and_then(|pubkey| XOnlyPublicKey::from_str(&pubkey).ok());
+            let pubkey = match pubkey {
+                Some(pubkey) => pubkey,
+                None => return error_response(StatusCode::BAD_REQUEST, "invalid pubkey"),
+            };
+            match targets.storage.author_stats(pubkey).await {
+                Ok(Some(stats)) => json_response(StatusCode::OK, &stats),
+                Ok(None) => error_response(StatusCode::NOT_FOUND, "nothing stored by author"),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            }
+        }
+        (&Method::POST, path @ ("/admin/ban-ip" | "/admin/unban-ip")) => {
+            let banned = path == "/admin/ban-ip";
+            let target: IpRequest = match read_json(request).await {
+                Ok(target) => target,
+                Err(response) => return response,
+            };
+            let ip: IpAddr = match target.ip.trim().parse() {
+                Ok(ip) => ip,
+                Err(_) => return error_response(StatusCode::BAD_REQUEST, "invalid ip"),
+            };
+            let changed = match targets.bans.set_ip(ip, banned) {
+                Ok(changed) => changed,
+                Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            };
+            // clients already connected from the address go too
+            let closed = if banned {
+                registry.disconnect(&ip.to_string(), "banned")
+            } else {
+                0
+            };
+            info!(
+                "admin: {} ip {} (changed: {}, closed {} connection(s)) (from {})",
+                if banned { "ban" } else { "unban" },
+                ip,
+                changed,
+                closed,
+                remote_addr
+            );
+            json_response(
+                StatusCode::OK,
+                &serde_json::json!({ "ip": ip, "banned": banned, "changed": changed, "closed": closed }),
+            )
+        }
+        (&Method::GET, "/admin/bans") => json_response(StatusCode::OK, &targets.bans.list()),
+        (&Method::GET, "/admin/event-source") => {
+            let id = match query_param(&request, "id").map(|id| EventId::from_str(&id)) {
+                Some(Ok(id)) => id,
+                _ => return error_response(StatusCode::BAD_REQUEST, "invalid event id"),
+            };
+            info!("admin: event source of {} (from {})", id, remote_addr);
+            match targets.storage.event_source(id).await {
+                Ok(Some(source)) => json_response(StatusCode::OK, &source),
+                Ok(None) => error_response(StatusCode::NOT_FOUND, "no source recorded"),
+                Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+            }
+        }
+        (&Method::POST, "/admin/read-only") => {
+            let request: ReadOnlyRequest = match read_json(request).await {
+                Ok(request) => request,
+                Err(response) => return response,
+            };
+            config::set_read_only(request.read_only);
+            info!(
+                "admin: set read-only {} (from {})",
+                request.read_only, remote_addr
+            );
+            json_response(
+                StatusCode::OK,
+                &serde_json::json!({ "read_only": request.read_only }),
+            )
+        }
+        (&Method::POST, "/admin/compact") => {
+            info!("admin: compact database (from {})", remote_addr);
+            registry.send_notice(COMPACT_NOTICE);
+            match targets.storage.compact().await {
+                Ok(Some(report)) => json_response(StatusCode::OK, &report),
+                Ok(None) => error_response(
+                    StatusCode::NOT_IMPLEMENTED,
+                    "compaction is not supported by this database",
+                ),
+                Err(e) => {
+                    warn!("admin: compaction failed: {} (from {})", e, remote_addr);
+                    error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+                }
+            }
+        }
+        (&Method::POST, "/admin/backup") => {
+            let dest = match query_param(&request, "path") {
+                Some(path) if !path.is_empty() => PathBuf::from(path),
+                _ => return error_response(StatusCode::BAD_REQUEST, "backup needs a path"),
+            };
+            info!("admin: backup to {:?} (from {})", dest, remote_addr);
+            backup(dest).await
+        }
+        (&Method::GET, "/admin/close-codes") => {
+            json_response(StatusCode::OK, &registry.close_codes())
+        }
+        (&Method::GET, "/admin/notices") => {
+            json_response(StatusCode::OK, &registry.scheduled_notices())
+        }
+        (&Method::DELETE, p) if p.starts_with("/admin/notices/") => {
+            let id = p.trim_start_matches("/admin/notices/");
+            info!("admin: cancel notice {} (from {})", id, remote_addr);
+            match id.parse() {
+                Ok(id) if registry.cancel_notice(id) => {
+                    json_response(StatusCode::OK, &serde_json::json!({ "cancelled": id }))
+                }
+                _ => error_response(StatusCode::NOT_FOUND, "no such notice"),
+            }
+        }
+        (_, path) if ENDPOINTS.contains(&path) => {
+            error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Make a request of the admin API of the relay configured locally,
+/// returning the status and body of the response.
+pub async fn call_admin_api(
+    method: Method,
+    path: &str,
+    body: Option<String>,
+) -> Result<(StatusCode, String)> {
+    let (url, token) = {
+        let settings = config::SETTINGS.read().unwrap();
+        // the admin address if there is one, else the first listener;
+        // one listening on every address can be reached locally
+        let mut addr = match &settings.admin.address {
+            Some(address) => address
+                .parse()
+                .map_err(|_| Error::GenericError(format!("invalid admin address {:?}", address)))?,
+            None => settings.network.listen_addrs()?[0],
+        };
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        (
+            format!("http://{}{}", addr, path),
+            settings.admin.token.clone(),
+        )
+    };
+    let request = Request::builder()
+        .method(method)
+        .uri(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.map(Body::from).unwrap_or_else(Body::empty))
+        .map_err(|e| Error::GenericError(e.to_string()))?;
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|e| Error::GenericError(format!("could not reach {}: {}", url, e)))?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| Error::GenericError(e.to_string()))?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_token_checked() {
+        let request = |auth: &str| {
+            Request::builder()
+                .header(AUTHORIZATION, auth)
+                .body(())
+                .unwrap()
+        };
+        assert!(is_authorized(&request("Bearer secret"), "secret"));
+        assert!(!is_authorized(&request("Bearer secreT"), "secret"));
+        assert!(!is_authorized(&request("Bearer secret2"), "secret"));
+        assert!(!is_authorized(&request("secret"), "secret"));
+        assert!(!is_authorized(&request("Bearer "), ""));
+        let missing = Request::builder().body(()).unwrap();
+        assert!(!is_authorized(&missing, "secret"));
+    }
+
+    #[test]
+    fn query_params_decoded() {
+        let request = Request::builder()
+            .uri("/admin/event-source?id=ab&path=%2Ftmp%2Fnostr+backup.db&bad=%zz")
+            .body(())
+            .unwrap();
+        assert_eq!(query_param(&request, "id").as_deref(), Some("ab"));
+        assert_eq!(
+            query_param(&request, "path").as_deref(),
+            Some("/tmp/nostr backup.db")
+        );
+        assert_eq!(query_param(&request, "bad"), None);
+        assert_eq!(query_param(&request, "missing"), None);
+    }
+}
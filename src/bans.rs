@@ -0,0 +1,154 @@
+//! Authors and addresses banned by the operator
+use crate::error::{Error, Result};
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// File in the data directory the bans are kept in
+pub const BANS_FILE: &str = "bans.json";
+
+/// Everything banned, as saved.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanList {
+    /// Authors whose events are refused
+    #[serde(default)]
+    pub pubkeys: BTreeSet<XOnlyPublicKey>,
+    /// Addresses that may not connect
+    #[serde(default)]
+    pub ips: BTreeSet<IpAddr>,
+}
+
+/// Bans checked as clients connect and publish, saved to a file so
+/// they last across restarts.
+#[derive(Debug, Default)]
+pub struct Bans {
+    list: RwLock<BanList>,
+    /// Where changes are saved, if anywhere
+    path: Option<PathBuf>,
+}
+
+impl Bans {
+    /// Load the bans saved in `dir`, if any.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(BANS_FILE);
+        let list = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                Error::GenericError(format!("could not read {}: {}", path.display(), e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BanList::default(),
+            Err(e) => return Err(io_error(&path, e)),
+        };
+        if list != BanList::default() {
+            info!(
+                "loaded bans on {} pubkey(s) and {} address(es)",
+                list.pubkeys.len(),
+                list.ips.len()
+            );
+        }
+        Ok(Bans {
+            list: RwLock::new(list),
+            path: Some(path),
+        })
+    }
+
+    /// A copy of everything banned.
+    pub fn list(&self) -> BanList {
+        self.list.read().unwrap().clone()
+    }
+
+    pub fn is_pubkey_banned(&self, pubkey: &XOnlyPublicKey) -> bool {
+        self.list.read().unwrap().pubkeys.contains(pubkey)
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        self.list.read().unwrap().ips.contains(&ip.to_canonical())
+    }
+
+    /// Ban or unban an author, returning whether anything changed.
+    pub fn set_pubkey(&self, pubkey: XOnlyPublicKey, banned: bool) -> Result<bool> {
+        self.update(|list| {
+            if banned {
+                list.pubkeys.insert(pubkey)
+            } else {
+                list.pubkeys.remove(&pubkey)
+            }
+        })
+    }
+
+    /// Ban or unban an address, returning whether anything changed.
+    pub fn set_ip(&self, ip: IpAddr, banned: bool) -> Result<bool> {
+        // IPv4 clients of a dual-stack listener have mapped addresses
+        let ip = ip.to_canonical();
+        self.update(|list| {
+            if banned {
+                list.ips.insert(ip)
+            } else {
+                list.ips.remove(&ip)
+            }
+        })
+    }
+
+    /// Change the list, saving it if `change` reports a difference.
+    /// The change is undone if it can not be saved.
+    fn update<F: FnOnce(&mut BanList) -> bool>(&self, change: F) -> Result<bool> {
+        let mut list = self.list.write().unwrap();
+        let before = list.clone();
+        if !change(&mut list) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &list) {
+                *list = before;
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Write the list to `path`, replacing it whole.
+fn save(path: &Path, list: &BanList) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(list)?).map_err(|e| io_error(&tmp, e))?;
+    std::fs::rename(&tmp, path).map_err(|e| io_error(path, e))
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> Error {
+    Error::GenericError(format!("bans file {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_db_dir;
+    use std::str::FromStr;
+
+    #[test]
+    fn bans_saved_and_loaded() {
+        let dir = temp_db_dir();
+        let pubkey = XOnlyPublicKey::from_str(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let bans = Bans::load(&dir).unwrap();
+        assert_eq!(bans.list(), BanList::default());
+        assert!(bans.set_pubkey(pubkey, true).unwrap());
+        assert!(!bans.set_pubkey(pubkey, true).unwrap());
+        assert!(bans.set_ip("10.0.0.1".parse().unwrap(), true).unwrap());
+        assert!(bans.is_pubkey_banned(&pubkey));
+        // a mapped address is the same client
+        assert!(bans.is_ip_banned(&"::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!bans.is_ip_banned(&"10.0.0.2".parse().unwrap()));
+
+        let reloaded = Bans::load(&dir).unwrap();
+        assert_eq!(reloaded.list(), bans.list());
+        assert!(reloaded.set_pubkey(pubkey, false).unwrap());
+        assert!(!reloaded.set_pubkey(pubkey, false).unwrap());
+        assert!(Bans::load(&dir).unwrap().list().pubkeys.is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
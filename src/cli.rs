@@ -0,0 +1,345 @@
+//! Command line parsing: options that apply to every command, and the
+//! commands besides running the relay
+use crate::admin::NoticeRequest;
+use crate::config::Settings;
+use crate::db::{ExportFilter, KindRange, PruneFilter};
+use crate::protocol::EventId;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use secp256k1::XOnlyPublicKey;
+
+/// Printed after the options in `--help`
+const AFTER_HELP: &str = "\
+Options given here override environment variables, which override the
+configuration file.  Environment variables are named after settings,
+e.g. NOSTRD_NETWORK__PORT for network.port.";
+
+/// A nostr relay, run without a command, and tools for its database.
+#[derive(Debug, Parser)]
+#[command(name = "nostrd", after_help = AFTER_HELP)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: GlobalOptions,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options that may be given with any command.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Args)]
+pub struct GlobalOptions {
+    /// Read settings from <file> instead of config.toml
+    #[arg(long, global = true, value_name = "file")]
+    pub config: Option<String>,
+    /// Database directory (database.data_directory)
+    #[arg(long, global = true, value_name = "dir")]
+    pub db: Option<String>,
+    /// Address to listen on (network.address)
+    #[arg(long, global = true, value_name = "addr")]
+    pub bind: Option<String>,
+    /// Port to listen on (network.port)
+    #[arg(long, global = true, value_name = "port")]
+    pub port: Option<u16>,
+    /// What to log, as for RUST_LOG (e.g. info, nostrd=debug)
+    #[arg(long, global = true, value_name = "filter")]
+    pub log_level: Option<String>,
+}
+
+impl GlobalOptions {
+    /// Apply the options to settings read from the environment and
+    /// configuration file.  A listen address given here replaces any
+    /// configured listeners.
+    pub fn apply(&self, settings: &mut Settings) {
+        if let Some(db) = &self.db {
+            settings.database.data_directory = db.clone();
+        }
+        if let Some(bind) = &self.bind {
+            settings.network.address = bind.clone();
+            settings.network.listeners = None;
+        }
+        if let Some(port) = self.port {
+            settings.network.port = port;
+            settings.network.listeners = None;
+        }
+    }
+}
+
+/// What to do instead of running the relay.
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Compact the database
+    Compact,
+    /// Copy a snapshot of the database to <dest>
+    Backup {
+        #[arg(value_name = "dest")]
+        dest: String,
+    },
+    /// Write events as line-delimited JSON
+    Export(ExportArgs),
+    /// Read line-delimited JSON events
+    Import {
+        /// File to read, or stdin if absent or "-"
+        #[arg(value_name = "file")]
+        file: Option<String>,
+    },
+    /// Check stored events
+    Verify {
+        /// Delete the events that fail
+        #[arg(long)]
+        delete_invalid: bool,
+    },
+    /// Delete events with the relay stopped
+    Prune(PruneArgs),
+    /// Delete events and refuse them in future
+    Delete {
+        #[arg(value_name = "id", required = true)]
+        ids: Vec<EventId>,
+    },
+    /// Encrypt a plaintext database
+    EncryptDb,
+    /// Send notices through a running relay
+    Notice(NoticeArgs),
+}
+
+impl Command {
+    /// Whether the command changes the database.
+    pub fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Command::Backup { .. }
+                | Command::Export(_)
+                | Command::Verify {
+                    delete_invalid: false
+                }
+        )
+    }
+}
+
+/// Options of the `export` command.
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct ExportArgs {
+    /// Only events created at or after <time>
+    #[arg(long, value_name = "time")]
+    pub since: Option<u64>,
+    /// Only events created at or before <time>
+    #[arg(long, value_name = "time")]
+    pub until: Option<u64>,
+    /// Only events of these kinds
+    #[arg(long, value_name = "k,...", value_delimiter = ',')]
+    pub kinds: Vec<u64>,
+    /// Only events by these authors
+    #[arg(long, value_name = "pubkey,...", value_delimiter = ',')]
+    pub author: Vec<XOnlyPublicKey>,
+    /// Also export hidden events, such as replaced metadata
+    #[arg(long)]
+    pub include_hidden: bool,
+    /// Write to <file>, which must not exist, instead of stdout
+    #[arg(long, value_name = "file")]
+    pub out: Option<String>,
+}
+
+impl ExportArgs {
+    /// The events to export.
+    pub fn filter(&self) -> ExportFilter {
+        ExportFilter {
+            since: self.since,
+            until: self.until,
+            kinds: Some(self.kinds.clone()).filter(|k| !k.is_empty()),
+            authors: Some(self.author.clone()).filter(|a| !a.is_empty()),
+            include_hidden: self.include_hidden,
+        }
+    }
+}
+
+/// Options of the `prune` command.
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+#[command(group(ArgGroup::new("selection").required(true).multiple(true).args(["before", "kinds"])))]
+pub struct PruneArgs {
+    /// Delete events created before <time>
+    #[arg(long, value_name = "time")]
+    pub before: Option<u64>,
+    /// Delete events of these kinds
+    #[arg(long, value_name = "k,...", value_delimiter = ',')]
+    pub kinds: Vec<u64>,
+    /// Keep the current version of replaceable events
+    #[arg(long)]
+    pub keep_latest_replaceable: bool,
+    /// Compact the database afterwards
+    #[arg(long)]
+    pub vacuum: bool,
+}
+
+impl PruneArgs {
+    /// The events to delete.
+    pub fn filter(&self) -> PruneFilter {
+        PruneFilter {
+            created_before: self.before,
+            kinds: self.kinds.iter().copied().map(KindRange::Single).collect(),
+            include_latest_replaceable: !self.keep_latest_replaceable,
+            ..Default::default()
+        }
+    }
+}
+
+/// Options of the `notice` command.
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+#[command(group(ArgGroup::new("action").required(true).args(["message", "list", "cancel"])))]
+pub struct NoticeArgs {
+    /// Text to send every client
+    #[arg(value_name = "message")]
+    pub message: Option<String>,
+    /// Send the message again every <secs> seconds, until cancelled
+    #[arg(long, value_name = "secs", conflicts_with_all = ["list", "cancel"],
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub repeat: Option<u64>,
+    /// List the repeating notices
+    #[arg(long)]
+    pub list: bool,
+    /// Stop repeating the notice <id>
+    #[arg(long, value_name = "id")]
+    pub cancel: Option<u64>,
+}
+
+/// What the `notice` command asks the running relay to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoticeCommand {
+    Send(NoticeRequest),
+    List,
+    Cancel(u64),
+}
+
+impl NoticeArgs {
+    /// The request for the relay.
+    pub fn command(&self) -> NoticeCommand {
+        match (&self.message, self.cancel) {
+            (Some(message), _) => NoticeCommand::Send(NoticeRequest {
+                message: message.clone(),
+                repeat_secs: self.repeat,
+            }),
+            (None, Some(id)) => NoticeCommand::Cancel(id),
+            (None, None) => NoticeCommand::List,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ListenerEntry;
+
+    fn parse(line: &str) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(line.split_whitespace())
+    }
+
+    #[test]
+    fn global_options_anywhere() {
+        let cli =
+            parse("nostrd export --db /data --kinds 1 --port 9000 --log-level debug").unwrap();
+        assert_eq!(cli.options.db.as_deref(), Some("/data"));
+        assert_eq!(cli.options.port, Some(9000));
+        assert_eq!(cli.options.log_level.as_deref(), Some("debug"));
+        match cli.command {
+            Some(Command::Export(export)) => assert_eq!(export.kinds, vec![1]),
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        // the form existing scripts use
+        let cli = parse("nostrd --db /data").unwrap();
+        assert_eq!(cli.options.db.as_deref(), Some("/data"));
+        assert_eq!(cli.command, None);
+
+        assert!(parse("nostrd --port").is_err());
+        assert!(parse("nostrd --port http").is_err());
+        assert!(parse("nostrd serve").is_err());
+        assert_eq!(
+            parse("nostrd -h").unwrap_err().kind(),
+            clap::error::ErrorKind::DisplayHelp
+        );
+    }
+
+    #[test]
+    fn positional_arguments_kept() {
+        // after "--", option names are only text
+        let cli = Cli::try_parse_from(["nostrd", "notice", "--", "--port"]).unwrap();
+        let notice = match cli.command {
+            Some(Command::Notice(notice)) => notice,
+            other => panic!("unexpected command {:?}", other),
+        };
+        assert_eq!(cli.options, GlobalOptions::default());
+        assert_eq!(
+            notice.command(),
+            NoticeCommand::Send(NoticeRequest {
+                message: "--port".to_owned(),
+                repeat_secs: None
+            })
+        );
+        let cli =
+            Cli::try_parse_from(["nostrd", "notice", "use -h for help", "--repeat", "60"]).unwrap();
+        match cli.command {
+            Some(Command::Notice(notice)) => assert_eq!(
+                notice.command(),
+                NoticeCommand::Send(NoticeRequest {
+                    message: "use -h for help".to_owned(),
+                    repeat_secs: Some(60)
+                })
+            ),
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn command_options_checked() {
+        let cli = parse("nostrd prune --kinds 1,7 --kinds 3 --keep-latest-replaceable").unwrap();
+        match cli.command {
+            Some(Command::Prune(prune)) => {
+                let filter = prune.filter();
+                assert_eq!(
+                    filter.kinds,
+                    vec![
+                        KindRange::Single(1),
+                        KindRange::Single(7),
+                        KindRange::Single(3)
+                    ]
+                );
+                assert!(!filter.include_latest_replaceable);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        assert!(parse("nostrd prune --vacuum").is_err());
+        assert!(parse("nostrd notice").is_err());
+        assert!(parse("nostrd notice --list --cancel 1").is_err());
+        assert!(parse("nostrd notice hello --repeat 0").is_err());
+        assert!(parse("nostrd notice --list --repeat 5").is_err());
+        assert!(parse("nostrd delete").is_err());
+        assert!(parse("nostrd delete abc").is_err());
+        assert!(parse("nostrd export --since soon").is_err());
+        let export = match parse("nostrd export").unwrap().command {
+            Some(Command::Export(export)) => export,
+            other => panic!("unexpected command {:?}", other),
+        };
+        assert_eq!(export.filter(), ExportFilter::default());
+        assert!(!parse("nostrd verify").unwrap().command.unwrap().writes());
+        assert!(parse("nostrd verify --delete-invalid")
+            .unwrap()
+            .command
+            .unwrap()
+            .writes());
+    }
+
+    #[test]
+    fn command_line_overrides_settings() {
+        let mut settings = Settings::default();
+        settings.network.listeners = Some(vec![ListenerEntry::Address("[::]:7000".to_owned())]);
+        let options = GlobalOptions {
+            db: Some("/data".to_owned()),
+            port: Some(9000),
+            ..Default::default()
+        };
+        options.apply(&mut settings);
+        assert_eq!(settings.database.data_directory, "/data");
+        assert_eq!(settings.network.port, 9000);
+        assert!(settings.network.listeners.is_none());
+        assert_eq!(
+            settings.network.address,
+            Settings::default().network.address
+        );
+    }
+}
@@ -1,12 +1,105 @@
+use crate::conn::{
+    DEFAULT_MAX_FILTERS, DEFAULT_MAX_PROTOCOL_ERRORS, DEFAULT_MAX_SUBSCRIPTIONS,
+    DEFAULT_MESSAGES_PER_SEC, DEFAULT_QUERY_RESULT_BUFFER, DEFAULT_SUBSCRIPTIONS_PER_MINUTE,
+    DEFAULT_SUBSCRIPTION_BURST, DEFAULT_THROTTLE_DISCONNECT,
+};
+use crate::db::{Compression, RetentionRule, SpoolFsync, SqlitePragmas};
+use crate::error::{Error, Result};
+use crate::firehose::DEFAULT_MAX_FIREHOSE_CONSUMERS;
+use crate::httplimit::{
+    RequestLimits, DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADER_BYTES,
+};
+use crate::logging::LogFormat;
+use crate::outbound::DEFAULT_SEND_QUEUE_SIZE;
+use crate::protocol::DEFAULT_MAX_SUBID_LENGTH;
+use crate::proxy::{IpNet, ProxyProtocol, TrustedProxies};
+use crate::ratelimit::ConnectionRate;
+use crate::stats::StatsAccess;
 use lazy_static::lazy_static;
 use log::*;
 use secp256k1::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::RwLock;
 
 // initialize a singleton default configuration
 lazy_static! {
     pub static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+    /// Configuration file named on the command line, if any
+    static ref CONFIG_FILE: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Prefix of environment variables overriding settings, as in
+/// `NOSTRD_NETWORK__PORT`
+const ENV_PREFIX: &str = "NOSTRD";
+
+/// Read settings from `path` instead of `config.toml`.
+pub fn set_config_file(path: &str) {
+    *CONFIG_FILE.write().unwrap() = Some(path.to_owned());
+}
+
+/// Whether new events are currently refused.  Initialized from
+/// `relay.read_only`, and may be changed while the relay is running.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Check if the relay is refusing new events.
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Start or stop refusing new events.
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Changed whenever settings change while the relay runs.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A number that changes whenever settings do, so that values
+/// computed from them can be recomputed.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+/// Read the configuration file again, and apply the settings that can
+/// change while the relay runs.  `overrides` are applied to the file's
+/// settings first, as they were at startup.  Returns the names of
+/// changed settings that need a restart, which keep their values.  If
+/// the file can not be read, no settings change.
+pub fn reload<F: FnOnce(&mut Settings)>(overrides: F) -> Result<Vec<String>> {
+    let mut new = Settings::new_from_default(&Settings::default())?;
+    overrides(&mut new);
+    let ignored = apply_reload(&mut SETTINGS.write().unwrap(), new, &READ_ONLY);
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+    Ok(ignored)
+}
+
+/// Apply reloaded settings, updating `read_only` only if the file's
+/// `relay.read_only` changed, so that a reload does not undo the
+/// operator turning it on or off through the admin API.
+fn apply_reload(settings: &mut Settings, new: Settings, read_only: &AtomicBool) -> Vec<String> {
+    let was_read_only = settings.relay.read_only;
+    let ignored = settings.apply_reloadable(new);
+    if settings.relay.read_only != was_read_only {
+        read_only.store(settings.relay.read_only, Ordering::Relaxed);
+    }
+    ignored
+}
+
+/// Keep the current value of a setting that can not change while the
+/// relay runs, noting its name if the new value differs.
+fn keep<T: PartialEq + Clone>(name: &str, current: &T, new: &mut T, ignored: &mut Vec<String>) {
+    if current != new {
+        ignored.push(name.to_owned());
+        *new = current.clone();
+    }
+}
+
+/// Whether two settings sections differ.
+fn section_changed<T: Serialize>(current: &T, new: &T) -> bool {
+    serde_json::to_value(current).ok() != serde_json::to_value(new).ok()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -17,12 +110,184 @@ pub struct Info {
     pub description: Option<String>,
     pub pubkey: Option<XOnlyPublicKey>,
     pub contact: Option<String>,
+    pub cors_origins: Option<Vec<String>>, // origins browsers may fetch relay info from ("*" for any); any if unset
+    pub cache_max_age_secs: u64, // how long clients may cache relay info (0 to always revalidate)
+    pub landing_page_path: Option<String>, // HTML page shown to browsers, instead of a line of text
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Database {
     pub data_directory: String,
+    pub auto_create_dir: bool,  // create the data directory if missing
+    pub min_free_space_mb: u64, // free disk space required at startup
+    pub engine: String,         // storage backend, "sqlite" or "postgres"
+    pub connection_url: Option<String>, // connection URL for the postgres engine
+    pub max_connections: usize, // connection pool size for the postgres engine
+    pub write_batch_size: usize, // max events committed in one transaction
+    pub write_batch_ms: u64,    // how long to wait for a batch to fill
+    pub maintenance_interval_seconds: u64, // how often to run maintenance (0 disables)
+    pub max_size_mb: Option<u64>, // prune or refuse events beyond this size
+    pub wal_checkpoint_mb: u64, // checkpoint when the WAL grows beyond this
+    pub stats_cache_seconds: u64, // how long computed database stats are reused
+    pub writer_restart_attempts: u32, // restarts of a failed writer before shutting down
+    pub writer_restart_seconds: u64, // delay before restarting a failed writer
+    pub shutdown_grace_seconds: u64, // how long shutdown may spend storing queued events
+    pub spool_max_mb: Option<u64>, // size of the overflow spool, disabled if unset
+    pub spool_fsync: SpoolFsync, // when spooled events are flushed to disk
+    pub recent_ids: usize,      // recently stored event ids kept for duplicate checks
+    pub keep_superseded: bool,  // hide replaced metadata/contact events instead of deleting them
+    pub log_event_source: bool, // record the IP and connection that submitted each event
+    pub event_source_max_age_seconds: Option<u64>, // delete recorded sources older than this
+    pub encryption_key_file: Option<String>, // file holding the SQLCipher key, if encrypted
+    pub pragmas: SqlitePragmas, // settings applied to every SQLite connection
+    pub compress_content: Compression, // how new event JSON is stored
+    pub compress_min_bytes: usize, // smallest event JSON that is compressed
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Verification {
+    pub enabled: bool,                  // check the NIP-05 identifiers of authors
+    pub reverify_interval_seconds: u64, // how often confirmed identifiers are checked again
+    pub fetch_timeout_seconds: u64,     // how long to wait for a domain to respond
+    pub domain_interval_ms: u64,        // minimum time between requests to one domain
+    pub max_backoff_seconds: u64,       // longest wait before retrying a failing domain
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Admin {
+    pub enabled: bool,           // serve the operator API under /admin/
+    pub token: String,           // bearer token required for every admin request
+    pub address: Option<String>, // serve it only on this address and port, instead of the relay's
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Metrics {
+    pub enabled: bool,           // serve Prometheus metrics under /metrics
+    pub address: Option<String>, // serve them only on this address and port, instead of the relay's
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Stats {
+    pub access: StatsAccess, // who may fetch /stats: "public", "admin" (with the admin token) or "off"
+    pub cache_secs: u64,     // how long a snapshot is served before another is taken
+    pub log_interval_secs: u64, // log a snapshot this often (0 to disable)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Firehose {
+    pub access: StatsAccess, // who may connect to /firehose: "public", "admin" (with the admin token) or "off"
+    pub max_consumers: usize, // firehose connections open at once (0 for unlimited)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Log {
+    pub level: Option<String>, // what to log, as for RUST_LOG, which overrides it
+    pub format: LogFormat,     // "text" or "json"
+    pub access: bool, // log HTTP requests other than websocket traffic, under the "access" target
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Mirror {
+    pub upstreams: Option<Vec<Upstream>>, // relays to copy events from
+    pub max_backoff_secs: u64,            // longest wait before reconnecting to a failing upstream
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[allow(unused)]
+pub struct Upstream {
+    pub url: String,            // websocket URL of the relay
+    pub filter: Option<String>, // JSON filter sent in the REQ; every event if unset
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Authorization {
+    pub pubkey_whitelist: Option<Vec<XOnlyPublicKey>>, // only these authors may publish events
+    pub pubkey_whitelist_file: Option<String>, // more authors who may publish, a hex pubkey per line
+    pub publish_own_only: bool, // authenticated clients may only publish their own events (NIP-42)
+}
+
+impl Authorization {
+    /// Whether only listed authors may publish.
+    pub fn is_restricted(&self) -> bool {
+        self.pubkey_whitelist.is_some() || self.pubkey_whitelist_file.is_some()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Hooks {
+    pub webhook_url: Option<String>, // POST a JSON notification here as clients connect and disconnect
+    pub webhook_retries: u32, // further attempts at a notification the endpoint did not accept
+    pub webhook_timeout_secs: u64, // how long to wait for the endpoint to respond
+    pub webhook_queue_size: usize, // notifications waiting to be delivered before more are dropped
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Runtime {
+    pub worker_threads: Option<usize>, // threads serving clients; one per CPU if unset
+    pub max_blocking_threads: Option<usize>, // threads for blocking work such as database queries; 512 if unset
+}
+
+/// Blocking threads tokio allows unless `runtime.max_blocking_threads`
+/// is set
+pub const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+impl Runtime {
+    /// Refuse thread counts the runtime can not be built with.
+    pub fn check(&self) -> Result<()> {
+        for (name, value) in [
+            ("worker_threads", self.worker_threads),
+            ("max_blocking_threads", self.max_blocking_threads),
+        ] {
+            if value == Some(0) {
+                return Err(Error::GenericError(format!(
+                    "runtime.{} must be at least 1",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// A warning if more queries may run at once than there are
+    /// blocking threads to run them, given the limit on subscriptions
+    /// across all connections, each of which runs a query.
+    pub fn blocking_threads_warning(&self, max_total_subscriptions: usize) -> Option<String> {
+        let threads = self.max_blocking_threads?;
+        if max_total_subscriptions == 0 || max_total_subscriptions > threads {
+            let queries = if max_total_subscriptions == 0 {
+                "unlimited (limits.max_total_subscriptions is 0)".to_owned()
+            } else {
+                format!(
+                    "up to {} (limits.max_total_subscriptions)",
+                    max_total_subscriptions
+                )
+            };
+            Some(format!(
+                "runtime.max_blocking_threads is {}, but concurrent queries are {}; \
+                 queries beyond {} at once wait for a thread, as does the database writer",
+                threads, queries, threads
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Relay {
+    pub read_only: bool, // serve stored events, but accept no new ones
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,6 +295,247 @@ pub struct Database {
 pub struct Network {
     pub port: u16,
     pub address: String,
+    pub listeners: Option<Vec<ListenerEntry>>, // "address:port" to listen on, or tables with their own TLS and relay URL, instead of address and port
+    pub ipv6_only: bool, // IPv6 listeners accept only IPv6 clients, rather than both stacks
+    pub idle_timeout_secs: u64, // close connections that send nothing for this long (0 to disable)
+    pub idle_counts_outbound: bool, // sending events to a client keeps it from being idle
+    pub ping_interval_secs: u64, // ping clients this often to keep connections open (0 to disable)
+    pub max_missed_pongs: u32, // close connections that leave this many pings in a row unanswered
+    pub send_queue_size: usize, // responses queued for each client before broadcast events are dropped
+    pub send_timeout_secs: u64, // disconnect clients that take longer than this to accept a message
+    pub stats_log_interval_secs: u64, // log statistics for open connections this often (0 to only log at disconnect)
+    pub shutdown_grace_secs: u64, // how long shutdown waits for clients to be told and disconnected
+    pub trusted_proxies: Option<Vec<String>>, // proxies (CIDR blocks) whose X-Forwarded-For and X-Real-IP headers are believed
+    pub proxy_protocol: ProxyProtocol, // PROXY protocol header load balancers send first ("off", "v1" or "v2")
+    pub allowed_origins: Option<Vec<String>>, // web pages (origins, "https://*.example.com" for subdomains) that may open websockets; any if unset or empty
+    pub allow_missing_origin: bool, // with allowed_origins, accept clients that send no Origin header (non-browser clients)
+    pub enable_compression: bool,   // compress messages for clients that offer permessage-deflate
+    pub tls: Option<Tls>,           // serve clients over TLS, if set
+    pub bind_retry: Option<BindRetry>, // keep trying to listen on an address still in use, if set
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Tls {
+    pub cert_path: String, // PEM certificate chain
+    pub key_path: String,  // PEM private key
+    pub port: Option<u16>, // listen for TLS here, keeping plain HTTP on the network port; TLS takes the network port if unset
+}
+
+/// An entry in `network.listeners`: an `address:port`, or a table
+/// naming the address with settings of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ListenerEntry {
+    Address(String),
+    Table(Listener),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Listener {
+    pub address: String,           // "address:port" to listen on
+    pub tls: Option<ListenerTls>, // serve only TLS here, with this certificate, rather than as network.tls says
+    pub relay_url: Option<String>, // relay information "id" for clients of this listener, instead of info.relay_url
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct ListenerTls {
+    pub cert_path: Option<String>, // PEM certificate chain
+    pub key_path: Option<String>,  // PEM private key
+}
+
+/// Certificate and key files for a TLS listener.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TlsFiles {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// A listener for clients, as resolved from the network settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientListener {
+    pub addr: SocketAddr,
+    /// Serve TLS with these files, or plain HTTP if unset
+    pub tls: Option<TlsFiles>,
+    /// Relay URL announced to clients of this listener, overriding
+    /// `info.relay_url`
+    pub relay_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct BindRetry {
+    pub attempts: u32, // further attempts after the first
+    pub delay_ms: u64, // wait between attempts
+}
+
+impl Network {
+    /// Addresses to listen on, from `listeners`, or else `address`
+    /// and `port`.
+    pub fn listen_addrs(&self) -> Result<Vec<SocketAddr>> {
+        match &self.listeners {
+            Some(listeners) if !listeners.is_empty() => listeners
+                .iter()
+                .map(|entry| parse_listener(entry.address()))
+                .collect(),
+            _ => Ok(vec![SocketAddr::new(parse_ip(&self.address)?, self.port)]),
+        }
+    }
+
+    /// Every listener for clients, in the order configured.  A
+    /// listener with TLS settings of its own serves only TLS; any
+    /// other serves plain HTTP, TLS, or both, as `tls` says.  A TLS
+    /// listener without both a certificate and a key is refused.
+    pub fn client_listeners(&self) -> Result<Vec<ClientListener>> {
+        let entries: Vec<Option<&Listener>> = match &self.listeners {
+            Some(listeners) if !listeners.is_empty() => {
+                listeners.iter().map(ListenerEntry::table).collect()
+            }
+            _ => vec![None],
+        };
+        let mut resolved = vec![];
+        for (addr, entry) in self.listen_addrs()?.into_iter().zip(entries) {
+            let relay_url = entry.and_then(|l| l.relay_url.clone());
+            if let Some(tls) = entry.and_then(|l| l.tls.as_ref()) {
+                let files = match (&tls.cert_path, &tls.key_path) {
+                    (Some(cert_path), Some(key_path)) => TlsFiles {
+                        cert_path: cert_path.clone(),
+                        key_path: key_path.clone(),
+                    },
+                    _ => {
+                        return Err(Error::GenericError(format!(
+                            "TLS listener {} needs both tls.cert_path and tls.key_path",
+                            addr
+                        )))
+                    }
+                };
+                resolved.push(ClientListener {
+                    addr,
+                    tls: Some(files),
+                    relay_url,
+                });
+                continue;
+            }
+            if self.tls.as_ref().is_none_or(|tls| tls.port.is_some()) {
+                resolved.push(ClientListener {
+                    addr,
+                    tls: None,
+                    relay_url: relay_url.clone(),
+                });
+            }
+            if let Some(tls) = &self.tls {
+                resolved.push(ClientListener {
+                    addr: SocketAddr::new(addr.ip(), tls.port.unwrap_or(addr.port())),
+                    tls: Some(TlsFiles {
+                        cert_path: tls.cert_path.clone(),
+                        key_path: tls.key_path.clone(),
+                    }),
+                    relay_url,
+                });
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// The proxies in `trusted_proxies`, refusing any block that is
+    /// not an address or CIDR block.
+    pub fn trusted_proxies(&self) -> Result<TrustedProxies> {
+        let blocks = self.trusted_proxies.as_deref().unwrap_or_default();
+        if let Some(block) = blocks.iter().find(|b| b.parse::<IpNet>().is_err()) {
+            return Err(Error::GenericError(format!(
+                "invalid network.trusted_proxies entry {:?}: expected an address or CIDR block such as 10.0.0.0/8 or fd00::/8",
+                block
+            )));
+        }
+        TrustedProxies::new(blocks)
+    }
+
+    /// Whether a websocket may be opened by a page from `origin`,
+    /// given as the `Origin` header if the client sent one.
+    pub fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        let allowed_origins = match &self.allowed_origins {
+            Some(allowed) if !allowed.is_empty() => allowed,
+            _ => return true,
+        };
+        match origin {
+            None => self.allow_missing_origin,
+            Some(origin) => allowed_origins
+                .iter()
+                .any(|allowed| origin_matches(allowed, origin)),
+        }
+    }
+}
+
+impl ListenerEntry {
+    /// The `address:port` to listen on.
+    pub fn address(&self) -> &str {
+        match self {
+            ListenerEntry::Address(address) => address,
+            ListenerEntry::Table(listener) => &listener.address,
+        }
+    }
+
+    fn table(&self) -> Option<&Listener> {
+        match self {
+            ListenerEntry::Address(_) => None,
+            ListenerEntry::Table(listener) => Some(listener),
+        }
+    }
+}
+
+/// Whether `origin` is the allowed origin, or a subdomain of it if
+/// the host starts with `*.`, as in `https://*.example.com`.
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    let allowed = allowed.trim().trim_end_matches('/').to_ascii_lowercase();
+    let origin = origin.trim().to_ascii_lowercase();
+    match allowed.split_once("://*.") {
+        Some((scheme, domain)) => origin
+            .strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .and_then(|host| host.strip_suffix(domain))
+            .and_then(|sub| sub.strip_suffix('.'))
+            .is_some_and(|sub| !sub.is_empty() && !sub.contains(['/', ':', '@'])),
+        None => allowed == origin,
+    }
+}
+
+/// Parse an address to listen on, such as `0.0.0.0`, `::` or
+/// `[::1]`.  Host names are refused rather than resolved, so the
+/// relay listens on the same addresses whatever DNS says.
+fn parse_ip(spec: &str) -> Result<IpAddr> {
+    let trimmed = spec.trim();
+    let bare = trimmed
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    bare.parse()
+        .map_err(|_| invalid_listen_address(spec, "an IP address such as 0.0.0.0 or ::"))
+}
+
+/// Parse an `address:port` to listen on, such as `0.0.0.0:8080` or
+/// `[::]:8080`.
+fn parse_listener(spec: &str) -> Result<SocketAddr> {
+    spec.trim().parse().map_err(|_| {
+        invalid_listen_address(
+            spec,
+            "an IP address and port such as 0.0.0.0:8080, with IPv6 addresses in brackets as in [::]:8080",
+        )
+    })
+}
+
+fn invalid_listen_address(spec: &str, expected: &str) -> Error {
+    let host = spec.trim().rsplit_once(':').map_or(spec, |(host, _)| host);
+    let hint = if host.chars().any(|c| c.is_ascii_alphabetic()) && !host.contains(':') {
+        " (host names are not resolved)"
+    } else {
+        ""
+    };
+    Error::GenericError(format!(
+        "invalid listen address {:?}{}: expected {}",
+        spec, hint, expected
+    ))
 }
 
 //
@@ -42,10 +548,12 @@ pub struct Options {
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Retention {
+    pub max_age_seconds: Option<u64>, // delete events older than this
+    pub max_events: Option<usize>,    // max events
+    pub protected_kinds: Option<Vec<u64>>, // kinds that are never deleted
+    pub rules: Option<Vec<RetentionRule>>, // per-kind limits, overriding the above
     // TODO: implement
-    pub max_events: Option<usize>,                // max events
     pub max_bytes: Option<usize>,                 // max size
-    pub persist_days: Option<usize>,              // oldest message
     pub whitelist_addresses: Option<Vec<String>>, // whitelisted addresses (never delete)
 }
 
@@ -57,7 +565,57 @@ pub struct Limits {
     pub max_ws_message_bytes: Option<usize>,
     pub max_ws_frame_bytes: Option<usize>,
     pub broadcast_buffer: usize, // events to buffer for subscribers (prevents slow readers from consuming memory)
-    pub event_persist_buffer: usize, // events to buffer for database commits (block senders if database writes are too slow)
+    pub event_persist_buffer: usize, // events to buffer for database commits (refuse events beyond it if database writes are too slow)
+    pub persist_enqueue_timeout_ms: u64, // wait this long for room in a full event_persist_buffer before refusing an event
+    pub max_subscriptions_per_connection: usize, // concurrent subscriptions per connection (0 for unlimited)
+    pub max_total_subscriptions: usize, // concurrent subscriptions across all connections (0 for unlimited)
+    pub max_filters: usize,             // filter objects in a single subscription (0 for unlimited)
+    pub max_subid_length: usize,        // characters in a subscription id (0 for unlimited)
+    pub subscriptions_per_min: u32, // REQ messages accepted per minute from each connection, sustained (0 for unlimited)
+    pub subscription_burst: u32,    // REQ messages accepted in a burst from each connection
+    pub query_result_buffer: usize, // query results buffered for each connection while it sends them
+    pub subscription_ttl_secs: u64, // close subscriptions this long after they are created or replaced (0 to disable)
+    pub conn_events_per_sec: u32, // EVENT messages accepted per second from each connection (0 for unlimited)
+    pub conn_requests_per_sec: u32, // REQ and CLOSE messages accepted per second from each connection (0 for unlimited)
+    pub conn_throttle_disconnect: u32, // disconnect after this many consecutive throttled messages (0 to never disconnect)
+    pub max_bytes_per_connection_per_hour: u64, // bytes sent and received by each connection per hour (0 for unlimited)
+    pub max_connections: usize, // websocket connections open at once (0 for unlimited)
+    pub connections_per_minute: u32, // websocket connections accepted per minute from each address (0 for unlimited)
+    pub connection_burst: u32,       // websocket connections accepted in a burst from each address
+    pub connection_rate_max_addresses: usize, // addresses whose connection rate is tracked at once
+    pub protocol_errors: ProtocolErrors, // when to give up on clients sending messages that cannot be handled
+    pub max_http_body_bytes: usize,      // largest body read from a plain HTTP request
+    pub max_http_headers: usize,         // headers allowed in an HTTP request
+    pub max_http_header_bytes: usize,    // total size of an HTTP request's headers
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct ProtocolErrors {
+    pub max_consecutive: u32, // close connections after this many invalid messages in a row (0 for no limit)
+    pub max_total: u32, // close connections after this many invalid messages in all (0 for no limit)
+    pub max_oversized: Option<u32>, // events over max_event_bytes in a row, instead of max_consecutive
+    pub max_binary: Option<u32>,    // binary messages in a row, instead of max_consecutive
+}
+
+impl Limits {
+    /// How often each address may connect.
+    pub fn connection_rate(&self) -> ConnectionRate {
+        ConnectionRate {
+            per_minute: self.connections_per_minute,
+            burst: self.connection_burst,
+            max_addresses: self.connection_rate_max_addresses,
+        }
+    }
+
+    /// How much of an HTTP request is read.
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_body_bytes: self.max_http_body_bytes,
+            max_headers: self.max_http_headers,
+            max_header_bytes: self.max_http_header_bytes,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -65,10 +623,21 @@ pub struct Limits {
 pub struct Settings {
     pub info: Info,
     pub database: Database,
+    pub relay: Relay,
     pub network: Network,
     pub limits: Limits,
     pub retention: Retention,
     pub options: Options,
+    pub verification: Verification,
+    pub authorization: Authorization,
+    pub admin: Admin,
+    pub metrics: Metrics,
+    pub stats: Stats,
+    pub firehose: Firehose,
+    pub log: Log,
+    pub hooks: Hooks,
+    pub mirror: Mirror,
+    pub runtime: Runtime,
 }
 
 impl Settings {
@@ -86,13 +655,108 @@ impl Settings {
         }
     }
 
+    /// Take the `info`, `limits`, `options`, `relay`, `authorization`,
+    /// `stats`, `firehose` and `log` sections from `new`, except
+    /// settings that size buffers and tasks at startup.  Returns the
+    /// names of the settings that differ but were not taken.
+    fn apply_reloadable(&mut self, mut new: Settings) -> Vec<String> {
+        let mut ignored = vec![];
+        let sections = [
+            ("database", section_changed(&self.database, &new.database)),
+            ("network", section_changed(&self.network, &new.network)),
+            (
+                "retention",
+                section_changed(&self.retention, &new.retention),
+            ),
+            (
+                "verification",
+                section_changed(&self.verification, &new.verification),
+            ),
+            ("admin", section_changed(&self.admin, &new.admin)),
+            ("metrics", section_changed(&self.metrics, &new.metrics)),
+            ("hooks", section_changed(&self.hooks, &new.hooks)),
+            ("mirror", section_changed(&self.mirror, &new.mirror)),
+            ("runtime", section_changed(&self.runtime, &new.runtime)),
+        ];
+        for (name, changed) in sections {
+            if changed {
+                ignored.push(name.to_owned());
+            }
+        }
+        let (current, limits) = (&self.limits, &mut new.limits);
+        keep(
+            "limits.messages_per_sec",
+            &current.messages_per_sec,
+            &mut limits.messages_per_sec,
+            &mut ignored,
+        );
+        keep(
+            "limits.broadcast_buffer",
+            &current.broadcast_buffer,
+            &mut limits.broadcast_buffer,
+            &mut ignored,
+        );
+        keep(
+            "limits.event_persist_buffer",
+            &current.event_persist_buffer,
+            &mut limits.event_persist_buffer,
+            &mut ignored,
+        );
+        keep(
+            "limits.max_total_subscriptions",
+            &current.max_total_subscriptions,
+            &mut limits.max_total_subscriptions,
+            &mut ignored,
+        );
+        keep(
+            "stats.log_interval_secs",
+            &self.stats.log_interval_secs,
+            &mut new.stats.log_interval_secs,
+            &mut ignored,
+        );
+        self.info = new.info;
+        self.limits = new.limits;
+        self.options = new.options;
+        self.relay = new.relay;
+        self.authorization = new.authorization;
+        self.stats = new.stats;
+        self.firehose = new.firehose;
+        self.log = new.log;
+        ignored
+    }
+
+    /// Read settings as [`Settings::new`] does, except that a
+    /// configuration file named on the command line must be readable.
+    pub fn load() -> Result<Self> {
+        if CONFIG_FILE.read().unwrap().is_some() {
+            Ok(Self::new_from_default(&Self::default())?)
+        } else {
+            Ok(Self::new())
+        }
+    }
+
     fn new_from_default(default: &Settings) -> Result<Self, config::ConfigError> {
+        let file = match CONFIG_FILE.read().unwrap().as_deref() {
+            Some(path) => config::File::new(path, config::FileFormat::Toml),
+            None => config::File::with_name("config").required(false),
+        };
+        Self::merge_file(default, file)
+    }
+
+    /// Settings from `default`, overridden by `file` and then the
+    /// environment.
+    fn merge_file<S>(default: &Settings, file: S) -> Result<Self, config::ConfigError>
+    where
+        S: config::Source + Send + Sync + 'static,
+    {
         let config: config::Config = config::Config::new();
         let settings: Settings = config
             // use defaults
             .with_merged(config::Config::try_from(default).unwrap())?
             // override with file contents
-            .with_merged(config::File::with_name("config"))?
+            .with_merged(file)?
+            // and then with the environment
+            .with_merged(config::Environment::with_prefix(ENV_PREFIX).separator("__"))?
             .try_into()?;
         Ok(settings)
     }
@@ -107,13 +771,58 @@ impl Default for Settings {
                 description: None,
                 pubkey: None,
                 contact: None,
+                cors_origins: None,
+                cache_max_age_secs: 3600,
+                landing_page_path: None,
             },
             database: Database {
                 data_directory: ".".to_owned(),
+                auto_create_dir: true,
+                min_free_space_mb: 100,
+                engine: "sqlite".to_owned(),
+                connection_url: None,
+                max_connections: 16,
+                write_batch_size: 100,
+                write_batch_ms: 0,
+                maintenance_interval_seconds: 300,
+                max_size_mb: None,
+                wal_checkpoint_mb: 64,
+                stats_cache_seconds: 60,
+                writer_restart_attempts: 3,
+                writer_restart_seconds: 5,
+                shutdown_grace_seconds: 30,
+                spool_max_mb: None,
+                spool_fsync: SpoolFsync::Never,
+                recent_ids: 100_000,
+                keep_superseded: false,
+                log_event_source: false,
+                event_source_max_age_seconds: Some(7 * 24 * 60 * 60),
+                encryption_key_file: None,
+                pragmas: SqlitePragmas::default(),
+                compress_content: Compression::Off,
+                compress_min_bytes: 1024,
             },
+            relay: Relay { read_only: false },
             network: Network {
                 port: 8080,
                 address: "0.0.0.0".to_owned(),
+                listeners: None,
+                ipv6_only: true,
+                idle_timeout_secs: 0,
+                idle_counts_outbound: false,
+                ping_interval_secs: 55,
+                max_missed_pongs: 2,
+                send_queue_size: DEFAULT_SEND_QUEUE_SIZE,
+                send_timeout_secs: 30,
+                stats_log_interval_secs: 0,
+                shutdown_grace_secs: 10,
+                trusted_proxies: None,
+                proxy_protocol: ProxyProtocol::Off,
+                allowed_origins: None,
+                allow_missing_origin: true,
+                enable_compression: false,
+                tls: None,
+                bind_retry: None,
             },
             limits: Limits {
                 messages_per_sec: None,
@@ -122,16 +831,321 @@ impl Default for Settings {
                 max_ws_frame_bytes: Some(2 << 17),   // 128K
                 broadcast_buffer: 4096,
                 event_persist_buffer: 16,
+                persist_enqueue_timeout_ms: 0,
+                max_subscriptions_per_connection: DEFAULT_MAX_SUBSCRIPTIONS,
+                max_total_subscriptions: 0,
+                max_filters: DEFAULT_MAX_FILTERS,
+                max_subid_length: DEFAULT_MAX_SUBID_LENGTH,
+                subscriptions_per_min: DEFAULT_SUBSCRIPTIONS_PER_MINUTE,
+                subscription_burst: DEFAULT_SUBSCRIPTION_BURST,
+                query_result_buffer: DEFAULT_QUERY_RESULT_BUFFER,
+                subscription_ttl_secs: 0,
+                conn_events_per_sec: DEFAULT_MESSAGES_PER_SEC,
+                conn_requests_per_sec: DEFAULT_MESSAGES_PER_SEC,
+                conn_throttle_disconnect: DEFAULT_THROTTLE_DISCONNECT,
+                max_bytes_per_connection_per_hour: 0,
+                max_connections: 0,
+                connections_per_minute: 0,
+                connection_burst: 0,
+                connection_rate_max_addresses: 100_000,
+                protocol_errors: ProtocolErrors {
+                    max_consecutive: DEFAULT_MAX_PROTOCOL_ERRORS,
+                    max_total: 0,
+                    max_oversized: None,
+                    max_binary: None,
+                },
+                max_http_body_bytes: DEFAULT_MAX_BODY_BYTES,
+                max_http_headers: DEFAULT_MAX_HEADERS,
+                max_http_header_bytes: DEFAULT_MAX_HEADER_BYTES,
             },
             retention: Retention {
+                max_age_seconds: None,     // oldest message
                 max_events: None,          // max events
+                protected_kinds: None,     // kinds that are never deleted
+                rules: None,               // per-kind limits
                 max_bytes: None,           // max size
-                persist_days: None,        // oldest message
                 whitelist_addresses: None, // whitelisted addresses (never delete)
             },
+            verification: Verification {
+                enabled: false,
+                reverify_interval_seconds: 24 * 60 * 60,
+                fetch_timeout_seconds: 10,
+                domain_interval_ms: 1000,
+                max_backoff_seconds: 24 * 60 * 60,
+            },
+            admin: Admin {
+                enabled: false,
+                token: "".to_owned(),
+                address: None,
+            },
+            metrics: Metrics {
+                enabled: false,
+                address: None,
+            },
+            stats: Stats {
+                access: StatsAccess::Public,
+                cache_secs: 5,
+                log_interval_secs: 0,
+            },
+            authorization: Authorization::default(),
+            firehose: Firehose {
+                access: StatsAccess::Off,
+                max_consumers: DEFAULT_MAX_FIREHOSE_CONSUMERS,
+            },
+            log: Log {
+                level: None,
+                format: LogFormat::Text,
+                access: true,
+            },
+            hooks: Hooks {
+                webhook_url: None,
+                webhook_retries: 3,
+                webhook_timeout_secs: 10,
+                webhook_queue_size: 1024,
+            },
+            mirror: Mirror {
+                upstreams: None,
+                max_backoff_secs: 5 * 60,
+            },
+            runtime: Runtime {
+                worker_threads: None,
+                max_blocking_threads: None,
+            },
             options: Options {
                 reject_future_seconds: Some(30 * 60), // Reject events 30min in the future or greater
             },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_keeps_startup_settings() {
+        let mut settings = Settings::default();
+        let mut new = Settings::default();
+        new.info.description = Some("changed".to_owned());
+        new.limits.max_filters = 3;
+        new.limits.broadcast_buffer += 1;
+        new.network.port += 1;
+        new.relay.read_only = true;
+        let ignored = settings.apply_reloadable(new);
+        assert_eq!(ignored, vec!["network", "limits.broadcast_buffer"]);
+        assert_eq!(settings.info.description.as_deref(), Some("changed"));
+        assert_eq!(settings.limits.max_filters, 3);
+        assert!(settings.relay.read_only);
+        assert_eq!(
+            settings.limits.broadcast_buffer,
+            Settings::default().limits.broadcast_buffer
+        );
+        assert_eq!(settings.network.port, Settings::default().network.port);
+        // changes to reloadable settings are not reported
+        assert!(settings.apply_reloadable(Settings::default()).is_empty());
+        assert!(!settings.relay.read_only);
+    }
+
+    #[test]
+    fn reload_keeps_read_only_toggle() {
+        let mut settings = Settings::default();
+        // turned on through the admin API, and kept by a reload that
+        // leaves the file's setting alone
+        let read_only = AtomicBool::new(true);
+        apply_reload(&mut settings, Settings::default(), &read_only);
+        assert!(read_only.load(Ordering::Relaxed));
+        // changing the file's setting takes effect
+        let mut new = Settings::default();
+        new.relay.read_only = true;
+        read_only.store(false, Ordering::Relaxed);
+        apply_reload(&mut settings, new, &read_only);
+        assert!(read_only.load(Ordering::Relaxed));
+        // turned off through the admin API, while the file still
+        // turns it on
+        read_only.store(false, Ordering::Relaxed);
+        let mut new = Settings::default();
+        new.relay.read_only = true;
+        apply_reload(&mut settings, new, &read_only);
+        assert!(!read_only.load(Ordering::Relaxed));
+        read_only.store(true, Ordering::Relaxed);
+        apply_reload(&mut settings, Settings::default(), &read_only);
+        assert!(!read_only.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn shipped_config_loaded() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml");
+        let file = config::File::new(path, config::FileFormat::Toml);
+        let settings = Settings::merge_file(&Settings::default(), file).unwrap();
+        // values set in the file took effect, rather than the file
+        // being dropped for a section it leaves out
+        assert_eq!(
+            settings.info.relay_url.as_deref(),
+            Some("wss://nostr.example.com/")
+        );
+        assert_eq!(settings.limits.max_event_bytes, Some(131072));
+        assert!(settings.mirror.upstreams.is_none());
+    }
+
+    #[test]
+    fn runtime_threads_checked() {
+        let mut runtime = Settings::default().runtime;
+        assert!(runtime.check().is_ok());
+        // tokio's defaults are kept
+        assert!(runtime.blocking_threads_warning(0).is_none());
+
+        runtime.max_blocking_threads = Some(64);
+        assert!(runtime.blocking_threads_warning(64).is_none());
+        assert!(runtime.blocking_threads_warning(65).is_some());
+        assert!(runtime.blocking_threads_warning(0).is_some());
+
+        runtime.worker_threads = Some(0);
+        assert!(runtime.check().is_err());
+    }
+
+    #[test]
+    fn origins_allowed() {
+        let mut network = Settings::default().network;
+        // anyone, unless restricted
+        assert!(network.origin_allowed(Some("https://evil.example.net")));
+        assert!(network.origin_allowed(None));
+
+        network.allowed_origins = Some(vec![]);
+        assert!(network.origin_allowed(Some("https://evil.example.net")));
+        network.allowed_origins = Some(vec![
+            "https://client.example.com".to_owned(),
+            "https://*.example.org/".to_owned(),
+        ]);
+        assert!(network.origin_allowed(Some("https://client.example.com")));
+        assert!(network.origin_allowed(Some("HTTPS://Client.Example.com")));
+        assert!(!network.origin_allowed(Some("http://client.example.com")));
+        assert!(!network.origin_allowed(Some("https://client.example.com:8443")));
+        assert!(!network.origin_allowed(Some("https://evil.example.net")));
+        assert!(!network.origin_allowed(Some("null")));
+        // wildcards match subdomains, but not the domain itself
+        assert!(network.origin_allowed(Some("https://app.example.org")));
+        assert!(network.origin_allowed(Some("https://a.b.example.org")));
+        assert!(!network.origin_allowed(Some("https://example.org")));
+        assert!(!network.origin_allowed(Some("https://badexample.org")));
+        assert!(!network.origin_allowed(Some("http://app.example.org")));
+        assert!(!network.origin_allowed(Some("https://app.example.org.evil.net")));
+        assert!(!network.origin_allowed(Some("https://evil.net/.example.org")));
+
+        assert!(network.origin_allowed(None));
+        network.allow_missing_origin = false;
+        assert!(!network.origin_allowed(None));
+    }
+
+    #[test]
+    fn listen_addresses_parsed() {
+        let mut network = Settings::default().network;
+        let addrs = |network: &Network| network.listen_addrs().map(|addrs| addrs[0].to_string());
+        network.port = 8080;
+        network.address = "::1".to_owned();
+        assert_eq!(addrs(&network).unwrap(), "[::1]:8080");
+        network.address = "[::1]".to_owned();
+        assert_eq!(addrs(&network).unwrap(), "[::1]:8080");
+        network.address = " 0.0.0.0 ".to_owned();
+        assert_eq!(addrs(&network).unwrap(), "0.0.0.0:8080");
+        network.address = "localhost".to_owned();
+        let err = addrs(&network).unwrap_err().to_string();
+        assert!(err.contains("host names are not resolved"), "{}", err);
+
+        let address = |spec: &str| ListenerEntry::Address(spec.to_owned());
+        network.listeners = Some(vec![address("0.0.0.0:8080"), address("[::]:8080")]);
+        let listeners = network.listen_addrs().unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners[1].is_ipv6());
+        network.listeners = Some(vec![address("::1:8080")]);
+        assert!(addrs(&network)
+            .unwrap_err()
+            .to_string()
+            .contains("brackets"));
+        network.listeners = Some(vec![address("relay.example.com:8080")]);
+        let err = addrs(&network).unwrap_err().to_string();
+        assert!(err.contains("host names are not resolved"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_trusted_proxies_named() {
+        let mut network = Settings::default().network;
+        assert!(network.trusted_proxies().is_ok());
+        network.trusted_proxies = Some(vec![
+            "10.0.0.0/8".to_owned(),
+            "::ffff:10.0.0.0/104".to_owned(),
+        ]);
+        let proxies = network.trusted_proxies().unwrap();
+        assert!(proxies.is_trusted("10.1.2.3".parse().unwrap()));
+        for bad in ["10.0.0.0/33", "localhost", "fd00::/8/8"] {
+            network.trusted_proxies = Some(vec!["127.0.0.1".to_owned(), bad.to_owned()]);
+            let err = network.trusted_proxies().unwrap_err().to_string();
+            assert!(err.contains(&format!("{:?}", bad)), "{}", err);
+            assert!(err.contains("network.trusted_proxies"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn listeners_resolved() {
+        let mut network = Settings::default().network;
+        network.port = 8080;
+        let tls = |cert: &str, key: &str| {
+            Some(TlsFiles {
+                cert_path: cert.to_owned(),
+                key_path: key.to_owned(),
+            })
+        };
+        // plain HTTP only, unless TLS is configured
+        let listeners = network.client_listeners().unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].addr.to_string(), "0.0.0.0:8080");
+        assert_eq!(listeners[0].tls, None);
+        network.tls = Some(Tls {
+            cert_path: "relay.crt".to_owned(),
+            key_path: "relay.key".to_owned(),
+            port: Some(8443),
+        });
+        let listeners = network.client_listeners().unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[1].addr.to_string(), "0.0.0.0:8443");
+        assert_eq!(listeners[1].tls, tls("relay.crt", "relay.key"));
+
+        // a listener with TLS of its own serves only that
+        network.tls = None;
+        network.listeners = Some(vec![
+            ListenerEntry::Address("127.0.0.1:8080".to_owned()),
+            ListenerEntry::Table(Listener {
+                address: "0.0.0.0:443".to_owned(),
+                tls: Some(ListenerTls {
+                    cert_path: Some("public.crt".to_owned()),
+                    key_path: Some("public.key".to_owned()),
+                }),
+                relay_url: Some("wss://relay.example.com/".to_owned()),
+            }),
+        ]);
+        let listeners = network.client_listeners().unwrap();
+        assert_eq!(
+            listeners,
+            vec![
+                ClientListener {
+                    addr: "127.0.0.1:8080".parse().unwrap(),
+                    tls: None,
+                    relay_url: None,
+                },
+                ClientListener {
+                    addr: "0.0.0.0:443".parse().unwrap(),
+                    tls: tls("public.crt", "public.key"),
+                    relay_url: Some("wss://relay.example.com/".to_owned()),
+                },
+            ]
+        );
+
+        if let Some(ListenerEntry::Table(listener)) =
+            network.listeners.as_mut().and_then(|l| l.get_mut(1))
+        {
+            listener.tls.as_mut().unwrap().key_path = None;
+        }
+        let err = network.client_listeners().unwrap_err().to_string();
+        assert!(err.contains("TLS listener 0.0.0.0:443"), "{}", err);
+        assert!(err.contains("key_path"), "{}", err);
+    }
+}
@@ -1,25 +1,661 @@
 //! Client connection state
+use crate::config::ProtocolErrors;
+use crate::db::{retention, WriteResult};
 use crate::error::Error;
 use crate::error::Result;
+use crate::protocol::auth;
 use crate::protocol::Close;
 use crate::protocol::Event;
 
-use crate::protocol::{Subscription, SubscriptionId};
+use crate::protocol::{Subscription, SubscriptionId, SubscriptionIndex};
+use governor::clock::DefaultClock;
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
 use log::*;
+use secp256k1::XOnlyPublicKey;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-/// A subscription identifier has a maximum length
-const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
+/// Concurrent subscriptions allowed per connection, unless configured
+pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 32;
+
+/// Filter objects allowed in a single subscription, unless configured
+pub const DEFAULT_MAX_FILTERS: usize = 10;
+
+/// Subscriptions a connection may create per minute, sustained,
+/// unless configured
+pub const DEFAULT_SUBSCRIPTIONS_PER_MINUTE: u32 = 20;
+
+/// Subscriptions a connection may create in a burst, unless configured
+pub const DEFAULT_SUBSCRIPTION_BURST: u32 = 60;
+
+/// Query results buffered for each connection, unless configured
+pub const DEFAULT_QUERY_RESULT_BUFFER: usize = 256;
+
+/// How long the query result buffer may stay full before the client
+/// is told it is reading too slowly
+const QUERY_BACKLOG_NOTICE_AFTER: Duration = Duration::from_secs(10);
+
+/// Longest header value kept from a client's upgrade request, in
+/// characters
+const MAX_HEADER_CHARS: usize = 256;
+
+/// Notices about closing unknown subscriptions sent per minute, so a
+/// client repeating a bad CLOSE cannot amplify it into traffic
+const UNKNOWN_CLOSE_NOTICES_PER_MINUTE: u32 = 10;
+
+/// Inbound messages allowed per second on each connection, unless
+/// configured
+pub const DEFAULT_MESSAGES_PER_SEC: u32 = 50;
+
+/// Consecutive throttled messages after which a client is
+/// disconnected, unless configured
+pub const DEFAULT_THROTTLE_DISCONNECT: u32 = 500;
+
+/// Malformed messages in a row after which a client is disconnected,
+/// unless configured
+pub const DEFAULT_MAX_PROTOCOL_ERRORS: u32 = 20;
+
+/// Period over which a connection's bandwidth is capped
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+/// A per-second limiter, or `None` if `rate` is zero.
+fn per_second(rate: u32) -> Option<DirectLimiter> {
+    NonZeroU32::new(rate).map(|r| RateLimiter::direct(Quota::per_second(r)))
+}
+
+/// A per-minute limiter allowing bursts of `burst`, or `None` if
+/// `rate` is zero.  A zero burst is the same as `rate`.
+fn per_minute(rate: u32, burst: u32) -> Option<DirectLimiter> {
+    NonZeroU32::new(rate).map(|r| {
+        let burst = NonZeroU32::new(burst).unwrap_or(r);
+        RateLimiter::direct(Quota::per_minute(r).allow_burst(burst))
+    })
+}
+
+/// Classes of client message with separate rate limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// `EVENT`
+    Event,
+    /// `REQ` and `CLOSE`
+    Request,
+}
+
+/// What to do with a message from a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    /// Handle the message
+    Allow,
+    /// Drop the message, telling the client if `notify` is set
+    Drop { notify: bool },
+    /// Drop the message and disconnect the client
+    Disconnect,
+}
+
+/// Rate limits on the messages a single client sends.
+pub struct InboundLimiter {
+    events: Option<DirectLimiter>,
+    requests: Option<DirectLimiter>,
+    /// Consecutive throttled messages allowed before disconnecting,
+    /// 0 for never
+    disconnect_after: u32,
+    /// Messages throttled since one was last allowed
+    consecutive: u32,
+    /// Throttled `EVENT` messages over the connection's lifetime
+    pub throttled_events: u64,
+    /// Throttled `REQ` and `CLOSE` messages over the connection's
+    /// lifetime
+    pub throttled_requests: u64,
+}
+
+impl InboundLimiter {
+    /// Create a limiter allowing `events_per_sec` events and
+    /// `requests_per_sec` requests, either unlimited if zero.
+    pub fn new(events_per_sec: u32, requests_per_sec: u32, disconnect_after: u32) -> Self {
+        InboundLimiter {
+            events: per_second(events_per_sec),
+            requests: per_second(requests_per_sec),
+            disconnect_after,
+            consecutive: 0,
+            throttled_events: 0,
+            throttled_requests: 0,
+        }
+    }
+
+    /// Decide what to do with a newly received message.  Only the
+    /// first message throttled after one was allowed is reported to
+    /// the client.
+    pub fn check(&mut self, kind: MessageKind) -> Throttle {
+        let limiter = match kind {
+            MessageKind::Event => &self.events,
+            MessageKind::Request => &self.requests,
+        };
+        if limiter.as_ref().is_none_or(|l| l.check().is_ok()) {
+            self.consecutive = 0;
+            return Throttle::Allow;
+        }
+        match kind {
+            MessageKind::Event => self.throttled_events += 1,
+            MessageKind::Request => self.throttled_requests += 1,
+        }
+        self.consecutive += 1;
+        if self.disconnect_after > 0 && self.consecutive > self.disconnect_after {
+            Throttle::Disconnect
+        } else {
+            Throttle::Drop {
+                notify: self.consecutive == 1,
+            }
+        }
+    }
+}
+
+/// How a subscription relates to an existing one with the same id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replaced {
+    /// There was no subscription with the same id
+    No,
+    /// A subscription with different filters was replaced
+    Changed,
+    /// The existing subscription has the same filters, and is kept
+    Identical,
+}
+
+impl Replaced {
+    /// Whether stored events must be queried for the subscription.
+    /// An identical subscription has already had them.
+    pub fn needs_query(self) -> bool {
+        self != Replaced::Identical
+    }
+}
+
+/// Limits the bytes a connection transfers in each hour, counting
+/// both directions.
+#[derive(Debug)]
+pub struct BandwidthCap {
+    /// Bytes allowed per hour, 0 for unlimited
+    max_per_hour: u64,
+    /// When the current hour started
+    window_start: Instant,
+    /// Bytes transferred before the current hour
+    window_base: u64,
+}
+
+impl BandwidthCap {
+    /// Create a cap of `max_per_hour` bytes, unlimited if zero.
+    pub fn new(max_per_hour: u64) -> Self {
+        BandwidthCap {
+            max_per_hour,
+            window_start: Instant::now(),
+            window_base: 0,
+        }
+    }
+
+    /// Whether a cap is set.
+    pub fn is_enabled(&self) -> bool {
+        self.max_per_hour > 0
+    }
+
+    /// Check whether the connection, having transferred `total` bytes
+    /// since it opened, is over the cap for the current hour.
+    pub fn exceeded(&mut self, total: u64) -> bool {
+        self.exceeded_at(total, Instant::now())
+    }
+
+    fn exceeded_at(&mut self, total: u64, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        if now.duration_since(self.window_start) >= BANDWIDTH_WINDOW {
+            self.window_start = now;
+            self.window_base = total;
+        }
+        total.saturating_sub(self.window_base) > self.max_per_hour
+    }
+}
+
+/// What to do when a keepalive ping is due.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PingAction {
+    /// Send a ping with this payload
+    Send(Vec<u8>),
+    /// The client missed too many pongs in a row
+    Dead,
+}
+
+/// Pings a client periodically, closing the connection if it stops
+/// answering, and measures the round trip time.
+#[derive(Debug)]
+pub struct Keepalive {
+    /// Consecutive unanswered pings before the client is considered
+    /// gone
+    max_missed: u32,
+    /// Sequence number of the last ping sent
+    seq: u64,
+    /// The ping waiting for a pong, with when it was sent
+    outstanding: Option<(u64, Instant)>,
+    /// Pings in a row that were not answered
+    missed: u32,
+    /// Round trip time of the last answered ping
+    rtt: Option<Duration>,
+}
+
+impl Keepalive {
+    /// Create a keepalive that gives up after `max_missed` unanswered
+    /// pings.
+    pub fn new(max_missed: u32) -> Self {
+        Keepalive {
+            max_missed: max_missed.max(1),
+            seq: 0,
+            outstanding: None,
+            missed: 0,
+            rtt: None,
+        }
+    }
+
+    /// Round trip time of the last answered ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Decide what to do now that a ping is due.
+    pub fn tick(&mut self) -> PingAction {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> PingAction {
+        if self.outstanding.is_some() {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return PingAction::Dead;
+            }
+        }
+        self.seq += 1;
+        self.outstanding = Some((self.seq, now));
+        PingAction::Send(self.seq.to_be_bytes().to_vec())
+    }
+
+    /// Record a pong from the client, received at `at`.  Any pong
+    /// shows the client is alive; one answering the outstanding ping
+    /// also gives the round trip time.
+    pub fn pong(&mut self, payload: &[u8], at: Instant) {
+        self.missed = 0;
+        if let Some((seq, sent)) = self.outstanding {
+            if payload == seq.to_be_bytes() {
+                self.rtt = Some(at.saturating_duration_since(sent));
+                self.outstanding = None;
+            }
+        }
+    }
+}
+
+/// Watches how full a connection's buffer of query results gets, to
+/// spot clients that read results more slowly than they are found.
+#[derive(Debug)]
+pub struct QueryBacklog {
+    /// Results the buffer holds
+    capacity: usize,
+    /// When the buffer was first seen full, if it has been since it
+    /// was last seen with room
+    full_since: Option<Instant>,
+    /// Whether the client has been told
+    notified: bool,
+}
+
+impl QueryBacklog {
+    /// Watch a buffer holding `capacity` results.
+    pub fn new(capacity: usize) -> Self {
+        QueryBacklog {
+            capacity,
+            full_since: None,
+            notified: false,
+        }
+    }
+
+    /// Record the number of results buffered, returning true the
+    /// first time the buffer has stayed full for too long.
+    pub fn observe(&mut self, depth: usize) -> bool {
+        self.observe_at(depth, Instant::now())
+    }
+
+    fn observe_at(&mut self, depth: usize, now: Instant) -> bool {
+        if depth < self.capacity {
+            self.full_since = None;
+            return false;
+        }
+        let since = *self.full_since.get_or_insert(now);
+        if self.notified || now.duration_since(since) < QUERY_BACKLOG_NOTICE_AFTER {
+            return false;
+        }
+        self.notified = true;
+        true
+    }
+}
+
+/// Active subscriptions across all connections, with an optional
+/// ceiling.  Each [`ClientConn`] returns its subscriptions when it
+/// is dropped, so the count stays accurate however a connection ends.
+#[derive(Debug, Default)]
+pub struct SubscriptionBudget {
+    active: AtomicUsize,
+    /// Most subscriptions allowed, 0 for unlimited
+    max: usize,
+    /// Whether the ceiling has been reached since it was last logged
+    saturated: AtomicBool,
+}
+
+impl SubscriptionBudget {
+    /// Create a budget allowing `max` subscriptions, or any number if
+    /// zero.
+    pub fn new(max: usize) -> Self {
+        SubscriptionBudget {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Number of subscriptions currently active.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Take a subscription from the budget, if any are left.
+    fn acquire(&self) -> bool {
+        let max = self.max;
+        let acquired = self
+            .active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (max == 0 || n < max).then_some(n + 1)
+            })
+            .is_ok();
+        if acquired {
+            self.saturated.store(false, Ordering::Relaxed);
+        } else if !self.saturated.swap(true, Ordering::Relaxed) {
+            warn!("relay subscription capacity ({}) reached", max);
+        }
+        acquired
+    }
+
+    /// Return `count` subscriptions to the budget.
+    fn release(&self, count: usize) {
+        self.active.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+/// Headers from a client's websocket upgrade request that identify
+/// its software, sanitized for logging.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientHeaders {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Requested `Sec-WebSocket-Protocol`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<String>,
+}
+
+impl ClientHeaders {
+    /// Take the identifying headers from an upgrade request.
+    pub fn from_headers(headers: &hyper::HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .map(|v| sanitize_text(v.as_bytes(), MAX_HEADER_CHARS))
+        };
+        ClientHeaders {
+            user_agent: get(hyper::header::USER_AGENT),
+            origin: get(hyper::header::ORIGIN),
+            protocol: get(hyper::header::SEC_WEBSOCKET_PROTOCOL),
+        }
+    }
+}
+
+/// Lists the headers present, each preceded by a space, for
+/// appending to a log line.
+impl fmt::Display for ClientHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("user_agent", &self.user_agent),
+            ("origin", &self.origin),
+            ("protocol", &self.protocol),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                write!(f, " {}: {:?}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Make text from a client safe to log: invalid UTF-8 and control
+/// characters are replaced, and it is cut to `max_chars`.
+pub(crate) fn sanitize_text(value: &[u8], max_chars: usize) -> String {
+    String::from_utf8_lossy(value)
+        .chars()
+        .take(max_chars)
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
+
+/// Activity counters for a single connection
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnStats {
+    /// Events the client submitted
+    pub events_published: u64,
+    /// Submitted events that were stored or queued for storage
+    pub events_accepted: u64,
+    /// Submitted events that were refused or failed to store
+    pub events_rejected: u64,
+    /// Submitted events that were already stored
+    pub events_duplicate: u64,
+    /// Events sent as results of the client's queries
+    pub query_events_sent: u64,
+    /// Events sent as they were broadcast to the client's subscriptions
+    pub broadcast_events_sent: u64,
+    /// Broadcast events missed by falling behind the broadcast channel
+    pub broadcast_events_missed: u64,
+    /// Bytes of websocket frames received
+    pub bytes_in: u64,
+    /// Bytes of websocket frames sent
+    pub bytes_out: u64,
+    /// Bytes permessage-deflate kept off the connection, both ways
+    pub bytes_saved_by_compression: u64,
+    /// Subscriptions the client opened or replaced
+    pub subscriptions_created: u64,
+    /// Subscriptions the client closed
+    pub subscriptions_closed: u64,
+    /// Notices sent to the client
+    pub notices_sent: u64,
+    /// Most query results waiting to be sent at once
+    pub query_results_peak: u64,
+    /// Round trip time of the last keepalive ping, in milliseconds
+    pub ping_rtt_ms: Option<u64>,
+    /// Messages that could not be handled, such as malformed JSON
+    pub protocol_errors: u64,
+    /// Messages that could not be handled since the last one that
+    /// could
+    pub consecutive_protocol_errors: u64,
+}
+
+impl ConnStats {
+    /// Record the number of query results waiting to be sent.
+    pub fn record_query_depth(&mut self, depth: usize) {
+        self.query_results_peak = self.query_results_peak.max(depth as u64);
+    }
+
+    /// Count broadcast events missed by falling behind, returning the
+    /// notice to send the client.
+    pub fn record_broadcast_lag(&mut self, skipped: u64) -> String {
+        self.broadcast_events_missed += skipped;
+        self.notices_sent += 1;
+        format!(
+            "missed {} event(s) by falling behind, re-subscribe to catch up",
+            skipped
+        )
+    }
+
+    /// Count a message that could not be handled, returning why the
+    /// connection should be closed if `policy` allows no more.
+    pub fn record_protocol_error(
+        &mut self,
+        kind: ProtocolErrorKind,
+        policy: &ProtocolErrors,
+    ) -> Option<String> {
+        self.protocol_errors += 1;
+        self.consecutive_protocol_errors += 1;
+        let (setting, limit) = match kind {
+            ProtocolErrorKind::Oversized if policy.max_oversized.is_some() => {
+                ("max_oversized", policy.max_oversized)
+            }
+            ProtocolErrorKind::Binary if policy.max_binary.is_some() => {
+                ("max_binary", policy.max_binary)
+            }
+            _ => ("max_consecutive", Some(policy.max_consecutive)),
+        };
+        let exceeded = |count: u64, limit: u32| limit > 0 && count >= u64::from(limit);
+        if let Some(limit) = limit.filter(|&l| exceeded(self.consecutive_protocol_errors, l)) {
+            Some(format!(
+                "{} {} in a row (limits.protocol_errors.{})",
+                limit,
+                kind.describe(),
+                setting
+            ))
+        } else if exceeded(self.protocol_errors, policy.max_total) {
+            Some(format!(
+                "{} invalid message(s) (limits.protocol_errors.max_total)",
+                policy.max_total
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Note a message that was handled, ending any run of errors.
+    pub fn record_valid_message(&mut self) {
+        self.consecutive_protocol_errors = 0;
+    }
+
+    /// Count the outcome of an event the client submitted.
+    pub fn record_write(&mut self, result: &WriteResult) {
+        match result {
+            WriteResult::Persisted | WriteResult::Queued => self.events_accepted += 1,
+            WriteResult::Duplicate => self.events_duplicate += 1,
+            WriteResult::Rejected(_)
+            | WriteResult::Restricted(_)
+            | WriteResult::AuthRequired(_)
+            | WriteResult::Error(_)
+            | WriteResult::Overloaded => self.events_rejected += 1,
+        }
+    }
+}
+
+/// Classes of message a client can get wrong, which
+/// `limits.protocol_errors` may allow different numbers of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// Not a valid Nostr message
+    Malformed,
+    /// An event larger than `limits.max_event_bytes`
+    Oversized,
+    /// A binary websocket message, where text is expected
+    Binary,
+}
+
+impl ProtocolErrorKind {
+    fn describe(self) -> &'static str {
+        match self {
+            ProtocolErrorKind::Malformed => "invalid message(s)",
+            ProtocolErrorKind::Oversized => "oversized event(s)",
+            ProtocolErrorKind::Binary => "binary message(s)",
+        }
+    }
+}
+
+impl fmt::Display for ConnStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "published {} (accepted {}, rejected {}, duplicate {}), \
+             sent {} from queries and {} from broadcasts, \
+             {} bytes in, {} bytes out, \
+             subscriptions {} opened and {} closed, {} notices, \
+             at most {} query results buffered",
+            self.events_published,
+            self.events_accepted,
+            self.events_rejected,
+            self.events_duplicate,
+            self.query_events_sent,
+            self.broadcast_events_sent,
+            self.bytes_in,
+            self.bytes_out,
+            self.subscriptions_created,
+            self.subscriptions_closed,
+            self.notices_sent,
+            self.query_results_peak
+        )?;
+        if self.broadcast_events_missed > 0 {
+            write!(f, ", {} broadcasts missed", self.broadcast_events_missed)?;
+        }
+        if self.bytes_saved_by_compression > 0 {
+            write!(
+                f,
+                ", {} bytes saved by compression",
+                self.bytes_saved_by_compression
+            )?;
+        }
+        if let Some(rtt) = self.ping_rtt_ms {
+            write!(f, ", ping {} ms", rtt)?;
+        }
+        if self.protocol_errors > 0 {
+            write!(f, ", {} invalid messages", self.protocol_errors)?;
+        }
+        Ok(())
+    }
+}
 
 /// State for a client connection
 pub struct ClientConn {
     /// Unique client identifier generated at connection time
     client_id: Uuid,
+    /// Address of the connected peer, if known
+    remote_addr: Option<SocketAddr>,
+    /// Headers identifying the client software
+    headers: ClientHeaders,
     /// The current set of active client subscriptions
     subscriptions: HashMap<SubscriptionId, Subscription>,
-    /// Per-connection maximum concurrent subscriptions
+    /// When each subscription was created or last replaced
+    subscribed_at: HashMap<SubscriptionId, Instant>,
+    /// Index of the subscriptions, for matching events
+    index: SubscriptionIndex,
+    /// How long a subscription lasts, zero for indefinitely
+    subscription_ttl: Duration,
+    /// Per-connection maximum concurrent subscriptions, 0 for unlimited
     max_subs: usize,
+    /// Limits how quickly subscriptions are created, if set
+    sub_rate_limiter: Option<DirectLimiter>,
+    /// Maximum filters in a subscription, 0 for unlimited
+    max_filters: usize,
+    /// Limits notices sent for CLOSE of unknown subscriptions
+    unknown_close_limiter: DirectLimiter,
+    /// Activity on this connection
+    stats: ConnStats,
+    /// Subscriptions available across the relay
+    budget: Arc<SubscriptionBudget>,
+    /// The challenge the client signs to authenticate (NIP-42)
+    auth_challenge: String,
+    /// The key the client authenticated with, if it has
+    auth_pubkey: Option<XOnlyPublicKey>,
 }
 
 impl Default for ClientConn {
@@ -31,72 +667,694 @@ impl Default for ClientConn {
 impl ClientConn {
     /// Create a new, empty connection state.
     pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_SUBSCRIPTIONS, DEFAULT_MAX_FILTERS)
+    }
+
+    /// Create a new, empty connection state allowing up to `max_subs`
+    /// concurrent subscriptions of up to `max_filters` filters each.
+    /// A limit of zero allows any number.
+    pub fn with_limits(max_subs: usize, max_filters: usize) -> Self {
         let client_id = Uuid::new_v4();
         ClientConn {
             client_id,
+            remote_addr: None,
+            headers: ClientHeaders::default(),
             subscriptions: HashMap::new(),
-            max_subs: 32,
+            subscribed_at: HashMap::new(),
+            index: SubscriptionIndex::default(),
+            subscription_ttl: Duration::ZERO,
+            max_subs,
+            sub_rate_limiter: None,
+            max_filters,
+            unknown_close_limiter: RateLimiter::direct(Quota::per_minute(
+                NonZeroU32::new(UNKNOWN_CLOSE_NOTICES_PER_MINUTE).unwrap(),
+            )),
+            stats: ConnStats::default(),
+            budget: Arc::new(SubscriptionBudget::default()),
+            auth_challenge: auth::challenge(),
+            auth_pubkey: None,
         }
     }
 
+    /// Limit subscription creation to `rate` per minute sustained,
+    /// with bursts of up to `burst`.  A rate of zero is unlimited.
+    pub fn with_subscription_rate(mut self, rate: u32, burst: u32) -> Self {
+        self.sub_rate_limiter = per_minute(rate, burst);
+        self
+    }
+
+    /// Expire subscriptions `ttl` after they are created or last
+    /// replaced.  A ttl of zero keeps them until closed.
+    pub fn with_subscription_ttl(mut self, ttl: Duration) -> Self {
+        self.subscription_ttl = ttl;
+        self
+    }
+
+    /// Share a budget of subscriptions with other connections.
+    pub fn with_budget(mut self, budget: Arc<SubscriptionBudget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Record the address the client connected from.
+    pub fn with_remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote_addr = Some(addr);
+        self
+    }
+
+    /// Get the client's unique identifier.
+    pub fn client_id(&self) -> Uuid {
+        self.client_id
+    }
+
+    /// Get the number of active subscriptions.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    /// Record the headers identifying the client software.
+    pub fn with_headers(mut self, headers: ClientHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Get the headers identifying the client software.
+    pub fn headers(&self) -> &ClientHeaders {
+        &self.headers
+    }
+
     /// Get a short prefix of the client's unique identifier, suitable
     /// for logging.
     pub fn get_client_prefix(&self) -> String {
         self.client_id.to_string().chars().take(8).collect()
     }
 
-    /// Find all matching subscriptions.
-    pub fn get_matching_subscriptions(&self, e: &Event) -> Vec<&SubscriptionId> {
-        let mut v: Vec<&SubscriptionId> = vec![];
-        for (id, sub) in self.subscriptions.iter() {
-            if sub.interested_in_event(e) {
-                v.push(id);
+    /// Get the address the client connected from.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Get the challenge the client signs to authenticate.
+    pub fn auth_challenge(&self) -> &str {
+        &self.auth_challenge
+    }
+
+    /// Authenticate the client with an `AUTH` event answering its
+    /// challenge, for the relay at `relay_url` if known.  Returns the
+    /// key it authenticated with, which replaces any earlier one.
+    pub fn authenticate(
+        &mut self,
+        event: &Event,
+        relay_url: Option<&str>,
+    ) -> Result<XOnlyPublicKey> {
+        let pubkey =
+            auth::authenticated_pubkey(event, &self.auth_challenge, relay_url, retention::now())?;
+        self.auth_pubkey = Some(pubkey);
+        Ok(pubkey)
+    }
+
+    /// Get the key the client authenticated with, if it has.
+    pub fn auth_pubkey(&self) -> Option<&XOnlyPublicKey> {
+        self.auth_pubkey.as_ref()
+    }
+
+    /// Whether `event` was signed by the key the client authenticated
+    /// with, or delegated to it by that key (NIP-26).
+    pub fn is_own_event(&self, event: &Event) -> bool {
+        match &self.auth_pubkey {
+            Some(pubkey) => {
+                event.get_pubkey() == pubkey || event.get_delegator().as_ref() == Some(pubkey)
             }
+            None => false,
+        }
+    }
+
+    /// Get the activity counters for this connection.
+    pub fn stats(&self) -> &ConnStats {
+        &self.stats
+    }
+
+    /// Get the activity counters for this connection, for updating.
+    pub fn stats_mut(&mut self) -> &mut ConnStats {
+        &mut self.stats
+    }
+
+    /// Describe the client for logging, as `client: <prefix>` followed
+    /// by `addr: <ip>:<port>` if the address is known.
+    pub fn log_label(&self) -> String {
+        match self.remote_addr {
+            Some(addr) => format!("client: {} addr: {}", self.get_client_prefix(), addr),
+            None => format!("client: {}", self.get_client_prefix()),
         }
-        v
     }
 
-    /// Add a new subscription for this connection.
-    pub fn subscribe(&mut self, s: Subscription) -> Result<()> {
+    /// Find all matching subscriptions.  Only those the index
+    /// selects as candidates are checked in full.
+    pub fn get_matching_subscriptions(&self, e: &Event) -> Vec<&SubscriptionId> {
+        self.index
+            .candidates(e)
+            .into_iter()
+            .filter(|id| {
+                self.subscriptions
+                    .get(*id)
+                    .is_some_and(|sub| sub.interested_in_event(e))
+            })
+            .collect()
+    }
+
+    /// Add a new subscription for this connection, reporting whether
+    /// it replaced an existing one.
+    pub fn subscribe(&mut self, s: Subscription) -> Result<Replaced> {
+        // identifiers were checked when the request was parsed
         let subs_id = s.get_id().clone();
-        let sub_id_len = subs_id.len();
-        // prevent arbitrarily long subscription identifiers from
-        // being used.
-        if sub_id_len > MAX_SUBSCRIPTION_ID_LEN {
-            info!(
-                "ignoring sub request with excessive length: ({})",
-                sub_id_len
-            );
-            return Err(Error::SubIdMaxLengthError);
+        let filter_count = s.get_filters().len();
+        if filter_count == 0 {
+            return Err(Error::SubNoFiltersError);
+        }
+        if self.max_filters > 0 && filter_count > self.max_filters {
+            return Err(Error::SubMaxFiltersError(self.max_filters));
+        }
+        // replacing a subscription counts too, since paging through
+        // results that way is as costly as opening new ones
+        if let Some(limiter) = &self.sub_rate_limiter {
+            if limiter.check().is_err() {
+                return Err(Error::SubRateLimitedError);
+            }
         }
         // check if an existing subscription exists, and replace if so
-        if self.subscriptions.contains_key(&subs_id) {
-            self.subscriptions.remove(&subs_id);
-            self.subscriptions.insert(subs_id, s);
+        if let Some(old) = self.subscriptions.get_mut(&subs_id) {
+            self.stats.subscriptions_created += 1;
+            self.subscribed_at.insert(subs_id, Instant::now());
+            if old.has_same_filters(&s) {
+                debug!("subscription repeated with identical filters");
+                return Ok(Replaced::Identical);
+            }
+            self.index.remove(old);
+            self.index.insert(&s);
+            *old = s;
             debug!("replaced existing subscription");
-            return Ok(());
+            return Ok(Replaced::Changed);
         }
 
         // check if there is room for another subscription.
-        if self.subscriptions.len() >= self.max_subs {
+        if self.max_subs > 0 && self.subscriptions.len() >= self.max_subs {
             return Err(Error::SubMaxExceededError);
         }
+        if !self.budget.acquire() {
+            return Err(Error::SubRelayCapacityError);
+        }
         // add subscription
+        self.index.insert(&s);
+        self.subscribed_at.insert(subs_id.clone(), Instant::now());
         self.subscriptions.insert(subs_id, s);
+        self.stats.subscriptions_created += 1;
         debug!(
             "registered new subscription, currently have {} active subs",
             self.subscriptions.len()
         );
-        Ok(())
+        Ok(Replaced::No)
     }
 
-    /// Remove the subscription for this connection.
-    pub fn unsubscribe(&mut self, c: Close) {
-        // TODO: return notice if subscription did not exist.
-        self.subscriptions.remove(&c.id);
+    /// Remove the subscription for this connection, returning whether
+    /// it existed.
+    pub fn unsubscribe(&mut self, c: &Close) -> bool {
+        if !self.remove_subscription(&c.id) {
+            debug!("close requested for unknown subscription");
+            return false;
+        }
         debug!(
             "removed subscription, currently have {} active subs",
             self.subscriptions.len()
         );
+        true
+    }
+
+    /// Remove subscriptions that have outlived the subscription ttl,
+    /// returning their ids.
+    pub fn expire_subscriptions(&mut self) -> Vec<SubscriptionId> {
+        self.expire_subscriptions_at(Instant::now())
+    }
+
+    fn expire_subscriptions_at(&mut self, now: Instant) -> Vec<SubscriptionId> {
+        if self.subscription_ttl.is_zero() {
+            return vec![];
+        }
+        let expired: Vec<SubscriptionId> = self
+            .subscribed_at
+            .iter()
+            .filter(|(_, at)| now.saturating_duration_since(**at) >= self.subscription_ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.remove_subscription(id);
+        }
+        expired
+    }
+
+    /// Remove a subscription and release its place in the budget,
+    /// returning whether it existed.
+    fn remove_subscription(&mut self, id: &SubscriptionId) -> bool {
+        let sub = match self.subscriptions.remove(id) {
+            Some(sub) => sub,
+            None => return false,
+        };
+        self.index.remove(&sub);
+        self.subscribed_at.remove(id);
+        self.stats.subscriptions_closed += 1;
+        self.budget.release(1);
+        true
+    }
+
+    /// Check whether a notice about closing an unknown subscription
+    /// may be sent now.  Subscription identifiers have already been
+    /// parsed as hashes, so only the rate needs checking.
+    pub fn allow_unknown_close_notice(&self) -> bool {
+        self.unknown_close_limiter.check().is_ok()
+    }
+}
+
+impl Drop for ClientConn {
+    fn drop(&mut self) {
+        self.budget.release(self.subscriptions.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn sub_with_filters(id: usize, filters: usize) -> Subscription {
+        let mut req = vec![
+            serde_json::json!("REQ"),
+            serde_json::json!(format!("sub{}", id)),
+        ];
+        req.extend((0..filters).map(|n| serde_json::json!({ "since": n })));
+        serde_json::from_value(serde_json::Value::Array(req)).unwrap()
+    }
+
+    fn sub(id: usize) -> Subscription {
+        sub_with_filters(id, 1)
+    }
+
+    #[test]
+    fn sub_max_exceeded() {
+        for limit in [1, DEFAULT_MAX_SUBSCRIPTIONS] {
+            let mut conn = ClientConn::with_limits(limit, DEFAULT_MAX_FILTERS);
+            for id in 0..limit {
+                conn.subscribe(sub(id)).unwrap();
+            }
+            assert!(matches!(
+                conn.subscribe(sub(limit)),
+                Err(Error::SubMaxExceededError)
+            ));
+            // replacing an existing subscription is always allowed
+            conn.subscribe(sub(0)).unwrap();
+        }
+    }
+
+    #[test]
+    fn filters_required() {
+        let mut conn = ClientConn::new();
+        assert!(matches!(
+            conn.subscribe(sub_with_filters(0, 0)),
+            Err(Error::SubNoFiltersError)
+        ));
+        assert_eq!(conn.subscription_count(), 0);
+    }
+
+    #[test]
+    fn unauthenticated_owns_nothing() {
+        let mut conn = ClientConn::new();
+        assert_ne!(conn.auth_challenge(), ClientConn::new().auth_challenge());
+        let note = crate::test_util::valid_event();
+        assert!(!conn.is_own_event(&note));
+        // an event that does not answer the challenge changes nothing
+        assert!(matches!(
+            conn.authenticate(&note, None),
+            Err(Error::AuthFailed(_))
+        ));
+        assert_eq!(conn.auth_pubkey(), None);
+        assert!(!conn.is_own_event(&note));
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let mut conn = ClientConn::with_limits(0, 0);
+        for id in 0..100 {
+            conn.subscribe(sub_with_filters(id, 20)).unwrap();
+        }
+    }
+
+    fn close(id: usize) -> Close {
+        Close {
+            id: SubscriptionId::from_str(&format!("sub{}", id)).unwrap(),
+        }
+    }
+
+    #[test]
+    fn close_after_close() {
+        let mut conn = ClientConn::new();
+        conn.subscribe(sub(0)).unwrap();
+        assert!(conn.unsubscribe(&close(0)));
+        assert!(!conn.unsubscribe(&close(0)));
+    }
+
+    #[test]
+    fn close_before_req() {
+        let mut conn = ClientConn::new();
+        assert!(!conn.unsubscribe(&close(0)));
+        conn.subscribe(sub(0)).unwrap();
+        assert!(conn.unsubscribe(&close(0)));
+    }
+
+    #[test]
+    fn identical_repeat_not_queried() {
+        let mut conn = ClientConn::new();
+        // the same REQ twice only queries stored events once
+        let queries = [sub(0), sub(0)]
+            .into_iter()
+            .map(|s| conn.subscribe(s).unwrap())
+            .filter(|replaced| replaced.needs_query())
+            .count();
+        assert_eq!(queries, 1);
+        assert_eq!(conn.subscribe(sub(0)).unwrap(), Replaced::Identical);
+        assert_eq!(
+            conn.subscribe(sub_with_filters(0, 2)).unwrap(),
+            Replaced::Changed
+        );
+        assert_eq!(conn.subscribe(sub(1)).unwrap(), Replaced::No);
+    }
+
+    #[test]
+    fn bandwidth_capped_per_hour() {
+        let mut cap = BandwidthCap::new(1000);
+        let start = cap.window_start;
+        assert!(!cap.exceeded_at(1000, start));
+        assert!(cap.exceeded_at(1001, start + Duration::from_secs(60)));
+        // a new hour starts counting afresh
+        let next_hour = start + BANDWIDTH_WINDOW;
+        assert!(!cap.exceeded_at(1500, next_hour));
+        assert!(!cap.exceeded_at(2500, next_hour));
+        assert!(cap.exceeded_at(2501, next_hour));
+        // no cap
+        assert!(!BandwidthCap::new(0).exceeded(u64::MAX));
+    }
+
+    #[test]
+    fn broadcast_lag_reported() {
+        use tokio::sync::broadcast::{self, error::TryRecvError};
+        let (tx, mut rx) = broadcast::channel::<u8>(1);
+        for n in 1..=3 {
+            tx.send(n).unwrap();
+        }
+        let mut stats = ConnStats::default();
+        let notice = match rx.try_recv() {
+            Err(TryRecvError::Lagged(skipped)) => stats.record_broadcast_lag(skipped),
+            other => panic!("expected lag, got {:?}", other),
+        };
+        assert_eq!(
+            notice,
+            "missed 2 event(s) by falling behind, re-subscribe to catch up"
+        );
+        assert_eq!(stats.broadcast_events_missed, 2);
+        assert_eq!(stats.notices_sent, 1);
+        assert!(stats.to_string().ends_with(", 2 broadcasts missed"));
+        // receiving carries on with the newest event
+        assert_eq!(rx.try_recv().unwrap(), 3);
+    }
+
+    #[test]
+    fn keepalive_detects_dead_peer() {
+        let mut keepalive = Keepalive::new(2);
+        let start = Instant::now();
+        let first = match keepalive.tick_at(start) {
+            PingAction::Send(payload) => payload,
+            PingAction::Dead => panic!("no ping sent yet"),
+        };
+        // a pong for the outstanding ping gives the round trip time
+        keepalive.pong(&first, start + Duration::from_millis(40));
+        assert_eq!(keepalive.rtt(), Some(Duration::from_millis(40)));
+        // one missed pong is tolerated, and a late pong resets the count
+        let second = keepalive.tick_at(start + Duration::from_secs(55));
+        assert!(matches!(
+            keepalive.tick_at(start + Duration::from_secs(110)),
+            PingAction::Send(_)
+        ));
+        if let PingAction::Send(payload) = second {
+            keepalive.pong(&payload, start + Duration::from_secs(111));
+        }
+        assert_eq!(keepalive.rtt(), Some(Duration::from_millis(40)));
+        assert!(matches!(
+            keepalive.tick_at(start + Duration::from_secs(165)),
+            PingAction::Send(_)
+        ));
+        // two in a row are not
+        assert_eq!(
+            keepalive.tick_at(start + Duration::from_secs(220)),
+            PingAction::Dead
+        );
+    }
+
+    #[test]
+    fn unknown_close_notices_limited() {
+        let conn = ClientConn::new();
+        let allowed = (0..100)
+            .filter(|_| conn.allow_unknown_close_notice())
+            .count();
+        assert_eq!(allowed, UNKNOWN_CLOSE_NOTICES_PER_MINUTE as usize);
+    }
+
+    #[test]
+    fn log_label_includes_addr() {
+        let conn = ClientConn::new();
+        let prefix = conn.get_client_prefix();
+        assert_eq!(conn.log_label(), format!("client: {}", prefix));
+        let conn = conn.with_remote_addr("192.0.2.7:4321".parse().unwrap());
+        assert_eq!(
+            conn.log_label(),
+            format!("client: {} addr: 192.0.2.7:4321", prefix)
+        );
+    }
+
+    #[test]
+    fn client_headers_sanitized() {
+        let mut headers = hyper::HeaderMap::new();
+        assert_eq!(
+            ClientHeaders::from_headers(&headers),
+            ClientHeaders::default()
+        );
+        assert_eq!(ClientHeaders::default().to_string(), "");
+        headers.insert(
+            hyper::header::USER_AGENT,
+            hyper::header::HeaderValue::from_bytes(b"client/1.0\t\xff").unwrap(),
+        );
+        headers.insert(hyper::header::ORIGIN, "x".repeat(1000).parse().unwrap());
+        let parsed = ClientHeaders::from_headers(&headers);
+        assert_eq!(parsed.user_agent.as_deref(), Some("client/1.0?\u{fffd}"));
+        assert_eq!(parsed.origin.unwrap().len(), MAX_HEADER_CHARS);
+        assert_eq!(parsed.protocol, None);
+    }
+
+    #[test]
+    fn inbound_messages_throttled() {
+        let mut limiter = InboundLimiter::new(2, 0, 3);
+        assert_eq!(limiter.check(MessageKind::Event), Throttle::Allow);
+        assert_eq!(limiter.check(MessageKind::Event), Throttle::Allow);
+        // only the first throttled message is reported
+        assert_eq!(
+            limiter.check(MessageKind::Event),
+            Throttle::Drop { notify: true }
+        );
+        assert_eq!(
+            limiter.check(MessageKind::Event),
+            Throttle::Drop { notify: false }
+        );
+        // requests are limited separately
+        assert_eq!(limiter.check(MessageKind::Request), Throttle::Allow);
+        assert_eq!(
+            limiter.check(MessageKind::Event),
+            Throttle::Drop { notify: true }
+        );
+        assert_eq!(
+            limiter.check(MessageKind::Event),
+            Throttle::Drop { notify: false }
+        );
+        assert_eq!(
+            limiter.check(MessageKind::Event),
+            Throttle::Drop { notify: false }
+        );
+        assert_eq!(limiter.check(MessageKind::Event), Throttle::Disconnect);
+        assert_eq!(limiter.throttled_events, 6);
+        assert_eq!(limiter.throttled_requests, 0);
+    }
+
+    #[test]
+    fn relay_capacity_shared() {
+        let budget = Arc::new(SubscriptionBudget::new(3));
+        let mut first = ClientConn::new().with_budget(budget.clone());
+        let mut second = ClientConn::new().with_budget(budget.clone());
+        first.subscribe(sub(0)).unwrap();
+        first.subscribe(sub(1)).unwrap();
+        second.subscribe(sub(0)).unwrap();
+        assert!(matches!(
+            second.subscribe(sub(1)),
+            Err(Error::SubRelayCapacityError)
+        ));
+        // existing subscriptions can still be replaced
+        first.subscribe(sub(1)).unwrap();
+        assert!(first.unsubscribe(&close(0)));
+        second.subscribe(sub(1)).unwrap();
+        assert_eq!(budget.active(), 3);
+        // a closed connection returns its subscriptions
+        drop(second);
+        assert_eq!(budget.active(), 1);
+        drop(first);
+        assert_eq!(budget.active(), 0);
+    }
+
+    #[test]
+    fn query_backlog_noticed_once() {
+        let mut backlog = QueryBacklog::new(4);
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+        assert!(!backlog.observe_at(4, start));
+        // room in the buffer starts the wait again
+        assert!(!backlog.observe_at(3, after(5)));
+        assert!(!backlog.observe_at(4, after(6)));
+        assert!(!backlog.observe_at(4, after(15)));
+        assert!(backlog.observe_at(4, after(16)));
+        assert!(!backlog.observe_at(4, after(60)));
+    }
+
+    #[test]
+    fn subscription_rate_limited() {
+        let mut conn = ClientConn::new().with_subscription_rate(1, 2);
+        conn.subscribe(sub(0)).unwrap();
+        assert!(conn.unsubscribe(&close(0)));
+        // closing does not use up the burst
+        assert!(!conn.unsubscribe(&close(0)));
+        conn.subscribe(sub(1)).unwrap();
+        assert!(matches!(
+            conn.subscribe(sub(2)),
+            Err(Error::SubRateLimitedError)
+        ));
+        // replacing a subscription counts as creating one
+        assert!(conn.subscribe(sub(1)).is_err());
+        assert_eq!(conn.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn subscriptions_expire() {
+        let mut conn = ClientConn::new().with_subscription_ttl(Duration::from_secs(60));
+        conn.subscribe(sub(0)).unwrap();
+        conn.subscribe(sub(1)).unwrap();
+        let now = Instant::now();
+        let earlier = now.checked_sub(Duration::from_secs(30)).unwrap();
+        for id in [sub(0).get_id(), sub(1).get_id()] {
+            conn.subscribed_at.insert(id.clone(), earlier);
+        }
+        // replacing a subscription restarts its ttl
+        conn.subscribe(sub(1)).unwrap();
+        let later = now + Duration::from_secs(30);
+        assert_eq!(
+            conn.expire_subscriptions_at(later),
+            vec![sub(0).get_id().clone()]
+        );
+        assert_eq!(conn.subscription_count(), 1);
+        assert!(!conn.unsubscribe(&close(0)));
+        assert_eq!(conn.stats().subscriptions_closed, 1);
+        // without a ttl, nothing expires
+        let mut conn = ClientConn::new();
+        conn.subscribe(sub(0)).unwrap();
+        assert!(conn.expire_subscriptions_at(later).is_empty());
+    }
+
+    #[test]
+    fn stats_count_session() {
+        let mut conn = ClientConn::with_limits(2, DEFAULT_MAX_FILTERS);
+        conn.subscribe(sub(0)).unwrap();
+        conn.subscribe(sub(1)).unwrap();
+        // replaced, then refused
+        conn.subscribe(sub(1)).unwrap();
+        assert!(conn.subscribe(sub(2)).is_err());
+        assert!(conn.unsubscribe(&close(0)));
+        assert!(!conn.unsubscribe(&close(0)));
+        for result in [
+            WriteResult::Persisted,
+            WriteResult::Queued,
+            WriteResult::Duplicate,
+            WriteResult::Rejected("blocked".to_owned()),
+        ] {
+            conn.stats_mut().events_published += 1;
+            conn.stats_mut().record_write(&result);
+        }
+        let stats = conn.stats();
+        assert_eq!(stats.subscriptions_created, 3);
+        assert_eq!(stats.subscriptions_closed, 1);
+        assert_eq!(stats.events_published, 4);
+        assert_eq!(stats.events_accepted, 2);
+        assert_eq!(stats.events_duplicate, 1);
+        assert_eq!(stats.events_rejected, 1);
+    }
+
+    #[test]
+    fn protocol_errors_limited() {
+        let policy = ProtocolErrors {
+            max_consecutive: 3,
+            max_total: 5,
+            max_oversized: Some(1),
+            max_binary: None,
+        };
+        let mut stats = ConnStats::default();
+        use ProtocolErrorKind::*;
+        assert_eq!(stats.record_protocol_error(Malformed, &policy), None);
+        assert_eq!(stats.record_protocol_error(Binary, &policy), None);
+        // a message handled in between starts the run again
+        stats.record_valid_message();
+        assert_eq!(stats.record_protocol_error(Malformed, &policy), None);
+        assert_eq!(stats.record_protocol_error(Malformed, &policy), None);
+        assert_eq!(
+            stats.record_protocol_error(Binary, &policy).unwrap(),
+            "3 binary message(s) in a row (limits.protocol_errors.max_consecutive)"
+        );
+        assert_eq!(stats.protocol_errors, 5);
+        assert!(stats.to_string().ends_with(", 5 invalid messages"));
+        // oversized events have their own limit
+        let mut stats = ConnStats::default();
+        assert_eq!(
+            stats.record_protocol_error(Oversized, &policy).unwrap(),
+            "1 oversized event(s) in a row (limits.protocol_errors.max_oversized)"
+        );
+        // and errors are limited in all
+        let mut stats = ConnStats::default();
+        for _ in 0..4 {
+            assert_eq!(stats.record_protocol_error(Malformed, &policy), None);
+            stats.record_valid_message();
+        }
+        assert_eq!(
+            stats.record_protocol_error(Malformed, &policy).unwrap(),
+            "5 invalid message(s) (limits.protocol_errors.max_total)"
+        );
+        // unless there is no limit
+        let unlimited = ProtocolErrors {
+            max_consecutive: 0,
+            max_total: 0,
+            max_oversized: None,
+            max_binary: Some(0),
+        };
+        for _ in 0..100 {
+            assert_eq!(stats.record_protocol_error(Binary, &unlimited), None);
+        }
+    }
+
+    #[test]
+    fn max_filters_exceeded() {
+        let mut conn = ClientConn::with_limits(DEFAULT_MAX_SUBSCRIPTIONS, 2);
+        conn.subscribe(sub_with_filters(0, 2)).unwrap();
+        assert!(matches!(
+            conn.subscribe(sub_with_filters(1, 3)),
+            Err(Error::SubMaxFiltersError(2))
+        ));
     }
 }
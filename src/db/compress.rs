@@ -0,0 +1,103 @@
+//! Optional compression of stored event JSON
+use crate::config::SETTINGS;
+use crate::error::{Error, Result};
+use rusqlite::types::Value;
+use rusqlite::Row;
+use serde::{Deserialize, Serialize};
+
+/// zstd level used for event content, favouring write speed.
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the `content` column of new events is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// As JSON text
+    Off,
+    /// As zstd compressed JSON, when large enough to benefit
+    Zstd,
+}
+
+/// Event JSON prepared for the `content` and `raw_size` columns.
+pub(crate) struct StoredContent {
+    /// JSON text, or compressed JSON as a blob
+    pub content: Value,
+    /// Length of the JSON if it was compressed
+    pub raw_size: Option<i64>,
+}
+
+/// Minimum size of event JSON that is compressed, or `None` if
+/// compression is disabled.
+pub(crate) fn compress_threshold() -> Option<usize> {
+    let config = SETTINGS.read().unwrap();
+    match config.database.compress_content {
+        Compression::Zstd => Some(config.database.compress_min_bytes),
+        Compression::Off => None,
+    }
+}
+
+/// Prepare event JSON for storage, compressing it if it is at least
+/// `threshold` bytes long and compression makes it smaller.
+pub(crate) fn encode(json: String, threshold: Option<usize>) -> Result<StoredContent> {
+    if let Some(min) = threshold {
+        if json.len() >= min {
+            let compressed = zstd::bulk::compress(json.as_bytes(), ZSTD_LEVEL)
+                .map_err(|e| Error::GenericError(format!("could not compress event: {}", e)))?;
+            if compressed.len() < json.len() {
+                return Ok(StoredContent {
+                    content: Value::Blob(compressed),
+                    raw_size: Some(json.len() as i64),
+                });
+            }
+        }
+    }
+    Ok(StoredContent {
+        content: Value::Text(json),
+        raw_size: None,
+    })
+}
+
+/// Recover event JSON from a stored `content` value, decompressing
+/// it if `raw_size` is set.
+pub(crate) fn decode(content: &[u8], raw_size: Option<i64>) -> Result<String> {
+    let json = match raw_size {
+        Some(size) => zstd::bulk::decompress(content, size as usize)
+            .map_err(|e| Error::GenericError(format!("could not decompress event: {}", e)))?,
+        None => content.to_vec(),
+    };
+    String::from_utf8(json).map_err(|e| Error::GenericError(e.to_string()))
+}
+
+/// Read event JSON from a row selecting `content, raw_size` starting
+/// at column `idx`.
+pub(crate) fn event_json(row: &Row, idx: usize) -> Result<String> {
+    let content = row
+        .get_ref(idx)?
+        .as_bytes()
+        .map_err(|e| Error::GenericError(e.to_string()))?;
+    decode(content, row.get(idx + 1)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_large_content_only() {
+        let large = format!("{{\"content\":\"{}\"}}", "x".repeat(4096));
+        let stored = encode(large.clone(), Some(1024)).unwrap();
+        assert_eq!(stored.raw_size, Some(large.len() as i64));
+        let blob = match &stored.content {
+            Value::Blob(b) => b.clone(),
+            other => panic!("unexpected value {:?}", other),
+        };
+        assert!(blob.len() < large.len());
+        assert_eq!(decode(&blob, stored.raw_size).unwrap(), large);
+
+        let small = "{}".to_owned();
+        let stored = encode(small.clone(), Some(1024)).unwrap();
+        assert_eq!(stored.raw_size, None);
+        assert_eq!(stored.content, Value::Text(small));
+        assert!(encode(large, None).unwrap().raw_size.is_none());
+    }
+}
@@ -0,0 +1,133 @@
+//! Export stored events as line-delimited JSON
+use crate::error::Result;
+use bitcoin_hashes::hex::ToHex;
+use log::*;
+use rusqlite::OpenFlags;
+use secp256k1::XOnlyPublicKey;
+use std::io::Write;
+use std::path::Path;
+
+/// Number of events between progress reports.
+pub const EXPORT_PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Selects the events to export.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    /// Only events created at or after this time
+    pub since: Option<u64>,
+    /// Only events created at or before this time
+    pub until: Option<u64>,
+    /// Only events of these kinds
+    pub kinds: Option<Vec<u64>>,
+    /// Only events by these authors
+    pub authors: Option<Vec<XOnlyPublicKey>>,
+    /// Also export hidden events, such as replaced metadata
+    pub include_hidden: bool,
+}
+
+impl ExportFilter {
+    /// SQL query selecting the raw JSON of matching events, oldest
+    /// first.  All values are integers or hex encoded keys.
+    fn query(&self) -> String {
+        let mut conditions = vec![];
+        if let Some(since) = self.since {
+            conditions.push(format!("created_at >= {}", since));
+        }
+        if let Some(until) = self.until {
+            conditions.push(format!("created_at <= {}", until));
+        }
+        if let Some(kinds) = &self.kinds {
+            let kinds: Vec<String> = kinds.iter().map(|k| k.to_string()).collect();
+            conditions.push(format!("kind IN ({})", kinds.join(", ")));
+        }
+        if let Some(authors) = &self.authors {
+            let authors: Vec<String> = authors
+                .iter()
+                .map(|a| format!("x'{}'", a.serialize().to_hex()))
+                .collect();
+            conditions.push(format!("author IN ({})", authors.join(", ")));
+        }
+        if !self.include_hidden {
+            conditions.push("hidden != TRUE".to_owned());
+        }
+        let mut query = "SELECT content, raw_size FROM event".to_owned();
+        if !conditions.is_empty() {
+            query.push_str(" WHERE ");
+            query.push_str(&conditions.join(" AND "));
+        }
+        query.push_str(" ORDER BY created_at ASC, id ASC");
+        query
+    }
+}
+
+/// Write every event in the database in `db_dir` matching `filter` to
+/// `out`, one JSON event per line.  Events are streamed from the
+/// database, so memory use does not grow with the number of events.
+///
+/// `progress` is called with the running total every
+/// [`EXPORT_PROGRESS_INTERVAL`] events.  Returns the number of events
+/// written.
+pub fn export_events<W: Write>(
+    db_dir: &Path,
+    filter: &ExportFilter,
+    out: &mut W,
+    mut progress: impl FnMut(u64),
+) -> Result<u64> {
+    let full_path = db_dir.join(super::sqlite::DB_FILE);
+    let conn = super::sqlite::open_db(
+        &full_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let query = filter.query();
+    debug!("export query: {}", query);
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        // compressed events are written as plain JSON
+        let event_json = super::compress::event_json(row, 0)?;
+        writeln!(out, "{}", event_json)
+            .map_err(|e| crate::error::Error::GenericError(e.to_string()))?;
+        count += 1;
+        if count % EXPORT_PROGRESS_INTERVAL == 0 {
+            progress(count);
+        }
+    }
+    out.flush()
+        .map_err(|e| crate::error::Error::GenericError(e.to_string()))?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{SqliteStorage, Storage};
+    use crate::test_util::{event_with_id, temp_db_dir};
+
+    #[tokio::test]
+    async fn export_filters() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        for n in 0..5u8 {
+            let mut e = event_with_id(n);
+            e.created_at = 100 * n as u64;
+            storage.write_event(e).await.unwrap();
+        }
+        let mut out = vec![];
+        let all = export_events(&dir, &ExportFilter::default(), &mut out, |_| {}).unwrap();
+        assert_eq!(all, 5);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 5);
+        let filter = ExportFilter {
+            since: Some(100),
+            until: Some(300),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        assert_eq!(export_events(&dir, &filter, &mut out, |_| {}).unwrap(), 3);
+        let first = String::from_utf8(out).unwrap();
+        let first: serde_json::Value = serde_json::from_str(first.lines().next().unwrap()).unwrap();
+        assert_eq!(first["created_at"], 100);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
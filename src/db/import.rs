@@ -0,0 +1,149 @@
+//! Import events from line-delimited JSON
+use crate::error::{Error, Result};
+use crate::protocol::Event;
+use log::*;
+use rusqlite::{Connection, OpenFlags};
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Number of events committed in each import transaction.
+pub const IMPORT_BATCH_SIZE: usize = 10_000;
+
+/// How long an import waits on a database locked by a running relay.
+const IMPORT_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Outcome of an import.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Events newly written to the database
+    pub imported: u64,
+    /// Events that were already stored
+    pub duplicates: u64,
+    /// Lines that were not valid, signed events
+    pub invalid: u64,
+    /// Valid events that could not be written
+    pub failed: u64,
+}
+
+impl ImportReport {
+    /// Number of non-empty lines read.
+    pub fn total(&self) -> u64 {
+        self.imported + self.duplicates + self.invalid + self.failed
+    }
+}
+
+/// Read events, one JSON event per line, from `input` into the
+/// database in `db_dir`, creating it if needed.
+///
+/// Each event's id and signature are checked, and events are stored
+/// through the same path as events received from clients.  Invalid
+/// lines are counted rather than aborting the import, and duplicates
+/// are skipped.  Durability is relaxed (`synchronous=OFF`) while the
+/// import runs.  `progress` is called after every committed batch.
+pub fn import_events<R: BufRead>(
+    db_dir: &Path,
+    mut input: R,
+    mut progress: impl FnMut(&ImportReport),
+) -> Result<ImportReport> {
+    let full_path = db_dir.join(super::sqlite::DB_FILE);
+    let mut conn = super::sqlite::open_db(
+        &full_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    conn.busy_timeout(IMPORT_BUSY_TIMEOUT)?;
+    super::sqlite::upgrade_db(&mut conn)?;
+    conn.execute_batch("PRAGMA synchronous = OFF;")?;
+    info!("importing events into {:?}", full_path);
+    let mut report = ImportReport::default();
+    let result = import_lines(&mut conn, &mut input, &mut report, &mut progress);
+    conn.execute_batch("PRAGMA synchronous = NORMAL;")?;
+    result.map(|_| report)
+}
+
+/// Read and write batches of events until `input` is exhausted.
+fn import_lines<R: BufRead>(
+    conn: &mut Connection,
+    input: &mut R,
+    report: &mut ImportReport,
+    progress: &mut impl FnMut(&ImportReport),
+) -> Result<()> {
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    let mut line = Vec::new();
+    let mut line_num = 0;
+    loop {
+        line.clear();
+        let read = input
+            .read_until(b'\n', &mut line)
+            .map_err(|e| Error::GenericError(format!("could not read input: {}", e)))?;
+        if read > 0 {
+            line_num += 1;
+            match parse_line(&line) {
+                Some(Ok(event)) => batch.push(event),
+                Some(Err(e)) => {
+                    debug!("skipping invalid event on line {}: {}", line_num, e);
+                    report.invalid += 1;
+                }
+                None => {}
+            }
+        }
+        if batch.len() >= IMPORT_BATCH_SIZE || (read == 0 && !batch.is_empty()) {
+            for result in super::sqlite::write_events(conn, &batch) {
+                match result {
+                    Ok(0) => report.duplicates += 1,
+                    Ok(_) => report.imported += 1,
+                    Err(e) => {
+                        warn!("could not import event: {}", e);
+                        report.failed += 1;
+                    }
+                }
+            }
+            batch.clear();
+            progress(report);
+        }
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Parse and validate a single line, ignoring blank lines.
+fn parse_line(line: &[u8]) -> Option<Result<Event>> {
+    let line = match std::str::from_utf8(line) {
+        Ok(line) => line.trim(),
+        Err(e) => return Some(Err(Error::GenericError(e.to_string()))),
+    };
+    if line.is_empty() {
+        None
+    } else {
+        Some(Event::from_str(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::testvec::event::VALID_EVENT;
+    use crate::test_util::temp_db_dir;
+
+    #[test]
+    fn import_counts_outcomes() {
+        let dir = temp_db_dir();
+        let event: serde_json::Value = serde_json::from_str(VALID_EVENT).unwrap();
+        let mut tampered = event.clone();
+        tampered["content"] = "changed".into();
+        let input = format!("{}\n\nnot json\n{}\n{}", event, tampered, event);
+        let report = import_events(&dir, input.as_bytes(), |_| {}).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 1,
+                duplicates: 1,
+                invalid: 2,
+                failed: 0,
+            }
+        );
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,447 @@
+//! Ordered SQLite schema migrations
+use crate::error::{Error, Result};
+use log::*;
+use rusqlite::{Connection, Transaction};
+
+/// A single schema change, moving the database from `version - 1`
+/// to `version`.
+pub(crate) struct Migration {
+    /// Schema version after the migration
+    pub version: usize,
+    /// Short description, for logging
+    pub name: &'static str,
+    /// Apply the change within an open transaction
+    pub up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// Every migration, in the order they are applied.  Versions start at
+/// one and increase by one; existing entries must never change.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        name: "hidden events",
+        up: hidden_column,
+    },
+    Migration {
+        version: 3,
+        name: "hidden event index",
+        up: hidden_index,
+    },
+    Migration {
+        version: 4,
+        name: "author statistics",
+        up: author_stats,
+    },
+    Migration {
+        version: 5,
+        name: "event sources",
+        up: event_sources,
+    },
+    Migration {
+        version: 6,
+        name: "tombstones",
+        up: tombstones,
+    },
+    Migration {
+        version: 7,
+        name: "compressed content",
+        up: raw_size_column,
+    },
+    Migration {
+        version: 8,
+        name: "user verification",
+        up: user_verification,
+    },
+];
+
+/// Latest schema version, stored in `PRAGMA user_version`.
+pub(crate) const DB_VERSION: usize = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Settings for a new database, which cannot be changed inside a
+/// transaction or once tables exist.
+const NEW_DB_SQL: &str = r##"
+PRAGMA encoding = "UTF-8";
+PRAGMA auto_vacuum = INCREMENTAL;
+PRAGMA journal_mode=WAL;
+PRAGMA application_id = 1654008667;
+"##;
+
+/// Recompute `author_stats` from the event table.
+pub(crate) const AUTHOR_STATS_BACKFILL_SQL: &str = r##"
+DELETE FROM author_stats;
+INSERT INTO author_stats (author, event_count, total_bytes, first_seen, last_seen)
+  SELECT author, COUNT(*), SUM(length(CAST(content AS BLOB))), MIN(first_seen), MAX(first_seen)
+  FROM event GROUP BY author;
+"##;
+
+/// Apply every migration newer than the database's `user_version`,
+/// each in its own transaction, so that a failure leaves the database
+/// at the last good version.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    migrate_to(conn, DB_VERSION)
+}
+
+/// Apply migrations up to and including `target`.
+fn migrate_to(conn: &mut Connection, target: usize) -> Result<()> {
+    let curr_version = super::sqlite::db_version(conn)?;
+    info!("DB version = {:?}", curr_version);
+    if curr_version > DB_VERSION {
+        return Err(Error::FutureSchemaVersion {
+            found: curr_version,
+            supported: DB_VERSION,
+        });
+    }
+    if curr_version == 0 {
+        conn.execute_batch(NEW_DB_SQL)?;
+    }
+    for m in MIGRATIONS
+        .iter()
+        .filter(|m| m.version > curr_version && m.version <= target)
+    {
+        let apply = |conn: &mut Connection| -> rusqlite::Result<()> {
+            let tx = conn.transaction()?;
+            (m.up)(&tx)?;
+            tx.pragma_update(None, "user_version", m.version)?;
+            tx.commit()
+        };
+        apply(conn).map_err(|source| Error::MigrationFailed {
+            from: curr_version,
+            to: DB_VERSION,
+            source,
+        })?;
+        info!("database schema upgraded to v{} ({})", m.version, m.name);
+    }
+    if curr_version == DB_VERSION {
+        debug!("Database version was already current");
+    }
+    Ok(())
+}
+
+/// Events, and the event and pubkey references found in their tags.
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+-- Event Table
+CREATE TABLE IF NOT EXISTS event (
+id INTEGER PRIMARY KEY,
+event_hash BLOB NOT NULL, -- 4-byte hash
+first_seen INTEGER NOT NULL, -- when the event was first seen (not authored!) (seconds since 1970)
+created_at INTEGER NOT NULL, -- when the event was authored
+author BLOB NOT NULL, -- author pubkey
+kind INTEGER NOT NULL, -- event kind
+content TEXT NOT NULL -- serialized json of event object
+);
+
+-- Event Indexes
+CREATE UNIQUE INDEX IF NOT EXISTS event_hash_index ON event(event_hash);
+CREATE INDEX IF NOT EXISTS created_at_index ON event(created_at);
+CREATE INDEX IF NOT EXISTS author_index ON event(author);
+CREATE INDEX IF NOT EXISTS kind_index ON event(kind);
+
+-- Event References Table
+CREATE TABLE IF NOT EXISTS event_ref (
+id INTEGER PRIMARY KEY,
+event_id INTEGER NOT NULL, -- an event ID that contains an #e tag.
+referenced_event BLOB NOT NULL, -- the event that is referenced.
+FOREIGN KEY(event_id) REFERENCES event(id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+-- Event References Index
+CREATE INDEX IF NOT EXISTS event_ref_index ON event_ref(referenced_event);
+
+-- Pubkey References Table
+CREATE TABLE IF NOT EXISTS pubkey_ref (
+id INTEGER PRIMARY KEY,
+event_id INTEGER NOT NULL, -- an event ID that contains an #p tag.
+referenced_pubkey BLOB NOT NULL, -- the pubkey that is referenced.
+FOREIGN KEY(event_id) REFERENCES event(id) ON UPDATE RESTRICT ON DELETE CASCADE
+);
+
+-- Pubkey References Index
+CREATE INDEX IF NOT EXISTS pubkey_ref_index ON pubkey_ref(referenced_pubkey);
+"##,
+    )
+}
+
+/// Mark events hidden when superseded, rather than deleting them.
+fn hidden_column(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+ALTER TABLE event ADD hidden INTEGER;
+UPDATE event SET hidden=FALSE;
+"##,
+    )
+}
+
+/// Index the superseded events still kept, so that they can be found
+/// without a table scan.
+fn hidden_index(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("CREATE INDEX IF NOT EXISTS hidden_index ON event(id) WHERE hidden=TRUE;")
+}
+
+/// Per-author totals, kept up to date by triggers on the event table
+/// so that every insert and delete path maintains them, starting from
+/// the events already stored.
+fn author_stats(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+CREATE TABLE IF NOT EXISTS author_stats (
+author BLOB PRIMARY KEY, -- author pubkey
+event_count INTEGER NOT NULL, -- number of stored events
+total_bytes INTEGER NOT NULL, -- size of the stored event JSON
+first_seen INTEGER NOT NULL, -- when the first stored event was received
+last_seen INTEGER NOT NULL -- when the latest stored event was received
+);
+
+CREATE TRIGGER IF NOT EXISTS author_stats_insert AFTER INSERT ON event BEGIN
+INSERT OR IGNORE INTO author_stats (author, event_count, total_bytes, first_seen, last_seen)
+  VALUES (NEW.author, 0, 0, NEW.first_seen, NEW.first_seen);
+UPDATE author_stats SET event_count=event_count+1,
+  total_bytes=total_bytes+length(CAST(NEW.content AS BLOB)),
+  last_seen=MAX(last_seen, NEW.first_seen)
+  WHERE author=NEW.author;
+END;
+
+CREATE TRIGGER IF NOT EXISTS author_stats_delete AFTER DELETE ON event BEGIN
+UPDATE author_stats SET event_count=event_count-1,
+  total_bytes=total_bytes-length(CAST(OLD.content AS BLOB))
+  WHERE author=OLD.author;
+DELETE FROM author_stats WHERE author=OLD.author AND event_count<=0;
+END;
+"##,
+    )?;
+    tx.execute_batch(AUTHOR_STATS_BACKFILL_SQL)
+}
+
+/// Where stored events were received from, kept apart from the event
+/// table so that it is never returned by queries and is pruned on its
+/// own schedule.
+fn event_sources(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+CREATE TABLE IF NOT EXISTS event_source (
+event_hash BLOB PRIMARY KEY, -- 32-byte hash of the received event
+remote_ip TEXT NOT NULL, -- IP address of the submitting connection
+client_id TEXT NOT NULL, -- client id prefix of the submitting connection
+received_at INTEGER NOT NULL -- when the event was received (seconds since 1970)
+);
+
+CREATE INDEX IF NOT EXISTS event_source_received_index ON event_source(received_at);
+"##,
+    )
+}
+
+/// Ids of events deleted by the operator, which are refused if
+/// submitted again.
+fn tombstones(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+CREATE TABLE IF NOT EXISTS tombstone (
+event_hash BLOB PRIMARY KEY, -- 32-byte hash of the deleted event
+deleted_at INTEGER NOT NULL -- when the event was deleted (seconds since 1970)
+);
+"##,
+    )
+}
+
+/// Record the uncompressed size of event JSON stored compressed,
+/// which also marks the content as compressed.  Existing rows are
+/// left as text.
+fn raw_size_column(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE event ADD raw_size INTEGER;")
+}
+
+/// The NIP-05 identifier of each author with one, and when it was
+/// last checked and confirmed.
+fn user_verification(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r##"
+CREATE TABLE IF NOT EXISTS user_verification (
+pubkey BLOB PRIMARY KEY, -- author pubkey
+name TEXT NOT NULL, -- NIP-05 identifier from the latest metadata
+last_checked INTEGER, -- when the identifier was last checked (seconds since 1970)
+last_success INTEGER, -- when the identifier was last confirmed
+failure_count INTEGER NOT NULL DEFAULT 0 -- checks failed since the last success
+);
+
+CREATE INDEX IF NOT EXISTS user_verification_checked_index ON user_verification(last_checked);
+"##,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An in-memory database migrated to `version`.
+    fn db_at(version: usize) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, version).unwrap();
+        assert_eq!(
+            super::super::sqlite::db_version(&mut conn).unwrap(),
+            version
+        );
+        conn
+    }
+
+    /// Store an event row for `author`, with `content` as its JSON.
+    fn insert_event(conn: &Connection, hash: u8, author: u8, content: &str) {
+        conn.execute(
+            "INSERT INTO event (event_hash, first_seen, created_at, author, kind, content) \
+             VALUES (?, ?, 1, ?, 1, ?)",
+            rusqlite::params![vec![hash; 32], 100 + hash as i64, vec![author; 32], content],
+        )
+        .unwrap();
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn versions_are_consecutive() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i + 1, "migration {:?} out of order", m.name);
+        }
+    }
+
+    #[test]
+    fn initial_schema_created() {
+        let conn = db_at(1);
+        insert_event(&conn, 1, 1, "{}");
+        conn.execute(
+            "INSERT INTO event_ref (event_id, referenced_event) VALUES (1, x'00')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO pubkey_ref (event_id, referenced_pubkey) VALUES (1, x'00')",
+            [],
+        )
+        .unwrap();
+        let app_id = count(&conn, "PRAGMA application_id");
+        assert_eq!(app_id, 1654008667);
+    }
+
+    #[test]
+    fn hidden_column_backfilled() {
+        let mut conn = db_at(1);
+        insert_event(&conn, 1, 1, "{}");
+        insert_event(&conn, 2, 1, "{}");
+        migrate_to(&mut conn, 2).unwrap();
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM event WHERE hidden=FALSE"),
+            2
+        );
+    }
+
+    #[test]
+    fn hidden_index_created() {
+        let mut conn = db_at(2);
+        insert_event(&conn, 1, 1, "{}");
+        conn.execute("UPDATE event SET hidden=TRUE", []).unwrap();
+        migrate_to(&mut conn, 3).unwrap();
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='index' AND name='hidden_index'"
+            ),
+            1
+        );
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM event WHERE hidden=TRUE"),
+            1
+        );
+    }
+
+    #[test]
+    fn author_stats_backfilled() {
+        let mut conn = db_at(3);
+        insert_event(&conn, 1, 1, "abc");
+        insert_event(&conn, 2, 1, "de");
+        insert_event(&conn, 3, 2, "f");
+        migrate_to(&mut conn, 4).unwrap();
+        let stats: (i64, i64, i64, i64) = conn
+            .query_row(
+                "SELECT event_count, total_bytes, first_seen, last_seen FROM author_stats \
+                 WHERE author = ?",
+                [vec![1u8; 32]],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(stats, (2, 5, 101, 102));
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM author_stats"), 2);
+        // the triggers keep the totals current from here on
+        insert_event(&conn, 4, 2, "gh");
+        assert_eq!(
+            count(
+                &conn,
+                "SELECT total_bytes FROM author_stats WHERE event_count = 2 AND last_seen = 104"
+            ),
+            3
+        );
+    }
+
+    #[test]
+    fn event_source_table_created() {
+        let mut conn = db_at(4);
+        insert_event(&conn, 1, 1, "{}");
+        migrate_to(&mut conn, 5).unwrap();
+        conn.execute(
+            "INSERT INTO event_source (event_hash, remote_ip, client_id, received_at) \
+             VALUES (?, '127.0.0.1', 'c', 1)",
+            [vec![1u8; 32]],
+        )
+        .unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM event_source"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM event"), 1);
+    }
+
+    #[test]
+    fn tombstone_table_created() {
+        let mut conn = db_at(5);
+        insert_event(&conn, 1, 1, "{}");
+        migrate_to(&mut conn, 6).unwrap();
+        conn.execute(
+            "INSERT INTO tombstone (event_hash, deleted_at) VALUES (?, 1)",
+            [vec![1u8; 32]],
+        )
+        .unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tombstone"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM event"), 1);
+    }
+
+    #[test]
+    fn raw_size_column_added() {
+        let mut conn = db_at(6);
+        insert_event(&conn, 1, 1, "{}");
+        migrate_to(&mut conn, 7).unwrap();
+        // rows stored before the migration read as uncompressed text
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM event WHERE raw_size IS NULL"),
+            1
+        );
+    }
+
+    #[test]
+    fn user_verification_table_created() {
+        let mut conn = db_at(7);
+        insert_event(&conn, 1, 1, "{}");
+        migrate_to(&mut conn, 8).unwrap();
+        conn.execute(
+            "INSERT INTO user_verification (pubkey, name) VALUES (?, 'bob@example.com')",
+            [vec![1u8; 32]],
+        )
+        .unwrap();
+        assert_eq!(
+            count(&conn, "SELECT failure_count FROM user_verification"),
+            0
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM event"), 1);
+    }
+}
@@ -0,0 +1,1593 @@
+//! Event persistence and querying
+use crate::config::SETTINGS;
+use crate::error::{Error, Result};
+use crate::metrics::METRICS;
+use crate::protocol::{BroadcastEvent, Event, EventId, Subscription};
+use async_trait::async_trait;
+use core::pin::Pin;
+use futures::stream::{BoxStream, Stream};
+use futures::task::{Context, Poll};
+use futures::{FutureExt, StreamExt};
+use governor::clock::Clock;
+use governor::{Quota, RateLimiter};
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::Instrument;
+
+mod compress;
+mod export;
+mod import;
+mod migrations;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod pragmas;
+mod recent;
+pub mod retention;
+mod spool;
+mod sqlite;
+mod verify;
+
+pub use compress::Compression;
+pub use export::{export_events, ExportFilter, EXPORT_PROGRESS_INTERVAL};
+pub use import::{import_events, ImportReport, IMPORT_BATCH_SIZE};
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStorage;
+pub use pragmas::{SqlitePragmas, Synchronous, TempStore};
+pub use recent::RecentIds;
+pub use retention::{
+    KindRange, PruneFilter, PruneReport, RetentionPolicy, RetentionRule, SizeBudget,
+};
+pub use rusqlite::backup::Progress as BackupProgress;
+pub use spool::{Spool, SpoolFsync};
+pub use sqlite::{
+    backup_to, compact, db_version, delete_event, delete_offline, encrypt_db, get_author_stats,
+    get_stats, get_verification, load_encryption_key, prune_offline, query_stream, upgrade_db,
+    write_event, write_events, CompactReport, OfflinePruneReport, PooledConnection, ReaderPool,
+    SqliteStorage, DB_FILES,
+};
+pub use verify::{verify_events, InvalidEvent, VerifyReport, VERIFY_PROGRESS_INTERVAL};
+
+/// Number of most common kinds reported in [`DbStats`].
+pub const TOP_KINDS_LIMIT: u64 = 20;
+
+/// Event count, hidden count, distinct authors and the range of
+/// `created_at`, valid for both SQLite and PostgreSQL.
+pub(crate) const STATS_SUMMARY_SQL: &str = "SELECT COUNT(*), \
+     COALESCE(SUM(CASE WHEN hidden = TRUE THEN 1 ELSE 0 END), 0), \
+     COUNT(DISTINCT author), MIN(created_at), MAX(created_at) FROM event";
+
+/// The most common kinds, with their event counts.
+pub(crate) fn top_kinds_sql() -> String {
+    format!(
+        "SELECT kind, COUNT(*) AS n FROM event GROUP BY kind ORDER BY n DESC, kind LIMIT {}",
+        TOP_KINDS_LIMIT
+    )
+}
+
+/// Number of authors using the most storage reported in [`DbStats`].
+pub const TOP_AUTHORS_LIMIT: u64 = 20;
+
+/// Columns of the `author_stats` table, in [`AuthorStats`] order.
+pub(crate) const AUTHOR_STATS_COLUMNS: &str =
+    "author, event_count, total_bytes, first_seen, last_seen";
+
+/// The authors using the most storage.
+pub(crate) fn top_authors_sql() -> String {
+    format!(
+        "SELECT {} FROM author_stats ORDER BY total_bytes DESC, author LIMIT {}",
+        AUTHOR_STATS_COLUMNS, TOP_AUTHORS_LIMIT
+    )
+}
+
+/// Storage used by a single author, maintained alongside the event
+/// table as events are written and deleted.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuthorStats {
+    /// Author public key, hex encoded
+    pub author: String,
+    /// Number of stored events
+    pub event_count: u64,
+    /// Size of the stored event JSON in bytes
+    pub total_bytes: u64,
+    /// When the author's first stored event was received
+    pub first_seen: u64,
+    /// When the author's latest stored event was received
+    pub last_seen: u64,
+}
+
+/// Outcome of an operator deleting an event by id.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeleteOutcome {
+    /// Whether a stored event was removed
+    pub deleted: bool,
+    /// Whether a tombstone was newly recorded, rather than already
+    /// present from an earlier deletion
+    pub tombstoned: bool,
+}
+
+/// Where and when an event was received, recorded for abuse
+/// investigations when `database.log_event_source` is set.  Never
+/// returned to clients.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct EventSource {
+    /// Remote IP address of the submitting connection
+    pub ip: String,
+    /// Client id prefix of the submitting connection
+    pub client_id: String,
+    /// When the event was received (seconds since 1970)
+    pub received_at: u64,
+}
+
+impl EventSource {
+    /// Describe an event received now from `ip` on connection `client_id`.
+    pub fn new(ip: String, client_id: String) -> Self {
+        EventSource {
+            ip,
+            client_id,
+            received_at: retention::now(),
+        }
+    }
+}
+
+/// The state of an author's NIP-05 identifier, as last checked
+/// against their domain.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserVerification {
+    /// Author
+    pub pubkey: XOnlyPublicKey,
+    /// NIP-05 identifier from the author's latest metadata
+    pub name: String,
+    /// When the identifier was last checked (seconds since 1970)
+    pub last_checked: Option<u64>,
+    /// When the identifier was last confirmed (seconds since 1970)
+    pub last_success: Option<u64>,
+    /// Checks failed since the identifier was last confirmed
+    pub failure_count: u32,
+}
+
+impl UserVerification {
+    /// An identifier that has not been checked yet.
+    pub fn new(pubkey: XOnlyPublicKey, name: String) -> Self {
+        UserVerification {
+            pubkey,
+            name,
+            last_checked: None,
+            last_success: None,
+            failure_count: 0,
+        }
+    }
+
+    /// Whether the identifier was confirmed within `max_age` seconds
+    /// of `now`.
+    pub fn is_verified(&self, now: u64, max_age: u64) -> bool {
+        self.last_success
+            .is_some_and(|t| now.saturating_sub(t) <= max_age)
+    }
+}
+
+/// Number of stored events of one kind.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KindCount {
+    /// Event kind
+    pub kind: u64,
+    /// Number of stored events of this kind
+    pub count: u64,
+}
+
+/// Summary numbers describing the contents of a [`Storage`] backend.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DbStats {
+    /// Number of stored events
+    pub event_count: u64,
+    /// Events hidden by a newer replaceable event
+    pub hidden_count: u64,
+    /// Number of distinct authors
+    pub author_count: u64,
+    /// Oldest `created_at` of any stored event
+    pub oldest_created_at: Option<u64>,
+    /// Newest `created_at` of any stored event
+    pub newest_created_at: Option<u64>,
+    /// Most common kinds, most events first
+    pub top_kinds: Vec<KindCount>,
+    /// Authors using the most storage, most bytes first
+    pub top_authors: Vec<AuthorStats>,
+    /// Size of the database file, for file based backends
+    pub file_size_bytes: Option<u64>,
+    /// Size of the write-ahead log, for file based backends
+    pub wal_size_bytes: Option<u64>,
+    /// Write attempts retried because the database was busy
+    pub retried_writes: u64,
+    /// Events that could not be written, even after retrying
+    pub failed_writes: u64,
+    /// Events deleted by retention pruning
+    pub pruned_events: u64,
+    /// Bytes used by stored data, if the backend tracks it
+    pub size_bytes: Option<u64>,
+    /// Configured size budget in bytes
+    pub max_size_bytes: Option<u64>,
+    /// Whether new events are refused because the budget is exhausted
+    pub storage_full: bool,
+    /// Events waiting in the overflow spool
+    pub spooled_events: u64,
+    /// Events dropped from the overflow spool because it was full
+    pub spool_dropped_events: u64,
+    /// Events the writer checked against recently stored ids
+    pub duplicate_checks: u64,
+    /// Events answered as duplicates without a write
+    pub duplicate_hits: u64,
+    /// Events stored compressed
+    pub compressed_events: u64,
+    /// Size of the JSON of compressed events, before compression
+    pub compressed_raw_bytes: u64,
+    /// Size of compressed events as stored
+    pub compressed_stored_bytes: u64,
+}
+
+impl DbStats {
+    /// How many times smaller compressed events are than their JSON,
+    /// if any are stored compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.compressed_stored_bytes > 0)
+            .then(|| self.compressed_raw_bytes as f64 / self.compressed_stored_bytes as f64)
+    }
+}
+
+/// Holds the last computed [`DbStats`] for `database.stats_cache_seconds`,
+/// since counting kinds and authors is expensive on large databases.
+#[derive(Debug)]
+pub(crate) struct StatsCache {
+    ttl: Duration,
+    cached: std::sync::Mutex<Option<(Instant, DbStats)>>,
+}
+
+impl StatsCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        StatsCache {
+            ttl,
+            cached: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn from_settings() -> Self {
+        let config = SETTINGS.read().unwrap();
+        Self::new(Duration::from_secs(config.database.stats_cache_seconds))
+    }
+
+    /// Cached stats, if they have not expired.
+    pub(crate) fn get(&self) -> Option<DbStats> {
+        match &*self.cached.lock().unwrap() {
+            Some((at, stats)) if at.elapsed() < self.ttl => Some(stats.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn put(&self, stats: &DbStats) {
+        *self.cached.lock().unwrap() = Some((Instant::now(), stats.clone()));
+    }
+}
+
+/// A backend capable of persisting and querying events.
+///
+/// Methods take owned values and return streams so that both
+/// blocking (SQLite) and natively async backends can implement them
+/// without borrowing from the caller.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Bring the backend schema up to date.  Called once at startup,
+    /// before any other method.
+    async fn migrate(&self) -> Result<()>;
+
+    /// Persist an event.  Returns the number of events inserted,
+    /// which is zero for duplicates.
+    async fn write_event(&self, event: Event) -> Result<usize>;
+
+    /// Persist a batch of events, returning one result per event in
+    /// the same order.  The default writes each event individually;
+    /// backends should override this to use fewer transactions.
+    async fn write_events(&self, events: Vec<Event>) -> Vec<Result<usize>> {
+        let mut results = Vec::with_capacity(events.len());
+        for event in events {
+            results.push(self.write_event(event).await);
+        }
+        results
+    }
+
+    /// Stream all stored events matching a subscription, in
+    /// ascending `created_at` order.  Dropping the stream cancels the
+    /// query.
+    fn query(&self, sub: Subscription) -> BoxStream<'static, Result<Event>>;
+
+    /// Count the stored events matching a subscription.
+    async fn count(&self, sub: Subscription) -> Result<u64>;
+
+    /// Delete an event by id.  Returns true if an event was removed.
+    async fn delete(&self, id: EventId) -> Result<bool>;
+
+    /// Delete an event on an operator's request, along with its
+    /// references, and record a tombstone so that the event is
+    /// refused with [`Error::EventDeleted`] if it is submitted again.
+    /// A tombstone is recorded even if the event is not stored.
+    async fn delete_event(&self, id: EventId) -> Result<DeleteOutcome>;
+
+    /// Delete up to `limit` of the oldest events selected by a
+    /// [`PruneFilter`], along with their references.  Returns the
+    /// number of events deleted.
+    async fn prune_events(&self, filter: PruneFilter, limit: u64) -> Result<u64>;
+
+    /// Count the stored events matching the kind and age conditions
+    /// of a [`PruneFilter`], including those pruning would keep.
+    async fn count_prunable(&self, filter: PruneFilter) -> Result<u64>;
+
+    /// Storage used by an author, or `None` if nothing by them is
+    /// stored.
+    async fn author_stats(&self, author: XOnlyPublicKey) -> Result<Option<AuthorStats>>;
+
+    /// Record where newly stored events were received from.  Backends
+    /// that do not keep sources ignore them.
+    async fn write_sources(&self, _sources: Vec<(EventId, EventSource)>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Where the event with this id was received from, if recorded.
+    async fn event_source(&self, _id: EventId) -> Result<Option<EventSource>> {
+        Ok(None)
+    }
+
+    /// Delete sources received before `before` (seconds since 1970),
+    /// independently of the events themselves.  Returns the number of
+    /// sources deleted.
+    async fn prune_sources(&self, _before: u64) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Record the NIP-05 verification state of an author, replacing
+    /// any earlier state.  Backends that do not keep verifications
+    /// ignore it.
+    async fn save_verification(&self, _verification: UserVerification) -> Result<()> {
+        Ok(())
+    }
+
+    /// Forget the NIP-05 verification state of an author.
+    async fn delete_verification(&self, _pubkey: XOnlyPublicKey) -> Result<()> {
+        Ok(())
+    }
+
+    /// The NIP-05 verification state of an author, if recorded.
+    async fn verification(&self, _pubkey: XOnlyPublicKey) -> Result<Option<UserVerification>> {
+        Ok(None)
+    }
+
+    /// Up to `limit` verifications never checked, or last checked
+    /// before `before` (seconds since 1970), least recently checked
+    /// first.
+    async fn verifications_due(&self, _before: u64, _limit: u64) -> Result<Vec<UserVerification>> {
+        Ok(vec![])
+    }
+
+    /// Ids of up to `limit` of the most recently stored events,
+    /// newest first.
+    async fn recent_ids(&self, _limit: u64) -> Result<Vec<EventId>> {
+        Ok(vec![])
+    }
+
+    /// Make a trivial read, to check the database is answering.
+    async fn ping(&self) -> Result<()>;
+
+    /// Bytes used by stored data, or `None` if the backend does not
+    /// track its size.  A database size budget only applies to
+    /// backends reporting a size.
+    async fn size_bytes(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Perform periodic backend housekeeping, such as checkpointing
+    /// logs and returning freed space to the operating system.
+    async fn maintain(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Rebuild the database to return unused space to the operating
+    /// system, holding off writes until it finishes.  Returns `None`
+    /// if the backend does not support compaction.
+    async fn compact(&self) -> Result<Option<CompactReport>> {
+        Ok(None)
+    }
+
+    /// Reopen connections after a failure, so that a restarted
+    /// writer does not reuse a broken one.
+    async fn reopen(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Flush any outstanding state before shutdown.  No other method
+    /// should be called afterwards.
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Gather statistics about stored events.
+    async fn stats(&self) -> Result<DbStats>;
+}
+
+/// Stream of query results fed by a background query task.
+///
+/// Dropping the stream closes the channel, which the query task
+/// notices and stops early.
+pub(crate) struct QueryStream {
+    pub(crate) rx: tokio::sync::mpsc::Receiver<Result<Event>>,
+}
+
+impl Stream for QueryStream {
+    type Item = Result<Event>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Outcome of persisting a submitted event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteResult {
+    /// The event was stored
+    Persisted,
+    /// The event was already stored
+    Duplicate,
+    /// The event was spooled to disk, and will be stored once the
+    /// writer catches up
+    Queued,
+    /// The event was refused by relay policy, with a reason
+    Rejected(String),
+    /// The event was refused because its author may not publish to
+    /// the relay, with a reason
+    Restricted(String),
+    /// The event was refused until the client authenticates, with a
+    /// reason
+    AuthRequired(String),
+    /// The event could not be stored
+    Error(String),
+    /// The event was refused because the writer is too far behind
+    Overloaded,
+}
+
+impl WriteResult {
+    /// Whether the event is now stored by the relay.
+    pub fn is_accepted(&self) -> bool {
+        matches!(
+            self,
+            WriteResult::Persisted | WriteResult::Duplicate | WriteResult::Queued
+        )
+    }
+
+    /// Human-readable message for the client, using NIP-20 prefixes.
+    pub fn message(&self) -> String {
+        match self {
+            WriteResult::Persisted | WriteResult::Queued => "".to_owned(),
+            WriteResult::Duplicate => "duplicate: already have this event".to_owned(),
+            WriteResult::Rejected(reason) => format!("blocked: {}", reason),
+            WriteResult::Restricted(reason) => format!("restricted: {}", reason),
+            WriteResult::AuthRequired(reason) => format!("auth-required: {}", reason),
+            WriteResult::Error(msg) => format!("error: {}", msg),
+            WriteResult::Overloaded => "rate-limited: relay is overloaded, retry later".to_owned(),
+        }
+    }
+}
+
+/// An event submitted for persistence, with an optional channel for
+/// reporting the outcome back to the submitter.
+#[derive(Debug)]
+pub struct SubmittedEvent {
+    /// Event to persist
+    pub event: Event,
+    /// Where the event was received from, if it should be recorded
+    pub source: Option<EventSource>,
+    /// Receives the result of the write, if present
+    pub notice_tx: Option<tokio::sync::oneshot::Sender<WriteResult>>,
+    /// Span of the connection that submitted the event, for logging
+    /// its outcome
+    pub span: tracing::Span,
+}
+
+impl SubmittedEvent {
+    /// Submit an event, returning a receiver for the write outcome.
+    pub fn new(
+        event: Event,
+        source: Option<EventSource>,
+    ) -> (Self, tokio::sync::oneshot::Receiver<WriteResult>) {
+        let (notice_tx, notice_rx) = tokio::sync::oneshot::channel();
+        let submitted = SubmittedEvent {
+            event,
+            source,
+            notice_tx: Some(notice_tx),
+            span: tracing::Span::current(),
+        };
+        (submitted, notice_rx)
+    }
+
+    /// Report the outcome of the write, if anyone is listening.
+    fn notify(self, result: WriteResult) -> Event {
+        if let Some(tx) = self.notice_tx {
+            tx.send(result).ok();
+        }
+        self.event
+    }
+}
+
+/// Outcome of submitting an event to an [`EventQueue`].
+#[derive(Debug)]
+pub enum Submission {
+    /// The writer will report the outcome on this channel
+    Pending(tokio::sync::oneshot::Receiver<WriteResult>),
+    /// The outcome is already known
+    Done(WriteResult),
+}
+
+/// Hands events to the database writer.
+///
+/// Events go to the writer's channel.  If a [`Spool`] is configured
+/// and the channel is full, events are appended to the spool instead,
+/// and keep going there until the writer has drained it, so that they
+/// are stored in the order they were submitted.  Sources of spooled
+/// events are not recorded.
+#[derive(Debug, Clone)]
+pub struct EventQueue {
+    tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
+    spool: Option<Arc<Spool>>,
+}
+
+impl EventQueue {
+    /// Create a queue feeding `tx`, overflowing into `spool`.
+    pub fn new(tx: tokio::sync::mpsc::Sender<SubmittedEvent>, spool: Option<Arc<Spool>>) -> Self {
+        EventQueue { tx, spool }
+    }
+
+    /// Submit an event for persistence, recording `source` if the
+    /// event is stored.  Without a spool, a full channel refuses the
+    /// event as [`WriteResult::Overloaded`], after waiting up to `wait`
+    /// for room.
+    pub async fn submit(
+        &self,
+        event: Event,
+        source: Option<EventSource>,
+        wait: Duration,
+    ) -> Submission {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => {
+                let (submitted, notice_rx) = SubmittedEvent::new(event, source);
+                let submitted = match self.tx.try_send(submitted) {
+                    Ok(()) => return Submission::Pending(notice_rx),
+                    Err(TrySendError::Full(submitted)) => submitted,
+                    Err(TrySendError::Closed(_)) => return Submission::Done(shutting_down()),
+                };
+                // a moment for the writer to make room
+                if !wait.is_zero() {
+                    match tokio::time::timeout(wait, self.tx.send(submitted)).await {
+                        Ok(Ok(())) => return Submission::Pending(notice_rx),
+                        Ok(Err(_)) => return Submission::Done(shutting_down()),
+                        Err(_) => {}
+                    }
+                }
+                return Submission::Done(WriteResult::Overloaded);
+            }
+        };
+        let event = if spool.depth() == 0 {
+            let (submitted, notice_rx) = SubmittedEvent::new(event, source);
+            match self.tx.try_send(submitted) {
+                Ok(()) => return Submission::Pending(notice_rx),
+                Err(TrySendError::Full(submitted)) => submitted.event,
+                Err(TrySendError::Closed(_)) => return Submission::Done(shutting_down()),
+            }
+        } else {
+            event
+        };
+        if self.tx.is_closed() {
+            return Submission::Done(shutting_down());
+        }
+        match spool.push(&event) {
+            Ok(()) => Submission::Done(WriteResult::Queued),
+            Err(e) => {
+                warn!("could not spool event: {}", e);
+                Submission::Done(WriteResult::Error("relay is overloaded".to_owned()))
+            }
+        }
+    }
+
+    /// Number of events waiting for the writer, in its channel and in
+    /// the spool.
+    pub fn depth(&self) -> u64 {
+        self.in_memory() + self.spool.as_ref().map_or(0, |spool| spool.depth())
+    }
+
+    /// Events queued in memory rather than spooled, which are lost if
+    /// the relay exits before the writer takes them.
+    pub fn in_memory(&self) -> u64 {
+        (self.tx.max_capacity() - self.tx.capacity()) as u64
+    }
+
+    /// Report the state of the spool, if any, in `stats`.
+    pub fn fill_stats(&self, stats: &mut DbStats) {
+        if let Some(spool) = &self.spool {
+            spool.fill_stats(stats);
+        }
+    }
+}
+
+fn shutting_down() -> WriteResult {
+    WriteResult::Error("relay is shutting down".to_owned())
+}
+
+/// Write every event left in `spool` by a previous run, before new
+/// events are accepted.  Returns the number of events newly stored.
+pub async fn replay_spool(storage: &dyn Storage, spool: &Spool, batch_size: usize) -> Result<u64> {
+    let mut stored = 0;
+    loop {
+        let events = spool.pop(batch_size.max(1))?;
+        if events.is_empty() {
+            break;
+        }
+        for result in storage.write_events(events).await {
+            match result {
+                Ok(0) => {}
+                Ok(_) => stored += 1,
+                Err(e) => warn!("could not store spooled event: {}", e),
+            }
+        }
+    }
+    if stored > 0 {
+        info!("stored {} events left in the spool", stored);
+    }
+    Ok(stored)
+}
+
+/// Whether events replaced by a newer metadata or contact list event
+/// are kept, hidden from queries, rather than deleted.
+pub(crate) fn keep_superseded() -> bool {
+    SETTINGS.read().unwrap().database.keep_superseded
+}
+
+/// Construct the storage backend selected by `database.engine`.
+pub fn storage_from_settings() -> Result<Arc<dyn Storage>> {
+    let config = SETTINGS.read().unwrap();
+    let engine = config.database.engine.trim().to_lowercase();
+    match engine.as_str() {
+        "sqlite" => {
+            let storage = SqliteStorage::open(Path::new(&config.database.data_directory))?;
+            Ok(Arc::new(storage))
+        }
+        #[cfg(feature = "postgres")]
+        "postgres" => {
+            let url = config.database.connection_url.as_ref().ok_or_else(|| {
+                Error::DatabaseEngineError("postgres requires a connection_url".to_owned())
+            })?;
+            let storage = PostgresStorage::connect(url, config.database.max_connections)?;
+            Ok(Arc::new(storage))
+        }
+        _ => Err(Error::DatabaseEngineError(format!(
+            "unknown engine \"{}\"",
+            engine
+        ))),
+    }
+}
+
+/// Consecutive batches that must fail entirely before the writer
+/// treats the storage backend as broken and stops.
+const WRITER_FAILURE_LIMIT: u32 = 5;
+
+/// Settings for the write loop.
+#[derive(Debug, Clone)]
+struct WriterOptions {
+    /// Events written per second, if limited
+    messages_per_sec: Option<u32>,
+    /// Maximum events written in one transaction
+    batch_size: usize,
+    /// How long to wait for a batch to fill
+    batch_wait: Duration,
+}
+
+/// What to do when the write loop fails.
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    /// Restarts attempted before giving up
+    attempts: u32,
+    /// Delay before each restart
+    delay: Duration,
+}
+
+/// Spawn a database writer that persists events to the storage backend.
+///
+/// If writing fails persistently, or the writer panics, it is
+/// restarted up to `database.writer_restart_attempts` times after
+/// reopening the storage backend.  Events submitted while the writer
+/// is down are answered with an error.  Once restarts are exhausted
+/// the task ends with the error, and the relay should shut down.
+///
+/// Whenever the channel is empty, events waiting in `spool` are
+/// written, oldest first.  Events found in `recent` are answered as
+/// duplicates without being written.
+pub async fn db_writer(
+    storage: Arc<dyn Storage>,
+    event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
+    spool: Option<Arc<Spool>>,
+    recent: Arc<RecentIds>,
+    bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+    budget: Arc<SizeBudget>,
+    shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> tokio::task::JoinHandle<Result<()>> {
+    // get rate limit, batching and restart settings
+    let (options, policy) = {
+        let config = SETTINGS.read().unwrap();
+        (
+            WriterOptions {
+                messages_per_sec: config.limits.messages_per_sec,
+                batch_size: config.database.write_batch_size.max(1),
+                batch_wait: Duration::from_millis(config.database.write_batch_ms),
+            },
+            RestartPolicy {
+                attempts: config.database.writer_restart_attempts,
+                delay: Duration::from_secs(config.database.writer_restart_seconds),
+            },
+        )
+    };
+    tokio::task::spawn(supervise_writer(
+        storage, event_rx, spool, recent, bcast_tx, budget, shutdown, options, policy,
+    ))
+}
+
+/// Run the write loop, restarting it according to `policy` when it
+/// fails.
+#[allow(clippy::too_many_arguments)]
+async fn supervise_writer(
+    storage: Arc<dyn Storage>,
+    mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
+    spool: Option<Arc<Spool>>,
+    recent: Arc<RecentIds>,
+    bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+    budget: Arc<SizeBudget>,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+    options: WriterOptions,
+    policy: RestartPolicy,
+) -> Result<()> {
+    let mut restarts = 0;
+    loop {
+        let run = AssertUnwindSafe(write_loop(
+            storage.as_ref(),
+            &mut event_rx,
+            spool.as_deref(),
+            &recent,
+            &bcast_tx,
+            &budget,
+            &mut shutdown,
+            &options,
+        ))
+        .catch_unwind()
+        .await;
+        let mut err = match run {
+            Ok(Ok(())) => {
+                info!("database writer stopped");
+                return Ok(());
+            }
+            Ok(Err(e)) => e,
+            Err(_) => Error::GenericError("database writer panicked".to_owned()),
+        };
+        loop {
+            if restarts >= policy.attempts {
+                error!(
+                    "database writer failed after {} restarts, giving up: {}",
+                    restarts, err
+                );
+                return Err(err);
+            }
+            restarts += 1;
+            error!(
+                "database writer failed, restarting in {:?} (attempt {} of {}): {}",
+                policy.delay, restarts, policy.attempts, err
+            );
+            // answer submissions while the writer is down
+            let restart_at = tokio::time::Instant::now() + policy.delay;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(restart_at) => break,
+                    _ = shutdown.recv() => return Err(err),
+                    next_event = event_rx.recv() => match next_event {
+                        Some(submitted) => {
+                            submitted.notify(WriteResult::Error("storage unavailable".to_owned()));
+                        }
+                        None => return Err(err),
+                    },
+                }
+            }
+            match storage.reopen().await {
+                Ok(()) => break,
+                Err(e) => err = e,
+            }
+        }
+        info!("database writer restarted");
+    }
+}
+
+/// Write submitted events until shutdown, or until every write fails
+/// for [`WRITER_FAILURE_LIMIT`] batches in a row.
+#[allow(clippy::too_many_arguments)]
+async fn write_loop(
+    storage: &dyn Storage,
+    event_rx: &mut tokio::sync::mpsc::Receiver<SubmittedEvent>,
+    spool: Option<&Spool>,
+    recent: &RecentIds,
+    bcast_tx: &tokio::sync::broadcast::Sender<BroadcastEvent>,
+    budget: &SizeBudget,
+    shutdown: &mut tokio::sync::broadcast::Receiver<()>,
+    options: &WriterOptions,
+) -> Result<()> {
+    let mut most_recent_rate_limit = Instant::now();
+    let mut lim_opt = None;
+    let clock = governor::clock::QuantaClock::default();
+    if let Some(rps) = options.messages_per_sec {
+        if rps > 0 {
+            info!("Enabling rate limits for event creation ({}/sec)", rps);
+            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
+            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
+        }
+    }
+    let mut failed_batches = 0;
+    let mut draining = false;
+    loop {
+        // events in the channel were submitted before any in the
+        // spool, so they are written first
+        let submitted = tokio::select! {
+            biased;
+            _ = shutdown.recv(), if !draining => {
+                // refuse new events, but store those already queued;
+                // spooled events wait for the next start
+                info!("shutting down database writer, storing queued events");
+                event_rx.close();
+                draining = true;
+                continue;
+            },
+            next_event = event_rx.recv() => match next_event {
+                Some(event) => next_batch(event, event_rx, options.batch_size, options.batch_wait).await,
+                // if the channel has closed, we will never get work
+                None => {
+                    if draining {
+                        info!("database writer stored all queued events");
+                    }
+                    return Ok(());
+                }
+            },
+            spooled = next_spooled(spool, options.batch_size), if !draining => spooled?,
+        };
+        // answer recently stored events without a transaction
+        let submitted: Vec<SubmittedEvent> = submitted
+            .into_iter()
+            .filter_map(|s| {
+                if recent.contains(&s.event.id) {
+                    debug!("ignoring recently stored event");
+                    METRICS.event_duplicate();
+                    s.notify(WriteResult::Duplicate);
+                    None
+                } else {
+                    Some(s)
+                }
+            })
+            .collect();
+        if submitted.is_empty() {
+            continue;
+        }
+        if budget.is_full() {
+            // refuse new events until maintenance frees up space
+            debug!("rejecting {} events, storage is full", submitted.len());
+            for s in submitted {
+                s.notify(WriteResult::Error("relay storage full".to_owned()));
+            }
+            continue;
+        }
+        let batch_len = submitted.len();
+        let start = Instant::now();
+        let events = submitted.iter().map(|s| s.event.clone()).collect();
+        let results = storage
+            .write_events(events)
+            .instrument(tracing::info_span!("write_events", batch = batch_len))
+            .await;
+        let mut written = 0;
+        let mut failed = 0;
+        let mut last_error = None;
+        let mut sources = vec![];
+        for (mut submitted, result) in submitted.into_iter().zip(results) {
+            // the outcome is logged with the submitting connection
+            let span = submitted.span.clone();
+            let _entered = span.enter();
+            match result {
+                Ok(updated) => {
+                    recent.insert(submitted.event.id);
+                    if updated == 0 {
+                        debug!("ignoring duplicate event");
+                        METRICS.event_duplicate();
+                        submitted.notify(WriteResult::Duplicate);
+                    } else {
+                        if let Some(source) = submitted.source.take() {
+                            sources.push((submitted.event.id, source));
+                        }
+                        let event = submitted.notify(WriteResult::Persisted);
+                        info!(
+                            "persisted event: {} in {:?}",
+                            event.get_short_event_id(),
+                            start.elapsed()
+                        );
+                        written += 1;
+                        METRICS.event_persisted();
+                        // send this out to all clients
+                        bcast_tx.send(BroadcastEvent::new(event)).ok();
+                    }
+                }
+                Err(Error::EventDeleted) => {
+                    debug!("refusing deleted event");
+                    submitted.notify(WriteResult::Rejected(
+                        "event was deleted by the relay operator".to_owned(),
+                    ));
+                }
+                Err(err) => {
+                    warn!("event insert failed: {}", err);
+                    submitted.notify(WriteResult::Error(err.to_string()));
+                    failed += 1;
+                    last_error = Some(err);
+                }
+            }
+        }
+        match last_error {
+            Some(err) if failed == batch_len => {
+                failed_batches += 1;
+                if failed_batches >= WRITER_FAILURE_LIMIT {
+                    return Err(Error::DatabaseEngineError(format!(
+                        "{} batches failed in a row, last error: {}",
+                        failed_batches, err
+                    )));
+                }
+            }
+            _ => failed_batches = 0,
+        }
+        if !sources.is_empty() {
+            if let Err(err) = storage.write_sources(sources).await {
+                warn!("could not record event sources: {}", err);
+            }
+        }
+        if batch_len > 1 {
+            tracing::debug!(
+                written,
+                batch = batch_len,
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                "wrote batched events"
+            );
+        }
+        // use rate limit, if defined, for each event actually written.
+        if let Some(ref lim) = lim_opt {
+            for _ in 0..written {
+                if let Err(n) = lim.check() {
+                    let wait_for = n.wait_time_from(clock.now());
+                    // check if we have recently logged rate
+                    // limits, but print out a message only once
+                    // per second.
+                    if most_recent_rate_limit.elapsed().as_secs() > 1 {
+                        warn!(
+                            "rate limit reached for event creation (sleep for {:?})",
+                            wait_for
+                        );
+                        // reset last rate limit message
+                        most_recent_rate_limit = Instant::now();
+                    }
+                    // hold off event writes, allowing them to queue up
+                    tokio::time::sleep(wait_for).await;
+                }
+            }
+        }
+    }
+}
+
+/// Wait for events in the spool, and take up to `max_len` of them,
+/// skipping repeats.  Never completes if there is no spool.
+async fn next_spooled(spool: Option<&Spool>, max_len: usize) -> Result<Vec<SubmittedEvent>> {
+    let spool = match spool {
+        Some(spool) => spool,
+        None => return futures::future::pending().await,
+    };
+    loop {
+        let events = spool.pop(max_len)?;
+        if !events.is_empty() {
+            let mut seen = std::collections::HashSet::new();
+            return Ok(events
+                .into_iter()
+                .filter(|e| seen.insert(e.id))
+                .map(|event| SubmittedEvent {
+                    event,
+                    source: None,
+                    notice_tx: None,
+                    span: tracing::Span::none(),
+                })
+                .collect());
+        }
+        spool.pushed().await;
+    }
+}
+
+/// Collect a batch of events to write, starting with `first`.
+///
+/// Events already queued on `event_rx` are taken immediately, up to
+/// `max_len`.  If the batch is still short, wait up to `max_wait` for
+/// more to arrive.
+async fn next_batch(
+    first: SubmittedEvent,
+    event_rx: &mut tokio::sync::mpsc::Receiver<SubmittedEvent>,
+    max_len: usize,
+    max_wait: Duration,
+) -> Vec<SubmittedEvent> {
+    let mut events = vec![first];
+    while events.len() < max_len {
+        match event_rx.try_recv() {
+            Ok(event) => events.push(event),
+            Err(_) => break,
+        }
+    }
+    if max_wait.is_zero() {
+        return events;
+    }
+    let deadline = tokio::time::Instant::now() + max_wait;
+    while events.len() < max_len {
+        match tokio::time::timeout_at(deadline, event_rx.recv()).await {
+            Ok(Some(event)) => events.push(event),
+            // channel closed or deadline reached
+            _ => break,
+        }
+    }
+    events
+}
+
+/// Spawn a task performing periodic database maintenance: pruning
+/// events according to the retention policy and size budget, and
+/// event sources older than `database.event_source_max_age_seconds`,
+/// followed by backend housekeeping.
+pub async fn db_maintenance(
+    storage: Arc<dyn Storage>,
+    budget: Arc<SizeBudget>,
+    recent: Arc<RecentIds>,
+    mut shutdown: tokio::sync::broadcast::Receiver<()>,
+) -> tokio::task::JoinHandle<()> {
+    let (interval_secs, policy, source_max_age) = {
+        let config = SETTINGS.read().unwrap();
+        let policy = RetentionPolicy::from_config(&config.retention).unwrap_or_else(|e| {
+            warn!("retention disabled: {}", e);
+            RetentionPolicy::default()
+        });
+        (
+            config.database.maintenance_interval_seconds,
+            policy,
+            config.database.event_source_max_age_seconds,
+        )
+    };
+    tokio::task::spawn(async move {
+        if interval_secs == 0 {
+            info!("database maintenance disabled");
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => {
+                    info!("shutting down database maintenance");
+                    break;
+                },
+                _ = interval.tick() => {
+                    let mut deleted = 0;
+                    if policy.is_enabled() {
+                        match retention::prune(storage.as_ref(), &policy).await {
+                            Ok(report) => deleted += report.total(),
+                            Err(err) => warn!("event pruning failed: {}", err),
+                        }
+                    }
+                    match retention::enforce_size(storage.as_ref(), &policy, &budget).await {
+                        Ok(count) => deleted += count,
+                        Err(err) => warn!("size budget enforcement failed: {}", err),
+                    }
+                    // forget ids of events that are no longer stored
+                    if deleted > 0 {
+                        if let Err(err) = recent.load(storage.as_ref()).await {
+                            warn!("could not reload recent event ids: {}", err);
+                        }
+                    }
+                    if let Some(max_age) = source_max_age {
+                        let before = retention::now().saturating_sub(max_age);
+                        match storage.prune_sources(before).await {
+                            Ok(n) if n > 0 => info!("pruned {} event sources", n),
+                            Ok(_) => {}
+                            Err(err) => warn!("event source pruning failed: {}", err),
+                        }
+                    }
+                    // runs after pruning, so freed space can be reclaimed
+                    if let Err(err) = storage.maintain().await {
+                        warn!("database maintenance failed: {}", err);
+                    }
+                },
+            }
+        }
+    })
+}
+
+/// Event resulting from a specific subscription request
+#[derive(PartialEq, Debug, Clone)]
+pub struct QueryResult {
+    /// Subscription identifier
+    pub sub_id: String,
+    /// Generation of the query that produced this result
+    pub generation: u64,
+    /// Serialized event
+    pub event: Event,
+}
+
+/// Queries running for a client connection, by subscription id.
+///
+/// Each query is given a new generation number, so results from a
+/// query that was cancelled or replaced can be recognised and
+/// dropped after they were queued.
+#[derive(Debug, Default)]
+pub struct RunningQueries {
+    next_generation: u64,
+    queries: HashMap<String, (u64, tokio::sync::oneshot::Sender<()>)>,
+}
+
+impl RunningQueries {
+    /// Register a new query for `sub_id`, cancelling any query
+    /// already running for it.  Returns the generation to tag results
+    /// with, and the channel that aborts the query.
+    pub fn start(&mut self, sub_id: &str) -> (u64, tokio::sync::oneshot::Receiver<()>) {
+        self.cancel(sub_id);
+        let (abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        self.next_generation += 1;
+        self.queries
+            .insert(sub_id.to_owned(), (self.next_generation, abandon_tx));
+        (self.next_generation, abandon_rx)
+    }
+
+    /// Cancel the query for `sub_id`, if one is running.
+    pub fn cancel(&mut self, sub_id: &str) {
+        if let Some((_, abandon_tx)) = self.queries.remove(sub_id) {
+            abandon_tx.send(()).ok();
+        }
+    }
+
+    /// Cancel all running queries.
+    pub fn cancel_all(&mut self) {
+        for (_, (_, abandon_tx)) in self.queries.drain() {
+            abandon_tx.send(()).ok();
+        }
+    }
+
+    /// Check whether a result came from the current query for its
+    /// subscription.
+    pub fn is_current(&self, result: &QueryResult) -> bool {
+        self.queries
+            .get(&result.sub_id)
+            .is_some_and(|(generation, _)| *generation == result.generation)
+    }
+}
+
+/// Queries cancelled because their connection's task ended without
+/// cancelling them, as when it is aborted at shutdown.
+static ABANDONED_QUERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of queries abandoned by connection tasks that were aborted
+/// or failed, since the relay started.
+pub fn abandoned_queries() -> u64 {
+    ABANDONED_QUERIES.load(Ordering::Relaxed)
+}
+
+impl Drop for RunningQueries {
+    fn drop(&mut self) {
+        // a query whose abandon channel is closed has finished
+        let running = self
+            .queries
+            .values()
+            .filter(|(_, abandon_tx)| !abandon_tx.is_closed())
+            .count();
+        ABANDONED_QUERIES.fetch_add(running as u64, Ordering::Relaxed);
+        self.cancel_all();
+    }
+}
+
+/// Perform a database query using a subscription.
+///
+/// The [`Subscription`] is run against the storage backend.  Each
+/// result is published on the `query_tx` channel as it is returned.
+/// If a message becomes available on the `abandon_query_rx` channel,
+/// the query is immediately aborted.  Results are tagged with
+/// `generation`.
+pub async fn db_query(
+    sub: Subscription,
+    generation: u64,
+    storage: Arc<dyn Storage>,
+    query_tx: tokio::sync::mpsc::Sender<QueryResult>,
+    mut abandon_query_rx: tokio::sync::oneshot::Receiver<()>,
+) {
+    let sub_id = sub.get_id().to_string();
+    // carries the subscription, and the connection it belongs to, into
+    // anything logged about the query
+    let span = tracing::info_span!("db_query", sub_id = %sub_id, generation);
+    let start = Instant::now();
+    let mut results = storage.query(sub);
+    let mut sent: u64 = 0;
+    tokio::spawn(
+        async move {
+            loop {
+                tokio::select! {
+                    _ = &mut abandon_query_rx => {
+                        // dropping the stream stops the running query
+                        debug!("query aborted");
+                        return;
+                    },
+                    next = results.next() => match next {
+                        Some(Ok(event)) => {
+                            let res = QueryResult {
+                                sub_id: sub_id.clone(),
+                                generation,
+                                event,
+                            };
+                            if query_tx.send(res).await.is_err() {
+                                return;
+                            }
+                            sent += 1;
+                        },
+                        Some(Err(e)) => {
+                            warn!("query failed: {}", e);
+                            return;
+                        },
+                        None => {
+                            let elapsed = start.elapsed();
+                            METRICS.query_finished(elapsed);
+                            tracing::debug!(
+                                elapsed_ms = elapsed.as_millis() as u64,
+                                results = sent,
+                                "query finished"
+                            );
+                            return;
+                        },
+                    },
+                }
+            }
+        }
+        .instrument(span),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{event_with_id, temp_db_dir, valid_event};
+    use bitcoin_hashes::Hash;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Storage whose writes fail until it is reopened.
+    #[derive(Default)]
+    struct BrokenStorage {
+        broken: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Storage for BrokenStorage {
+        async fn migrate(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn write_event(&self, _event: Event) -> Result<usize> {
+            if self.broken.load(Ordering::SeqCst) {
+                Err(Error::DatabaseEngineError("disk I/O error".to_owned()))
+            } else {
+                Ok(1)
+            }
+        }
+        fn query(&self, _sub: Subscription) -> BoxStream<'static, Result<Event>> {
+            futures::stream::empty().boxed()
+        }
+        async fn count(&self, _sub: Subscription) -> Result<u64> {
+            Ok(0)
+        }
+        async fn ping(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn delete(&self, _id: EventId) -> Result<bool> {
+            Ok(false)
+        }
+        async fn prune_events(&self, _filter: PruneFilter, _limit: u64) -> Result<u64> {
+            Ok(0)
+        }
+        async fn count_prunable(&self, _filter: PruneFilter) -> Result<u64> {
+            Ok(0)
+        }
+        async fn reopen(&self) -> Result<()> {
+            self.broken.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn stats(&self) -> Result<DbStats> {
+            Ok(DbStats::default())
+        }
+        async fn author_stats(&self, _author: XOnlyPublicKey) -> Result<Option<AuthorStats>> {
+            Ok(None)
+        }
+        async fn delete_event(&self, _id: EventId) -> Result<DeleteOutcome> {
+            Ok(DeleteOutcome::default())
+        }
+    }
+
+    /// Submit a copy of the test event and wait for the outcome.
+    async fn submit(tx: &tokio::sync::mpsc::Sender<SubmittedEvent>, n: u8) -> WriteResult {
+        let (submitted, rx) = SubmittedEvent::new(event_with_id(n), None);
+        tx.send(submitted).await.unwrap();
+        rx.await.unwrap()
+    }
+
+    #[test]
+    fn dropped_queries_abandoned() {
+        let before = abandoned_queries();
+        let mut queries = RunningQueries::default();
+        let (_, finished) = queries.start("finished");
+        drop(finished);
+        let (_, mut running) = queries.start("running");
+        drop(queries);
+        // only the query still running counts, and it is told to stop
+        assert_eq!(abandoned_queries() - before, 1);
+        assert!(running.try_recv().is_ok());
+
+        let mut queries = RunningQueries::default();
+        let (_, _running) = queries.start("cancelled");
+        queries.cancel_all();
+        drop(queries);
+        assert_eq!(abandoned_queries() - before, 1);
+    }
+
+    /// Settings for a writer under test: one event per batch, no rate
+    /// limit, no spool or recent ids, and no restarts, unless a test
+    /// changes them.
+    struct TestWriter {
+        storage: Arc<dyn Storage>,
+        spool: Option<Arc<Spool>>,
+        recent: Arc<RecentIds>,
+        options: WriterOptions,
+        policy: RestartPolicy,
+    }
+
+    impl TestWriter {
+        fn new(storage: Arc<dyn Storage>) -> Self {
+            TestWriter {
+                storage,
+                spool: None,
+                recent: Arc::new(RecentIds::new(0)),
+                options: WriterOptions {
+                    messages_per_sec: None,
+                    batch_size: 1,
+                    batch_wait: Duration::ZERO,
+                },
+                policy: RestartPolicy {
+                    attempts: 0,
+                    delay: Duration::ZERO,
+                },
+            }
+        }
+
+        fn spool(mut self, spool: Arc<Spool>) -> Self {
+            self.spool = Some(spool);
+            self
+        }
+
+        fn recent(mut self, recent: Arc<RecentIds>) -> Self {
+            self.recent = recent;
+            self
+        }
+
+        fn batch_size(mut self, batch_size: usize) -> Self {
+            self.options.batch_size = batch_size;
+            self
+        }
+
+        fn restarts(mut self, attempts: u32, delay: Duration) -> Self {
+            self.policy = RestartPolicy { attempts, delay };
+            self
+        }
+
+        /// Write the events from `event_rx` until `shutdown`.
+        fn run(
+            self,
+            event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
+            bcast_tx: tokio::sync::broadcast::Sender<BroadcastEvent>,
+            shutdown: tokio::sync::broadcast::Receiver<()>,
+        ) -> impl std::future::Future<Output = Result<()>> {
+            supervise_writer(
+                self.storage,
+                event_rx,
+                self.spool,
+                self.recent,
+                bcast_tx,
+                Arc::new(SizeBudget::new(None)),
+                shutdown,
+                self.options,
+                self.policy,
+            )
+        }
+    }
+
+    // the clock only moves when every task is waiting, so the writer
+    // is reliably still down when the first event after failing arrives
+    #[tokio::test(start_paused = true)]
+    async fn failed_writer_restarts() {
+        let storage = Arc::new(BrokenStorage::default());
+        storage.broken.store(true, Ordering::SeqCst);
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(16);
+        let (bcast_tx, _) = tokio::sync::broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let writer = tokio::spawn(
+            TestWriter::new(storage.clone())
+                .restarts(1, Duration::from_secs(60))
+                .run(event_rx, bcast_tx, shutdown_rx),
+        );
+        for n in 0..WRITER_FAILURE_LIMIT {
+            let result = submit(&event_tx, n as u8).await;
+            assert_eq!(
+                result.message(),
+                "error: Database engine error, Reason : disk I/O error"
+            );
+        }
+        // the writer is down until restarted
+        assert_eq!(
+            submit(&event_tx, 100).await,
+            WriteResult::Error("storage unavailable".to_owned())
+        );
+        // restarting reopens the storage, repairing it
+        while storage.broken.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        assert_eq!(submit(&event_tx, 101).await, WriteResult::Persisted);
+        // no restarts are left after the next failure
+        storage.broken.store(true, Ordering::SeqCst);
+        for n in 0..WRITER_FAILURE_LIMIT {
+            submit(&event_tx, n as u8).await;
+        }
+        assert!(writer.await.unwrap().is_err());
+        drop(shutdown_tx);
+    }
+
+    #[tokio::test]
+    async fn shutdown_stores_queued_events() {
+        let storage = Arc::new(BrokenStorage::default());
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(16);
+        let mut pending = vec![];
+        for n in 0..3 {
+            let (submitted, rx) = SubmittedEvent::new(event_with_id(n), None);
+            event_tx.send(submitted).await.unwrap();
+            pending.push(rx);
+        }
+        let (bcast_tx, _) = tokio::sync::broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        // shut down before the writer has taken anything
+        shutdown_tx.send(()).unwrap();
+        TestWriter::new(storage)
+            .run(event_rx, bcast_tx, shutdown_rx)
+            .await
+            .unwrap();
+        for rx in pending {
+            assert_eq!(rx.await.unwrap(), WriteResult::Persisted);
+        }
+        // later events are refused
+        let (submitted, _) = SubmittedEvent::new(valid_event(), None);
+        assert!(event_tx.send(submitted).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn recent_duplicates_skip_storage() {
+        // writes would fail, so a duplicate result can only come from
+        // the recent id set
+        let storage = Arc::new(BrokenStorage::default());
+        storage.broken.store(true, Ordering::SeqCst);
+        let recent = Arc::new(RecentIds::new(10));
+        recent.insert(EventId::from_inner([1; 32]));
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(16);
+        let (bcast_tx, _) = tokio::sync::broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let writer = tokio::spawn(TestWriter::new(storage).recent(recent.clone()).run(
+            event_rx,
+            bcast_tx,
+            shutdown_rx,
+        ));
+        assert_eq!(submit(&event_tx, 1).await, WriteResult::Duplicate);
+        assert!(!submit(&event_tx, 2).await.is_accepted());
+        let mut stats = DbStats::default();
+        recent.fill_stats(&mut stats);
+        assert_eq!((stats.duplicate_checks, stats.duplicate_hits), (2, 1));
+        shutdown_tx.send(()).unwrap();
+        writer.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn full_queue_refused_without_waiting() {
+        // a writer that has stalled, taking nothing from its channel
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(1);
+        let queue = EventQueue::new(event_tx, None);
+        let mut event = valid_event();
+        let first = queue.submit(event.clone(), None, Duration::ZERO).await;
+        assert!(matches!(first, Submission::Pending(_)));
+        event.id = EventId::from_inner([1; 32]);
+        let started = Instant::now();
+        match queue.submit(event.clone(), None, Duration::ZERO).await {
+            Submission::Done(result) => {
+                assert_eq!(result, WriteResult::Overloaded);
+                assert_eq!(
+                    result.message(),
+                    "rate-limited: relay is overloaded, retry later"
+                );
+            }
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+        // a bounded wait, then the same refusal
+        let refused = queue
+            .submit(event.clone(), None, Duration::from_millis(50))
+            .await;
+        assert!(matches!(refused, Submission::Done(WriteResult::Overloaded)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // room made during the wait is taken
+        let drain = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            event_rx.recv().await.unwrap();
+            event_rx
+        });
+        let waited = queue
+            .submit(event.clone(), None, Duration::from_secs(5))
+            .await;
+        assert!(matches!(waited, Submission::Pending(_)));
+        drop(drain.await.unwrap());
+        let closed = queue.submit(event, None, Duration::ZERO).await;
+        assert!(matches!(closed, Submission::Done(WriteResult::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn overflow_is_spooled_in_order() {
+        let dir = temp_db_dir();
+        let spool = Arc::new(Spool::open(&dir, 1024 * 1024, SpoolFsync::Never).unwrap());
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(1);
+        let queue = EventQueue::new(event_tx, Some(spool.clone()));
+        let mut event = valid_event();
+        let mut outcomes = vec![];
+        for n in 0..3 {
+            event.id = EventId::from_inner([n; 32]);
+            outcomes.push(queue.submit(event.clone(), None, Duration::ZERO).await);
+        }
+        // the channel holds one event, the rest are spooled
+        assert!(matches!(outcomes[0], Submission::Pending(_)));
+        assert!(matches!(outcomes[1], Submission::Done(WriteResult::Queued)));
+        assert!(matches!(outcomes[2], Submission::Done(WriteResult::Queued)));
+        assert_eq!(spool.depth(), 2);
+        assert_eq!(queue.depth(), 3);
+
+        let (bcast_tx, mut bcast_rx) = tokio::sync::broadcast::channel(16);
+        let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+        let writer = tokio::spawn(
+            TestWriter::new(Arc::new(BrokenStorage::default()))
+                .spool(spool.clone())
+                .batch_size(10)
+                .run(event_rx, bcast_tx, shutdown_rx),
+        );
+        for n in 0..3 {
+            assert_eq!(
+                bcast_rx.recv().await.unwrap().id,
+                EventId::from_inner([n; 32])
+            );
+        }
+        assert_eq!(spool.depth(), 0);
+        assert_eq!(queue.depth(), 0);
+        let mut stats = DbStats::default();
+        queue.fill_stats(&mut stats);
+        assert_eq!(stats.spooled_events, 0);
+        shutdown_tx.send(()).unwrap();
+        writer.await.unwrap().unwrap();
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,726 @@
+//! PostgreSQL storage backend
+use super::{
+    keep_superseded, top_authors_sql, top_kinds_sql, AuthorStats, DbStats, DeleteOutcome,
+    EventSource, KindCount, PruneFilter, QueryStream, StatsCache, Storage, UserVerification,
+    AUTHOR_STATS_COLUMNS, STATS_SUMMARY_SQL,
+};
+use crate::error::{Error, Result};
+use crate::protocol::{Event, EventId, Subscription};
+use async_trait::async_trait;
+use bitcoin_hashes::{hex::ToHex, Hash};
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::NoTls;
+
+/// Number of query results buffered between a query task and its consumer
+const QUERY_STREAM_BUFFER: usize = 256;
+
+/// Current schema version
+const DB_VERSION: i32 = 2;
+
+/// Schema definition
+const INIT_SQL: &str = r##"
+-- Schema version bookkeeping
+CREATE TABLE IF NOT EXISTS schema_version (
+version INTEGER NOT NULL
+);
+
+-- Event Table
+CREATE TABLE IF NOT EXISTS event (
+id BIGSERIAL PRIMARY KEY,
+event_hash BYTEA NOT NULL, -- 32-byte hash
+first_seen BIGINT NOT NULL, -- when the event was first seen (not authored!) (seconds since 1970)
+created_at BIGINT NOT NULL, -- when the event was authored
+author BYTEA NOT NULL, -- author pubkey
+kind BIGINT NOT NULL, -- event kind
+hidden BOOLEAN NOT NULL DEFAULT FALSE, -- relevant for queries
+content TEXT NOT NULL -- serialized json of event object
+);
+
+-- Event Indexes
+CREATE UNIQUE INDEX IF NOT EXISTS event_hash_index ON event(event_hash);
+CREATE INDEX IF NOT EXISTS created_at_index ON event(created_at);
+CREATE INDEX IF NOT EXISTS author_index ON event(author);
+CREATE INDEX IF NOT EXISTS kind_index ON event(kind);
+CREATE INDEX IF NOT EXISTS hidden_index ON event(id) WHERE hidden;
+
+-- Tag Table, holding both event ("e") and pubkey ("p") references
+CREATE TABLE IF NOT EXISTS tag (
+id BIGSERIAL PRIMARY KEY,
+event_id BIGINT NOT NULL REFERENCES event(id) ON DELETE CASCADE,
+name TEXT NOT NULL, -- tag name
+value BYTEA NOT NULL -- referenced event id or pubkey
+);
+
+-- Tag Indexes
+CREATE INDEX IF NOT EXISTS tag_value_index ON tag(name, value);
+CREATE INDEX IF NOT EXISTS tag_event_index ON tag(event_id);
+
+-- Per-author totals, kept up to date by triggers on the event table
+CREATE TABLE IF NOT EXISTS author_stats (
+author BYTEA PRIMARY KEY, -- author pubkey
+event_count BIGINT NOT NULL, -- number of stored events
+total_bytes BIGINT NOT NULL, -- size of the stored event JSON
+first_seen BIGINT NOT NULL, -- when the first stored event was received
+last_seen BIGINT NOT NULL -- when the latest stored event was received
+);
+
+CREATE OR REPLACE FUNCTION author_stats_insert() RETURNS trigger AS $$
+BEGIN
+INSERT INTO author_stats (author, event_count, total_bytes, first_seen, last_seen)
+  VALUES (NEW.author, 1, octet_length(NEW.content), NEW.first_seen, NEW.first_seen)
+  ON CONFLICT (author) DO UPDATE SET event_count = author_stats.event_count + 1,
+  total_bytes = author_stats.total_bytes + EXCLUDED.total_bytes,
+  last_seen = GREATEST(author_stats.last_seen, EXCLUDED.last_seen);
+RETURN NULL;
+END $$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION author_stats_delete() RETURNS trigger AS $$
+BEGIN
+UPDATE author_stats SET event_count = event_count - 1,
+  total_bytes = total_bytes - octet_length(OLD.content)
+  WHERE author = OLD.author;
+DELETE FROM author_stats WHERE author = OLD.author AND event_count <= 0;
+RETURN NULL;
+END $$ LANGUAGE plpgsql;
+
+-- Where stored events were received from, never returned by queries
+CREATE TABLE IF NOT EXISTS event_source (
+event_hash BYTEA PRIMARY KEY, -- 32-byte hash of the received event
+remote_ip TEXT NOT NULL, -- IP address of the submitting connection
+client_id TEXT NOT NULL, -- client id prefix of the submitting connection
+received_at BIGINT NOT NULL -- when the event was received (seconds since 1970)
+);
+CREATE INDEX IF NOT EXISTS event_source_received_index ON event_source(received_at);
+
+-- Ids of events deleted by the operator, refused if submitted again
+CREATE TABLE IF NOT EXISTS tombstone (
+event_hash BYTEA PRIMARY KEY, -- 32-byte hash of the deleted event
+deleted_at BIGINT NOT NULL -- when the event was deleted (seconds since 1970)
+);
+
+-- NIP-05 identifiers of authors, and when they were last checked
+CREATE TABLE IF NOT EXISTS user_verification (
+pubkey BYTEA PRIMARY KEY, -- author pubkey
+name TEXT NOT NULL, -- NIP-05 identifier from the latest metadata
+last_checked BIGINT, -- when the identifier was last checked (seconds since 1970)
+last_success BIGINT, -- when the identifier was last confirmed
+failure_count INTEGER NOT NULL DEFAULT 0 -- checks failed since the last success
+);
+CREATE INDEX IF NOT EXISTS user_verification_checked_index ON user_verification(last_checked);
+
+DROP TRIGGER IF EXISTS author_stats_insert ON event;
+CREATE TRIGGER author_stats_insert AFTER INSERT ON event
+  FOR EACH ROW EXECUTE PROCEDURE author_stats_insert();
+DROP TRIGGER IF EXISTS author_stats_delete ON event;
+CREATE TRIGGER author_stats_delete AFTER DELETE ON event
+  FOR EACH ROW EXECUTE PROCEDURE author_stats_delete();
+"##;
+
+/// Recompute `author_stats` from the event table.
+const AUTHOR_STATS_BACKFILL_SQL: &str = r##"
+DELETE FROM author_stats;
+INSERT INTO author_stats (author, event_count, total_bytes, first_seen, last_seen)
+  SELECT author, COUNT(*), SUM(octet_length(content)), MIN(first_seen), MAX(first_seen)
+  FROM event GROUP BY author;
+"##;
+
+/// Read an `author_stats` row selected with [`AUTHOR_STATS_COLUMNS`].
+fn author_stats_from_row(row: &tokio_postgres::Row) -> AuthorStats {
+    AuthorStats {
+        author: row.get::<_, Vec<u8>>(0).to_hex(),
+        event_count: row.get::<_, i64>(1) as u64,
+        total_bytes: row.get::<_, i64>(2) as u64,
+        first_seen: row.get::<_, i64>(3) as u64,
+        last_seen: row.get::<_, i64>(4) as u64,
+    }
+}
+
+/// Columns selected for a [`UserVerification`].
+const VERIFICATION_COLUMNS: &str = "pubkey, name, last_checked, last_success, failure_count";
+
+/// Read a `user_verification` row selected with [`VERIFICATION_COLUMNS`].
+fn verification_from_row(row: &tokio_postgres::Row) -> Result<UserVerification> {
+    let pubkey = XOnlyPublicKey::from_slice(&row.get::<_, Vec<u8>>(0))
+        .map_err(|e| Error::DatabaseEngineError(format!("invalid stored pubkey: {}", e)))?;
+    Ok(UserVerification {
+        pubkey,
+        name: row.get(1),
+        last_checked: row.get::<_, Option<i64>>(2).map(|t| t as u64),
+        last_success: row.get::<_, Option<i64>>(3).map(|t| t as u64),
+        failure_count: row.get::<_, i32>(4) as u32,
+    })
+}
+
+/// Event storage in a PostgreSQL database, shared by any number of
+/// relay processes.
+pub struct PostgresStorage {
+    pool: Pool,
+    /// Events deleted by retention pruning
+    pruned: AtomicU64,
+    /// Recently computed statistics
+    stats_cache: StatsCache,
+}
+
+impl PostgresStorage {
+    /// Create a connection pool for the database at `url`.
+    /// Connections are established lazily.
+    pub fn connect(url: &str, max_connections: usize) -> Result<Self> {
+        let pg_config = tokio_postgres::Config::from_str(url)?;
+        let manager = Manager::from_config(
+            pg_config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(max_connections)
+            .build()
+            .map_err(|e| Error::DatabaseEngineError(e.to_string()))?;
+        Ok(PostgresStorage {
+            pool,
+            pruned: AtomicU64::new(0),
+            stats_cache: StatsCache::from_settings(),
+        })
+    }
+
+    /// Get a pooled client.
+    async fn client(&self) -> Result<deadpool_postgres::Client> {
+        self.pool
+            .get()
+            .await
+            .map_err(|e| Error::DatabaseEngineError(e.to_string()))
+    }
+}
+
+/// A dynamically built SQL statement with its parameters.
+struct SqlQuery {
+    sql: String,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+}
+
+impl SqlQuery {
+    /// Add a parameter, returning its placeholder.
+    fn bind<T: ToSql + Sync + Send + 'static>(&mut self, value: T) -> String {
+        self.params.push(Box::new(value));
+        format!("${}", self.params.len())
+    }
+
+    /// Parameters in the form expected by tokio-postgres.
+    fn param_refs(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params
+            .iter()
+            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+            .collect()
+    }
+}
+
+/// Kind of an event as stored in the database.
+fn kind_value(e: &Event) -> Result<i64> {
+    let kind = serde_json::to_value(&e.kind)?
+        .as_u64()
+        .ok_or_else(|| Error::GenericError("expect a kind".to_owned()))?;
+    Ok(kind as i64)
+}
+
+/// Build a query selecting the events that match a subscription,
+/// prefixed with the given select clause.  Hidden events never match.
+fn query_from_sub(select: &str, sub: &Subscription) -> SqlQuery {
+    let mut q = SqlQuery {
+        sql: select.to_owned(),
+        params: Vec::new(),
+    };
+    // for every filter in the subscription, generate a where clause
+    let mut filter_clauses: Vec<String> = Vec::new();
+    // a filter without conditions matches every event
+    let mut matches_all = false;
+    for f in sub.get_filters().iter() {
+        // individual filter components
+        let mut filter_components: Vec<String> = Vec::new();
+        if let Some(authors) = &f.authors {
+            let authors: Vec<Vec<u8>> = authors.iter().map(|a| a.serialize().to_vec()).collect();
+            let p = q.bind(authors);
+            filter_components.push(format!("e.author = ANY({})", p));
+        }
+        if let Some(kinds) = &f.kinds {
+            let kinds: Vec<i64> = kinds
+                .iter()
+                .filter_map(|k| serde_json::to_value(k).ok()?.as_i64())
+                .collect();
+            let p = q.bind(kinds);
+            filter_components.push(format!("e.kind = ANY({})", p));
+        }
+        if let Some(ids) = &f.ids {
+            let ids: Vec<Vec<u8>> = ids.iter().map(|id| id.as_inner().to_vec()).collect();
+            let p = q.bind(ids);
+            filter_components.push(format!("e.event_hash = ANY({})", p));
+        }
+        if let Some(events) = &f.events {
+            let events: Vec<Vec<u8>> = events.iter().map(|id| id.as_inner().to_vec()).collect();
+            let p = q.bind(events);
+            filter_components.push(format!(
+                "EXISTS (SELECT 1 FROM tag t WHERE t.event_id = e.id AND t.name = 'e' AND t.value = ANY({}))",
+                p
+            ));
+        }
+        if let Some(pubkeys) = &f.pubkeys {
+            let pubkeys: Vec<Vec<u8>> = pubkeys.iter().map(|pk| pk.serialize().to_vec()).collect();
+            let p = q.bind(pubkeys);
+            filter_components.push(format!(
+                "EXISTS (SELECT 1 FROM tag t WHERE t.event_id = e.id AND t.name = 'p' AND t.value = ANY({}))",
+                p
+            ));
+        }
+        if let Some(since) = f.since {
+            let p = q.bind(since as i64);
+            filter_components.push(format!("e.created_at > {}", p));
+        }
+        if let Some(until) = f.until {
+            let p = q.bind(until as i64);
+            filter_components.push(format!("e.created_at < {}", p));
+        }
+        // combine all clauses, and add to filter_clauses
+        if !filter_components.is_empty() {
+            filter_clauses.push(format!("( {} )", filter_components.join(" AND ")));
+        } else {
+            matches_all = true;
+        }
+    }
+    // never display hidden events (matches the SQLite backend)
+    q.sql.push_str(" WHERE NOT e.hidden");
+    // combine all filters with OR clauses, if any exist
+    if !matches_all && !filter_clauses.is_empty() {
+        q.sql.push_str(" AND ( ");
+        q.sql.push_str(&filter_clauses.join(" OR "));
+        q.sql.push_str(" )");
+    }
+    q
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        tx.batch_execute(INIT_SQL).await?;
+        let curr_version: Option<i32> = tx
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+            .map(|row| row.get(0));
+        match curr_version {
+            None => {
+                tx.execute(
+                    "INSERT INTO schema_version (version) VALUES ($1)",
+                    &[&DB_VERSION],
+                )
+                .await?;
+                info!("postgres schema initialized to v{}", DB_VERSION);
+            }
+            Some(1) => {
+                // author statistics start from the events already stored
+                tx.batch_execute(AUTHOR_STATS_BACKFILL_SQL).await?;
+                tx.execute("UPDATE schema_version SET version = $1", &[&DB_VERSION])
+                    .await?;
+                info!("postgres schema upgraded v1 -> v{}", DB_VERSION);
+            }
+            Some(v) if v == DB_VERSION => debug!("Database version was already current"),
+            Some(v) if v > DB_VERSION => {
+                return Err(Error::FutureSchemaVersion {
+                    found: v as usize,
+                    supported: DB_VERSION as usize,
+                })
+            }
+            Some(v) => {
+                return Err(Error::DatabaseEngineError(format!(
+                    "postgres schema version {} is not supported (expected {})",
+                    v, DB_VERSION
+                )))
+            }
+        }
+        if !keep_superseded() {
+            // superseded events are only hidden while they are kept;
+            // tags are removed by cascade.
+            let deleted = tx.execute("DELETE FROM event WHERE hidden", &[]).await?;
+            if deleted > 0 {
+                info!("deleted {} superseded events", deleted);
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn write_event(&self, e: Event) -> Result<usize> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let id_blob = e.id.as_inner().to_vec();
+        let pubkey_blob = e.pubkey.serialize().to_vec();
+        let event_str = serde_json::to_string(&e)?;
+        let event_kind = kind_value(&e)?;
+        let created_at = e.created_at as i64;
+        let tombstoned = tx
+            .query_opt("SELECT 1 FROM tombstone WHERE event_hash=$1", &[&id_blob])
+            .await?
+            .is_some();
+        if tombstoned {
+            return Err(Error::EventDeleted);
+        }
+        // ignore if the event hash is a duplicate.
+        let inserted = tx
+            .query_opt(
+                "INSERT INTO event (event_hash, created_at, kind, author, content, first_seen, hidden) \
+                 VALUES ($1, $2, $3, $4, $5, EXTRACT(EPOCH FROM NOW())::BIGINT, FALSE) \
+                 ON CONFLICT (event_hash) DO NOTHING RETURNING id",
+                &[&id_blob, &created_at, &event_kind, &pubkey_blob, &event_str],
+            )
+            .await?;
+        let ev_id: i64 = match inserted {
+            Some(row) => row.get(0),
+            // if the event was a duplicate, no need to insert tags.
+            None => return Ok(0),
+        };
+        for etag in e.get_event_tags().unwrap_or_default() {
+            let value = etag.get_event_id()?.as_inner().to_vec();
+            tx.execute(
+                "INSERT INTO tag (event_id, name, value) VALUES ($1, 'e', $2)",
+                &[&ev_id, &value],
+            )
+            .await?;
+        }
+        for ptag in e.get_pubkey_tags().unwrap_or_default() {
+            let value = ptag.get_pubkey()?.serialize().to_vec();
+            tx.execute(
+                "INSERT INTO tag (event_id, name, value) VALUES ($1, 'p', $2)",
+                &[&ev_id, &value],
+            )
+            .await?;
+        }
+        // metadata and contact updates replace every earlier event
+        // of the same kind from the same author.
+        if event_kind == 0 || event_kind == 3 {
+            let superseded = "id<>$1 AND kind=$2 AND author=$3 AND created_at <= $4";
+            let (sql, action) = if keep_superseded() {
+                (
+                    format!(
+                        "UPDATE event SET hidden=TRUE WHERE {} AND NOT hidden",
+                        superseded
+                    ),
+                    "hid",
+                )
+            } else {
+                // tags are removed by cascade.
+                (format!("DELETE FROM event WHERE {}", superseded), "deleted")
+            };
+            let update_count = tx
+                .execute(
+                    sql.as_str(),
+                    &[&ev_id, &event_kind, &pubkey_blob, &created_at],
+                )
+                .await?;
+            if update_count > 0 {
+                info!(
+                    "{} {} older kind {} events",
+                    action, update_count, event_kind
+                );
+            }
+        }
+        tx.commit().await?;
+        Ok(1)
+    }
+
+    fn query(&self, sub: Subscription) -> BoxStream<'static, Result<Event>> {
+        let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event>>(QUERY_STREAM_BUFFER);
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let q = query_from_sub("SELECT e.content FROM event e", &sub);
+            let sql = format!("{} ORDER BY e.created_at ASC", q.sql);
+            debug!("query string: {}", sql);
+            let run = async {
+                let client = pool
+                    .get()
+                    .await
+                    .map_err(|e| Error::DatabaseEngineError(e.to_string()))?;
+                let rows = client.query_raw(sql.as_str(), q.param_refs()).await?;
+                futures::pin_mut!(rows);
+                let mut row_count: usize = 0;
+                while let Some(row) = rows.next().await {
+                    let event_json: String = row?.get(0);
+                    row_count += 1;
+                    if tx.send(Event::from_str(&event_json)).await.is_err() {
+                        debug!("query aborted");
+                        return Ok(());
+                    }
+                }
+                debug!(
+                    "query completed ({} rows) in {:?}",
+                    row_count,
+                    start.elapsed()
+                );
+                Ok::<(), Error>(())
+            };
+            if let Err(e) = run.await {
+                tx.send(Err(e)).await.ok();
+            }
+        });
+        QueryStream { rx }.boxed()
+    }
+
+    async fn count(&self, sub: Subscription) -> Result<u64> {
+        let client = self.client().await?;
+        let q = query_from_sub("SELECT COUNT(*) FROM event e", &sub);
+        let count: i64 = client
+            .query_one(q.sql.as_str(), &q.param_refs())
+            .await?
+            .get(0);
+        Ok(count as u64)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.client().await?.query_one("SELECT 1", &[]).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, id: EventId) -> Result<bool> {
+        let client = self.client().await?;
+        // tags are removed by cascade.
+        let deleted = client
+            .execute(
+                "DELETE FROM event WHERE event_hash=$1",
+                &[&id.as_inner().to_vec()],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn delete_event(&self, id: EventId) -> Result<DeleteOutcome> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let id_blob = id.as_inner().to_vec();
+        // tags are removed by cascade.
+        let deleted = tx
+            .execute("DELETE FROM event WHERE event_hash=$1", &[&id_blob])
+            .await?;
+        let tombstoned = tx
+            .execute(
+                "INSERT INTO tombstone (event_hash, deleted_at) \
+                 VALUES ($1, EXTRACT(EPOCH FROM NOW())::BIGINT) \
+                 ON CONFLICT (event_hash) DO NOTHING",
+                &[&id_blob],
+            )
+            .await?;
+        tx.commit().await?;
+        Ok(DeleteOutcome {
+            deleted: deleted > 0,
+            tombstoned: tombstoned > 0,
+        })
+    }
+
+    async fn prune_events(&self, filter: PruneFilter, limit: u64) -> Result<u64> {
+        let client = self.client().await?;
+        // tags are removed by cascade.
+        let deleted = client
+            .execute(filter.delete_sql(limit).as_str(), &[])
+            .await?;
+        self.pruned.fetch_add(deleted, Ordering::Relaxed);
+        Ok(deleted)
+    }
+
+    async fn count_prunable(&self, filter: PruneFilter) -> Result<u64> {
+        let client = self.client().await?;
+        let count: i64 = client
+            .query_one(filter.count_sql().as_str(), &[])
+            .await?
+            .get(0);
+        Ok(count as u64)
+    }
+
+    async fn author_stats(&self, author: XOnlyPublicKey) -> Result<Option<AuthorStats>> {
+        let client = self.client().await?;
+        let query = format!(
+            "SELECT {} FROM author_stats WHERE author=$1",
+            AUTHOR_STATS_COLUMNS
+        );
+        let row = client
+            .query_opt(query.as_str(), &[&author.serialize().to_vec()])
+            .await?;
+        Ok(row.as_ref().map(author_stats_from_row))
+    }
+
+    async fn write_sources(&self, sources: Vec<(EventId, EventSource)>) -> Result<()> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let stmt = tx
+            .prepare(
+                "INSERT INTO event_source (event_hash, remote_ip, client_id, received_at) \
+                 VALUES ($1, $2, $3, $4) ON CONFLICT (event_hash) DO NOTHING",
+            )
+            .await?;
+        for (id, source) in &sources {
+            tx.execute(
+                &stmt,
+                &[
+                    &id.as_inner().to_vec(),
+                    &source.ip,
+                    &source.client_id,
+                    &(source.received_at as i64),
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn event_source(&self, id: EventId) -> Result<Option<EventSource>> {
+        let client = self.client().await?;
+        let row = client
+            .query_opt(
+                "SELECT remote_ip, client_id, received_at FROM event_source WHERE event_hash=$1",
+                &[&id.as_inner().to_vec()],
+            )
+            .await?;
+        Ok(row.map(|row| EventSource {
+            ip: row.get(0),
+            client_id: row.get(1),
+            received_at: row.get::<_, i64>(2) as u64,
+        }))
+    }
+
+    async fn prune_sources(&self, before: u64) -> Result<u64> {
+        let client = self.client().await?;
+        let deleted = client
+            .execute(
+                "DELETE FROM event_source WHERE received_at < $1",
+                &[&(before as i64)],
+            )
+            .await?;
+        Ok(deleted)
+    }
+
+    async fn save_verification(&self, verification: UserVerification) -> Result<()> {
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO user_verification \
+                 (pubkey, name, last_checked, last_success, failure_count) \
+                 VALUES ($1, $2, $3, $4, $5) \
+                 ON CONFLICT (pubkey) DO UPDATE SET name = EXCLUDED.name, \
+                 last_checked = EXCLUDED.last_checked, last_success = EXCLUDED.last_success, \
+                 failure_count = EXCLUDED.failure_count",
+                &[
+                    &verification.pubkey.serialize().to_vec(),
+                    &verification.name,
+                    &verification.last_checked.map(|t| t as i64),
+                    &verification.last_success.map(|t| t as i64),
+                    &(verification.failure_count as i32),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_verification(&self, pubkey: XOnlyPublicKey) -> Result<()> {
+        let client = self.client().await?;
+        client
+            .execute(
+                "DELETE FROM user_verification WHERE pubkey=$1",
+                &[&pubkey.serialize().to_vec()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn verification(&self, pubkey: XOnlyPublicKey) -> Result<Option<UserVerification>> {
+        let client = self.client().await?;
+        let query = format!(
+            "SELECT {} FROM user_verification WHERE pubkey=$1",
+            VERIFICATION_COLUMNS
+        );
+        let row = client
+            .query_opt(query.as_str(), &[&pubkey.serialize().to_vec()])
+            .await?;
+        row.as_ref().map(verification_from_row).transpose()
+    }
+
+    async fn verifications_due(&self, before: u64, limit: u64) -> Result<Vec<UserVerification>> {
+        let client = self.client().await?;
+        let query = format!(
+            "SELECT {} FROM user_verification \
+             WHERE last_checked IS NULL OR last_checked < $1 \
+             ORDER BY last_checked ASC NULLS FIRST LIMIT $2",
+            VERIFICATION_COLUMNS
+        );
+        let rows = client
+            .query(query.as_str(), &[&(before as i64), &(limit as i64)])
+            .await?;
+        rows.iter().map(verification_from_row).collect()
+    }
+
+    async fn recent_ids(&self, limit: u64) -> Result<Vec<EventId>> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT event_hash FROM event ORDER BY id DESC LIMIT $1",
+                &[&(limit as i64)],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|r| EventId::from_slice(r.get::<_, &[u8]>(0)).ok())
+            .collect())
+    }
+
+    async fn close(&self) -> Result<()> {
+        // connections in use are closed as they are returned
+        self.pool.close();
+        info!("closed postgres connection pool");
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<DbStats> {
+        let mut stats = match self.stats_cache.get() {
+            Some(stats) => stats,
+            None => {
+                let client = self.client().await?;
+                let row = client.query_one(STATS_SUMMARY_SQL, &[]).await?;
+                let top_kinds = client
+                    .query(top_kinds_sql().as_str(), &[])
+                    .await?
+                    .iter()
+                    .map(|r| KindCount {
+                        kind: r.get::<_, i64>(0) as u64,
+                        count: r.get::<_, i64>(1) as u64,
+                    })
+                    .collect();
+                let top_authors = client
+                    .query(top_authors_sql().as_str(), &[])
+                    .await?
+                    .iter()
+                    .map(author_stats_from_row)
+                    .collect();
+                let stats = DbStats {
+                    event_count: row.get::<_, i64>(0) as u64,
+                    hidden_count: row.get::<_, i64>(1) as u64,
+                    author_count: row.get::<_, i64>(2) as u64,
+                    oldest_created_at: row.get::<_, Option<i64>>(3).map(|t| t as u64),
+                    newest_created_at: row.get::<_, Option<i64>>(4).map(|t| t as u64),
+                    top_kinds,
+                    top_authors,
+                    ..Default::default()
+                };
+                self.stats_cache.put(&stats);
+                stats
+            }
+        };
+        stats.pruned_events = self.pruned.load(Ordering::Relaxed);
+        Ok(stats)
+    }
+}
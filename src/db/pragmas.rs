@@ -0,0 +1,170 @@
+//! Tunable SQLite connection settings
+use crate::error::{Error, Result};
+use log::*;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// Value of `PRAGMA synchronous`, how often SQLite waits for writes
+/// to reach the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    /// Never; a power loss may corrupt the database
+    Off,
+    /// At checkpoints; a power loss may lose the latest commits
+    Normal,
+    /// At every commit
+    Full,
+    /// At every commit, and after deleting the journal
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Value of `PRAGMA temp_store`, where temporary tables and indexes
+/// are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempStore {
+    /// As chosen when SQLite was compiled
+    Default,
+    /// In temporary files
+    File,
+    /// In memory
+    Memory,
+}
+
+impl TempStore {
+    fn as_sql(self) -> &'static str {
+        match self {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        }
+    }
+}
+
+/// Settings applied to every SQLite connection after it is opened,
+/// configured in `[database.pragmas]`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SqlitePragmas {
+    /// Bytes of the database file accessed through memory mapping
+    pub mmap_size: u64,
+    /// Page cache size; pages if positive, KiB if negative
+    pub cache_size: i64,
+    /// How often writes wait for the disk
+    pub synchronous: Synchronous,
+    /// Pages in the write-ahead log before an automatic checkpoint,
+    /// zero disables them
+    pub wal_autocheckpoint: u32,
+    /// Where temporary tables and indexes are kept
+    pub temp_store: TempStore,
+    /// Allow settings that risk corrupting the database
+    pub i_know_what_im_doing: bool,
+}
+
+impl Default for SqlitePragmas {
+    fn default() -> Self {
+        SqlitePragmas {
+            mmap_size: 512 * 1024 * 1024,
+            cache_size: -2000,
+            synchronous: Synchronous::Normal,
+            wal_autocheckpoint: 1000,
+            temp_store: TempStore::Default,
+            i_know_what_im_doing: false,
+        }
+    }
+}
+
+impl SqlitePragmas {
+    /// Refuse settings that are unsafe without being acknowledged.
+    pub fn validate(&self) -> Result<()> {
+        if self.synchronous == Synchronous::Off && !self.i_know_what_im_doing {
+            return Err(Error::ConfigError(config::ConfigError::Message(
+                "database.pragmas: synchronous = \"off\" can corrupt the database on power loss, \
+                 set i_know_what_im_doing = true to allow it"
+                    .to_owned(),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Apply the settings to a newly opened connection.
+    pub fn apply(&self, conn: &Connection) -> Result<()> {
+        conn.execute_batch(&format!(
+            "PRAGMA mmap_size = {};\n\
+             PRAGMA cache_size = {};\n\
+             PRAGMA main.synchronous = {};\n\
+             PRAGMA wal_autocheckpoint = {};\n\
+             PRAGMA temp_store = {};",
+            self.mmap_size,
+            self.cache_size,
+            self.synchronous.as_sql(),
+            self.wal_autocheckpoint,
+            self.temp_store.as_sql()
+        ))?;
+        Ok(())
+    }
+}
+
+/// Log the settings in effect on `conn`, as reported by SQLite.
+pub fn log_effective(conn: &Connection) -> Result<()> {
+    let get = |name: &str| -> Result<i64> {
+        Ok(conn.query_row(&format!("PRAGMA {};", name), [], |row| row.get(0))?)
+    };
+    info!(
+        "sqlite pragmas: mmap_size={} cache_size={} synchronous={} wal_autocheckpoint={} temp_store={}",
+        get("mmap_size")?,
+        get("cache_size")?,
+        get("synchronous")?,
+        get("wal_autocheckpoint")?,
+        get("temp_store")?
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_and_validates() {
+        let conn = Connection::open_in_memory().unwrap();
+        let pragmas = SqlitePragmas {
+            cache_size: 500,
+            synchronous: Synchronous::Full,
+            temp_store: TempStore::Memory,
+            ..Default::default()
+        };
+        pragmas.validate().unwrap();
+        pragmas.apply(&conn).unwrap();
+        let get = |name: &str| -> i64 {
+            conn.query_row(&format!("PRAGMA {};", name), [], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(get("cache_size"), 500);
+        assert_eq!(get("synchronous"), 2);
+        assert_eq!(get("temp_store"), 2);
+
+        let unsafe_pragmas = SqlitePragmas {
+            synchronous: Synchronous::Off,
+            ..Default::default()
+        };
+        assert!(unsafe_pragmas.validate().is_err());
+        SqlitePragmas {
+            i_know_what_im_doing: true,
+            ..unsafe_pragmas
+        }
+        .validate()
+        .unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Bounded set of recently written event ids
+use super::{DbStats, Storage};
+use crate::config::SETTINGS;
+use crate::error::Result;
+use crate::protocol::EventId;
+use log::*;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The ids of the most recently written events, used by the writer to
+/// answer resubmitted events as duplicates without a transaction.
+///
+/// The set is exact: an id is only present if the event was stored,
+/// so there are no false positives.  Once full, the oldest ids are
+/// evicted.  A capacity of zero disables the set.
+#[derive(Debug)]
+pub struct RecentIds {
+    /// Maximum number of ids held
+    capacity: usize,
+    /// Ids, and the order they were added in
+    inner: Mutex<RecentInner>,
+    /// Events checked against the set
+    checks: AtomicU64,
+    /// Events found in the set
+    hits: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct RecentInner {
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+}
+
+impl RecentInner {
+    fn insert(&mut self, id: EventId, capacity: usize) {
+        if !self.ids.insert(id) {
+            return;
+        }
+        self.order.push_back(id);
+        while self.order.len() > capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl RecentIds {
+    /// Create an empty set holding up to `capacity` ids.
+    pub fn new(capacity: usize) -> Self {
+        RecentIds {
+            capacity,
+            inner: Mutex::new(RecentInner::default()),
+            checks: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Create an empty set sized by `database.recent_ids`.
+    pub fn from_settings() -> Self {
+        Self::new(SETTINGS.read().unwrap().database.recent_ids)
+    }
+
+    /// Replace the contents with the most recently stored events.
+    /// Called at startup, and after events are deleted, so that the
+    /// set never holds the id of an event that is no longer stored.
+    pub async fn load(&self, storage: &dyn Storage) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let recent = storage.recent_ids(self.capacity as u64).await?;
+        let mut inner = self.inner.lock().unwrap();
+        *inner = RecentInner::default();
+        // oldest first, so they are the first evicted
+        for id in recent.into_iter().rev() {
+            inner.insert(id, self.capacity);
+        }
+        debug!("loaded {} recent event ids", inner.order.len());
+        Ok(())
+    }
+
+    /// Whether the event with this id is known to be stored.
+    pub fn contains(&self, id: &EventId) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        let hit = self.inner.lock().unwrap().ids.contains(id);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        hit
+    }
+
+    /// Record that the event with this id is stored.
+    pub fn insert(&self, id: EventId) {
+        if self.capacity > 0 {
+            self.inner.lock().unwrap().insert(id, self.capacity);
+        }
+    }
+
+    /// Forget the event with this id, once it is deleted.
+    pub fn remove(&self, id: &EventId) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.ids.remove(id) {
+            inner.order.retain(|other| other != id);
+        }
+    }
+
+    /// Report how often duplicates were caught in `stats`.
+    pub fn fill_stats(&self, stats: &mut DbStats) {
+        stats.duplicate_checks = self.checks.load(Ordering::Relaxed);
+        stats.duplicate_hits = self.hits.load(Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::Hash;
+
+    #[test]
+    fn evicts_oldest() {
+        let recent = RecentIds::new(2);
+        for n in 0..3 {
+            recent.insert(EventId::from_inner([n; 32]));
+        }
+        assert!(!recent.contains(&EventId::from_inner([0; 32])));
+        assert!(recent.contains(&EventId::from_inner([1; 32])));
+        assert!(recent.contains(&EventId::from_inner([2; 32])));
+        let mut stats = DbStats::default();
+        recent.fill_stats(&mut stats);
+        assert_eq!((stats.duplicate_checks, stats.duplicate_hits), (3, 2));
+        recent.remove(&EventId::from_inner([1; 32]));
+        assert!(!recent.contains(&EventId::from_inner([1; 32])));
+    }
+}
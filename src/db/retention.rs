@@ -0,0 +1,654 @@
+//! Event retention policy and pruning
+use super::{DbStats, Storage};
+use crate::config::{Retention, SETTINGS};
+use crate::error::{Error, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// Maximum number of events deleted in a single statement, so that
+/// pruning never holds the database for long.
+const PRUNE_BATCH_SIZE: u64 = 1000;
+
+/// When over the size budget, prune until the database is below this
+/// percentage of the budget.
+const SIZE_LOW_WATER_PERCENT: u64 = 90;
+
+/// Events deleted between size checks when over the size budget.
+const SIZE_PRUNE_BATCH_SIZE: u64 = 100;
+
+/// A single event kind, or an inclusive range of kinds.  Serialized
+/// as in NIP-11, either `7` or `[40, 49]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KindRange {
+    Single(u64),
+    Range(u64, u64),
+}
+
+impl KindRange {
+    /// Lowest and highest kind in the range.
+    fn bounds(&self) -> (u64, u64) {
+        match *self {
+            KindRange::Single(k) => (k, k),
+            KindRange::Range(lo, hi) => (lo, hi),
+        }
+    }
+
+    /// Number of kinds in the range.
+    fn span(&self) -> u64 {
+        let (lo, hi) = self.bounds();
+        hi.saturating_sub(lo) + 1
+    }
+
+    /// Check if every kind in `other` is also in this range.
+    fn contains(&self, other: &KindRange) -> bool {
+        let (lo, hi) = self.bounds();
+        let (olo, ohi) = other.bounds();
+        lo <= olo && ohi <= hi
+    }
+
+    /// Check if any kind is in both ranges.
+    fn overlaps(&self, other: &KindRange) -> bool {
+        let (lo, hi) = self.bounds();
+        let (olo, ohi) = other.bounds();
+        lo <= ohi && olo <= hi
+    }
+
+    /// SQL condition selecting events with a kind in this range.
+    fn sql(&self) -> String {
+        match *self {
+            KindRange::Single(k) => format!("e.kind = {}", k),
+            KindRange::Range(lo, hi) => format!("e.kind BETWEEN {} AND {}", lo, hi),
+        }
+    }
+}
+
+/// Retention limits for a set of event kinds.  A rule without limits
+/// keeps its kinds forever.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    /// Kinds this rule applies to
+    #[serde(default)]
+    pub kinds: Vec<KindRange>,
+    /// Delete events authored more than this many seconds ago
+    pub max_age_seconds: Option<u64>,
+    /// Delete the oldest events beyond this count
+    pub max_events: Option<u64>,
+}
+
+impl RetentionRule {
+    /// Number of kinds the rule applies to, smaller is more specific.
+    fn span(&self) -> u64 {
+        self.kinds
+            .iter()
+            .fold(0u64, |acc, k| acc.saturating_add(k.span()))
+    }
+
+    /// Check if the rule limits storage at all.
+    fn has_limits(&self) -> bool {
+        self.max_age_seconds.is_some() || self.max_events.is_some()
+    }
+
+    /// Check if the rule applies to any kind that `other` applies to.
+    fn overlaps(&self, other: &RetentionRule) -> bool {
+        self.kinds
+            .iter()
+            .any(|k| other.kinds.iter().any(|o| k.overlaps(o)))
+    }
+
+    /// Check if every kind of `other` is covered by this rule.
+    fn contains(&self, other: &RetentionRule) -> bool {
+        other
+            .kinds
+            .iter()
+            .all(|o| self.kinds.iter().any(|k| k.contains(o)))
+    }
+}
+
+/// Limits on how long, and how many, events are kept.
+///
+/// Kinds covered by one of the `rules` use the most specific
+/// matching rule, and all other kinds use the `default` rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Rules for specific kinds, most specific first
+    pub rules: Vec<RetentionRule>,
+    /// Limits for kinds not covered by any rule
+    pub default: RetentionRule,
+}
+
+impl RetentionPolicy {
+    /// Build a policy from per-kind rules and a default rule, ordering
+    /// the rules most specific first.
+    ///
+    /// Rules may only overlap when one is nested inside the other, so
+    /// that the most specific rule for a kind is never ambiguous.
+    pub fn new(default: RetentionRule, mut rules: Vec<RetentionRule>) -> Result<Self> {
+        for rule in &rules {
+            if rule.kinds.is_empty() {
+                return Err(retention_error("every rule must list kinds".to_owned()));
+            }
+            for k in &rule.kinds {
+                let (lo, hi) = k.bounds();
+                if lo > hi {
+                    return Err(retention_error(format!(
+                        "invalid kind range [{}, {}]",
+                        lo, hi
+                    )));
+                }
+            }
+        }
+        for (i, a) in rules.iter().enumerate() {
+            for b in &rules[i + 1..] {
+                if !a.overlaps(b) {
+                    continue;
+                }
+                let nested = a.contains(b) != b.contains(a);
+                if !nested && a != b {
+                    return Err(retention_error(format!(
+                        "rules for kinds {:?} and {:?} overlap",
+                        a.kinds, b.kinds
+                    )));
+                }
+            }
+        }
+        rules.sort_by_key(|r| r.span());
+        rules.dedup();
+        Ok(RetentionPolicy {
+            rules,
+            default: RetentionRule {
+                kinds: vec![],
+                ..default
+            },
+        })
+    }
+
+    /// Build a policy from the `[retention]` configuration.  Protected
+    /// kinds become rules without limits.
+    pub fn from_config(retention: &Retention) -> Result<Self> {
+        let default = RetentionRule {
+            kinds: vec![],
+            max_age_seconds: retention.max_age_seconds,
+            max_events: retention.max_events.map(|n| n as u64),
+        };
+        let mut rules = retention.rules.clone().unwrap_or_default();
+        if let Some(kinds) = &retention.protected_kinds {
+            rules.extend(kinds.iter().map(|k| RetentionRule {
+                kinds: vec![KindRange::Single(*k)],
+                ..Default::default()
+            }));
+        }
+        RetentionPolicy::new(default, rules)
+    }
+
+    /// Check if the policy limits storage at all.
+    pub fn is_enabled(&self) -> bool {
+        self.default.has_limits() || self.rules.iter().any(|r| r.has_limits())
+    }
+
+    /// Kinds covered by rules without limits, which are kept forever.
+    fn protected_kinds(&self) -> Vec<KindRange> {
+        self.rules
+            .iter()
+            .filter(|r| !r.has_limits())
+            .flat_map(|r| r.kinds.iter().copied())
+            .collect()
+    }
+
+    /// Each rule, most specific first, with the kinds it must leave
+    /// to more specific rules.  The default rule comes last.
+    fn scoped_rules(&self) -> Vec<(&RetentionRule, Vec<KindRange>)> {
+        let mut scoped = vec![];
+        let mut claimed: Vec<KindRange> = vec![];
+        for rule in self.rules.iter().chain(std::iter::once(&self.default)) {
+            scoped.push((rule, claimed.clone()));
+            claimed.extend(rule.kinds.iter().copied());
+        }
+        scoped
+    }
+}
+
+/// Error for an invalid retention configuration.
+fn retention_error(msg: String) -> Error {
+    Error::ConfigError(config::ConfigError::Message(format!("retention: {}", msg)))
+}
+
+/// Selects events that may be deleted by retention pruning.
+///
+/// The current version of replaceable events is only deleted when
+/// explicitly included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PruneFilter {
+    /// Only select events authored before this time
+    pub created_before: Option<u64>,
+    /// Only select these kinds, or all kinds if empty
+    pub kinds: Vec<KindRange>,
+    /// Never select these kinds
+    pub excluded_kinds: Vec<KindRange>,
+    /// Also select the current version of replaceable events
+    pub include_latest_replaceable: bool,
+}
+
+impl PruneFilter {
+    /// Conditions on kind and age.
+    fn conditions(&self) -> Vec<String> {
+        let mut conditions = vec![];
+        if !self.kinds.is_empty() {
+            let kinds: Vec<String> = self.kinds.iter().map(|k| k.sql()).collect();
+            conditions.push(format!("({})", kinds.join(" OR ")));
+        }
+        if !self.excluded_kinds.is_empty() {
+            let kinds: Vec<String> = self.excluded_kinds.iter().map(|k| k.sql()).collect();
+            conditions.push(format!("NOT ({})", kinds.join(" OR ")));
+        }
+        if let Some(cutoff) = self.created_before {
+            conditions.push(format!("e.created_at < {}", cutoff));
+        }
+        conditions
+    }
+
+    /// SQL statement counting events matching the kind and age
+    /// conditions, including current replaceable events.
+    pub(crate) fn count_sql(&self) -> String {
+        let conditions = self.conditions();
+        if conditions.is_empty() {
+            "SELECT COUNT(*) FROM event e".to_owned()
+        } else {
+            format!(
+                "SELECT COUNT(*) FROM event e WHERE {}",
+                conditions.join(" AND ")
+            )
+        }
+    }
+
+    /// SQL statement deleting up to `limit` of the oldest selected
+    /// events.  The statement is valid for both SQLite and
+    /// PostgreSQL, and references are removed by cascade.
+    pub(crate) fn delete_sql(&self, limit: u64) -> String {
+        let mut conditions = vec![];
+        if !self.include_latest_replaceable {
+            // replaceable events are kept until a newer version exists
+            conditions.push("(NOT (e.kind IN (0, 3) OR e.kind BETWEEN 10000 AND 19999) \
+                 OR EXISTS (SELECT 1 FROM event n WHERE n.author = e.author AND n.kind = e.kind AND n.created_at > e.created_at))"
+                .to_owned());
+        }
+        conditions.extend(self.conditions());
+        if conditions.is_empty() {
+            conditions.push("TRUE".to_owned());
+        }
+        format!(
+            "DELETE FROM event WHERE id IN (SELECT e.id FROM event e WHERE {} ORDER BY e.created_at ASC LIMIT {})",
+            conditions.join(" AND "),
+            limit
+        )
+    }
+}
+
+/// Number of events removed by a pruning run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Events older than the age limit
+    pub expired: u64,
+    /// Events beyond the count limit
+    pub excess: u64,
+}
+
+impl PruneReport {
+    /// Total events removed.
+    pub fn total(&self) -> u64 {
+        self.expired + self.excess
+    }
+}
+
+/// A limit on database size, shared by the maintenance task that
+/// enforces it and the writer that stops accepting events once it
+/// cannot be met.
+#[derive(Debug, Default)]
+pub struct SizeBudget {
+    /// Maximum size in bytes, if limited
+    max_bytes: Option<u64>,
+    /// Set while the database is over budget with nothing left to prune
+    full: AtomicBool,
+}
+
+impl SizeBudget {
+    /// Create a budget of `max_bytes`, or an unlimited one.
+    pub fn new(max_bytes: Option<u64>) -> Self {
+        SizeBudget {
+            max_bytes,
+            full: AtomicBool::new(false),
+        }
+    }
+
+    /// Create a budget from `database.max_size_mb`.
+    pub fn from_settings() -> Self {
+        let max_size_mb = SETTINGS.read().unwrap().database.max_size_mb;
+        SizeBudget::new(max_size_mb.map(|mb| mb * 1024 * 1024))
+    }
+
+    /// Maximum size in bytes, if limited.
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    /// Check if new events should be refused.
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Add the budget and its status to backend statistics.
+    pub fn fill_stats(&self, stats: &mut DbStats) {
+        stats.max_size_bytes = self.max_bytes;
+        stats.storage_full = self.is_full();
+    }
+}
+
+/// Seconds since the epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Delete events that fall outside the retention policy, in batches.
+pub async fn prune(storage: &dyn Storage, policy: &RetentionPolicy) -> Result<PruneReport> {
+    let mut report = PruneReport::default();
+    for (rule, excluded_kinds) in policy.scoped_rules() {
+        let filter = PruneFilter {
+            created_before: None,
+            kinds: rule.kinds.clone(),
+            excluded_kinds,
+            ..Default::default()
+        };
+        let rule_report = prune_rule(storage, rule, filter).await?;
+        if rule_report.total() > 0 {
+            debug!(
+                "pruned {} events for kinds {:?}",
+                rule_report.total(),
+                rule.kinds
+            );
+        }
+        report.expired += rule_report.expired;
+        report.excess += rule_report.excess;
+    }
+    if report.total() > 0 {
+        info!(
+            "pruned {} events ({} expired, {} over count limit)",
+            report.total(),
+            report.expired,
+            report.excess
+        );
+    } else {
+        debug!("no events to prune");
+    }
+    Ok(report)
+}
+
+/// Apply the limits of a single rule to the events it selects.
+async fn prune_rule(
+    storage: &dyn Storage,
+    rule: &RetentionRule,
+    filter: PruneFilter,
+) -> Result<PruneReport> {
+    let mut report = PruneReport::default();
+    if let Some(max_age) = rule.max_age_seconds {
+        let expired = PruneFilter {
+            created_before: Some(now().saturating_sub(max_age)),
+            ..filter.clone()
+        };
+        loop {
+            let deleted = storage
+                .prune_events(expired.clone(), PRUNE_BATCH_SIZE)
+                .await?;
+            report.expired += deleted;
+            if deleted < PRUNE_BATCH_SIZE {
+                break;
+            }
+        }
+    }
+    if let Some(max_events) = rule.max_events {
+        let mut excess = storage
+            .count_prunable(filter.clone())
+            .await?
+            .saturating_sub(max_events);
+        while excess > 0 {
+            let deleted = storage
+                .prune_events(filter.clone(), excess.min(PRUNE_BATCH_SIZE))
+                .await?;
+            if deleted == 0 {
+                // everything left is protected
+                break;
+            }
+            report.excess += deleted;
+            excess = excess.saturating_sub(deleted);
+        }
+    }
+    Ok(report)
+}
+
+/// Prune the oldest unprotected events while the database is over its
+/// size budget, until it is below the low-water mark.  If that is not
+/// possible, mark the budget as full so that new events are refused.
+/// Returns the number of events deleted.
+pub async fn enforce_size(
+    storage: &dyn Storage,
+    policy: &RetentionPolicy,
+    budget: &SizeBudget,
+) -> Result<u64> {
+    let max_bytes = match budget.max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(0),
+    };
+    let mut size = match storage.size_bytes().await? {
+        Some(size) => size,
+        None => {
+            warn!("storage backend does not report its size, ignoring size budget");
+            return Ok(0);
+        }
+    };
+    let mut deleted = 0;
+    if size > max_bytes {
+        let low_water = max_bytes / 100 * SIZE_LOW_WATER_PERCENT;
+        let filter = PruneFilter {
+            created_before: None,
+            kinds: vec![],
+            excluded_kinds: policy.protected_kinds(),
+            ..Default::default()
+        };
+        info!(
+            "database size {} bytes exceeds budget of {} bytes, pruning",
+            size, max_bytes
+        );
+        while size > low_water {
+            let n = storage
+                .prune_events(filter.clone(), SIZE_PRUNE_BATCH_SIZE)
+                .await?;
+            if n == 0 {
+                break;
+            }
+            deleted += n;
+            size = storage.size_bytes().await?.unwrap_or(0);
+        }
+        info!(
+            "pruned {} events for size budget, now {} bytes",
+            deleted, size
+        );
+    }
+    let full = size > max_bytes;
+    if full != budget.is_full() {
+        if full {
+            warn!("database is over its size budget, refusing new events");
+        } else {
+            info!("database is within its size budget, accepting new events");
+        }
+        budget.full.store(full, Ordering::Relaxed);
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SqliteStorage;
+    use crate::protocol::{Event, EventId};
+    use crate::test_util::{event_with_id, temp_db_dir};
+    use bitcoin_hashes::Hash;
+
+    /// Copy of the test vector event with a distinct id and time.
+    fn event_at(n: u8, created_at: u64, kind: u64) -> Event {
+        let mut e = event_with_id(n);
+        e.created_at = created_at;
+        e.kind = serde_json::from_value(kind.into()).unwrap();
+        e
+    }
+
+    fn rule(kinds: Vec<KindRange>, max_age_seconds: Option<u64>) -> RetentionRule {
+        RetentionRule {
+            kinds,
+            max_age_seconds,
+            max_events: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_by_age_and_count() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let recent = now() - 10;
+        for e in [
+            // old notes
+            event_at(1, 100, 1),
+            event_at(2, 200, 1),
+            // old metadata, replaced by the newest when written
+            event_at(3, 300, 0),
+            event_at(4, 400, 0),
+            // recent notes
+            event_at(5, recent, 1),
+            event_at(6, recent + 1, 1),
+            event_at(7, recent + 2, 1),
+        ] {
+            storage.write_event(e).await.unwrap();
+        }
+        let default = RetentionRule {
+            kinds: vec![],
+            max_age_seconds: Some(3600),
+            max_events: Some(3),
+        };
+        let policy = RetentionPolicy::new(default, vec![]).unwrap();
+        let report = prune(&storage, &policy).await.unwrap();
+        assert_eq!(report.expired, 2);
+        assert_eq!(report.excess, 1);
+        assert_eq!(storage.stats().await.unwrap().event_count, 3);
+        assert_eq!(storage.stats().await.unwrap().pruned_events, 3);
+        // current metadata survives, along with the newest notes
+        assert!(!storage.delete(EventId::from_inner([5; 32])).await.unwrap());
+        assert!(storage.delete(EventId::from_inner([4; 32])).await.unwrap());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn most_specific_rule_applies() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let age = now() - 600;
+        storage.write_event(event_at(1, age, 1)).await.unwrap();
+        storage.write_event(event_at(2, age, 2)).await.unwrap();
+        storage.write_event(event_at(3, 100, 2)).await.unwrap();
+        // kinds 1-2 expire after 5 minutes, but kind 2 is kept for a day
+        let policy = RetentionPolicy::new(
+            RetentionRule::default(),
+            vec![
+                rule(vec![KindRange::Range(1, 2)], Some(300)),
+                rule(vec![KindRange::Single(2)], Some(86_400)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(policy.rules[0].kinds, vec![KindRange::Single(2)]);
+        let report = prune(&storage, &policy).await.unwrap();
+        assert_eq!(report.total(), 2);
+        assert!(storage.delete(EventId::from_inner([2; 32])).await.unwrap());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn size_budget() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        for n in 0..200 {
+            let mut e = event_at(n, 1000 + n as u64, 1);
+            e.content = "x".repeat(4096);
+            storage.write_event(e).await.unwrap();
+        }
+        let size = storage.size_bytes().await.unwrap().unwrap();
+        // protected events can not be pruned, so storage fills up
+        let protect_notes = RetentionPolicy::new(
+            RetentionRule::default(),
+            vec![rule(vec![KindRange::Single(1)], None)],
+        )
+        .unwrap();
+        let budget = SizeBudget::new(Some(size / 4 * 3));
+        assert_eq!(
+            enforce_size(&storage, &protect_notes, &budget)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(budget.is_full());
+        // otherwise the oldest events are removed
+        let policy = RetentionPolicy::default();
+        let deleted = enforce_size(&storage, &policy, &budget).await.unwrap();
+        assert!(deleted > 0);
+        assert!(!budget.is_full());
+        assert!(storage.size_bytes().await.unwrap().unwrap() <= size / 4 * 3);
+        // the newest event is kept
+        assert!(storage
+            .delete(EventId::from_inner([199; 32]))
+            .await
+            .unwrap());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn overlapping_rules_rejected() {
+        // partial overlap is ambiguous
+        assert!(RetentionPolicy::new(
+            RetentionRule::default(),
+            vec![
+                rule(vec![KindRange::Range(1, 10)], Some(60)),
+                rule(vec![KindRange::Range(5, 20)], Some(60)),
+            ],
+        )
+        .is_err());
+        // same kinds with different limits contradict each other
+        assert!(RetentionPolicy::new(
+            RetentionRule::default(),
+            vec![
+                rule(vec![KindRange::Single(7)], Some(60)),
+                rule(vec![KindRange::Single(7)], None),
+            ],
+        )
+        .is_err());
+        // nested rules are fine
+        assert!(RetentionPolicy::new(
+            RetentionRule::default(),
+            vec![
+                rule(vec![KindRange::Range(1, 10)], Some(60)),
+                rule(vec![KindRange::Single(7)], None),
+            ],
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn kind_range_serialization() {
+        let kinds: Vec<KindRange> = serde_json::from_str("[7, [40, 49]]").unwrap();
+        assert_eq!(kinds, vec![KindRange::Single(7), KindRange::Range(40, 49)]);
+        assert_eq!(serde_json::to_string(&kinds).unwrap(), "[7,[40,49]]");
+    }
+}
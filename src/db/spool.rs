@@ -0,0 +1,297 @@
+//! Overflow journal for events the database writer has not caught up with
+use super::DbStats;
+use crate::config::SETTINGS;
+use crate::error::{Error, Result};
+use crate::protocol::Event;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Name of the journal file in the data directory.
+const SPOOL_FILE: &str = "spool.jsonl";
+
+/// Name of the file recording how much of the journal has been read.
+const SPOOL_POS_FILE: &str = "spool.pos";
+
+/// When spooled events are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpoolFsync {
+    /// After every spooled event
+    Always,
+    /// Whenever the operating system decides
+    Never,
+}
+
+/// An append-only journal of events, one JSON event per line, used
+/// when the writer's channel is full.
+///
+/// Events are read back in the order they were spooled.  The journal
+/// is capped at a maximum size; once full, the oldest events are
+/// dropped to make room.  A journal left over from a previous run is
+/// picked up when the spool is opened.
+#[derive(Debug)]
+pub struct Spool {
+    /// Location of the journal
+    path: PathBuf,
+    /// Location of the saved read position
+    pos_path: PathBuf,
+    /// Maximum size of unread events in bytes
+    max_bytes: u64,
+    /// When to flush appended events to disk
+    fsync: SpoolFsync,
+    /// Open journal and read position
+    state: Mutex<SpoolState>,
+    /// Wakes the writer when an event is appended
+    pushed: tokio::sync::Notify,
+}
+
+#[derive(Debug)]
+struct SpoolState {
+    /// Journal, opened for appending and reading
+    file: File,
+    /// Offset of the oldest unread event
+    read_pos: u64,
+    /// Length of the journal
+    len: u64,
+    /// Number of unread events
+    depth: u64,
+    /// Events dropped because the journal was full
+    dropped: u64,
+}
+
+impl SpoolState {
+    /// Read the line at `read_pos` and advance past it.
+    fn read_line(&mut self) -> Result<Option<String>> {
+        if self.read_pos >= self.len {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(&self.file);
+        reader
+            .seek(SeekFrom::Start(self.read_pos))
+            .map_err(io_error)?;
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).map_err(io_error)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.read_pos += read as u64;
+        self.depth = self.depth.saturating_sub(1);
+        Ok(Some(line))
+    }
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::GenericError(format!("spool error: {}", e))
+}
+
+impl Spool {
+    /// Open, or create, the journal in `dir`.
+    pub fn open(dir: &Path, max_bytes: u64, fsync: SpoolFsync) -> Result<Self> {
+        let path = dir.join(SPOOL_FILE);
+        let pos_path = dir.join(SPOOL_POS_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len();
+        let read_pos = std::fs::read_to_string(&pos_path)
+            .ok()
+            .and_then(|p| p.trim().parse().ok())
+            .filter(|p| *p <= len)
+            .unwrap_or(0);
+        let mut reader = BufReader::new(&file);
+        reader.seek(SeekFrom::Start(read_pos)).map_err(io_error)?;
+        let depth = reader.lines().count() as u64;
+        if depth > 0 {
+            info!("found {} spooled events in {:?}", depth, path);
+        }
+        Ok(Spool {
+            path,
+            pos_path,
+            max_bytes,
+            fsync,
+            state: Mutex::new(SpoolState {
+                file,
+                read_pos,
+                len,
+                depth,
+                dropped: 0,
+            }),
+            pushed: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// Record the read position, so that events already read are not
+    /// returned again after a restart.
+    fn save_pos(&self, state: &SpoolState) -> Result<()> {
+        std::fs::write(&self.pos_path, state.read_pos.to_string()).map_err(io_error)
+    }
+
+    /// Open the journal configured with `database.spool_max_mb`, if any.
+    pub fn from_settings() -> Result<Option<Self>> {
+        let config = SETTINGS.read().unwrap();
+        match config.database.spool_max_mb {
+            Some(mb) if mb > 0 => Self::open(
+                Path::new(&config.database.data_directory),
+                mb * 1024 * 1024,
+                config.database.spool_fsync,
+            )
+            .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Number of events waiting in the journal.
+    pub fn depth(&self) -> u64 {
+        self.state.lock().unwrap().depth
+    }
+
+    /// Append an event, dropping the oldest events if the journal
+    /// would grow beyond its maximum size.
+    pub fn push(&self, event: &Event) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let line_len = line.len() as u64;
+        let mut state = self.state.lock().unwrap();
+        if line_len > self.max_bytes {
+            state.dropped += 1;
+            return Err(Error::GenericError(
+                "event is larger than the spool".to_owned(),
+            ));
+        }
+        let mut dropped = 0;
+        while state.len - state.read_pos + line_len > self.max_bytes {
+            if state.read_line()?.is_none() {
+                break;
+            }
+            dropped += 1;
+        }
+        if dropped > 0 {
+            warn!("spool full, dropped {} oldest events", dropped);
+            state.dropped += dropped;
+            self.save_pos(&state)?;
+        }
+        state.file.write_all(line.as_bytes()).map_err(io_error)?;
+        if self.fsync == SpoolFsync::Always {
+            state.file.sync_data().map_err(io_error)?;
+        }
+        state.len += line_len;
+        state.depth += 1;
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    /// Wait until an event is appended.  An append since the last wait
+    /// completes it immediately.
+    pub(crate) async fn pushed(&self) {
+        self.pushed.notified().await
+    }
+
+    /// Remove and return up to `max` of the oldest events.  Lines that
+    /// cannot be parsed are skipped.
+    pub fn pop(&self, max: usize) -> Result<Vec<Event>> {
+        let mut state = self.state.lock().unwrap();
+        let mut events = Vec::new();
+        if state.len == 0 {
+            return Ok(events);
+        }
+        while events.len() < max {
+            match state.read_line()? {
+                Some(line) => match serde_json::from_str(&line) {
+                    Ok(event) => events.push(event),
+                    Err(e) => warn!("skipping unreadable spooled event: {}", e),
+                },
+                None => break,
+            }
+        }
+        if state.read_pos >= state.len {
+            // everything has been read, start the journal over
+            state.file.set_len(0).map_err(io_error)?;
+            state.read_pos = 0;
+            state.len = 0;
+            state.depth = 0;
+        } else if state.read_pos > self.max_bytes {
+            self.rewrite(&mut state)?;
+        }
+        self.save_pos(&state)?;
+        Ok(events)
+    }
+
+    /// Rewrite the journal without the events already read, so that
+    /// it does not grow without bound under constant pressure.
+    fn rewrite(&self, state: &mut SpoolState) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut reader = BufReader::new(&state.file);
+            reader
+                .seek(SeekFrom::Start(state.read_pos))
+                .map_err(io_error)?;
+            let mut tmp = File::create(&tmp_path).map_err(io_error)?;
+            std::io::copy(&mut reader, &mut tmp).map_err(io_error)?;
+            tmp.sync_all().map_err(io_error)?;
+        }
+        // if interrupted, re-reading old events is harmless, since
+        // duplicates are ignored when written
+        std::fs::write(&self.pos_path, "0").map_err(io_error)?;
+        std::fs::rename(&tmp_path, &self.path).map_err(io_error)?;
+        state.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(io_error)?;
+        state.len -= state.read_pos;
+        state.read_pos = 0;
+        Ok(())
+    }
+
+    /// Report spool depth and dropped events in `stats`.
+    pub fn fill_stats(&self, stats: &mut DbStats) {
+        let state = self.state.lock().unwrap();
+        stats.spooled_events = state.depth;
+        stats.spool_dropped_events = state.dropped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::EventId;
+    use crate::test_util::{event_with_id as event, temp_db_dir};
+    use bitcoin_hashes::Hash;
+
+    #[test]
+    fn spool_order_and_cap() {
+        let dir = temp_db_dir();
+        let line_len = serde_json::to_string(&event(0)).unwrap().len() as u64 + 1;
+        // room for three events
+        let spool = Spool::open(&dir, line_len * 3, SpoolFsync::Never).unwrap();
+        for n in 0..5 {
+            spool.push(&event(n)).unwrap();
+        }
+        assert_eq!(spool.depth(), 3);
+        let mut stats = DbStats::default();
+        spool.fill_stats(&mut stats);
+        assert_eq!(stats.spool_dropped_events, 2);
+        let first = spool.pop(1).unwrap();
+        assert_eq!(first[0].id, EventId::from_inner([2; 32]));
+        drop(spool);
+
+        // unread events are found when reopened
+        let spool = Spool::open(&dir, line_len * 3, SpoolFsync::Never).unwrap();
+        assert_eq!(spool.depth(), 2);
+        let ids: Vec<EventId> = spool.pop(10).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(
+            ids,
+            [EventId::from_inner([3; 32]), EventId::from_inner([4; 32])]
+        );
+        assert_eq!(spool.depth(), 0);
+        assert_eq!(std::fs::metadata(dir.join(SPOOL_FILE)).unwrap().len(), 0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,1871 @@
+//! SQLite storage backend
+use super::compress::{self, compress_threshold};
+use super::{
+    keep_superseded, top_authors_sql, top_kinds_sql, AuthorStats, DbStats, DeleteOutcome,
+    EventSource, KindCount, PruneFilter, QueryStream, SqlitePragmas, StatsCache, Storage,
+    UserVerification, AUTHOR_STATS_COLUMNS,
+};
+use crate::config::SETTINGS;
+use crate::error::{Error, Result};
+use crate::protocol::{Event, EventId, Subscription};
+use async_trait::async_trait;
+use bitcoin_hashes::{hex::ToHex, Hash};
+use futures::stream::{BoxStream, Stream};
+use futures::StreamExt;
+use log::*;
+use rusqlite::backup::{Backup, Progress};
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::ErrorCode;
+use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use rusqlite::Transaction;
+use secp256k1::XOnlyPublicKey;
+use serde::Serialize;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task;
+
+/// Database file
+pub(crate) const DB_FILE: &str = "nostr.db";
+
+/// Database file, and the write-ahead log and shared memory files
+/// SQLite keeps beside it.
+pub const DB_FILES: [&str; 3] = ["nostr.db", "nostr.db-wal", "nostr.db-shm"];
+
+/// Maximum number of idle reader connections kept open by a [`ReaderPool`]
+const MAX_IDLE_READERS: usize = 8;
+
+/// Number of query results buffered between the blocking query and a [`query_stream`] consumer
+const QUERY_STREAM_BUFFER: usize = 256;
+
+/// How long the writer waits on a locked database before a statement
+/// fails with a busy error.
+const WRITER_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Attempts made at a write that fails because the database is busy.
+const BUSY_RETRY_ATTEMPTS: u32 = 5;
+
+/// Delay before retrying a busy write, doubled after each attempt.
+const BUSY_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Value of `PRAGMA auto_vacuum` for incremental vacuuming.
+const AUTO_VACUUM_INCREMENTAL: u32 = 2;
+
+/// Startup DB Pragmas, applied along with the configured
+/// [`SqlitePragmas`]
+const STARTUP_SQL: &str = r##"
+PRAGMA foreign_keys = ON;
+"##;
+
+/// The connection settings configured in `[database.pragmas]`.
+fn configured_pragmas() -> SqlitePragmas {
+    SETTINGS.read().unwrap().database.pragmas.clone()
+}
+
+/// Upgrade DB to latest version, and execute pragma settings
+pub fn upgrade_db(conn: &mut Connection) -> Result<()> {
+    super::migrations::migrate(conn)?;
+    // Setup PRAGMA
+    conn.execute_batch(STARTUP_SQL)?;
+    configured_pragmas().apply(conn)?;
+    if !keep_superseded() {
+        purge_superseded(conn)?;
+    }
+    Ok(())
+}
+
+/// Delete events hidden by a newer replaceable event, along with
+/// their references.  Hidden events are only created while
+/// `database.keep_superseded` is set.
+fn purge_superseded(conn: &Connection) -> Result<()> {
+    let deleted = conn.execute("DELETE FROM event WHERE hidden=TRUE", [])?;
+    if deleted > 0 {
+        info!("deleted {} superseded events", deleted);
+    }
+    Ok(())
+}
+
+pub fn db_version(conn: &mut Connection) -> Result<usize> {
+    let query = "PRAGMA user_version;";
+    let curr_version = conn.query_row(query, [], |row| row.get(0))?;
+    Ok(curr_version)
+}
+
+/// Persist an event to the database.
+pub fn write_event(conn: &mut Connection, e: &Event) -> Result<usize> {
+    let keep = keep_superseded();
+    let compress = compress_threshold();
+    // start transaction
+    let tx = conn.transaction()?;
+    let ins_count = insert_event(&tx, e, keep, compress)?;
+    tx.commit()?;
+    Ok(ins_count)
+}
+
+/// Persist a batch of events to the database in a single
+/// transaction, returning one result per event.
+///
+/// If the batch transaction fails, each event is retried in its own
+/// transaction so that one bad event does not cause the others to be
+/// reported as failures.
+///
+/// Writes that fail because the database is busy or locked are
+/// retried with exponential backoff.
+pub fn write_events(conn: &mut Connection, events: &[Event]) -> Vec<Result<usize>> {
+    write_events_counted(conn, events, &WriteCounters::default())
+}
+
+/// Batch write, recording retries and failures in `counters`.
+fn write_events_counted(
+    conn: &mut Connection,
+    events: &[Event],
+    counters: &WriteCounters,
+) -> Vec<Result<usize>> {
+    let results: Vec<Result<usize>> = match retry_busy(counters, || write_batch(conn, events)) {
+        Ok(counts) => counts.into_iter().map(Ok).collect(),
+        Err(err) => {
+            debug!(
+                "batch of {} events failed ({}), writing individually",
+                events.len(),
+                err
+            );
+            events
+                .iter()
+                .map(|e| retry_busy(counters, || write_event(conn, e)))
+                .collect()
+        }
+    };
+    let failed = results
+        .iter()
+        .filter(|r| r.is_err() && !matches!(r, Err(Error::EventDeleted)))
+        .count();
+    counters.failed.fetch_add(failed as u64, Ordering::Relaxed);
+    results
+}
+
+/// Counts of retried, failed and pruned writes, reported in [`DbStats`].
+#[derive(Debug, Default)]
+struct WriteCounters {
+    /// Write attempts repeated because the database was busy
+    retried: AtomicU64,
+    /// Events that could not be written
+    failed: AtomicU64,
+    /// Events deleted by retention pruning
+    pruned: AtomicU64,
+}
+
+/// Check if an error was caused by the database being busy or locked.
+fn is_busy(err: &Error) -> bool {
+    match err {
+        Error::SqlError(rusqlite::Error::SqliteFailure(e, _)) => {
+            matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+        }
+        _ => false,
+    }
+}
+
+/// Run a write, retrying with exponential backoff while the database
+/// is busy.  Other errors are returned immediately.
+fn retry_busy<T>(counters: &WriteCounters, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut backoff = BUSY_RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(err) if is_busy(&err) && attempt < BUSY_RETRY_ATTEMPTS => {
+                warn!(
+                    "database busy on write attempt {}, retrying in {:?}",
+                    attempt, backoff
+                );
+                counters.retried.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Insert all events in one transaction, failing if any insert fails.
+fn write_batch(conn: &mut Connection, events: &[Event]) -> Result<Vec<usize>> {
+    let keep = keep_superseded();
+    let compress = compress_threshold();
+    let tx = conn.transaction()?;
+    let counts = events
+        .iter()
+        .map(|e| insert_event(&tx, e, keep, compress))
+        .collect::<Result<Vec<usize>>>()?;
+    tx.commit()?;
+    Ok(counts)
+}
+
+/// Insert an event and its references within an open transaction.
+///
+/// Metadata and contact list events replace every earlier event of
+/// the same kind from the same author.  The replaced events are
+/// deleted, or only hidden from queries if `keep_superseded` is set.
+/// Events deleted by the operator fail with [`Error::EventDeleted`].
+/// Event JSON of at least `compress_above` bytes is compressed.
+fn insert_event(
+    tx: &Transaction,
+    e: &Event,
+    keep_superseded: bool,
+    compress_above: Option<usize>,
+) -> Result<usize> {
+    // get relevant fields from event and convert to blobs.
+    let id_blob = e.id.as_inner().to_vec();
+    let tombstoned = tx
+        .query_row(
+            "SELECT 1 FROM tombstone WHERE event_hash=?",
+            params![id_blob],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if tombstoned {
+        return Err(Error::EventDeleted);
+    }
+    let pubkey_blob = e.pubkey.serialize().to_vec();
+    let stored = compress::encode(serde_json::to_string(&e)?, compress_above)?;
+    let event_kind = serde_json::to_value(&e.kind)?
+        .as_u64()
+        .expect("expect a kind");
+    // ignore if the event hash is a duplicate.
+    let ins_count = tx.execute(
+        "INSERT OR IGNORE INTO event (event_hash, created_at, kind, author, content, raw_size, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%s','now'), FALSE);",
+        params![id_blob, e.created_at, event_kind, pubkey_blob, stored.content, stored.raw_size]
+    )?;
+    if ins_count == 0 {
+        // if the event was a duplicate, no need to insert event or
+        // pubkey references.
+        return Ok(ins_count);
+    }
+    // remember primary key of the event most recently inserted.
+    let ev_id = tx.last_insert_rowid();
+    // add all event tags into the event_ref table
+    for etag in e.get_event_tags().unwrap_or_default() {
+        tx.execute(
+            "INSERT OR IGNORE INTO event_ref (event_id, referenced_event) VALUES (?1, ?2)",
+            params![ev_id, etag.get_event_id()?.as_inner().to_vec()],
+        )?;
+    }
+    // add all pubkey tags into the pubkey_ref table
+    for ptag in e.get_pubkey_tags().unwrap_or_default() {
+        tx.execute(
+            "INSERT OR IGNORE INTO pubkey_ref (event_id, referenced_pubkey) VALUES (?1, ?2)",
+            params![ev_id, ptag.get_pubkey()?.serialize().to_vec()],
+        )?;
+    }
+    // if this event is for a metadata or contact update, replace
+    // every other event of that kind from the same author that was
+    // issued earlier than this.
+    if event_kind == 0 || event_kind == 3 {
+        let superseded = "id!=? AND kind=? AND author=? AND created_at <= ?";
+        let (sql, action) = if keep_superseded {
+            (
+                format!(
+                    "UPDATE event SET hidden=TRUE WHERE {} AND hidden!=TRUE",
+                    superseded
+                ),
+                "hid",
+            )
+        } else {
+            // event and pubkey references are removed by cascade.
+            (format!("DELETE FROM event WHERE {}", superseded), "deleted")
+        };
+        let update_count =
+            tx.execute(&sql, params![ev_id, event_kind, pubkey_blob, e.created_at])?;
+        if update_count > 0 {
+            info!(
+                "{} {} older kind {} events",
+                action, update_count, event_kind
+            );
+        }
+    }
+    Ok(ins_count)
+}
+
+/// Check if a string contains only hex characters.
+fn is_hex(s: &str) -> bool {
+    s.chars().all(|x| char::is_ascii_hexdigit(&x))
+}
+
+/// Tables joined by every subscription query
+const QUERY_JOINS: &str = "FROM event e LEFT JOIN event_ref er ON e.id=er.event_id LEFT JOIN pubkey_ref pr ON e.id=pr.event_id ";
+
+/// Create a dynamic SQL query string from a subscription.
+fn query_from_sub(sub: &Subscription) -> String {
+    let mut query = format!("SELECT DISTINCT e.content, e.raw_size {}", QUERY_JOINS);
+    query.push_str(&where_from_sub(sub));
+    // add order clause
+    query.push_str(" ORDER BY created_at ASC");
+    debug!("query string: {}", query);
+    query
+}
+
+/// Create a SQL query string counting the events matching a subscription.
+fn count_from_sub(sub: &Subscription) -> String {
+    let mut query = format!("SELECT COUNT(DISTINCT(e.id)) {}", QUERY_JOINS);
+    query.push_str(&where_from_sub(sub));
+    debug!("count query string: {}", query);
+    query
+}
+
+/// Create the WHERE clause selecting events that match a subscription.
+/// Hidden events never match.
+fn where_from_sub(sub: &Subscription) -> String {
+    // build a dynamic SQL query.  all user-input is either an integer
+    // (sqli-safe), or a string that is filtered to only contain
+    // hexadecimal characters.
+    let mut query = String::new();
+    // for every filter in the subscription, generate a where clause
+    let mut filter_clauses: Vec<String> = Vec::new();
+    // a filter without conditions matches every event
+    let mut matches_all = false;
+    for f in sub.get_filters().iter() {
+        // individual filter components
+        let mut filter_components: Vec<String> = Vec::new();
+        // Query for "authors"
+        if f.authors.is_some() {
+            let authors_escaped: Vec<String> = f
+                .authors
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|&x| is_hex(&x.to_hex()))
+                .map(|x| format!("x'{}'", x))
+                .collect();
+            let authors_clause = format!("author IN ({})", authors_escaped.join(", "));
+            filter_components.push(authors_clause);
+        }
+        // Query for Kind
+        if let Some(ks) = &f.kinds {
+            // kind is number, no escaping needed
+            let str_kinds: Vec<String> = ks.iter().map(|x| x.to_string()).collect();
+            let kind_clause = format!("kind IN ({})", str_kinds.join(", "));
+            filter_components.push(kind_clause);
+        }
+        // Query for event
+        if f.ids.is_some() {
+            let ids_escaped: Vec<String> = f
+                .ids
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|&x| is_hex(&x.to_hex()))
+                .map(|x| format!("x'{}'", x))
+                .collect();
+            let id_clause = format!("event_hash IN ({})", ids_escaped.join(", "));
+            filter_components.push(id_clause);
+        }
+        // Query for referenced event
+        if f.events.is_some() {
+            let events_escaped: Vec<String> = f
+                .events
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|&x| is_hex(&x.to_hex()))
+                .map(|x| format!("x'{}'", x))
+                .collect();
+            let events_clause = format!("referenced_event IN ({})", events_escaped.join(", "));
+            filter_components.push(events_clause);
+        }
+        // Query for referenced pubkey
+        if f.pubkeys.is_some() {
+            let pubkeys_escaped: Vec<String> = f
+                .pubkeys
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|&x| is_hex(&x.to_hex()))
+                .map(|x| format!("x'{}'", x))
+                .collect();
+            let pubkeys_clause = format!("referenced_pubkey IN ({})", pubkeys_escaped.join(", "));
+            filter_components.push(pubkeys_clause);
+        }
+
+        // Query for timestamp
+        if f.since.is_some() {
+            let created_clause = format!("created_at > {}", f.since.unwrap());
+            filter_components.push(created_clause);
+        }
+        // Query for timestamp
+        if f.until.is_some() {
+            let until_clause = format!("created_at < {}", f.until.unwrap());
+            filter_components.push(until_clause);
+        }
+
+        // combine all clauses, and add to filter_clauses
+        if !filter_components.is_empty() {
+            let mut fc = "( ".to_owned();
+            fc.push_str(&filter_components.join(" AND "));
+            fc.push_str(" )");
+            filter_clauses.push(fc);
+        } else {
+            matches_all = true;
+        }
+    }
+
+    // never display hidden events, whichever filter matched
+    query.push_str(" WHERE hidden!=TRUE");
+    // combine all filters with OR clauses, if any exist
+    if !matches_all && !filter_clauses.is_empty() {
+        query.push_str(" AND ( ");
+        query.push_str(&filter_clauses.join(" OR "));
+        query.push_str(" )");
+    }
+    query
+}
+
+/// A pool of read-only connections to the event database.
+///
+/// Connections are opened lazily and returned to the pool when the
+/// [`PooledConnection`] handle is dropped.  Cloning the pool is cheap,
+/// all clones share the same set of connections.
+#[derive(Clone)]
+pub struct ReaderPool {
+    inner: Arc<ReaderPoolInner>,
+}
+
+struct ReaderPoolInner {
+    /// Full path to the database file
+    path: PathBuf,
+    /// Connections available for reuse
+    idle: Mutex<Vec<Connection>>,
+    /// Set once the pool is closed, after which no connections are
+    /// handed out or kept
+    closed: AtomicBool,
+    /// Settings applied to each new connection
+    pragmas: SqlitePragmas,
+}
+
+impl ReaderPool {
+    /// Create a pool for the database stored in `db_dir`.
+    pub fn new(db_dir: &Path) -> Self {
+        ReaderPool {
+            inner: Arc::new(ReaderPoolInner {
+                path: db_dir.join(DB_FILE),
+                idle: Mutex::new(Vec::new()),
+                closed: AtomicBool::new(false),
+                pragmas: configured_pragmas(),
+            }),
+        }
+    }
+
+    /// Create a pool for the database in the configured data directory.
+    pub fn from_settings() -> Self {
+        let config = SETTINGS.read().unwrap();
+        Self::new(Path::new(&config.database.data_directory))
+    }
+
+    /// Get a read-only connection, opening a new one if none are idle.
+    pub fn get(&self) -> Result<PooledConnection> {
+        if self.inner.closed.load(Ordering::Acquire) {
+            return Err(Error::DatabaseEngineError(
+                "database is shutting down".to_owned(),
+            ));
+        }
+        let reused = self.inner.idle.lock().unwrap().pop();
+        let conn = match reused {
+            Some(conn) => conn,
+            None => {
+                let conn = open_db(&self.inner.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                self.inner.pragmas.apply(&conn)?;
+                debug!("opened database for reading");
+                conn
+            }
+        };
+        Ok(PooledConnection {
+            conn: Some(conn),
+            pool: self.inner.clone(),
+        })
+    }
+
+    /// Close idle connections, and any in use as they are returned.
+    /// Returns the number of idle connections closed.
+    pub fn close(&self) -> usize {
+        self.inner.closed.store(true, Ordering::Release);
+        let idle = std::mem::take(&mut *self.inner.idle.lock().unwrap());
+        idle.len()
+    }
+}
+
+/// A reader connection borrowed from a [`ReaderPool`].
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: Arc<ReaderPoolInner>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection present until drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_READERS && !self.pool.closed.load(Ordering::Acquire) {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+/// Run a [`Subscription`] against the database, producing matching
+/// events as a [`Stream`].
+///
+/// The query runs on a blocking thread, and is aborted as soon as
+/// the stream is dropped.  Events are produced in ascending
+/// `created_at` order.  Must be called from within a tokio runtime.
+pub fn query_stream(sub: Subscription, pool: &ReaderPool) -> impl Stream<Item = Result<Event>> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event>>(QUERY_STREAM_BUFFER);
+    let pool = pool.clone();
+    task::spawn_blocking(move || {
+        if let Err(e) = run_query(&sub, &pool, &tx) {
+            tx.blocking_send(Err(e)).ok();
+        }
+    });
+    QueryStream { rx }
+}
+
+/// Execute the SQL query for a subscription, sending each result on
+/// `tx` until the query completes or the receiver goes away.
+fn run_query(
+    sub: &Subscription,
+    pool: &ReaderPool,
+    tx: &tokio::sync::mpsc::Sender<Result<Event>>,
+) -> Result<()> {
+    let conn = pool.get()?;
+    debug!("going to query for: {:?}", sub);
+    let mut row_count: usize = 0;
+    let start = Instant::now();
+    // generate SQL query
+    let q = query_from_sub(sub);
+    // execute the query
+    let mut stmt = conn.prepare(&q)?;
+    let mut event_rows = stmt.query([])?;
+    while let Some(row) = event_rows.next()? {
+        // check if the consumer is still interested
+        if tx.is_closed() {
+            debug!("query aborted");
+            return Ok(());
+        }
+        row_count += 1;
+        let event_json = compress::event_json(row, 0)?;
+        let event = Event::from_str(&event_json)?;
+        if tx.blocking_send(Ok(event)).is_err() {
+            debug!("query aborted");
+            return Ok(());
+        }
+    }
+    debug!(
+        "query completed ({} rows) in {:?}",
+        row_count,
+        start.elapsed()
+    );
+    Ok(())
+}
+
+/// Bytes of the database file holding data, excluding free pages and
+/// the write-ahead log.
+fn used_bytes(conn: &Connection) -> Result<u64> {
+    let page_count: u64 = conn.query_row("PRAGMA page_count;", [], |row| row.get(0))?;
+    let freelist_count: u64 = conn.query_row("PRAGMA freelist_count;", [], |row| row.get(0))?;
+    let page_size: u64 = conn.query_row("PRAGMA page_size;", [], |row| row.get(0))?;
+    Ok(page_count.saturating_sub(freelist_count) * page_size)
+}
+
+/// Size of the write-ahead log file, zero if it does not exist.
+fn wal_size(wal_path: &Path) -> u64 {
+    std::fs::metadata(wal_path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Copy the write-ahead log into the database and truncate it.
+fn checkpoint(conn: &Connection, wal_path: &Path) -> Result<()> {
+    let before = wal_size(wal_path);
+    let start = Instant::now();
+    let (busy, log_pages, checkpointed): (i64, i64, i64) =
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+    if busy != 0 {
+        warn!("WAL checkpoint blocked by readers, will retry later");
+    }
+    info!(
+        "checkpointed {} of {} WAL pages in {:?}, WAL size {} -> {} bytes",
+        checkpointed,
+        log_pages,
+        start.elapsed(),
+        before,
+        wal_size(wal_path)
+    );
+    Ok(())
+}
+
+/// Key for an encrypted database, loaded by [`load_encryption_key`].
+static ENCRYPTION_KEY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Read the key from `database.encryption_key_file`, if set, and use
+/// it for every database connection opened afterwards.  Called once
+/// at startup, so the key file may be removed while the relay runs.
+pub fn load_encryption_key() -> Result<()> {
+    let key_file = SETTINGS
+        .read()
+        .unwrap()
+        .database
+        .encryption_key_file
+        .clone();
+    let key_file = match key_file {
+        Some(key_file) => key_file,
+        None => return Ok(()),
+    };
+    if !cfg!(feature = "sqlcipher") {
+        return Err(Error::GenericError(
+            "database.encryption_key_file needs nostrd built with the sqlcipher feature".to_owned(),
+        ));
+    }
+    let key = std::fs::read_to_string(&key_file).map_err(|e| {
+        Error::GenericError(format!(
+            "could not read encryption key file {}: {}",
+            key_file, e
+        ))
+    })?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(Error::GenericError(format!(
+            "encryption key file {} is empty",
+            key_file
+        )));
+    }
+    *ENCRYPTION_KEY.lock().unwrap() = Some(key.to_owned());
+    info!("loaded database encryption key");
+    Ok(())
+}
+
+/// Open the database at `path`, unlocking it with the encryption key
+/// if one was loaded.  A wrong key fails with
+/// [`Error::EncryptionKeyInvalid`].
+pub(crate) fn open_db(path: &Path, flags: OpenFlags) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, flags)?;
+    #[cfg(feature = "sqlcipher")]
+    if let Some(key) = ENCRYPTION_KEY.lock().unwrap().as_deref() {
+        // the key must be set before anything else reads the file
+        conn.pragma_update(None, "key", key)?;
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|_| Error::EncryptionKeyInvalid)?;
+    }
+    Ok(conn)
+}
+
+/// Hold an exclusive lock on the database until the connection is
+/// closed, failing immediately if another process, such as a running
+/// relay, is using it.
+fn lock_exclusive(conn: &Connection) -> Result<()> {
+    conn.busy_timeout(Duration::ZERO)?;
+    conn.execute_batch("PRAGMA locking_mode = EXCLUSIVE; BEGIN EXCLUSIVE; COMMIT;")
+        .map_err(|e| {
+            Error::GenericError(format!("database is in use, stop the relay first ({})", e))
+        })?;
+    Ok(())
+}
+
+/// Fail unless there is a database at `full_path`.
+fn check_exists(full_path: &Path) -> Result<()> {
+    if !full_path.is_file() {
+        return Err(Error::GenericError(format!(
+            "no database found at {:?}",
+            full_path
+        )));
+    }
+    Ok(())
+}
+
+/// Open the database at `full_path` and hold an exclusive lock on it
+/// until the connection is closed.
+fn open_exclusive(full_path: &Path) -> Result<Connection> {
+    check_exists(full_path)?;
+    let conn = open_db(full_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    lock_exclusive(&conn)?;
+    Ok(conn)
+}
+
+/// Encrypt the plaintext database in `db_dir` with the loaded key,
+/// replacing it.  The relay must not be running.  The encrypted copy
+/// is written next to the database with `sqlcipher_export`, and only
+/// renamed over it once complete.
+#[cfg(feature = "sqlcipher")]
+pub fn encrypt_db(db_dir: &Path) -> Result<()> {
+    let key = ENCRYPTION_KEY.lock().unwrap().clone().ok_or_else(|| {
+        Error::GenericError("set database.encryption_key_file to encrypt the database".to_owned())
+    })?;
+    let full_path = db_dir.join(DB_FILE);
+    let tmp_path = db_dir.join(format!("{}.encrypted", DB_FILE));
+    check_exists(&full_path)?;
+    // the database is still plaintext, so it is opened without a key
+    let mut conn = Connection::open_with_flags(&full_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    lock_exclusive(&conn)?;
+    let version = db_version(&mut conn).map_err(|_| {
+        Error::GenericError("database is not plaintext, or is already encrypted".to_owned())
+    })?;
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path).map_err(|e| Error::GenericError(e.to_string()))?;
+    }
+    info!("encrypting {:?}", full_path);
+    conn.execute(
+        "ATTACH DATABASE ?1 AS encrypted KEY ?2",
+        params![tmp_path.to_string_lossy(), key],
+    )?;
+    conn.query_row("SELECT sqlcipher_export('encrypted')", [], |_| Ok(()))?;
+    conn.execute_batch(&format!(
+        "PRAGMA encrypted.user_version = {}; DETACH DATABASE encrypted;",
+        version
+    ))?;
+    drop(conn);
+    std::fs::rename(&tmp_path, &full_path).map_err(|e| Error::GenericError(e.to_string()))?;
+    info!("database encrypted");
+    Ok(())
+}
+
+/// Encrypting needs SQLCipher, which this build does not include.
+#[cfg(not(feature = "sqlcipher"))]
+pub fn encrypt_db(_db_dir: &Path) -> Result<()> {
+    Err(Error::GenericError(
+        "nostrd was built without the sqlcipher feature".to_owned(),
+    ))
+}
+
+/// Delete an event on an operator's request, along with its tag
+/// references, and record a tombstone so that it is refused if
+/// submitted again.  Both happen in one transaction.
+pub fn delete_event(conn: &mut Connection, id: &EventId) -> Result<DeleteOutcome> {
+    let id_blob = id.as_inner().to_vec();
+    let tx = conn.transaction()?;
+    // event and pubkey references are removed by cascade.
+    let deleted = tx.execute("DELETE FROM event WHERE event_hash=?", params![id_blob])?;
+    let tombstoned = tx.execute(
+        "INSERT OR IGNORE INTO tombstone (event_hash, deleted_at) VALUES (?, strftime('%s','now'))",
+        params![id_blob],
+    )?;
+    tx.commit()?;
+    Ok(DeleteOutcome {
+        deleted: deleted > 0,
+        tombstoned: tombstoned > 0,
+    })
+}
+
+/// How long an offline deletion waits on a database locked by a
+/// running relay.
+const OFFLINE_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delete events by id from the database in `db_dir`, as with
+/// [`delete_event`].  The database may be in use by a running relay;
+/// a relay that recently stored one of the events may still answer
+/// its resubmission as a duplicate, but will not store or broadcast
+/// it again.
+pub fn delete_offline(db_dir: &Path, ids: &[EventId]) -> Result<Vec<DeleteOutcome>> {
+    let full_path = db_dir.join(DB_FILE);
+    check_exists(&full_path)?;
+    let mut conn = open_db(&full_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    conn.busy_timeout(OFFLINE_BUSY_TIMEOUT)?;
+    upgrade_db(&mut conn)?;
+    ids.iter().map(|id| delete_event(&mut conn, id)).collect()
+}
+
+/// Events deleted in each transaction of an offline prune.
+const OFFLINE_PRUNE_BATCH_SIZE: u64 = 10_000;
+
+/// Outcome of an offline prune.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OfflinePruneReport {
+    /// Events deleted
+    pub deleted: u64,
+    /// Unused database pages after deleting
+    pub free_pages: u64,
+}
+
+/// Delete every event matching `filter` from the database in
+/// `db_dir`, along with its tag references.
+///
+/// The database is locked exclusively, so this fails if a relay is
+/// using it.  Events are deleted in batched transactions, so an
+/// interrupted prune keeps the batches already committed.  Freed
+/// pages are reused by later writes, or returned to the operating
+/// system by [`compact`].
+pub fn prune_offline(db_dir: &Path, filter: &PruneFilter) -> Result<OfflinePruneReport> {
+    let full_path = db_dir.join(DB_FILE);
+    let mut conn = open_exclusive(&full_path)?;
+    conn.execute_batch(STARTUP_SQL)?;
+    configured_pragmas().apply(&conn)?;
+    info!("pruning {:?}", full_path);
+    let sql = filter.delete_sql(OFFLINE_PRUNE_BATCH_SIZE);
+    let mut deleted = 0;
+    loop {
+        let tx = conn.transaction()?;
+        let batch = tx.execute(&sql, [])? as u64;
+        tx.commit()?;
+        deleted += batch;
+        if batch < OFFLINE_PRUNE_BATCH_SIZE {
+            break;
+        }
+        info!("pruned {} events so far", deleted);
+    }
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    let free_pages: u64 = conn.query_row("PRAGMA freelist_count;", [], |row| row.get(0))?;
+    info!("pruned {} events, {} free pages", deleted, free_pages);
+    Ok(OfflinePruneReport {
+        deleted,
+        free_pages,
+    })
+}
+
+/// Database size before and after compaction.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Bytes used by the database and its WAL before compaction
+    pub size_before: u64,
+    /// Bytes used by the database and its WAL after compaction
+    pub size_after: u64,
+}
+
+/// Interval between progress messages during compaction.
+const COMPACT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Rebuild the database in `db_dir` with `VACUUM`, returning unused
+/// space to the operating system.
+///
+/// The database is opened exclusively, so this fails if a relay is
+/// using it; a running relay compacts through
+/// [`Storage::compact`](super::Storage::compact) instead.
+/// Compaction needs up to the current database size in free disk
+/// space, and is refused if that is not available.
+pub fn compact(db_dir: &Path) -> Result<CompactReport> {
+    let full_path = db_dir.join(DB_FILE);
+    if !full_path.is_file() {
+        return Err(Error::GenericError(format!(
+            "no database found at {:?}",
+            full_path
+        )));
+    }
+    let conn = open_exclusive(&full_path)?;
+    vacuum(&conn, &full_path)
+}
+
+/// Run `VACUUM` on `conn`, open on the database at `full_path`, with
+/// progress logging, refusing if there is not the free space for it.
+fn vacuum(conn: &Connection, full_path: &Path) -> Result<CompactReport> {
+    let wal_path = full_path.with_file_name(format!("{}-wal", DB_FILE));
+    let db_dir = full_path.parent().unwrap_or_else(|| Path::new("."));
+    let file_size = |p: &Path| std::fs::metadata(p).map(|m| m.len()).unwrap_or(0);
+    let size_before = file_size(full_path) + file_size(&wal_path);
+    let available = fs2::available_space(db_dir)
+        .map_err(|e| Error::GenericError(format!("could not check free space: {}", e)))?;
+    if available < size_before {
+        return Err(Error::GenericError(format!(
+            "compaction needs {} bytes of free space, only {} available",
+            size_before, available
+        )));
+    }
+    info!("compacting {:?} ({} bytes)", full_path, size_before);
+    // VACUUM offers no progress reporting, so log that it is still running.
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    let start = Instant::now();
+    let progress = thread::spawn(move || {
+        while done_rx.recv_timeout(COMPACT_PROGRESS_INTERVAL).is_err() {
+            info!("still compacting, {:?} elapsed", start.elapsed());
+        }
+    });
+    let res = conn
+        .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+        .map_err(Error::from);
+    done_tx.send(()).ok();
+    progress.join().ok();
+    res?;
+    let size_after = file_size(full_path) + file_size(&wal_path);
+    info!(
+        "compaction finished in {:?}, {} -> {} bytes",
+        start.elapsed(),
+        size_before,
+        size_after
+    );
+    Ok(CompactReport {
+        size_before,
+        size_after,
+    })
+}
+
+/// Pages copied in each step of an online backup.
+const BACKUP_PAGES_PER_STEP: i32 = 256;
+
+/// Pause between backup steps, letting other connections use the
+/// database.
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+
+/// Copy a consistent snapshot of the database in `db_dir` to `dest`,
+/// using the SQLite online backup API.  Safe to run against a live
+/// relay: pages are copied in small steps with pauses in between, and
+/// writes made during the backup cause it to restart rather than
+/// produce an inconsistent copy.  The snapshot includes changes still
+/// in the write-ahead log.
+///
+/// `progress` is called after each step.  The snapshot is checked
+/// with `PRAGMA integrity_check` once complete.
+pub fn backup_to(db_dir: &Path, dest: &Path, progress: Option<fn(Progress)>) -> Result<()> {
+    let full_path = db_dir.join(DB_FILE);
+    let src = open_db(&full_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    // an encrypted database is backed up with the same key
+    let mut dst = open_db(
+        dest,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    let start = Instant::now();
+    info!("backing up {:?} to {:?}", full_path, dest);
+    {
+        let backup = Backup::new(&src, &mut dst)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, progress)?;
+    }
+    let integrity: String = dst.query_row("PRAGMA integrity_check;", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(Error::GenericError(format!(
+            "backup failed integrity check: {}",
+            integrity
+        )));
+    }
+    info!("backup to {:?} finished in {:?}", dest, start.elapsed());
+    Ok(())
+}
+
+/// Compute statistics describing the stored events, and the space
+/// they use.  Counting kinds and authors reads the whole event table,
+/// so callers should cache the result.
+pub fn get_stats(conn: &Connection) -> Result<DbStats> {
+    let mut stats = conn.query_row(super::STATS_SUMMARY_SQL, [], |row| {
+        Ok(DbStats {
+            event_count: row.get(0)?,
+            hidden_count: row.get(1)?,
+            author_count: row.get(2)?,
+            oldest_created_at: row.get(3)?,
+            newest_created_at: row.get(4)?,
+            ..Default::default()
+        })
+    })?;
+    let mut stmt = conn.prepare(&top_kinds_sql())?;
+    stats.top_kinds = stmt
+        .query_map([], |row| {
+            Ok(KindCount {
+                kind: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut stmt = conn.prepare(&top_authors_sql())?;
+    stats.top_authors = stmt
+        .query_map([], author_stats_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    (
+        stats.compressed_events,
+        stats.compressed_raw_bytes,
+        stats.compressed_stored_bytes,
+    ) = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(raw_size), 0), COALESCE(SUM(length(content)), 0) \
+         FROM event WHERE raw_size IS NOT NULL",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    stats.size_bytes = Some(used_bytes(conn)?);
+    Ok(stats)
+}
+
+/// Storage used by `author`, or `None` if nothing by them is stored.
+pub fn get_author_stats(conn: &Connection, author: &XOnlyPublicKey) -> Result<Option<AuthorStats>> {
+    let query = format!(
+        "SELECT {} FROM author_stats WHERE author=?",
+        AUTHOR_STATS_COLUMNS
+    );
+    let stats = conn
+        .query_row(
+            &query,
+            params![author.serialize().to_vec()],
+            author_stats_from_row,
+        )
+        .optional()?;
+    Ok(stats)
+}
+
+/// Columns selected for a [`UserVerification`].
+const VERIFICATION_COLUMNS: &str = "pubkey, name, last_checked, last_success, failure_count";
+
+/// The NIP-05 verification state of `pubkey`, if recorded.  Cheap
+/// enough to consult while accepting an event.
+pub fn get_verification(
+    conn: &Connection,
+    pubkey: &XOnlyPublicKey,
+) -> Result<Option<UserVerification>> {
+    let query = format!(
+        "SELECT {} FROM user_verification WHERE pubkey=?",
+        VERIFICATION_COLUMNS
+    );
+    let verification = conn
+        .query_row(
+            &query,
+            params![pubkey.serialize().to_vec()],
+            verification_from_row,
+        )
+        .optional()?;
+    Ok(verification)
+}
+
+/// Read a `user_verification` row selected with [`VERIFICATION_COLUMNS`].
+fn verification_from_row(row: &rusqlite::Row) -> rusqlite::Result<UserVerification> {
+    let pubkey: Vec<u8> = row.get(0)?;
+    let pubkey = XOnlyPublicKey::from_slice(&pubkey).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(e))
+    })?;
+    Ok(UserVerification {
+        pubkey,
+        name: row.get(1)?,
+        last_checked: row.get(2)?,
+        last_success: row.get(3)?,
+        failure_count: row.get(4)?,
+    })
+}
+
+/// Read an `author_stats` row selected with [`AUTHOR_STATS_COLUMNS`].
+fn author_stats_from_row(row: &rusqlite::Row) -> rusqlite::Result<AuthorStats> {
+    Ok(AuthorStats {
+        author: row.get::<_, Vec<u8>>(0)?.to_hex(),
+        event_count: row.get(1)?,
+        total_bytes: row.get(2)?,
+        first_seen: row.get(3)?,
+        last_seen: row.get(4)?,
+    })
+}
+
+/// Open a connection for writing to the database at `full_path`.
+fn open_writer(full_path: &Path) -> Result<Connection> {
+    let conn = open_db(
+        full_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+    )?;
+    conn.busy_timeout(WRITER_BUSY_TIMEOUT)?;
+    info!("opened database {:?} for writing", full_path);
+    Ok(conn)
+}
+
+/// Event storage in a local SQLite database.
+///
+/// A single connection is used for all writes, while queries are
+/// served from a [`ReaderPool`].
+pub struct SqliteStorage {
+    /// Connection used for writes and schema changes, until closed
+    writer: Arc<Mutex<Option<Connection>>>,
+    /// Connections used for queries
+    pool: ReaderPool,
+    /// Write retry and failure counts
+    counters: Arc<WriteCounters>,
+    /// Location of the write-ahead log
+    wal_path: PathBuf,
+    /// Recently computed statistics
+    stats_cache: StatsCache,
+}
+
+impl SqliteStorage {
+    /// Open (creating if necessary) the database in `db_dir`.
+    pub fn open(db_dir: &Path) -> Result<Self> {
+        let conn = open_writer(&db_dir.join(DB_FILE))?;
+        Ok(SqliteStorage {
+            writer: Arc::new(Mutex::new(Some(conn))),
+            pool: ReaderPool::new(db_dir),
+            counters: Arc::new(WriteCounters::default()),
+            wal_path: db_dir.join(format!("{}-wal", DB_FILE)),
+            stats_cache: StatsCache::from_settings(),
+        })
+    }
+
+    /// The pool of reader connections used for queries.
+    pub fn pool(&self) -> &ReaderPool {
+        &self.pool
+    }
+
+    /// Run a closure with the writer connection on a blocking thread.
+    async fn with_writer<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        task::spawn_blocking(move || {
+            let mut conn = writer.lock().unwrap();
+            match conn.as_mut() {
+                Some(conn) => f(conn),
+                None => Err(Error::DatabaseEngineError("database is closed".to_owned())),
+            }
+        })
+        .await
+        .map_err(|e| Error::GenericError(e.to_string()))?
+    }
+
+    /// Run a closure with a reader connection on a blocking thread.
+    async fn with_reader<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            f(&conn)
+        })
+        .await
+        .map_err(|e| Error::GenericError(e.to_string()))?
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<()> {
+        self.with_writer(|conn| {
+            upgrade_db(conn)?;
+            super::pragmas::log_effective(conn)
+        })
+        .await
+    }
+
+    async fn write_event(&self, event: Event) -> Result<usize> {
+        let counters = self.counters.clone();
+        self.with_writer(move |conn| {
+            let res = retry_busy(&counters, || write_event(conn, &event));
+            if res.is_err() {
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            res
+        })
+        .await
+    }
+
+    async fn write_events(&self, events: Vec<Event>) -> Vec<Result<usize>> {
+        let count = events.len();
+        let counters = self.counters.clone();
+        match self
+            .with_writer(move |conn| Ok(write_events_counted(conn, &events, &counters)))
+            .await
+        {
+            Ok(results) => results,
+            Err(e) => (0..count)
+                .map(|_| Err(Error::GenericError(e.to_string())))
+                .collect(),
+        }
+    }
+
+    fn query(&self, sub: Subscription) -> BoxStream<'static, Result<Event>> {
+        query_stream(sub, &self.pool).boxed()
+    }
+
+    async fn count(&self, sub: Subscription) -> Result<u64> {
+        self.with_reader(move |conn| {
+            let count = conn.query_row(&count_from_sub(&sub), [], |row| row.get(0))?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: EventId) -> Result<bool> {
+        self.with_writer(move |conn| {
+            // event and pubkey references are removed by cascade.
+            let deleted = conn.execute(
+                "DELETE FROM event WHERE event_hash=?",
+                params![id.as_inner().to_vec()],
+            )?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+
+    async fn delete_event(&self, id: EventId) -> Result<DeleteOutcome> {
+        self.with_writer(move |conn| delete_event(conn, &id)).await
+    }
+
+    async fn prune_events(&self, filter: PruneFilter, limit: u64) -> Result<u64> {
+        let counters = self.counters.clone();
+        self.with_writer(move |conn| {
+            let deleted = retry_busy(&counters, || {
+                Ok(conn.execute(&filter.delete_sql(limit), [])?)
+            })?;
+            counters.pruned.fetch_add(deleted as u64, Ordering::Relaxed);
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn count_prunable(&self, filter: PruneFilter) -> Result<u64> {
+        self.with_reader(move |conn| {
+            let count = conn.query_row(&filter.count_sql(), [], |row| row.get(0))?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn author_stats(&self, author: XOnlyPublicKey) -> Result<Option<AuthorStats>> {
+        self.with_reader(move |conn| get_author_stats(conn, &author))
+            .await
+    }
+
+    async fn write_sources(&self, sources: Vec<(EventId, EventSource)>) -> Result<()> {
+        self.with_writer(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO event_source (event_hash, remote_ip, client_id, received_at) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                )?;
+                for (id, source) in &sources {
+                    stmt.execute(params![
+                        id.as_inner().to_vec(),
+                        source.ip,
+                        source.client_id,
+                        source.received_at
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn event_source(&self, id: EventId) -> Result<Option<EventSource>> {
+        self.with_reader(move |conn| {
+            let source = conn
+                .query_row(
+                    "SELECT remote_ip, client_id, received_at FROM event_source WHERE event_hash=?",
+                    params![id.as_inner().to_vec()],
+                    |row| {
+                        Ok(EventSource {
+                            ip: row.get(0)?,
+                            client_id: row.get(1)?,
+                            received_at: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(source)
+        })
+        .await
+    }
+
+    async fn prune_sources(&self, before: u64) -> Result<u64> {
+        self.with_writer(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM event_source WHERE received_at < ?",
+                params![before],
+            )?;
+            Ok(deleted as u64)
+        })
+        .await
+    }
+
+    async fn save_verification(&self, verification: UserVerification) -> Result<()> {
+        self.with_writer(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO user_verification \
+                 (pubkey, name, last_checked, last_success, failure_count) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    verification.pubkey.serialize().to_vec(),
+                    verification.name,
+                    verification.last_checked,
+                    verification.last_success,
+                    verification.failure_count
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_verification(&self, pubkey: XOnlyPublicKey) -> Result<()> {
+        self.with_writer(move |conn| {
+            conn.execute(
+                "DELETE FROM user_verification WHERE pubkey=?",
+                params![pubkey.serialize().to_vec()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn verification(&self, pubkey: XOnlyPublicKey) -> Result<Option<UserVerification>> {
+        self.with_reader(move |conn| get_verification(conn, &pubkey))
+            .await
+    }
+
+    async fn verifications_due(&self, before: u64, limit: u64) -> Result<Vec<UserVerification>> {
+        self.with_reader(move |conn| {
+            let query = format!(
+                "SELECT {} FROM user_verification \
+                 WHERE last_checked IS NULL OR last_checked < ? \
+                 ORDER BY last_checked ASC LIMIT ?",
+                VERIFICATION_COLUMNS
+            );
+            let mut stmt = conn.prepare(&query)?;
+            let due = stmt
+                .query_map(params![before, limit], verification_from_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(due)
+        })
+        .await
+    }
+
+    async fn recent_ids(&self, limit: u64) -> Result<Vec<EventId>> {
+        self.with_reader(move |conn| {
+            let mut stmt = conn.prepare("SELECT event_hash FROM event ORDER BY id DESC LIMIT ?")?;
+            let hashes = stmt.query_map(params![limit], |row| row.get::<_, Vec<u8>>(0))?;
+            let mut ids = vec![];
+            for hash in hashes {
+                if let Ok(id) = EventId::from_slice(&hash?) {
+                    ids.push(id);
+                }
+            }
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.with_reader(|conn| Ok(conn.query_row("SELECT 1;", [], |_| Ok(()))?))
+            .await
+    }
+
+    async fn size_bytes(&self) -> Result<Option<u64>> {
+        self.with_reader(|conn| Ok(Some(used_bytes(conn)?))).await
+    }
+
+    async fn maintain(&self) -> Result<()> {
+        let wal_path = self.wal_path.clone();
+        let threshold = SETTINGS.read().unwrap().database.wal_checkpoint_mb * 1024 * 1024;
+        // holding the writer means this runs between write batches.
+        self.with_writer(move |conn| {
+            if wal_size(&wal_path) > threshold {
+                checkpoint(conn, &wal_path)?;
+            }
+            conn.execute_batch("PRAGMA optimize;")?;
+            // incremental vacuum is only possible for databases
+            // created with it enabled.
+            let auto_vacuum: u32 = conn.query_row("PRAGMA auto_vacuum;", [], |row| row.get(0))?;
+            let free_pages: u64 = conn.query_row("PRAGMA freelist_count;", [], |row| row.get(0))?;
+            if auto_vacuum == AUTO_VACUUM_INCREMENTAL && free_pages > 0 {
+                conn.execute_batch("PRAGMA incremental_vacuum;")?;
+                debug!("incremental vacuum released {} free pages", free_pages);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn compact(&self) -> Result<Option<CompactReport>> {
+        let full_path = self.wal_path.with_file_name(DB_FILE);
+        // holding the writer leaves submitted events queued until the
+        // vacuum finishes
+        self.with_writer(move |conn| vacuum(conn, &full_path).map(Some))
+            .await
+    }
+
+    async fn reopen(&self) -> Result<()> {
+        let full_path = self.wal_path.with_file_name(DB_FILE);
+        self.with_writer(move |conn| {
+            let mut fresh = open_writer(&full_path)?;
+            upgrade_db(&mut fresh)?;
+            *conn = fresh;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn close(&self) -> Result<()> {
+        let wal_path = self.wal_path.clone();
+        let writer = self.writer.clone();
+        let pool = self.pool.clone();
+        task::spawn_blocking(move || {
+            // writes are finished, so the WAL can be emptied, leaving
+            // nothing to recover on the next start
+            let conn = writer.lock().unwrap().take();
+            if let Some(conn) = conn {
+                info!("checkpointing database before shutdown");
+                checkpoint(&conn, &wal_path)?;
+                conn.close().map_err(|(_, e)| e)?;
+                info!("closed database writer");
+            }
+            let idle = pool.close();
+            info!("closed {} idle database readers", idle);
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::GenericError(e.to_string()))?
+    }
+
+    async fn stats(&self) -> Result<DbStats> {
+        let mut stats = match self.stats_cache.get() {
+            Some(stats) => stats,
+            None => {
+                let stats = self.with_reader(get_stats).await?;
+                self.stats_cache.put(&stats);
+                stats
+            }
+        };
+        stats.size_bytes = Some(self.with_reader(used_bytes).await?);
+        stats.file_size_bytes = std::fs::metadata(self.wal_path.with_file_name(DB_FILE))
+            .map(|m| m.len())
+            .ok();
+        stats.wal_size_bytes = Some(wal_size(&self.wal_path));
+        stats.retried_writes = self.counters.retried.load(Ordering::Relaxed);
+        stats.failed_writes = self.counters.failed.load(Ordering::Relaxed);
+        stats.pruned_events = self.counters.pruned.load(Ordering::Relaxed);
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::DB_VERSION;
+    use crate::protocol::testvec::{event::VALID_EVENT, subscription::KINDS_SUBS};
+    use crate::test_util::{temp_db_dir, valid_event};
+
+    #[test]
+    fn newer_schema_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("PRAGMA user_version = 99;").unwrap();
+        match upgrade_db(&mut conn) {
+            Err(Error::FutureSchemaVersion { found, supported }) => {
+                assert_eq!((found, supported), (99, DB_VERSION))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_migration_reported() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // a v1 database without its event table cannot be upgraded
+        conn.execute_batch("PRAGMA user_version = 1;").unwrap();
+        match upgrade_db(&mut conn) {
+            Err(Error::MigrationFailed { from, to, .. }) => {
+                assert_eq!((from, to), (1, DB_VERSION))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(db_version(&mut conn).unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn storage_roundtrip() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let event = valid_event();
+        let sub: Subscription = serde_json::from_str(KINDS_SUBS).unwrap();
+
+        assert_eq!(storage.write_event(event.clone()).await.unwrap(), 1);
+        // duplicates are ignored
+        assert_eq!(storage.write_event(event.clone()).await.unwrap(), 0);
+        assert_eq!(storage.count(sub.clone()).await.unwrap(), 1);
+        assert_eq!(storage.stats().await.unwrap().event_count, 1);
+
+        let results: Vec<Event> = storage
+            .query(sub.clone())
+            .map(|r| r.unwrap())
+            .collect()
+            .await;
+        assert_eq!(results, vec![event.clone()]);
+
+        assert!(storage.delete(event.id).await.unwrap());
+        assert!(!storage.delete(event.id).await.unwrap());
+        assert_eq!(storage.count(sub).await.unwrap(), 0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn compressed_events_readable() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let event = valid_event();
+        let e = event.clone();
+        storage
+            .with_writer(move |conn| {
+                let tx = conn.transaction()?;
+                insert_event(&tx, &e, false, Some(0))?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        let stats = storage.stats().await.unwrap();
+        assert_eq!(stats.compressed_events, 1);
+        assert!(stats.compression_ratio().unwrap() > 1.0);
+
+        let sub: Subscription = serde_json::from_str(KINDS_SUBS).unwrap();
+        let results: Vec<Event> = storage.query(sub).map(|r| r.unwrap()).collect().await;
+        assert_eq!(results, vec![event.clone()]);
+        // exports are plain JSON
+        let mut out = Vec::new();
+        super::super::export_events(&dir, &Default::default(), &mut out, |_| {}).unwrap();
+        let exported: Event = serde_json::from_slice(&out).unwrap();
+        assert_eq!(exported, event);
+        let report = super::super::verify_events(&dir, false, 1, |_| {}).unwrap();
+        assert!(report.invalid.is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    /// Copies of the test vector event with distinct ids.
+    fn distinct_events(n: u8) -> Vec<Event> {
+        let event = valid_event();
+        (0..n)
+            .map(|i| {
+                let mut e = event.clone();
+                e.id = EventId::from_inner([i; 32]);
+                e
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_write() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let mut events = distinct_events(3);
+        // duplicate within the batch is ignored, not an error
+        events.push(events[0].clone());
+        let results: Vec<usize> = write_events(&mut conn, &events)
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(results, vec![1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn batch_write_failure_falls_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        // reject a single event to make the batch transaction fail
+        conn.execute_batch(
+            "CREATE TRIGGER reject BEFORE INSERT ON event \
+             WHEN NEW.event_hash = zeroblob(32) BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+        )
+        .unwrap();
+        let events = distinct_events(3);
+        let results = write_events(&mut conn, &events);
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+        assert_eq!(results[2].as_ref().unwrap(), &1);
+        let stored: u64 = conn
+            .query_row("SELECT COUNT(*) FROM event", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 2);
+    }
+
+    #[tokio::test]
+    async fn close_truncates_wal() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        storage.write_events(distinct_events(10)).await;
+        let wal_path = dir.join(format!("{}-wal", DB_FILE));
+        assert!(wal_size(&wal_path) > 0);
+        storage.maintain().await.unwrap();
+        storage.close().await.unwrap();
+        assert_eq!(wal_size(&wal_path), 0);
+        // nothing can use the database once closed
+        assert!(storage.pool().get().is_err());
+        assert!(storage
+            .write_event(distinct_events(1)[0].clone())
+            .await
+            .is_err());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn compact_shrinks_database() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let mut events = distinct_events(100);
+        for e in events.iter_mut() {
+            e.content = "x".repeat(4096);
+        }
+        storage.write_events(events.clone()).await;
+        for e in &events {
+            storage.delete(e.id).await.unwrap();
+        }
+        // refused while the database is open
+        assert!(compact(&dir).is_err());
+        drop(storage);
+        let report = compact(&dir).unwrap();
+        assert!(report.size_after < report.size_before);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn live_compaction_holds_writes() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let mut events = distinct_events(101);
+        for e in events.iter_mut() {
+            e.content = "x".repeat(4096);
+        }
+        let late = events.pop().unwrap();
+        storage.write_events(events.clone()).await;
+        for e in &events {
+            storage.delete(e.id).await.unwrap();
+        }
+        // a write submitted during compaction waits for it
+        let (report, written) = tokio::join!(storage.compact(), storage.write_event(late));
+        let report = report.unwrap().unwrap();
+        assert!(report.size_after < report.size_before);
+        assert_eq!(written.unwrap(), 1);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn offline_prune() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let mut events = distinct_events(10);
+        for (i, e) in events.iter_mut().enumerate() {
+            e.created_at = i as u64;
+        }
+        storage.write_events(events).await;
+        let filter = PruneFilter {
+            created_before: Some(4),
+            include_latest_replaceable: true,
+            ..Default::default()
+        };
+        // refused while the database is open
+        assert!(prune_offline(&dir, &filter).is_err());
+        drop(storage);
+        let report = prune_offline(&dir, &filter).unwrap();
+        assert_eq!(report.deleted, 4);
+        let conn = Connection::open(dir.join(DB_FILE)).unwrap();
+        let refs: u64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM event_ref WHERE event_id NOT IN (SELECT id FROM event)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(refs, 0);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn superseded_metadata_deleted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let mut metadata: serde_json::Value = serde_json::from_str(VALID_EVENT).unwrap();
+        metadata["kind"] = 0.into();
+        for (i, mut e) in distinct_events(2).into_iter().enumerate() {
+            e.kind = serde_json::from_value(metadata["kind"].clone()).unwrap();
+            e.created_at = 100 + i as u64;
+            write_event(&mut conn, &e).unwrap();
+        }
+        let count = |conn: &Connection, sql: &str| -> u64 {
+            conn.query_row(sql, [], |r| r.get(0)).unwrap()
+        };
+        // only the newest metadata, and its references, remain
+        assert_eq!(count(&conn, "SELECT created_at FROM event"), 101);
+        for table in ["event_ref", "pubkey_ref"] {
+            let orphans = format!(
+                "SELECT COUNT(*) FROM {} WHERE event_id NOT IN (SELECT id FROM event)",
+                table
+            );
+            assert_eq!(count(&conn, &orphans), 0);
+        }
+        // events hidden while superseded events were kept are purged
+        conn.execute("UPDATE event SET hidden=TRUE", []).unwrap();
+        upgrade_db(&mut conn).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM event"), 0);
+    }
+
+    #[test]
+    fn superseded_metadata_hidden() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let tx = conn.transaction().unwrap();
+        for (i, mut e) in distinct_events(2).into_iter().enumerate() {
+            e.kind = serde_json::from_value(0.into()).unwrap();
+            e.created_at = 100 + i as u64;
+            insert_event(&tx, &e, true, None).unwrap();
+        }
+        tx.commit().unwrap();
+        let author = valid_event().pubkey.to_string();
+        let found = |filters: serde_json::Value| -> Vec<u64> {
+            let mut req = vec![serde_json::json!("REQ"), serde_json::json!("sub")];
+            req.extend(filters.as_array().unwrap().iter().cloned());
+            let sub: Subscription = serde_json::from_value(serde_json::Value::Array(req)).unwrap();
+            let mut stmt = conn.prepare(&query_from_sub(&sub)).unwrap();
+            let rows = stmt.query_map([], |r| r.get::<_, String>(0)).unwrap();
+            rows.map(|r| {
+                serde_json::from_str::<Event>(&r.unwrap())
+                    .unwrap()
+                    .created_at
+            })
+            .collect()
+        };
+        // the older event is kept, but no filter finds it
+        assert_eq!(
+            found(serde_json::json!([{ "authors": [author] }])),
+            vec![101]
+        );
+        assert_eq!(
+            found(serde_json::json!([{ "kinds": [0] }, { "since": 50 }])),
+            vec![101]
+        );
+        assert_eq!(
+            found(serde_json::json!([{ "authors": [author] }, {}])),
+            vec![101]
+        );
+        assert_eq!(
+            found(serde_json::json!([{ "kinds": [1] }])),
+            Vec::<u64>::new()
+        );
+    }
+
+    #[test]
+    fn deleted_event_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let events = distinct_events(2);
+        write_event(&mut conn, &events[0]).unwrap();
+        let outcome = delete_event(&mut conn, &events[0].id).unwrap();
+        assert_eq!(
+            outcome,
+            DeleteOutcome {
+                deleted: true,
+                tombstoned: true
+            }
+        );
+        let refs: u64 = conn
+            .query_row("SELECT COUNT(*) FROM event_ref", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(refs, 0);
+        // a second deletion finds nothing to do
+        assert_eq!(
+            delete_event(&mut conn, &events[0].id).unwrap(),
+            DeleteOutcome::default()
+        );
+        // the deleted event is refused, without failing the batch
+        let results = write_events(&mut conn, &events);
+        assert!(matches!(results[0], Err(Error::EventDeleted)));
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+    }
+
+    #[tokio::test]
+    async fn computed_stats() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let mut events = distinct_events(5);
+        for (i, e) in events.iter_mut().enumerate() {
+            e.created_at = 100 + i as u64;
+        }
+        storage.write_events(events).await;
+        storage
+            .with_writer(|conn| {
+                Ok(conn.execute("UPDATE event SET hidden=TRUE WHERE created_at=100", [])?)
+            })
+            .await
+            .unwrap();
+        let stats = storage.stats().await.unwrap();
+        assert_eq!(stats.event_count, 5);
+        assert_eq!(stats.hidden_count, 1);
+        assert_eq!(stats.author_count, 1);
+        assert_eq!(stats.oldest_created_at, Some(100));
+        assert_eq!(stats.newest_created_at, Some(104));
+        assert_eq!(stats.top_kinds, vec![KindCount { kind: 2, count: 5 }]);
+        assert!(stats.file_size_bytes.unwrap() > 0);
+        // aggregates are cached, but counters are not
+        storage.delete(EventId::from_inner([0; 32])).await.unwrap();
+        assert_eq!(storage.stats().await.unwrap().event_count, 5);
+        let conn = storage.pool().get().unwrap();
+        assert_eq!(get_stats(&conn).unwrap().event_count, 4);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn backup_during_writes() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let events = distinct_events(250);
+        let (before, during) = events.split_at(200);
+        for e in before {
+            let mut e = e.clone();
+            e.content = "x".repeat(2048);
+            storage.write_event(e).await.unwrap();
+        }
+        let dest = dir.join("backup.db");
+        let backup_dest = dest.clone();
+        let backup_dir = dir.clone();
+        let backup =
+            task::spawn_blocking(move || backup_to(&backup_dir, &backup_dest, None).unwrap());
+        for e in during {
+            storage.write_event(e.clone()).await.unwrap();
+        }
+        backup.await.unwrap();
+        // snapshot opens cleanly, with at least the events written
+        // before the backup started
+        let snapshot = Connection::open(&dest).unwrap();
+        let integrity: String = snapshot
+            .query_row("PRAGMA integrity_check;", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(integrity, "ok");
+        let stored: u64 = snapshot
+            .query_row("SELECT COUNT(*) FROM event", [], |row| row.get(0))
+            .unwrap();
+        assert!(stored >= 200);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn busy_writes_retried() {
+        let counters = WriteCounters::default();
+        let busy = || {
+            Error::SqlError(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            ))
+        };
+        // succeeds after two busy failures
+        let mut attempts = 0;
+        let res = retry_busy(&counters, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(counters.retried.load(Ordering::Relaxed), 2);
+        // other errors are not retried
+        let mut attempts = 0;
+        let res: Result<()> = retry_busy(&counters, || {
+            attempts += 1;
+            Err(Error::GenericError("not busy".to_owned()))
+        });
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,315 @@
+//! Offline verification of stored events
+use crate::error::{Error, Result};
+use crate::protocol::Event;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::Hash;
+use log::*;
+use rusqlite::{params, Connection, OpenFlags};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of rows between progress reports.
+pub const VERIFY_PROGRESS_INTERVAL: u64 = 10_000;
+
+/// Rows handed to a worker thread at a time.
+const VERIFY_CHUNK_SIZE: usize = 256;
+
+/// A stored event row, as read from the event table.
+struct StoredRow {
+    row_id: i64,
+    event_hash: Vec<u8>,
+    author: Vec<u8>,
+    kind: u64,
+    created_at: u64,
+    content: Vec<u8>,
+    raw_size: Option<i64>,
+}
+
+/// A stored event that failed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEvent {
+    /// Primary key of the row in the event table
+    pub row_id: i64,
+    /// Event id column, hex encoded
+    pub event_hash: String,
+    /// Why the event is invalid
+    pub reason: String,
+}
+
+/// Outcome of a verification scan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of events checked
+    pub checked: u64,
+    /// Events that failed verification
+    pub invalid: Vec<InvalidEvent>,
+    /// Number of invalid events deleted
+    pub deleted: u64,
+    /// Authors, hex encoded, whose statistics disagree with their
+    /// stored events
+    pub author_mismatches: Vec<String>,
+    /// Whether author statistics were rebuilt to fix mismatches
+    pub authors_rebuilt: bool,
+}
+
+/// Check every event stored in the database in `db_dir`.
+///
+/// Each event's JSON is parsed, its id recomputed and its signature
+/// verified, and the indexed columns are compared against it.  Rows
+/// are streamed to `workers` threads for checking, so memory use does
+/// not grow with the database.  Per-author statistics are then
+/// checked against the stored events.  When `delete_invalid` is set,
+/// invalid events are deleted along with their tag references, and
+/// mismatched author statistics are rebuilt.  `progress` is
+/// called with the number of rows read every
+/// [`VERIFY_PROGRESS_INTERVAL`] rows.
+pub fn verify_events(
+    db_dir: &Path,
+    delete_invalid: bool,
+    workers: usize,
+    mut progress: impl FnMut(u64),
+) -> Result<VerifyReport> {
+    let full_path = db_dir.join(super::sqlite::DB_FILE);
+    let conn = super::sqlite::open_db(
+        &full_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let workers = workers.max(1);
+    info!(
+        "verifying events in {:?} with {} workers",
+        full_path, workers
+    );
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Vec<StoredRow>>(workers * 2);
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    let (invalid_tx, invalid_rx) = mpsc::channel::<InvalidEvent>();
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let chunk_rx = chunk_rx.clone();
+            let invalid_tx = invalid_tx.clone();
+            thread::spawn(move || loop {
+                // hold the lock only while waiting for the next chunk
+                let chunk = match chunk_rx.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return,
+                };
+                for row in chunk {
+                    if let Err(reason) = check_row(&row) {
+                        let invalid = InvalidEvent {
+                            row_id: row.row_id,
+                            event_hash: row.event_hash.to_hex(),
+                            reason,
+                        };
+                        if invalid_tx.send(invalid).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(invalid_tx);
+
+    let mut checked = 0;
+    let scan = (|| -> Result<()> {
+        let mut stmt = conn.prepare(
+            "SELECT id, event_hash, author, kind, created_at, content, raw_size FROM event \
+             ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+        let mut chunk = Vec::with_capacity(VERIFY_CHUNK_SIZE);
+        while let Some(row) = rows.next()? {
+            chunk.push(StoredRow {
+                row_id: row.get(0)?,
+                event_hash: row.get(1)?,
+                author: row.get(2)?,
+                kind: row.get(3)?,
+                created_at: row.get(4)?,
+                content: row
+                    .get_ref(5)?
+                    .as_bytes()
+                    .map_err(|e| Error::GenericError(e.to_string()))?
+                    .to_vec(),
+                raw_size: row.get(6)?,
+            });
+            checked += 1;
+            if checked % VERIFY_PROGRESS_INTERVAL == 0 {
+                progress(checked);
+            }
+            if chunk.len() == VERIFY_CHUNK_SIZE {
+                let full = std::mem::replace(&mut chunk, Vec::with_capacity(VERIFY_CHUNK_SIZE));
+                if chunk_tx.send(full).is_err() {
+                    return Err(Error::GenericError(
+                        "verification worker stopped".to_owned(),
+                    ));
+                }
+            }
+        }
+        if !chunk.is_empty() && chunk_tx.send(chunk).is_err() {
+            return Err(Error::GenericError(
+                "verification worker stopped".to_owned(),
+            ));
+        }
+        Ok(())
+    })();
+    drop(chunk_tx);
+    for handle in handles {
+        if handle.join().is_err() {
+            return Err(Error::GenericError(
+                "verification worker panicked".to_owned(),
+            ));
+        }
+    }
+    scan?;
+
+    let mut invalid: Vec<InvalidEvent> = invalid_rx.into_iter().collect();
+    invalid.sort_by_key(|i| i.row_id);
+    let deleted = if delete_invalid && !invalid.is_empty() {
+        delete_rows(&full_path, &invalid)?
+    } else {
+        0
+    };
+    let author_mismatches = author_mismatches(&conn)?;
+    let authors_rebuilt = delete_invalid && !author_mismatches.is_empty();
+    if authors_rebuilt {
+        let conn = super::sqlite::open_db(&full_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        conn.execute_batch(&format!(
+            "BEGIN;\n{}\nCOMMIT;",
+            super::migrations::AUTHOR_STATS_BACKFILL_SQL
+        ))?;
+        info!("rebuilt statistics for {} authors", author_mismatches.len());
+    }
+    Ok(VerifyReport {
+        checked,
+        invalid,
+        deleted,
+        author_mismatches,
+        authors_rebuilt,
+    })
+}
+
+/// Authors whose `author_stats` row is missing, stale, or has no
+/// stored events.
+fn author_mismatches(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT e.author FROM \
+         (SELECT author, COUNT(*) AS n, SUM(length(CAST(content AS BLOB))) AS bytes \
+          FROM event GROUP BY author) e \
+         LEFT JOIN author_stats s ON s.author = e.author \
+         WHERE s.author IS NULL OR s.event_count != e.n OR s.total_bytes != e.bytes \
+         UNION ALL \
+         SELECT s.author FROM author_stats s \
+         WHERE NOT EXISTS (SELECT 1 FROM event WHERE event.author = s.author)",
+    )?;
+    let authors = stmt
+        .query_map([], |row| row.get::<_, Vec<u8>>(0))?
+        .map(|author| author.map(|a| a.to_hex()))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(authors)
+}
+
+/// Check that a row holds a valid event matching its indexed columns.
+fn check_row(row: &StoredRow) -> std::result::Result<(), String> {
+    let json = super::compress::decode(&row.content, row.raw_size).map_err(|e| e.to_string())?;
+    let event: Event =
+        serde_json::from_str(&json).map_err(|e| format!("unparseable JSON: {}", e))?;
+    event.verify().map_err(|e| e.to_string())?;
+    if event.id.as_inner()[..] != row.event_hash[..] {
+        return Err("event_hash column does not match id".to_owned());
+    }
+    if event.pubkey.serialize()[..] != row.author[..] {
+        return Err("author column does not match pubkey".to_owned());
+    }
+    let kind = serde_json::to_value(&event.kind)
+        .ok()
+        .and_then(|k| k.as_u64());
+    if kind != Some(row.kind) {
+        return Err("kind column does not match kind".to_owned());
+    }
+    if event.created_at != row.created_at {
+        return Err("created_at column does not match created_at".to_owned());
+    }
+    Ok(())
+}
+
+/// Delete invalid rows, and by cascade their tag references.
+fn delete_rows(full_path: &Path, invalid: &[InvalidEvent]) -> Result<u64> {
+    let mut conn = super::sqlite::open_db(full_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    let tx = conn.transaction()?;
+    let mut deleted = 0;
+    {
+        let mut stmt = tx.prepare("DELETE FROM event WHERE id = ?")?;
+        for i in invalid {
+            deleted += stmt.execute(params![i.row_id])? as u64;
+        }
+    }
+    tx.commit()?;
+    info!("deleted {} invalid events", deleted);
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::sqlite::{upgrade_db, write_event, DB_FILE};
+    use crate::protocol::EventId;
+    use crate::test_util::{temp_db_dir, valid_event};
+
+    #[test]
+    fn detects_and_deletes_corruption() {
+        let dir = temp_db_dir();
+        let mut conn = Connection::open(dir.join(DB_FILE)).unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let event = valid_event();
+        write_event(&mut conn, &event).unwrap();
+        // a copy with the wrong id, and one with altered content
+        let mut forged = event.clone();
+        forged.id = EventId::from_inner([7; 32]);
+        write_event(&mut conn, &forged).unwrap();
+        let mut tampered = event;
+        tampered.content = "tampered".to_owned();
+        tampered.id = EventId::from_inner([8; 32]);
+        write_event(&mut conn, &tampered).unwrap();
+        let refs = |conn: &Connection| -> u64 {
+            conn.query_row("SELECT COUNT(*) FROM event_ref", [], |r| r.get(0))
+                .unwrap()
+        };
+        let refs_before = refs(&conn);
+
+        let report = verify_events(&dir, false, 2, |_| {}).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.invalid.len(), 2);
+        assert_eq!(report.deleted, 0);
+
+        let report = verify_events(&dir, true, 2, |_| {}).unwrap();
+        assert_eq!(report.deleted, 2);
+        assert_eq!(refs(&conn), refs_before / 3);
+        let report = verify_events(&dir, false, 2, |_| {}).unwrap();
+        assert_eq!(report.checked, 1);
+        assert!(report.invalid.is_empty());
+        assert!(report.author_mismatches.is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn rebuilds_author_stats() {
+        let dir = temp_db_dir();
+        let mut conn = Connection::open(dir.join(DB_FILE)).unwrap();
+        upgrade_db(&mut conn).unwrap();
+        let event = valid_event();
+        write_event(&mut conn, &event).unwrap();
+        conn.execute("UPDATE author_stats SET event_count = 5", [])
+            .unwrap();
+
+        let report = verify_events(&dir, false, 1, |_| {}).unwrap();
+        assert_eq!(report.author_mismatches, [event.pubkey.to_string()]);
+        assert!(!report.authors_rebuilt);
+        let report = verify_events(&dir, true, 1, |_| {}).unwrap();
+        assert!(report.authors_rebuilt);
+        let report = verify_events(&dir, false, 1, |_| {}).unwrap();
+        assert!(report.author_mismatches.is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
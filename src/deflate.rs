@@ -0,0 +1,678 @@
+//! The permessage-deflate websocket extension (RFC 7692).
+//!
+//! tungstenite does not implement the extension, so it is applied
+//! beneath it: compressed messages from the client are inflated into
+//! plain frames before tungstenite reads them, and messages
+//! tungstenite writes are deflated on their way out.  Both sides
+//! compress each message on its own (no context takeover), so no
+//! compression state is kept between messages, and the buffers are
+//! shared by the connections on each thread.
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use hyper::header::{HeaderMap, HeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+use std::cell::RefCell;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Name of the extension, as negotiated
+const EXTENSION: &str = "permessage-deflate";
+
+/// Accepted offers are answered with this
+const RESPONSE: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+
+/// Every compressed message ends with the tail of an empty stored
+/// block, which is left off when it is sent.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The only window the compressor uses; offers that need a smaller
+/// one are declined.
+const WINDOW_BITS: u8 = 15;
+
+/// Compression level for outgoing messages, favouring speed
+const LEVEL: u32 = 3;
+
+/// Largest message inflated if no limit is configured
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 << 20;
+
+/// Bytes written before tungstenite has to wait for the client
+const WRITE_BUFFER_BYTES: usize = 128 * 1024;
+
+thread_local! {
+    static COMPRESS: RefCell<Compress> = RefCell::new(Compress::new(Compression::new(LEVEL), false));
+    static DECOMPRESS: RefCell<Decompress> = RefCell::new(Decompress::new(false));
+}
+
+/// The `Sec-WebSocket-Extensions` response accepting a client's
+/// offer of permessage-deflate, if it made one that can be accepted.
+pub fn negotiate(request: &HeaderMap) -> Option<HeaderValue> {
+    let offers = request
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for offer in offers {
+        if acceptable(offer) {
+            return Some(HeaderValue::from_static(RESPONSE));
+        }
+    }
+    None
+}
+
+/// Whether an offer is of permessage-deflate with parameters that
+/// can be met.
+fn acceptable(offer: &str) -> bool {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next() != Some(EXTENSION) {
+        return false;
+    }
+    let mut seen = vec![];
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        if seen.contains(&name) {
+            return false;
+        }
+        seen.push(name);
+        let bits = |value: Option<&str>| value.and_then(|v| v.parse::<u8>().ok());
+        let ok = match name {
+            "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+            // the client may use any window, as inflating allows the
+            // largest
+            "client_max_window_bits" => {
+                value.is_none() || matches!(bits(value), Some(8..=WINDOW_BITS))
+            }
+            "server_max_window_bits" => bits(value) == Some(WINDOW_BITS),
+            _ => false,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    true
+}
+
+/// The header of a websocket frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    fin: bool,
+    /// Set on the first frame of a compressed message
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header itself
+    len: usize,
+    payload_len: usize,
+}
+
+const OP_CONTINUATION: u8 = 0;
+const OP_TEXT: u8 = 1;
+const OP_BINARY: u8 = 2;
+
+impl Header {
+    /// Parse the header at the start of `buf`, if it is all there.
+    fn parse(buf: &[u8]) -> Option<Header> {
+        let (&first, rest) = buf.split_first()?;
+        let (&second, rest) = rest.split_first()?;
+        let (payload_len, rest, mut len) = match second & 0x7f {
+            126 => (
+                u16::from_be_bytes(rest.get(..2)?.try_into().unwrap()) as u64,
+                &rest[2..],
+                4,
+            ),
+            127 => (
+                u64::from_be_bytes(rest.get(..8)?.try_into().unwrap()),
+                &rest[8..],
+                10,
+            ),
+            n => (n as u64, rest, 2),
+        };
+        let mask = if second & 0x80 != 0 {
+            len += 4;
+            Some(rest.get(..4)?.try_into().unwrap())
+        } else {
+            None
+        };
+        Some(Header {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            len,
+            payload_len: usize::try_from(payload_len).unwrap_or(usize::MAX),
+        })
+    }
+
+    fn is_data(&self) -> bool {
+        self.opcode == OP_TEXT || self.opcode == OP_BINARY
+    }
+}
+
+/// Append a whole frame carrying `payload` to `out`, masked with zeros
+/// if `masked`, as a client's frames must be.
+fn write_frame(out: &mut Vec<u8>, opcode: u8, rsv1: bool, masked: bool, payload: &[u8]) {
+    out.push(0x80 | if rsv1 { 0x40 } else { 0 } | opcode);
+    let mask_bit = if masked { 0x80 } else { 0 };
+    match payload.len() {
+        n if n < 126 => out.push(mask_bit | n as u8),
+        n if n <= u16::MAX as usize => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    if masked {
+        out.extend_from_slice(&[0; 4]);
+    }
+    out.extend_from_slice(payload);
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Deflate a message, leaving off the tail of the final block.
+fn deflate(payload: &[u8]) -> io::Result<Vec<u8>> {
+    COMPRESS.with(|compress| {
+        let mut compress = compress.borrow_mut();
+        compress.reset();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let consumed = compress.total_in() as usize;
+            compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(|e| invalid(&e.to_string()))?;
+            // done once everything is in and the output had room to spare
+            if compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        Ok(out)
+    })
+}
+
+/// Inflate a message, failing if it would be larger than `max`
+/// bytes.
+fn inflate(payload: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    DECOMPRESS.with(|decompress| {
+        let mut decompress = decompress.borrow_mut();
+        decompress.reset(false);
+        let mut out = Vec::with_capacity((payload.len() * 4).min(max) + 1);
+        for input in [payload, &TAIL[..]] {
+            let start = decompress.total_in();
+            loop {
+                let consumed = (decompress.total_in() - start) as usize;
+                if consumed == input.len() && out.len() < out.capacity() {
+                    break;
+                }
+                if out.len() == out.capacity() {
+                    if out.len() > max {
+                        return Err(invalid("inflated message too large"));
+                    }
+                    // allow one byte over the limit, to tell
+                    out.reserve_exact((out.capacity() * 2).min(max + 1) - out.len());
+                }
+                let (before_in, before_out) = (decompress.total_in(), out.len());
+                let status = decompress
+                    .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                    .map_err(|e| invalid(&e.to_string()))?;
+                if status == Status::StreamEnd {
+                    break;
+                }
+                if decompress.total_in() == before_in && out.len() == before_out {
+                    return Err(invalid("compressed message cut short"));
+                }
+            }
+        }
+        if out.len() > max {
+            return Err(invalid("inflated message too large"));
+        }
+        Ok(out)
+    })
+}
+
+/// Limits applied to what the client sends, after inflating.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_message_bytes: Option<usize>,
+    pub max_frame_bytes: Option<usize>,
+}
+
+/// Frames from the client, with compressed messages inflated.
+#[derive(Debug, Default)]
+struct Inbound {
+    /// Bytes received and not yet parsed
+    raw: Vec<u8>,
+    /// Frames ready for tungstenite to read
+    ready: Vec<u8>,
+    ready_pos: usize,
+    /// Opcode and payload of a compressed message still arriving
+    message: Option<(u8, Vec<u8>)>,
+    eof: bool,
+}
+
+/// Frames written by tungstenite, compressed on the way out.
+#[derive(Debug, Default)]
+struct Outbound {
+    /// Bytes written and not yet parsed
+    raw: Vec<u8>,
+    /// Frames ready to send to the client
+    ready: Vec<u8>,
+    ready_pos: usize,
+    /// Set while sending an uncompressed fragmented message
+    fragmented: bool,
+}
+
+/// A client connection, compressing messages if the client agreed to
+/// permessage-deflate, and passing everything through untouched if
+/// not.
+#[derive(Debug)]
+pub struct DeflateStream<S> {
+    inner: S,
+    /// Set if messages are compressed
+    limits: Option<Limits>,
+    inbound: Inbound,
+    outbound: Outbound,
+    /// Bytes compression kept from being sent or received
+    saved: Arc<AtomicU64>,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap a connection, compressing messages if `limits` are given,
+    /// as they are once the extension is negotiated.
+    pub fn new(inner: S, limits: Option<Limits>) -> Self {
+        DeflateStream {
+            inner,
+            limits,
+            inbound: Inbound::default(),
+            outbound: Outbound::default(),
+            saved: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Counter of the bytes compression kept off the connection, in
+    /// both directions.
+    pub fn bytes_saved(&self) -> Arc<AtomicU64> {
+        self.saved.clone()
+    }
+
+    fn save(&self, plain: usize, compressed: usize) {
+        self.saved
+            .fetch_add(plain.saturating_sub(compressed) as u64, Ordering::Relaxed);
+    }
+
+    /// Turn complete frames from the client into frames for
+    /// tungstenite, returning whether any were.
+    fn process_inbound(&mut self, limits: Limits) -> io::Result<bool> {
+        let max_message = limits
+            .max_message_bytes
+            .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES);
+        let mut start = 0;
+        while let Some(header) = Header::parse(&self.inbound.raw[start..]) {
+            if limits
+                .max_frame_bytes
+                .is_some_and(|max| header.payload_len > max)
+            {
+                return Err(invalid("frame too large"));
+            }
+            let end = start
+                .checked_add(header.len)
+                .and_then(|n| n.checked_add(header.payload_len))
+                .ok_or_else(|| invalid("frame too large"))?;
+            if end > self.inbound.raw.len() {
+                break;
+            }
+            let frame = &self.inbound.raw[start..end];
+            let unmasked = || {
+                let mut payload = frame[header.len..].to_vec();
+                if let Some(mask) = header.mask {
+                    for (i, byte) in payload.iter_mut().enumerate() {
+                        *byte ^= mask[i % 4];
+                    }
+                }
+                payload
+            };
+            let compressed = if header.is_data() && header.rsv1 {
+                if self.inbound.message.is_some() {
+                    return Err(invalid("message started inside another"));
+                }
+                Some((header.opcode, unmasked()))
+            } else if header.opcode == OP_CONTINUATION && self.inbound.message.is_some() {
+                if header.rsv1 {
+                    return Err(invalid("compression set on a continuation frame"));
+                }
+                let (opcode, mut payload) = self.inbound.message.take().unwrap();
+                payload.extend_from_slice(&unmasked());
+                Some((opcode, payload))
+            } else {
+                // uncompressed, or a control frame, which may come
+                // between the frames of a message
+                self.inbound.ready.extend_from_slice(frame);
+                None
+            };
+            if let Some((opcode, payload)) = compressed {
+                if payload.len() > max_message {
+                    return Err(invalid("message too large"));
+                }
+                if header.fin {
+                    let inflated = inflate(&payload, max_message)?;
+                    self.save(inflated.len(), payload.len());
+                    write_frame(&mut self.inbound.ready, opcode, false, true, &inflated);
+                } else {
+                    self.inbound.message = Some((opcode, payload));
+                }
+            }
+            start = end;
+        }
+        self.inbound.raw.drain(..start);
+        Ok(start > 0)
+    }
+
+    /// Turn complete frames from tungstenite into frames for the
+    /// client.
+    fn process_outbound(&mut self) -> io::Result<()> {
+        let mut start = 0;
+        while let Some(header) = Header::parse(&self.outbound.raw[start..]) {
+            let end = start + header.len + header.payload_len;
+            if end > self.outbound.raw.len() {
+                break;
+            }
+            let frame = &self.outbound.raw[start..end];
+            let payload = &frame[header.len..];
+            if header.is_data() && header.fin && !self.outbound.fragmented {
+                let compressed = deflate(payload)?;
+                // each message stands alone, so any may be sent as is
+                if compressed.len() < payload.len() {
+                    self.save(payload.len(), compressed.len());
+                    write_frame(
+                        &mut self.outbound.ready,
+                        header.opcode,
+                        true,
+                        false,
+                        &compressed,
+                    );
+                } else {
+                    self.outbound.ready.extend_from_slice(frame);
+                }
+            } else {
+                if header.is_data() || header.opcode == OP_CONTINUATION {
+                    self.outbound.fragmented = !header.fin;
+                }
+                self.outbound.ready.extend_from_slice(frame);
+            }
+            start = end;
+        }
+        self.outbound.raw.drain(..start);
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Send frames waiting for the client, until done or it would
+    /// block.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let outbound = &mut self.outbound;
+        while outbound.ready_pos < outbound.ready.len() {
+            let n = match Pin::new(&mut self.inner)
+                .poll_write(cx, &outbound.ready[outbound.ready_pos..])
+            {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            outbound.ready_pos += n;
+        }
+        outbound.ready.clear();
+        outbound.ready_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(limits) = this.limits else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            let inbound = &mut this.inbound;
+            if inbound.ready_pos < inbound.ready.len() {
+                let n = buf.remaining().min(inbound.ready.len() - inbound.ready_pos);
+                buf.put_slice(&inbound.ready[inbound.ready_pos..inbound.ready_pos + n]);
+                inbound.ready_pos += n;
+                if inbound.ready_pos == inbound.ready.len() {
+                    inbound.ready.clear();
+                    inbound.ready_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if inbound.eof {
+                // let tungstenite see any partial frame, and the end
+                let rest = std::mem::take(&mut inbound.raw);
+                if rest.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                inbound.ready = rest;
+                continue;
+            }
+            if this.process_inbound(limits)? {
+                continue;
+            }
+            let mut chunk = [0; 8192];
+            let mut read = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {
+                    if read.filled().is_empty() {
+                        this.inbound.eof = true;
+                    } else {
+                        this.inbound.raw.extend_from_slice(read.filled());
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.limits.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        if this.outbound.ready.len() - this.outbound.ready_pos >= WRITE_BUFFER_BYTES {
+            // the client is behind, so tungstenite waits for it
+            if this.poll_send(cx)?.is_pending() {
+                return Poll::Pending;
+            }
+        }
+        this.outbound.raw.extend_from_slice(buf);
+        this.process_outbound()?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.limits.is_some() && this.poll_send(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.limits.is_some() && this.poll_send(cx)?.is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::WebSocketStream;
+    use tungstenite::protocol::{Message, Role};
+
+    fn offer(value: &str) -> Option<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(SEC_WEBSOCKET_EXTENSIONS, value.parse().unwrap());
+        negotiate(&headers)
+    }
+
+    #[test]
+    fn offers_negotiated() {
+        assert!(negotiate(&HeaderMap::new()).is_none());
+        // as browsers offer it
+        assert_eq!(
+            offer("permessage-deflate; client_max_window_bits").unwrap(),
+            RESPONSE
+        );
+        assert!(offer("permessage-deflate").is_some());
+        assert!(offer("permessage-deflate; server_max_window_bits=15").is_some());
+        assert!(offer("permessage-deflate; client_max_window_bits=\"10\"").is_some());
+        // a window the compressor can not keep to
+        assert!(offer("permessage-deflate; server_max_window_bits=10").is_none());
+        assert!(
+            offer("permessage-deflate; server_max_window_bits=10, permessage-deflate").is_some()
+        );
+        assert!(offer("permessage-deflate; unknown_param").is_none());
+        assert!(offer(
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover"
+        )
+        .is_none());
+        assert!(offer("x-webkit-deflate-frame").is_none());
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = br#"["EVENT",{"content":"hello hello hello hello hello"}]"#;
+        let compressed = deflate(message).unwrap();
+        assert!(compressed.len() < message.len());
+        assert_eq!(inflate(&compressed, 1000).unwrap(), message);
+        // a small message inflating to something huge
+        let bomb = deflate(&vec![b'a'; 1 << 20]).unwrap();
+        assert!(bomb.len() < 2000);
+        assert!(inflate(&bomb, 1000).is_err());
+        assert_eq!(inflate(&bomb, 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    /// Read the next frame the server sent.
+    async fn read_frame<R: AsyncRead + Unpin>(client: &mut R) -> (Header, Vec<u8>) {
+        let mut buf = vec![];
+        loop {
+            if let Some(header) = Header::parse(&buf) {
+                if buf.len() >= header.len + header.payload_len {
+                    return (header, buf[header.len..].to_vec());
+                }
+            }
+            let mut byte = [0];
+            client.read_exact(&mut byte).await.unwrap();
+            buf.push(byte[0]);
+        }
+    }
+
+    /// A frame as a client sends it, masked.
+    fn client_frame(opcode: u8, rsv1: bool, fin: bool, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mask = [1, 2, 3, 4];
+        let first = if fin { 0x80 } else { 0 } | if rsv1 { 0x40 } else { 0 } | opcode;
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn compressed_connection() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let limits = Limits {
+            max_message_bytes: Some(1000),
+            max_frame_bytes: Some(1000),
+        };
+        let stream = DeflateStream::new(server, Some(limits));
+        let saved = stream.bytes_saved();
+        let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+        // a compressed message split across two frames, with a ping
+        // between them
+        let text = r#"["REQ","sub",{"kinds":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]}]"#;
+        let compressed = deflate(text.as_bytes()).unwrap();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        client
+            .write_all(&client_frame(OP_TEXT, true, false, first))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(0x9, false, true, b"ping"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(OP_CONTINUATION, false, true, second))
+            .await
+            .unwrap();
+        // an uncompressed message
+        client
+            .write_all(&client_frame(OP_TEXT, false, true, b"plain"))
+            .await
+            .unwrap();
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Ping(b"ping".to_vec())
+        );
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Text(text.into())
+        );
+        assert_eq!(
+            ws.next().await.unwrap().unwrap(),
+            Message::Text("plain".into())
+        );
+        assert!(saved.load(Ordering::Relaxed) > 0);
+        // the ping was answered
+        let (header, payload) = read_frame(&mut client).await;
+        assert_eq!((header.opcode, payload.as_slice()), (0xa, &b"ping"[..]));
+
+        // large messages are sent compressed, small ones as they are
+        let reply = format!("[\"EVENT\",\"sub\",{}]", "{\"kind\":1}".repeat(50));
+        ws.send(Message::Text(reply.clone())).await.unwrap();
+        let (header, payload) = read_frame(&mut client).await;
+        assert!(header.rsv1 && header.fin && header.mask.is_none());
+        assert_eq!(inflate(&payload, 10_000).unwrap(), reply.as_bytes());
+        ws.send(Message::Text("[]".into())).await.unwrap();
+        let (header, payload) = read_frame(&mut client).await;
+        assert!(!header.rsv1);
+        assert_eq!(payload, b"[]");
+
+        // too large once inflated
+        let bomb = deflate(&[b' '; 5000]).unwrap();
+        client
+            .write_all(&client_frame(OP_TEXT, true, true, &bomb))
+            .await
+            .unwrap();
+        assert!(ws.next().await.unwrap().is_err());
+    }
+}
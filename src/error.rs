@@ -1,4 +1,5 @@
 //! Error handling
+use std::net::SocketAddr;
 use std::result;
 use thiserror::Error;
 use tungstenite::error::Error as WsError;
@@ -11,8 +12,14 @@ pub type Result<T, E = Error> = result::Result<T, E>;
 pub enum Error {
     #[error("Protocol parse error")]
     ProtoParseError,
+    #[error("binary messages are not supported")]
+    BinaryMessage,
     #[error("Connection error")]
     ConnError,
+    #[error("websocket message too large, size: {0}")]
+    MessageTooBig(usize),
+    #[error("client closed the connection with {0}")]
+    ClientClosed(crate::protostream::ClientClose),
     #[error("Client write error")]
     ConnWriteError,
     #[error("EVENT parse failed")]
@@ -23,10 +30,20 @@ pub enum Error {
     EventInvalid(String),
     #[error("Event too large, Size : {0}")]
     EventMaxLengthError(usize),
-    #[error("Subscription identifier max length exceeded")]
-    SubIdMaxLengthError,
+    #[error("authentication failed: {0}")]
+    AuthFailed(String),
+    #[error("invalid subscription id")]
+    SubIdInvalid(String),
     #[error("Maximum concurrent subscription count reached")]
     SubMaxExceededError,
+    #[error("relay subscription capacity reached")]
+    SubRelayCapacityError,
+    #[error("too many subscriptions, slow down")]
+    SubRateLimitedError,
+    #[error("subscription has no filters")]
+    SubNoFiltersError,
+    #[error("too many filters (max {0})")]
+    SubMaxFiltersError(usize),
     #[error("JSON parsing failed, Reason : {0}")]
     JsonParseFailed(#[from] serde_json::Error),
     #[error("WebSocket error : Reason : {0}")]
@@ -37,8 +54,35 @@ pub enum Error {
     SqlError(#[from] rusqlite::Error),
     #[error("Config error, Reason : {0}")]
     ConfigError(#[from] config::ConfigError),
-    #[error("Data directory does not exist")]
+    #[error("Data directory is not usable")]
     DatabaseDirError,
+    #[error("Database migration from version {from} to {to} failed, Reason : {source}")]
+    MigrationFailed {
+        from: usize,
+        to: usize,
+        source: rusqlite::Error,
+    },
+    #[error("Database schema version {found} is newer than this executable supports ({supported}), upgrade nostrd to use this database")]
+    FutureSchemaVersion { found: usize, supported: usize },
+    #[error("Event was deleted by the relay operator")]
+    EventDeleted,
+    #[error("Database could not be read with the configured encryption key")]
+    EncryptionKeyInvalid,
+    #[error("Database engine error, Reason : {0}")]
+    DatabaseEngineError(String),
+    #[cfg(feature = "postgres")]
+    #[error("Postgres error, Reason : {0}")]
+    PostgresError(#[from] tokio_postgres::Error),
+    #[error("invalid PROXY protocol header: {0}")]
+    ProxyHeaderInvalid(String),
+    #[error("could not listen on {addr}: {reason}")]
+    ListenError { addr: SocketAddr, reason: String },
+    #[error("TLS configuration error, Reason : {0}")]
+    TlsConfigError(String),
+    #[error("{0}\n\nRun nostrd --help for usage")]
+    UsageError(String),
+    #[error("shutdown abandoned work: {0}")]
+    ShutdownAbandoned(crate::shutdown::ShutdownReport),
     #[error("Generic Error, Reason: {0}")]
     GenericError(String),
 }
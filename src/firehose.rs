@@ -0,0 +1,254 @@
+//! A websocket stream of every event the relay accepts, served under
+//! `/firehose` for indexers
+use crate::metrics::METRICS;
+use crate::protocol::BroadcastEvent;
+use crate::protostream::{close_message, response_message, NostrResponse, CLOSE_POLICY_VIOLATION};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+/// Subscription id every firehose event is sent under
+pub const FIREHOSE_SUBSCRIPTION_ID: &str = "firehose";
+
+/// Firehose consumers allowed at once, unless configured
+pub const DEFAULT_MAX_FIREHOSE_CONSUMERS: usize = 16;
+
+/// Firehose connections open across the relay.
+#[derive(Debug, Default)]
+pub struct FirehoseConsumers {
+    open: Arc<AtomicUsize>,
+}
+
+impl FirehoseConsumers {
+    /// Admit a consumer if fewer than `max` are open (any number if
+    /// zero), returning the slot it holds until it disconnects.
+    pub fn admit(&self, max: usize) -> Option<ConsumerSlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (max == 0 || n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConsumerSlot {
+            open: self.open.clone(),
+        })
+    }
+
+    /// Number of consumers connected.
+    pub fn len(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Check whether no consumers are connected.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A firehose consumer's place, given back when dropped.
+#[derive(Debug)]
+pub struct ConsumerSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConsumerSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Why a firehose connection ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FirehoseExit {
+    /// The consumer closed the connection, or it failed
+    Disconnected,
+    /// The consumer fell behind the broadcast channel, missing
+    /// `skipped` events
+    Lagged { skipped: u64 },
+    /// A write did not complete within the send timeout
+    SlowConsumer,
+    /// The relay is stopping
+    Shutdown,
+}
+
+/// Send every event broadcast on `events` to a consumer as an `EVENT`
+/// message under [`FIREHOSE_SUBSCRIPTION_ID`], until it disconnects or
+/// `shutdown` fires.
+///
+/// Nothing the consumer sends is answered; its messages are read only
+/// to notice it leaving.  A consumer that falls behind is closed with
+/// a policy violation rather than buffered for, and so is one that
+/// takes longer than `send_timeout` to accept a message.
+pub async fn stream_events<Tx, Rx>(
+    mut sink: Tx,
+    mut incoming: Rx,
+    mut events: broadcast::Receiver<BroadcastEvent>,
+    send_timeout: Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> FirehoseExit
+where
+    Tx: Sink<Message> + Unpin,
+    Rx: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    loop {
+        let (message, exit) = tokio::select! {
+            received = events.recv() => match received {
+                Ok(event) => {
+                    let response = NostrResponse::new_serialized_event(FIREHOSE_SUBSCRIPTION_ID, &event);
+                    match response_message(&response) {
+                        Ok(message) => (message, None),
+                        Err(e) => {
+                            warn!("could not serialize firehose event: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => (
+                    close_message(CLOSE_POLICY_VIOLATION, "fell behind the firehose"),
+                    Some(FirehoseExit::Lagged { skipped }),
+                ),
+                Err(RecvError::Closed) => (
+                    close_message(CloseCode::Away.into(), "relay shutting down"),
+                    Some(FirehoseExit::Shutdown),
+                ),
+            },
+            received = incoming.next() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return FirehoseExit::Disconnected,
+                // REQ, EVENT and the rest are ignored
+                Some(Ok(_)) => continue,
+            },
+            _ = shutdown.recv() => (
+                close_message(CloseCode::Away.into(), "relay shutting down"),
+                Some(FirehoseExit::Shutdown),
+            ),
+        };
+        let close_code = match &message {
+            Message::Close(Some(frame)) => Some(u16::from(frame.code)),
+            _ => None,
+        };
+        match tokio::time::timeout(send_timeout, sink.send(message)).await {
+            Ok(Ok(())) => {
+                if let Some(code) = close_code {
+                    METRICS.close_sent(code);
+                }
+            }
+            Ok(Err(_)) => return exit.unwrap_or(FirehoseExit::Disconnected),
+            Err(_) => {
+                METRICS.write_timed_out();
+                return exit.unwrap_or(FirehoseExit::SlowConsumer);
+            }
+        }
+        if let Some(exit) = exit {
+            return exit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::valid_event;
+
+    fn event() -> BroadcastEvent {
+        BroadcastEvent::new(valid_event())
+    }
+
+    #[test]
+    fn consumers_limited() {
+        let consumers = FirehoseConsumers::default();
+        let first = consumers.admit(2).unwrap();
+        let _second = consumers.admit(2).unwrap();
+        assert!(consumers.admit(2).is_none());
+        assert_eq!(consumers.len(), 2);
+        drop(first);
+        assert!(consumers.admit(2).is_some());
+        assert_eq!(consumers.len(), 1);
+        assert!(consumers.admit(0).is_some());
+    }
+
+    #[tokio::test]
+    async fn events_streamed_until_shutdown() {
+        let (bcast_tx, bcast_rx) = broadcast::channel(8);
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (tx, mut rx) = futures::channel::mpsc::unbounded();
+        // a consumer that sends a REQ, which is not answered
+        let incoming =
+            futures::stream::iter(vec![Ok(Message::Text(r#"["REQ","x",{}]"#.to_owned()))])
+                .chain(futures::stream::pending());
+        let streaming = tokio::spawn(stream_events(
+            tx,
+            incoming,
+            bcast_rx,
+            Duration::from_secs(1),
+            shutdown_rx,
+        ));
+        let event = event();
+        bcast_tx.send(event.clone()).unwrap();
+        bcast_tx.send(event.clone()).unwrap();
+        for _ in 0..2 {
+            match rx.next().await.unwrap() {
+                Message::Text(text) => {
+                    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    assert_eq!(value[0], "EVENT");
+                    assert_eq!(value[1], FIREHOSE_SUBSCRIPTION_ID);
+                    let json: serde_json::Value = serde_json::from_str(event.json()).unwrap();
+                    assert_eq!(value[2], json);
+                }
+                other => panic!("expected an event, got {:?}", other),
+            }
+        }
+        shutdown_tx.send(()).unwrap();
+        assert_eq!(streaming.await.unwrap(), FirehoseExit::Shutdown);
+        match rx.next().await.unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_consumer_closed() {
+        let (bcast_tx, bcast_rx) = broadcast::channel(2);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        // more events than the channel holds, before the consumer reads
+        for _ in 0..5 {
+            bcast_tx.send(event()).unwrap();
+        }
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let exit = stream_events(
+            tx,
+            futures::stream::pending(),
+            bcast_rx,
+            Duration::from_secs(1),
+            shutdown_rx,
+        )
+        .await;
+        assert_eq!(exit, FirehoseExit::Lagged { skipped: 3 });
+        let written: Vec<Message> = rx.collect().await;
+        match &written[..] {
+            [Message::Close(Some(frame))] => {
+                assert_eq!(u16::from(frame.code), CLOSE_POLICY_VIOLATION);
+            }
+            other => panic!("expected only a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn consumer_leaving_noticed() {
+        let (_bcast_tx, bcast_rx) = broadcast::channel(2);
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (tx, _rx) = futures::channel::mpsc::unbounded();
+        let exit = stream_events(
+            tx,
+            futures::stream::iter(vec![Ok(Message::Close(None))]),
+            bcast_rx,
+            Duration::from_secs(1),
+            shutdown_rx,
+        )
+        .await;
+        assert_eq!(exit, FirehoseExit::Disconnected);
+    }
+}
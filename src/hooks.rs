@@ -0,0 +1,484 @@
+//! Notifications of clients connecting, authenticating and
+//! disconnecting, for
+//! embedders and side-car tooling
+use crate::config;
+use crate::conn::{ClientHeaders, ConnStats};
+use crate::db::retention;
+use crate::error::{Error, Result};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use lazy_static::lazy_static;
+use log::*;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Wait before the first retry of a notification, doubled for each
+/// one after
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Hooks an embedder registered, called alongside any webhook
+    static ref REGISTERED: RwLock<Vec<Arc<dyn ConnectionHooks>>> = RwLock::new(vec![]);
+}
+
+/// A client connection, as reported to hooks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    /// Identifier of the connection, as used in the log
+    pub client_id: String,
+    /// Address of the client, after any trusted proxy
+    pub address: Option<IpAddr>,
+    #[serde(flatten)]
+    pub headers: ClientHeaders,
+}
+
+/// Callbacks for clients connecting, authenticating and
+/// disconnecting.
+///
+/// They are called from the connection's own task, so must return at
+/// once; anything slow belongs in a queue drained elsewhere, as
+/// [`WebhookHooks`] does.  Every method does nothing unless
+/// implemented.
+pub trait ConnectionHooks: Send + Sync {
+    /// A client completed the websocket handshake.
+    fn on_connect(&self, _conn: &ConnectionInfo) {}
+
+    /// A client proved it holds `pubkey`, with NIP-42 `AUTH`.
+    fn on_auth(&self, _conn: &ConnectionInfo, _pubkey: &str) {}
+
+    /// A client's connection ended, with its final statistics.
+    fn on_disconnect(&self, _conn: &ConnectionInfo, _stats: &ConnStats) {}
+}
+
+/// Hooks that do nothing, used unless a webhook is configured.
+pub struct NoHooks;
+
+impl ConnectionHooks for NoHooks {}
+
+/// Several hooks, each called in turn.
+pub struct HookList(pub Vec<Arc<dyn ConnectionHooks>>);
+
+impl ConnectionHooks for HookList {
+    fn on_connect(&self, conn: &ConnectionInfo) {
+        for hooks in &self.0 {
+            hooks.on_connect(conn);
+        }
+    }
+
+    fn on_auth(&self, conn: &ConnectionInfo, pubkey: &str) {
+        for hooks in &self.0 {
+            hooks.on_auth(conn, pubkey);
+        }
+    }
+
+    fn on_disconnect(&self, conn: &ConnectionInfo, stats: &ConnStats) {
+        for hooks in &self.0 {
+            hooks.on_disconnect(conn, stats);
+        }
+    }
+}
+
+/// Call `hooks` for every client of a relay started in this process,
+/// alongside any configured webhook.  Hooks registered once the relay
+/// is running are not called.
+pub fn register(hooks: Arc<dyn ConnectionHooks>) {
+    REGISTERED.write().unwrap().push(hooks);
+}
+
+/// The hooks `hooks` configures, a webhook if `webhook_url` is set,
+/// together with any that were [`register`]ed.  Must be called from
+/// within the runtime, which delivers webhook notifications.
+pub fn from_config(hooks: &config::Hooks) -> Result<Arc<dyn ConnectionHooks>> {
+    let configured = webhook_from_config(hooks)?;
+    let registered = REGISTERED.read().unwrap().clone();
+    if registered.is_empty() {
+        return Ok(configured);
+    }
+    let mut all = vec![configured];
+    all.extend(registered);
+    Ok(Arc::new(HookList(all)))
+}
+
+/// A webhook if `webhook_url` is set, otherwise no hooks.
+fn webhook_from_config(hooks: &config::Hooks) -> Result<Arc<dyn ConnectionHooks>> {
+    match &hooks.webhook_url {
+        Some(url) => {
+            let url: Uri = url.parse().map_err(|e| {
+                Error::GenericError(format!("invalid hooks.webhook_url {:?}: {}", url, e))
+            })?;
+            if !matches!(url.scheme_str(), Some("http" | "https")) {
+                return Err(Error::GenericError(format!(
+                    "hooks.webhook_url {:?} must be an http or https URL",
+                    url.to_string()
+                )));
+            }
+            Ok(Arc::new(WebhookHooks::start(hooks, url)))
+        }
+        None => Ok(Arc::new(NoHooks)),
+    }
+}
+
+/// A notification, as posted to a webhook.
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    /// "connect", "auth" or "disconnect"
+    event: &'static str,
+    /// Unix time the notification was made
+    time: u64,
+    #[serde(flatten)]
+    conn: &'a ConnectionInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a ConnStats>,
+}
+
+/// Hooks that post each notification as JSON to a URL.
+///
+/// Notifications are queued and delivered in order by a separate
+/// task, retrying those the endpoint fails to accept, so a slow
+/// endpoint never holds up a connection.  When the queue is full,
+/// further notifications are dropped.
+pub struct WebhookHooks {
+    queue: mpsc::Sender<String>,
+    dropped: AtomicU64,
+}
+
+impl WebhookHooks {
+    /// Start delivering notifications to `url`.
+    pub fn start(settings: &config::Hooks, url: Uri) -> Self {
+        let (queue, pending) = mpsc::channel(settings.webhook_queue_size.max(1));
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let delivery = Delivery {
+            client: Client::builder().build(https),
+            url,
+            retries: settings.webhook_retries,
+            timeout: Duration::from_secs(settings.webhook_timeout_secs),
+        };
+        tokio::spawn(delivery.run(pending));
+        WebhookHooks {
+            queue,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Notifications dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn notify(&self, notification: Notification<'_>) {
+        let body = match serde_json::to_string(&notification) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("could not serialize webhook notification: {}", e);
+                return;
+            }
+        };
+        if self.queue.try_send(body).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // the first few, then ever more rarely
+            if dropped.is_power_of_two() {
+                warn!(
+                    "dropped {} webhook notification(s), the endpoint is not keeping up",
+                    dropped
+                );
+            }
+        }
+    }
+}
+
+impl ConnectionHooks for WebhookHooks {
+    fn on_connect(&self, conn: &ConnectionInfo) {
+        self.notify(Notification {
+            event: "connect",
+            time: retention::now(),
+            conn,
+            pubkey: None,
+            stats: None,
+        });
+    }
+
+    fn on_auth(&self, conn: &ConnectionInfo, pubkey: &str) {
+        self.notify(Notification {
+            event: "auth",
+            time: retention::now(),
+            conn,
+            pubkey: Some(pubkey),
+            stats: None,
+        });
+    }
+
+    fn on_disconnect(&self, conn: &ConnectionInfo, stats: &ConnStats) {
+        self.notify(Notification {
+            event: "disconnect",
+            time: retention::now(),
+            conn,
+            pubkey: None,
+            stats: Some(stats),
+        });
+    }
+}
+
+/// Posts queued notifications to a webhook.
+struct Delivery {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    url: Uri,
+    retries: u32,
+    timeout: Duration,
+}
+
+impl Delivery {
+    /// Deliver notifications until every [`WebhookHooks`] sending them
+    /// is dropped.
+    async fn run(self, mut pending: mpsc::Receiver<String>) {
+        while let Some(body) = pending.recv().await {
+            let mut attempt = 0;
+            loop {
+                match self.post(body.clone()).await {
+                    Ok(()) => break,
+                    Err(e) if attempt < self.retries => {
+                        debug!("webhook notification failed, retrying: {}", e);
+                        tokio::time::sleep(RETRY_DELAY * 2u32.saturating_pow(attempt)).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "gave up on webhook notification after {} attempt(s): {}",
+                            attempt + 1,
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn post(&self, body: String) -> Result<()> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|e| Error::GenericError(e.to_string()))?;
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| Error::GenericError("request timed out".to_owned()))?
+            .map_err(|e| Error::GenericError(e.to_string()))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(Error::GenericError(format!(
+                "unexpected status {}",
+                response.status()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    fn info(client_id: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            client_id: client_id.to_owned(),
+            address: Some("192.0.2.1".parse().unwrap()),
+            headers: ClientHeaders {
+                user_agent: Some("test".to_owned()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn settings(retries: u32) -> config::Hooks {
+        config::Hooks {
+            webhook_url: None,
+            webhook_retries: retries,
+            webhook_timeout_secs: 5,
+            webhook_queue_size: 16,
+        }
+    }
+
+    /// Serve a webhook on localhost that records the bodies posted to
+    /// it, failing the first `failures` requests.
+    async fn endpoint(failures: usize) -> (Uri, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let requests = Arc::new(AtomicU64::new(0));
+        let make_service = {
+            let received = received.clone();
+            make_service_fn(move |_| {
+                let (received, requests) = (received.clone(), requests.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let (received, requests) = (received.clone(), requests.clone());
+                        async move {
+                            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                            let mut response = Response::new(Body::empty());
+                            if requests.fetch_add(1, Ordering::Relaxed) < failures as u64 {
+                                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                            } else {
+                                received
+                                    .lock()
+                                    .unwrap()
+                                    .push(serde_json::from_slice(&body).unwrap());
+                            }
+                            Ok::<_, Infallible>(response)
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/hook", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    /// Wait for `count` notifications to arrive.
+    async fn wait_for(received: &Mutex<Vec<serde_json::Value>>, count: usize) {
+        for _ in 0..100 {
+            if received.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("notifications did not arrive");
+    }
+
+    /// Hooks that record what they were called with.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ConnectionHooks for Recorder {
+        fn on_connect(&self, conn: &ConnectionInfo) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("connect {}", conn.client_id));
+        }
+
+        fn on_auth(&self, conn: &ConnectionInfo, pubkey: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("auth {} as {}", conn.client_id, pubkey));
+        }
+
+        fn on_disconnect(&self, conn: &ConnectionInfo, stats: &ConnStats) {
+            self.0.lock().unwrap().push(format!(
+                "disconnect {} after {} event(s)",
+                conn.client_id, stats.events_published
+            ));
+        }
+    }
+
+    #[test]
+    fn unimplemented_hooks_ignored() {
+        let recorder = Recorder::default();
+        let hooks: &dyn ConnectionHooks = &recorder;
+        hooks.on_connect(&info("a"));
+        let stats = ConnStats {
+            events_published: 2,
+            ..Default::default()
+        };
+        hooks.on_disconnect(&info("a"), &stats);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["connect a", "disconnect a after 2 event(s)"]
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_in_order() {
+        let (url, received) = endpoint(0).await;
+        let hooks = WebhookHooks::start(&settings(0), url);
+        hooks.on_connect(&info("a"));
+        hooks.on_auth(&info("a"), "8e0d3d3e");
+        let stats = ConnStats {
+            events_published: 3,
+            ..Default::default()
+        };
+        hooks.on_disconnect(&info("a"), &stats);
+        wait_for(&received, 3).await;
+        let received = received.lock().unwrap();
+        let events: Vec<_> = received.iter().map(|n| n["event"].clone()).collect();
+        assert_eq!(events, ["connect", "auth", "disconnect"]);
+        assert_eq!(received[0]["client_id"], "a");
+        assert_eq!(received[0]["address"], "192.0.2.1");
+        assert_eq!(received[0]["user_agent"], "test");
+        assert!(received[0].get("stats").is_none());
+        assert!(received[0].get("pubkey").is_none());
+        assert_eq!(received[1]["pubkey"], "8e0d3d3e");
+        assert_eq!(received[2]["stats"]["events_published"], 3);
+    }
+
+    #[tokio::test]
+    async fn webhook_retried() {
+        let (url, received) = endpoint(2).await;
+        let hooks = WebhookHooks::start(&settings(2), url);
+        hooks.on_connect(&info("a"));
+        hooks.on_connect(&info("b"));
+        wait_for(&received, 2).await;
+        let received = received.lock().unwrap();
+        assert_eq!(received[0]["client_id"], "a");
+        assert_eq!(received[1]["client_id"], "b");
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops() {
+        // an endpoint that never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let hooks = WebhookHooks::start(&settings(0), url);
+        for n in 0..40 {
+            hooks.on_connect(&info(&n.to_string()));
+        }
+        assert!(hooks.dropped() > 0);
+    }
+
+    #[test]
+    fn registered_hooks_called() {
+        let recorder = Arc::new(Recorder::default());
+        register(recorder.clone());
+        let hooks = from_config(&settings(0)).unwrap();
+        hooks.on_connect(&info("a"));
+        hooks.on_auth(&info("a"), "8e0d3d3e");
+        hooks.on_disconnect(&info("a"), &ConnStats::default());
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "connect a",
+                "auth a as 8e0d3d3e",
+                "disconnect a after 0 event(s)"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_url_refused() {
+        let mut hooks = settings(0);
+        hooks.webhook_url = Some("ftp://example.com/".to_owned());
+        assert!(from_config(&hooks).is_err());
+        hooks.webhook_url = Some("not a url".to_owned());
+        assert!(from_config(&hooks).is_err());
+    }
+}
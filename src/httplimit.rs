@@ -0,0 +1,152 @@
+//! Limits on plain HTTP requests, checked before anything reads them
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, CONTENT_LENGTH};
+use hyper::{Body, Request, Response, StatusCode};
+
+/// Largest request body read, unless configured
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Most headers allowed in a request, unless configured
+pub const DEFAULT_MAX_HEADERS: usize = 64;
+
+/// Largest total size of a request's header names and values, unless
+/// configured
+pub const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// How much of a request the relay is willing to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub max_body_bytes: usize,
+    pub max_headers: usize,
+    pub max_header_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+        }
+    }
+}
+
+fn refuse(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Refuse a request with too many headers, or too large ones.
+pub fn check_headers(headers: &HeaderMap, limits: &RequestLimits) -> Result<(), Response<Body>> {
+    let bytes: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if headers.len() > limits.max_headers || bytes > limits.max_header_bytes {
+        return Err(refuse(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "Request headers too large.",
+        ));
+    }
+    Ok(())
+}
+
+/// Read a request's body into memory, refusing it with 413 as soon as
+/// it is known to be larger than `max_bytes`.
+///
+/// A declared `Content-Length` over the limit is refused without
+/// reading anything, and a body without one is read a chunk at a
+/// time, so a client cannot make the relay buffer more than the limit.
+pub async fn read_body(
+    request: Request<Body>,
+    max_bytes: usize,
+) -> Result<Request<Body>, Response<Body>> {
+    let too_large = || refuse(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large.");
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if declared.map_or(false, |len| len > max_bytes as u64) {
+        return Err(too_large());
+    }
+    let (parts, mut body) = request.into_parts();
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk =
+            chunk.map_err(|_| refuse(StatusCode::BAD_REQUEST, "Could not read request body."))?;
+        if read.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(Request::from_parts(parts, Body::from(read)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            max_body_bytes: 8,
+            max_headers: 2,
+            max_header_bytes: 32,
+        }
+    }
+
+    #[test]
+    fn headers_limited() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "localhost".parse().unwrap());
+        assert!(check_headers(&headers, &limits()).is_ok());
+        headers.insert("accept", "*/*".parse().unwrap());
+        headers.insert("user-agent", "x".parse().unwrap());
+        let refused = check_headers(&headers, &limits()).unwrap_err();
+        assert_eq!(
+            refused.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "x".repeat(64).parse().unwrap());
+        assert!(check_headers(&headers, &limits()).is_err());
+    }
+
+    #[tokio::test]
+    async fn small_body_read() {
+        let request = Request::new(Body::from("12345678"));
+        let request = read_body(request, 8).await.unwrap();
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        assert_eq!(body, "12345678");
+    }
+
+    #[tokio::test]
+    async fn declared_length_refused_unread() {
+        // a body that never ends, which must not be waited for
+        let (_sender, body) = Body::channel();
+        let request = Request::builder()
+            .header(CONTENT_LENGTH, "1000000000")
+            .body(body)
+            .unwrap();
+        let refused = read_body(request, 8).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn streamed_body_refused_at_limit() {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in ["12345", "67890", "never read"] {
+                if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let request = Request::new(body);
+        let refused = read_body(request, 8).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,9 +1,33 @@
 use crate::config;
+use crate::db::{KindRange, RetentionPolicy, RetentionRule};
+use crate::error::{Error, Result};
+use crate::version;
+use bitcoin_hashes::{hex::ToHex, sha256, Hash};
+use hyper::body::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, ORIGIN, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+use lazy_static::lazy_static;
+use log::*;
 use secp256k1::XOnlyPublicKey;
 /// Relay Info
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
-const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+/// Largest landing page served, in bytes
+const MAX_LANDING_PAGE_BYTES: u64 = 1024 * 1024;
+
+/// Shown to browsers if no landing page is configured
+const DEFAULT_LANDING_TEXT: &str = "Please use a Nostr client to connect.";
+
+lazy_static! {
+    static ref INFO_CACHE: InfoCache = InfoCache::default();
+    /// Page shown to browsers, read at startup and on reload
+    static ref LANDING_PAGE: RwLock<Option<Arc<LandingPage>>> = RwLock::new(None);
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[allow(unused)]
@@ -24,6 +48,379 @@ pub struct RelayInfo {
     pub software: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Vec<RetentionInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limitation: Option<Limitation>,
+}
+
+/// Restrictions on what clients may do, as advertised in relay info.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_filters: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscriptions_per_minute: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_burst: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_ttl_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+}
+
+/// A retention limit, as advertised in relay info.  A `time` of
+/// `null` means events are kept indefinitely.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kinds: Option<Vec<KindRange>>,
+    pub time: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+}
+
+impl From<&RetentionRule> for RetentionInfo {
+    fn from(rule: &RetentionRule) -> Self {
+        RetentionInfo {
+            kinds: if rule.kinds.is_empty() {
+                None
+            } else {
+                Some(rule.kinds.clone())
+            },
+            time: rule.max_age_seconds,
+            count: rule.max_events,
+        }
+    }
+}
+
+/// Describe a retention policy in relay info form, with the most
+/// specific rules first and the default last.
+fn retention_info(policy: &RetentionPolicy) -> Option<Vec<RetentionInfo>> {
+    if !policy.is_enabled() {
+        return None;
+    }
+    let info = policy
+        .rules
+        .iter()
+        .chain(std::iter::once(&policy.default))
+        .map(RetentionInfo::from)
+        .collect();
+    Some(info)
+}
+
+impl RelayInfo {
+    /// Build relay info from the full configuration.
+    pub fn from_settings(settings: &config::Settings) -> Self {
+        let mut info = RelayInfo::from(settings.info.clone());
+        info.retention = RetentionPolicy::from_config(&settings.retention)
+            .ok()
+            .and_then(|policy| retention_info(&policy));
+        let max_subs = settings.limits.max_subscriptions_per_connection;
+        let max_filters = settings.limits.max_filters;
+        let max_subid_length = settings.limits.max_subid_length;
+        let subs_per_min = settings.limits.subscriptions_per_min;
+        let sub_ttl = settings.limits.subscription_ttl_secs;
+        // a burst of zero is the same as the rate
+        let sub_burst = match settings.limits.subscription_burst {
+            0 => subs_per_min,
+            burst => burst,
+        };
+        let limitation = Limitation {
+            max_subscriptions: (max_subs > 0).then_some(max_subs),
+            max_filters: (max_filters > 0).then_some(max_filters),
+            max_subid_length: (max_subid_length > 0).then_some(max_subid_length),
+            subscriptions_per_minute: (subs_per_min > 0).then_some(subs_per_min),
+            subscription_burst: (subs_per_min > 0).then_some(sub_burst),
+            subscription_ttl_secs: (sub_ttl > 0).then_some(sub_ttl),
+            restricted_writes: (config::is_read_only()
+                || settings.authorization.is_restricted()
+                || settings.authorization.publish_own_only)
+                .then_some(true),
+        };
+        if limitation != Limitation::default() {
+            info.limitation = Some(limitation);
+        }
+        info
+    }
+}
+
+/// The `Access-Control-Allow-Origin` value for a browser request from
+/// `origin`, if relay info may be shared with it.  Any origin is
+/// allowed unless `info.cors_origins` is set.
+fn cors_origin(settings: &config::Settings, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+    let allowed = match &settings.info.cors_origins {
+        Some(allowed) => allowed,
+        None => return Some(HeaderValue::from_static("*")),
+    };
+    if allowed.iter().any(|a| a == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let origin = origin?;
+    allowed
+        .iter()
+        .any(|a| a.as_bytes() == origin.as_bytes())
+        .then(|| origin.clone())
+}
+
+/// Allow `origin` to read `response`, if relay info may be shared
+/// with it.
+fn add_cors_headers(
+    response: &mut Response<Body>,
+    settings: &config::Settings,
+    origin: Option<&HeaderValue>,
+) {
+    if let Some(allowed) = cors_origin(settings, origin) {
+        let headers = response.headers_mut();
+        // the answer depends on the origin unless every one is allowed
+        if allowed != "*" {
+            headers.insert(VARY, HeaderValue::from_static("origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+    }
+}
+
+/// Relay info, serialized once.
+struct CachedInfo {
+    /// Settings generation the document was built from
+    generation: u64,
+    body: Bytes,
+    /// Strong entity tag, from a hash of the body
+    etag: HeaderValue,
+}
+
+impl CachedInfo {
+    fn new(settings: &config::Settings, generation: u64, relay_url: Option<&str>) -> Result<Self> {
+        let mut info = RelayInfo::from_settings(settings);
+        if let Some(relay_url) = relay_url {
+            info.id = Some(relay_url.to_owned());
+        }
+        let body = serde_json::to_string_pretty(&info)?;
+        let hash = sha256::Hash::hash(body.as_bytes()).to_hex();
+        let etag = HeaderValue::from_str(&format!("\"{}\"", &hash[..32])).unwrap();
+        Ok(CachedInfo {
+            generation,
+            body: Bytes::from(body),
+            etag,
+        })
+    }
+
+    /// Whether the client's `If-None-Match` header names this
+    /// version of the document.
+    fn matches(&self, if_none_match: Option<&HeaderValue>) -> bool {
+        let etag = self.etag.as_bytes();
+        if_none_match
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .any(|tag| tag == "*" || tag.trim_start_matches("W/").as_bytes() == etag)
+            })
+    }
+}
+
+/// The serialized relay information document, serialized again only
+/// when settings change.  Listeners with their own relay URL each
+/// have a document of their own.
+#[derive(Default)]
+pub struct InfoCache {
+    cached: RwLock<HashMap<Option<String>, Arc<CachedInfo>>>,
+}
+
+impl InfoCache {
+    fn get(&self, settings: &config::Settings, relay_url: Option<&str>) -> Result<Arc<CachedInfo>> {
+        let generation = config::generation();
+        let key = relay_url.map(str::to_owned);
+        if let Some(cached) = self.cached.read().unwrap().get(&key) {
+            if cached.generation == generation {
+                return Ok(cached.clone());
+            }
+        }
+        let cached = Arc::new(CachedInfo::new(settings, generation, relay_url)?);
+        self.cached.write().unwrap().insert(key, cached.clone());
+        Ok(cached)
+    }
+
+    /// The relay information document, or 304 Not Modified if the
+    /// client already has it.  Browsers on allowed origins may read
+    /// it.  `relay_url`, if given, replaces `info.relay_url`.
+    pub fn response(
+        &self,
+        settings: &config::Settings,
+        request: &HeaderMap,
+        relay_url: Option<&str>,
+    ) -> Response<Body> {
+        let cached = match self.get(settings, relay_url) {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("could not serialize relay info: {}", e);
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        };
+        let max_age = settings.info.cache_max_age_secs;
+        let cache_control = match max_age {
+            0 => "no-cache".to_owned(),
+            secs => format!("max-age={}", secs),
+        };
+        let builder = Response::builder()
+            .header(ETAG, cached.etag.clone())
+            .header(CACHE_CONTROL, cache_control);
+        let mut response = if cached.matches(request.get(IF_NONE_MATCH)) {
+            builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap()
+        } else {
+            builder
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/nostr+json")
+                .body(Body::from(cached.body.clone()))
+                .unwrap()
+        };
+        add_cors_headers(&mut response, settings, request.get(ORIGIN));
+        response
+    }
+}
+
+/// The relay information document, answering a request with the
+/// headers in `request` on a listener announcing `relay_url`, if it
+/// has its own.
+pub fn info_response(
+    settings: &config::Settings,
+    request: &HeaderMap,
+    relay_url: Option<&str>,
+) -> Response<Body> {
+    INFO_CACHE.response(settings, request, relay_url)
+}
+
+/// Answer a CORS preflight request, so browsers will send the
+/// `Accept` header a relay information request needs.
+pub fn preflight_response(
+    settings: &config::Settings,
+    origin: Option<&HeaderValue>,
+) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    if cors_origin(settings, origin).is_some() {
+        let headers = response.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_static("accept"),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, HEAD, OPTIONS"),
+        );
+    }
+    add_cors_headers(&mut response, settings, origin);
+    response
+}
+
+/// An HTML page for people who open the relay's address in a
+/// browser.  `{{name}}`, `{{description}}` and `{{connections}}` are
+/// replaced with the relay's name, description and open connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LandingPage {
+    html: String,
+    /// Whether there are placeholders to fill in
+    templated: bool,
+}
+
+impl LandingPage {
+    pub fn new(html: String) -> Self {
+        let templated = html.contains("{{");
+        LandingPage { html, templated }
+    }
+
+    /// Read a page from `path`, refusing one that is too large.
+    pub fn read(path: &str) -> Result<Self> {
+        let error = |e: &dyn std::fmt::Display| {
+            Error::GenericError(format!("landing page {}: {}", path, e))
+        };
+        let size = std::fs::metadata(path).map_err(|e| error(&e))?.len();
+        if size > MAX_LANDING_PAGE_BYTES {
+            return Err(error(&format!(
+                "{} bytes is larger than {} bytes",
+                size, MAX_LANDING_PAGE_BYTES
+            )));
+        }
+        let html = std::fs::read_to_string(path).map_err(|e| error(&e))?;
+        Ok(LandingPage::new(html))
+    }
+
+    /// The page, with placeholders filled in.
+    pub fn render(&self, info: &config::Info, connections: usize) -> String {
+        if !self.templated {
+            return self.html.clone();
+        }
+        self.html
+            .replace(
+                "{{name}}",
+                &escape_html(info.name.as_deref().unwrap_or_default()),
+            )
+            .replace(
+                "{{description}}",
+                &escape_html(info.description.as_deref().unwrap_or_default()),
+            )
+            .replace("{{connections}}", &connections.to_string())
+    }
+}
+
+/// Escape text for use in HTML content or attribute values.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Read the page at `info.landing_page_path`, if set, to be served
+/// until this is next called.  The page in use is kept if the new
+/// one can not be read.
+pub fn load_landing_page(settings: &config::Settings) -> Result<()> {
+    let page = match &settings.info.landing_page_path {
+        Some(path) => {
+            let page = LandingPage::read(path)?;
+            debug!("read landing page from {}", path);
+            Some(Arc::new(page))
+        }
+        None => None,
+    };
+    *LANDING_PAGE.write().unwrap() = page;
+    Ok(())
+}
+
+/// Answer a browser visiting the relay, with the landing page if one
+/// is configured, given the number of open `connections`.
+pub fn landing_response(settings: &config::Settings, connections: usize) -> Response<Body> {
+    let page = LANDING_PAGE.read().unwrap().clone();
+    match page {
+        Some(page) => Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(page.render(&settings.info, connections)))
+            .unwrap(),
+        None => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(DEFAULT_LANDING_TEXT))
+            .unwrap(),
+    }
 }
 
 /// Convert an Info configuration into public Relay Info
@@ -37,7 +434,119 @@ impl From<config::Info> for RelayInfo {
             contact: i.contact,
             supported_nips: Some(vec![1, 2, 11]),
             software: Some("https://github.com/rajarshimaitra/rust-nostr".to_owned()),
-            version: CARGO_PKG_VERSION.map(|x| x.to_owned()),
+            version: Some(version::full_version()),
+            retention: None,
+            limitation: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cors_headers_sent() {
+        let mut settings = config::Settings::default();
+        let origin = HeaderValue::from_static("https://client.example.com");
+        let mut request = HeaderMap::new();
+        request.insert(ORIGIN, origin.clone());
+        let get = InfoCache::default().response(&settings, &request, None);
+        assert_eq!(get.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        let options = preflight_response(&settings, Some(&origin));
+        assert_eq!(options.status(), StatusCode::NO_CONTENT);
+        assert_eq!(options.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(options.headers()[ACCESS_CONTROL_ALLOW_HEADERS], "accept");
+        assert!(options.headers().contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+        // a listed origin is named, and no other is allowed
+        settings.info.cors_origins = Some(vec!["https://client.example.com".to_owned()]);
+        let get = InfoCache::default().response(&settings, &request, None);
+        assert_eq!(get.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(get.headers()[VARY], "origin");
+        let mut other = HeaderMap::new();
+        other.insert(ORIGIN, HeaderValue::from_static("https://evil.example.com"));
+        let get = InfoCache::default().response(&settings, &other, None);
+        assert!(!get.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // an empty list turns CORS off
+        settings.info.cors_origins = Some(vec![]);
+        for response in [
+            InfoCache::default().response(&settings, &request, None),
+            preflight_response(&settings, Some(&origin)),
+        ] {
+            assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert!(!response
+                .headers()
+                .contains_key(ACCESS_CONTROL_ALLOW_HEADERS));
+        }
+    }
+
+    #[test]
+    fn whitelist_restricts_writes() {
+        let mut settings = config::Settings::default();
+        settings.authorization.pubkey_whitelist = Some(vec![]);
+        let limitation = RelayInfo::from_settings(&settings).limitation.unwrap();
+        assert_eq!(limitation.restricted_writes, Some(true));
+    }
+
+    #[test]
+    fn landing_page_filled_in() {
+        let mut info = config::Settings::default().info;
+        info.name = Some("<My Relay>".to_owned());
+        info.description = None;
+        let page = LandingPage::new(
+            "<h1>{{name}}</h1><p>{{description}}</p><p>{{connections}} online</p>".to_owned(),
+        );
+        assert_eq!(
+            page.render(&info, 42),
+            "<h1>&lt;My Relay&gt;</h1><p></p><p>42 online</p>"
+        );
+        let plain = LandingPage::new("<h1>Hello</h1>".to_owned());
+        assert_eq!(plain.render(&info, 0), "<h1>Hello</h1>");
+
+        let path = std::env::temp_dir().join(format!("nostrd-landing-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "<p>{{connections}}</p>").unwrap();
+        let read = LandingPage::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(read.render(&info, 1), "<p>1</p>");
+        // too large to serve
+        std::fs::write(&path, vec![b'x'; MAX_LANDING_PAGE_BYTES as usize + 1]).unwrap();
+        assert!(LandingPage::read(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).ok();
+        assert!(LandingPage::read(path.to_str().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unchanged_info_not_sent_again() {
+        let settings = config::Settings::default();
+        let cache = InfoCache::default();
+        let first = cache.response(&settings, &HeaderMap::new(), None);
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[CACHE_CONTROL], "max-age=3600");
+        let etag = first.headers()[ETAG].clone();
+        let mut request = HeaderMap::new();
+        request.insert(IF_NONE_MATCH, etag.clone());
+        let second = cache.response(&settings, &request, None);
+        assert_eq!(second.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(second.headers()[ETAG], etag);
+        let body = hyper::body::to_bytes(second.into_body()).await.unwrap();
+        assert!(body.is_empty());
+        // a stale tag gets the document
+        request.insert(IF_NONE_MATCH, HeaderValue::from_static("\"0\""));
+        assert_eq!(
+            cache.response(&settings, &request, None).status(),
+            StatusCode::OK
+        );
+
+        // a listener with its own relay URL has its own document
+        request.remove(IF_NONE_MATCH);
+        let url = "wss://relay.example.com/";
+        let other = cache.response(&settings, &request, Some(url));
+        assert_ne!(other.headers()[ETAG], etag);
+        let body = hyper::body::to_bytes(other.into_body()).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["id"], url);
+        let again = cache.response(&settings, &request, None);
+        assert_eq!(again.headers()[ETAG], etag);
+    }
+}
@@ -1,7 +1,31 @@
+pub mod admin;
+pub mod bans;
+pub mod cli;
 pub mod config;
 pub mod conn;
 pub mod db;
+pub mod deflate;
 pub mod error;
+pub mod firehose;
+pub mod hooks;
+pub mod httplimit;
 pub mod info;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod mirror;
+pub mod nip05;
+pub mod outbound;
 pub mod protocol;
 pub mod protostream;
+pub mod proxy;
+pub mod ratelimit;
+pub mod registry;
+pub mod shutdown;
+pub mod stats;
+pub mod systemd;
+#[cfg(test)]
+mod test_util;
+pub mod tls;
+pub mod version;
+pub mod whitelist;
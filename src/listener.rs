@@ -0,0 +1,331 @@
+//! Accepting client connections
+use crate::config::BindRetry;
+use crate::error::{Error, Result};
+use crate::proxy::{read_proxy_header, ProxyProtocol};
+use crate::tls::ReloadableAcceptor;
+use hyper::server::accept::Accept;
+use log::*;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+
+/// How long a client may take to send a PROXY header, and then to
+/// complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections ready to be served, waiting for hyper
+const ACCEPT_QUEUE: usize = 64;
+
+/// Pause after failing to accept a connection, so running out of
+/// file descriptors does not spin
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_secs(1);
+
+/// Connections waiting to be accepted before the kernel refuses more
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Listen on `addr`.  An IPv6 listener accepts IPv4 clients too,
+/// with mapped addresses, unless `ipv6_only` is set.
+pub fn bind(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    // restarting the relay need not wait for old connections to clear
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Why listening on `addr` failed, with a hint at the fix for the
+/// common causes.
+fn describe_bind_error(addr: SocketAddr, e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => "address already in use, by another process or another \
+             instance of the relay (network.bind_retry waits for it to be released)"
+            .to_owned(),
+        io::ErrorKind::PermissionDenied if addr.port() < 1024 => format!(
+            "permission denied for port {}; ports below 1024 need root or the \
+             CAP_NET_BIND_SERVICE capability (e.g. AmbientCapabilities=CAP_NET_BIND_SERVICE \
+             under systemd, or setcap cap_net_bind_service=+ep on the executable)",
+            addr.port()
+        ),
+        io::ErrorKind::AddrNotAvailable => format!(
+            "address not available; {} is not assigned to any interface of this machine",
+            addr.ip()
+        ),
+        _ => e.to_string(),
+    }
+}
+
+/// Listen on `addr` as [`bind`] does, trying again as `retry` allows
+/// while the address is in use.  The listener's local address has the
+/// port chosen if `addr` asks for port 0.
+pub async fn bind_with_retry(
+    addr: SocketAddr,
+    ipv6_only: bool,
+    retry: Option<BindRetry>,
+) -> Result<TcpListener> {
+    let mut attempt = 0;
+    loop {
+        match bind(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e)
+                if e.kind() == io::ErrorKind::AddrInUse
+                    && retry.is_some_and(|retry| attempt < retry.attempts) =>
+            {
+                let retry = retry.unwrap();
+                attempt += 1;
+                warn!(
+                    "{} is in use, trying again in {}ms ({} of {})",
+                    addr, retry.delay_ms, attempt, retry.attempts
+                );
+                tokio::time::sleep(Duration::from_millis(retry.delay_ms)).await;
+            }
+            Err(e) => {
+                return Err(Error::ListenError {
+                    addr,
+                    reason: describe_bind_error(addr, &e),
+                })
+            }
+        }
+    }
+}
+
+/// How connections on a listener start.
+#[derive(Clone)]
+pub struct ListenerOptions {
+    /// PROXY header sent by a load balancer
+    pub proxy_protocol: ProxyProtocol,
+    /// Acceptor for TLS connections, if they use TLS
+    pub tls: Option<Arc<ReloadableAcceptor>>,
+}
+
+enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// A client connection, past any PROXY header and TLS handshake.
+pub struct ClientConnection {
+    stream: ClientStream,
+    remote_addr: SocketAddr,
+    proxy_addr: Option<SocketAddr>,
+}
+
+impl ClientConnection {
+    /// The address of the client, as given by a load balancer if
+    /// there is one.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// The load balancer the connection came through, if its PROXY
+    /// header named the client.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy_addr
+    }
+}
+
+impl AsyncRead for ClientConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            ClientStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ClientStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            ClientStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ClientStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            ClientStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            ClientStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            ClientStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ClientStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connections for hyper to serve.
+pub struct Incoming {
+    connections: mpsc::Receiver<ClientConnection>,
+}
+
+impl Accept for Incoming {
+    type Conn = ClientConnection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientConnection>>> {
+        self.connections.poll_recv(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+/// Read the PROXY header and complete the TLS handshake, as the
+/// listener requires.
+async fn start(
+    mut tcp: TcpStream,
+    peer: SocketAddr,
+    options: &ListenerOptions,
+) -> Option<ClientConnection> {
+    let proxy_header = read_proxy_header(&mut tcp, options.proxy_protocol);
+    let (remote_addr, proxy_addr) =
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, proxy_header).await {
+            Ok(Ok(Some(addr))) => (addr, Some(peer)),
+            Ok(Ok(None)) => (peer, None),
+            Ok(Err(e)) => {
+                info!("dropping connection from {}: {}", peer, e);
+                return None;
+            }
+            Err(_) => {
+                info!("dropping connection from {}: no PROXY header", peer);
+                return None;
+            }
+        };
+    let stream = match &options.tls {
+        Some(acceptor) => {
+            let handshake = acceptor.current().accept(tcp);
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(stream)) => ClientStream::Tls(Box::new(stream)),
+                // scanners fail handshakes all the time
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return None;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", remote_addr);
+                    return None;
+                }
+            }
+        }
+        None => ClientStream::Plain(tcp),
+    };
+    Some(ClientConnection {
+        stream,
+        remote_addr,
+        proxy_addr,
+    })
+}
+
+/// Accept connections on `listener`, reading PROXY headers and
+/// performing TLS handshakes in the background so a slow client does
+/// not hold up others.
+pub fn incoming(listener: TcpListener, options: ListenerOptions) -> Incoming {
+    let (tx, rx) = mpsc::channel(ACCEPT_QUEUE);
+    let immediate = options.proxy_protocol == ProxyProtocol::Off && options.tls.is_none();
+    tokio::spawn(async move {
+        loop {
+            let (tcp, peer) = tokio::select! {
+                // the server has stopped
+                _ = tx.closed() => break,
+                accepted = listener.accept() => match accepted {
+                    // IPv4 clients of a dual-stack listener are shown
+                    // as themselves, not as mapped IPv6 addresses
+                    Ok((tcp, peer)) => (tcp, SocketAddr::new(peer.ip().to_canonical(), peer.port())),
+                    Err(e) => {
+                        warn!("could not accept connection: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                        continue;
+                    }
+                },
+            };
+            // plain connections are ready as they are
+            if immediate {
+                let conn = ClientConnection {
+                    stream: ClientStream::Plain(tcp),
+                    remote_addr: peer,
+                    proxy_addr: None,
+                };
+                if tx.send(conn).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            let options = options.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Some(conn) = start(tcp, peer, &options).await {
+                    tx.send(conn).await.ok();
+                }
+            });
+        }
+    });
+    Incoming { connections: rx }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn address_in_use_retried() {
+        let held = bind("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = held.local_addr().unwrap();
+        match bind_with_retry(addr, true, None).await {
+            Err(Error::ListenError {
+                addr: failed,
+                reason,
+            }) => {
+                assert_eq!(failed, addr);
+                assert!(reason.contains("already in use"), "{}", reason);
+            }
+            other => panic!("bound an address in use: {:?}", other.map(|_| ())),
+        }
+        // released while retrying
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+        let retry = BindRetry {
+            attempts: 50,
+            delay_ms: 20,
+        };
+        let listener = bind_with_retry(addr, true, Some(retry)).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn bind_errors_explained() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let reason = describe_bind_error("0.0.0.0:443".parse().unwrap(), &denied);
+        assert!(reason.contains("CAP_NET_BIND_SERVICE"), "{}", reason);
+        let reason = describe_bind_error("0.0.0.0:8443".parse().unwrap(), &denied);
+        assert!(!reason.contains("CAP_NET_BIND_SERVICE"), "{}", reason);
+        let unavailable = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        let reason = describe_bind_error("192.0.2.1:8080".parse().unwrap(), &unavailable);
+        assert!(reason.contains("192.0.2.1 is not assigned"), "{}", reason);
+    }
+}
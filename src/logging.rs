@@ -0,0 +1,184 @@
+//! Logging through `tracing`, with a filter and output format that
+//! can change while the relay runs.  Records from the `log` macros are
+//! forwarded, so both may be used.
+use crate::config::Settings;
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+/// What is logged when nothing is configured
+const DEFAULT_FILTER: &str = "error";
+
+/// Environment variable overriding `log.level`
+const FILTER_ENV: &str = "RUST_LOG";
+
+/// How log lines are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Lines for people to read
+    #[default]
+    Text,
+    /// A JSON object per line, including the fields of every span
+    Json,
+}
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type Output = Box<dyn Layer<Filtered> + Send + Sync>;
+
+/// Handles for changing the installed subscriber.
+struct Handles {
+    filter: reload::Handle<EnvFilter, Registry>,
+    output: reload::Handle<Output, Filtered>,
+    /// Filter given on the command line, which settings do not
+    /// override
+    command_line: Option<String>,
+}
+
+static HANDLES: OnceLock<Handles> = OnceLock::new();
+
+/// The filter to use: from the command line, else `RUST_LOG`, else
+/// `log.level`.
+fn filter_spec(command_line: Option<&str>, configured: Option<&str>) -> String {
+    command_line
+        .map(str::to_owned)
+        .or_else(|| std::env::var(FILTER_ENV).ok().filter(|s| !s.is_empty()))
+        .or_else(|| configured.map(str::to_owned))
+        .unwrap_or_else(|| DEFAULT_FILTER.to_owned())
+}
+
+fn parse_filter(spec: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(spec)
+        .map_err(|e| Error::GenericError(format!("invalid log filter {:?}: {}", spec, e)))
+}
+
+fn output(format: LogFormat) -> Output {
+    let layer = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_span_list(true).boxed(),
+    }
+}
+
+/// Let through records from the `log` macros that the filter may
+/// want, as the most verbose level can change on reload.
+fn sync_log_level() {
+    let level = match LevelFilter::current().into_level() {
+        None => log::LevelFilter::Off,
+        Some(tracing::Level::ERROR) => log::LevelFilter::Error,
+        Some(tracing::Level::WARN) => log::LevelFilter::Warn,
+        Some(tracing::Level::INFO) => log::LevelFilter::Info,
+        Some(tracing::Level::DEBUG) => log::LevelFilter::Debug,
+        Some(tracing::Level::TRACE) => log::LevelFilter::Trace,
+    };
+    log::set_max_level(level);
+}
+
+/// Start logging as text, before settings are read, with the filter
+/// given on the command line or in `RUST_LOG`.
+pub fn init(command_line: Option<&str>) -> Result<()> {
+    let filter = parse_filter(&filter_spec(command_line, None))
+        .map_err(|e| Error::UsageError(e.to_string()))?;
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let (output, output_handle) = reload::Layer::new(output(LogFormat::Text));
+    // fails if a subscriber is already installed, as in tests
+    if Registry::default()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .is_ok()
+    {
+        HANDLES.get_or_init(|| Handles {
+            filter: filter_handle,
+            output: output_handle,
+            command_line: command_line.map(str::to_owned),
+        });
+        sync_log_level();
+    }
+    Ok(())
+}
+
+/// Apply the `log` settings, at startup and when they are reloaded.
+/// An invalid filter leaves the current one in place.
+pub fn configure(settings: &Settings) -> Result<()> {
+    let Some(handles) = HANDLES.get() else {
+        return Ok(());
+    };
+    let spec = filter_spec(
+        handles.command_line.as_deref(),
+        settings.log.level.as_deref(),
+    );
+    let filter = parse_filter(&spec)?;
+    handles
+        .filter
+        .reload(filter)
+        .map_err(|e| Error::GenericError(e.to_string()))?;
+    handles
+        .output
+        .reload(output(settings.log.format))
+        .map_err(|e| Error::GenericError(e.to_string()))?;
+    sync_log_level();
+    Ok(())
+}
+
+/// An HTTP request the relay answered, for the access log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord<'a> {
+    pub method: &'a str,
+    /// Path, without the query
+    pub path: &'a str,
+    pub status: u16,
+    /// Bytes in the response body, if known
+    pub bytes: Option<u64>,
+    pub latency: Duration,
+    /// Client address, as reported by a trusted proxy if there is one
+    pub client: IpAddr,
+    /// The proxy or load balancer that reported the client address
+    pub proxy: Option<IpAddr>,
+    pub user_agent: Option<&'a str>,
+}
+
+impl AccessRecord<'_> {
+    /// Log the request under the `access` target, as fields, so that
+    /// lines are key=value pairs as text and objects as JSON.
+    pub fn log(&self) {
+        tracing::info!(
+            target: "access",
+            method = self.method,
+            path = self.path,
+            status = self.status,
+            bytes = self.bytes,
+            latency_us = self.latency.as_micros() as u64,
+            client = %self.client,
+            proxy = self.proxy.map(tracing::field::display),
+            user_agent = self.user_agent,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_filter_preferred() {
+        // RUST_LOG is not set under cargo test unless the caller sets it
+        if std::env::var(FILTER_ENV).is_ok() {
+            return;
+        }
+        assert_eq!(filter_spec(Some("debug"), Some("warn")), "debug");
+        assert_eq!(filter_spec(None, Some("warn")), "warn");
+        assert_eq!(filter_spec(None, None), DEFAULT_FILTER);
+        assert!(parse_filter("nostrd=debug,hyper=warn").is_ok());
+        assert!(parse_filter("nostrd=loud").is_err());
+    }
+}
@@ -1,64 +1,313 @@
 //! Server process
-use futures::SinkExt;
-use futures::StreamExt;
+use clap::Parser;
+use futures::{FutureExt, StreamExt};
+use hyper::body::HttpBody;
 use hyper::header::ACCEPT;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::upgrade::Upgraded;
-use hyper::{
-    header, server::conn::AddrStream, upgrade, Body, Request, Response, Server, StatusCode,
-};
+use hyper::{header, upgrade, Body, Method, Request, Response, Server, StatusCode};
 use log::*;
+use nostrd::admin::{self, AdminTargets};
+use nostrd::bans::Bans;
+use nostrd::cli::{Cli, Command, GlobalOptions, NoticeCommand};
 use nostrd::config;
 use nostrd::conn;
 use nostrd::db;
+use nostrd::db::WriteResult;
+use nostrd::deflate::{self, DeflateStream};
 use nostrd::error::{Error, Result};
-use nostrd::info::RelayInfo;
-use nostrd::protocol::Event;
+use nostrd::firehose::{self, FirehoseConsumers, FirehoseExit};
+use nostrd::hooks::{self, ConnectionHooks, ConnectionInfo};
+use nostrd::httplimit;
+use nostrd::info;
+use nostrd::listener::{self, ClientConnection, Incoming, ListenerOptions};
+use nostrd::logging::{self, AccessRecord};
+use nostrd::metrics::{self, METRICS};
+use nostrd::mirror::{self, UpstreamStats};
+use nostrd::outbound::{write_outbound, FinishOnDrop, OutboundQueue, WriterExit};
+use nostrd::protocol::{BroadcastEvent, Event, EventId};
 use nostrd::protostream;
-use nostrd::protostream::{NostrMessage, NostrResponse};
-use std::collections::HashMap;
+use nostrd::protostream::{
+    NostrMessage, NostrResponse, CLOSE_ABNORMAL, CLOSE_MESSAGE_TOO_BIG, CLOSE_POLICY_VIOLATION,
+};
+use nostrd::proxy::{ProxyProtocol, TrustedProxies};
+use nostrd::ratelimit::ConnectionRateLimiter;
+use nostrd::registry::{ConnectionRegistry, ConnectionSlot, ConnectionTasks};
+use nostrd::shutdown::{self, ShutdownReport};
+use nostrd::stats::{LiveCounts, StatsAccess, StatsReporter};
+use nostrd::systemd;
+use nostrd::tls::ReloadableAcceptor;
+use nostrd::version;
+use nostrd::whitelist;
 use std::convert::Infallible;
 use std::env;
 use std::net::SocketAddr;
-use std::path::Path;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Builder;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
 use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
 use tungstenite::handshake;
 use tungstenite::protocol::WebSocketConfig;
 
-/// Return a requested DB name from command line arguments.
-fn db_from_args(args: Vec<String>) -> Option<String> {
-    if args.len() == 3 && args.get(1) == Some(&"--db".to_owned()) {
-        return args.get(2).map(|x| x.to_owned());
+/// How long a client waits for the database writer to report the
+/// outcome of a submitted event.
+const WRITE_RESULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent to clients, and given as the close reason, when the relay
+/// shuts down.
+const SHUTDOWN_MESSAGE: &str = "relay shutting down";
+
+/// How long a connection may spend telling its client about a
+/// shutdown.
+const SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often a connection updates its entry in the connection
+/// registry.
+const REGISTRY_REFRESH: Duration = Duration::from_secs(5);
+
+/// Times connections must fall behind the broadcast channel before
+/// the buffer size is questioned; the warning repeats each time the
+/// count doubles
+const LAG_WARNING_THRESHOLD: u64 = 4;
+
+/// Seconds a client refused at the connection limit is asked to wait
+const CONNECTION_RETRY_AFTER_SECS: u64 = 10;
+
+/// Addresses listed in metrics for having connections throttled
+const THROTTLED_ADDRESSES_REPORTED: usize = 10;
+
+/// How often close codes and websocket errors are summarized in the
+/// log
+const WEBSOCKET_SUMMARY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Ask the running relay, through its admin API, to send or manage
+/// notices to its clients, and print the result.
+fn notice_relay(command: NoticeCommand) -> Result<()> {
+    let (method, path, body) = match command {
+        NoticeCommand::Send(notice) => (
+            hyper::Method::POST,
+            "/admin/notice".to_owned(),
+            Some(serde_json::to_string(&notice)?),
+        ),
+        NoticeCommand::List => (hyper::Method::GET, "/admin/notices".to_owned(), None),
+        NoticeCommand::Cancel(id) => (
+            hyper::Method::DELETE,
+            format!("/admin/notices/{}", id),
+            None,
+        ),
+    };
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    let (status, body) = rt.block_on(admin::call_admin_api(method, &path, body))?;
+    println!("{}", body);
+    if !status.is_success() {
+        return Err(Error::GenericError(format!("relay answered {}", status)));
     }
-    None
+    Ok(())
+}
+
+/// Relay-wide state shared by every client connection
+#[derive(Clone)]
+struct ClientContext {
+    /// Events stored by the database writer, for live subscriptions
+    broadcast: Sender<BroadcastEvent>,
+    /// Events submitted for storage
+    events: db::EventQueue,
+    storage: Arc<dyn db::Storage>,
+    /// Subscriptions available across the relay
+    sub_budget: Arc<conn::SubscriptionBudget>,
+    /// Connections currently open
+    registry: Arc<ConnectionRegistry>,
+    /// Tasks serving websocket connections
+    tasks: Arc<ConnectionTasks>,
+    /// Proxies trusted to report client addresses
+    proxies: Arc<TrustedProxies>,
+    /// Ids of recently stored events
+    recent: Arc<db::RecentIds>,
+    /// Authors and addresses banned by the operator
+    bans: Arc<Bans>,
+    /// How often each address has connected
+    connection_rate: Arc<ConnectionRateLimiter>,
+    /// Snapshots of the relay for `/stats`
+    stats: Arc<StatsReporter>,
+    /// Told as clients connect and disconnect
+    hooks: Arc<dyn ConnectionHooks>,
+    /// Consumers of `/firehose`
+    firehose: Arc<FirehoseConsumers>,
+    /// Relays events are copied from
+    mirrors: Arc<[Arc<UpstreamStats>]>,
+    /// Relay URL announced on the listener the client came to, if it
+    /// has its own
+    relay_url: Option<Arc<str>>,
+}
+
+/// Methods a websocket handshake may use
+const ALLOW_UPGRADE: &[Method] = &[Method::GET];
+
+/// Methods answered for `/`: the relay information document, or a
+/// page for browsers, and CORS preflight requests
+const ALLOW_ROOT: &[Method] = &[Method::GET, Method::HEAD, Method::OPTIONS];
+
+/// Methods answered for documents that are only read, such as metrics
+const ALLOW_READ: &[Method] = &[Method::GET, Method::HEAD];
+
+/// A response refusing a request's method, naming the methods allowed.
+fn method_not_allowed(allow: &[Method]) -> Response<Body> {
+    let allow: Vec<&str> = allow.iter().map(Method::as_str).collect();
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, allow.join(", "))
+        .body(Body::from("Method not allowed."))
+        .unwrap()
 }
 
 /// Handle arbitrary HTTP requests, including for WebSocket upgrades.
 async fn handle_web_request(
     mut request: Request<Body>,
     remote_addr: SocketAddr,
-    broadcast: Sender<Event>,
-    event_tx: tokio::sync::mpsc::Sender<Event>,
+    ctx: ClientContext,
     shutdown: Receiver<()>,
 ) -> Result<Response<Body>, Infallible> {
-    match (
-        request.uri().path(),
-        request.headers().contains_key(header::UPGRADE),
-    ) {
+    // behind a reverse proxy, the client is elsewhere
+    let remote_addr = ctx.proxies.client_addr(remote_addr, request.headers());
+    // bound what is read of a request before any route sees it;
+    // websocket handshakes carry no body
+    let limits = config::SETTINGS.read().unwrap().limits.request_limits();
+    if let Err(response) = httplimit::check_headers(request.headers(), &limits) {
+        return Ok(response);
+    }
+    if !request.headers().contains_key(header::UPGRADE) {
+        request = match httplimit::read_body(request, limits.max_body_bytes).await {
+            Ok(request) => request,
+            Err(response) => return Ok(response),
+        };
+    }
+    // methods each route answers; hyper sends only the headers of
+    // responses to HEAD
+    let path = request.uri().path();
+    let upgrade = request.headers().contains_key(header::UPGRADE);
+    let allow = match (path, upgrade) {
+        ("/", true) | ("/firehose", true) => Some(ALLOW_UPGRADE),
+        ("/", false) => Some(ALLOW_ROOT),
+        ("/metrics", false) if metrics_on_relay_port() => Some(ALLOW_READ),
+        ("/stats", false) | ("/version", false) => Some(ALLOW_READ),
+        // the admin API checks methods itself, and anything else is
+        // not found whatever the method
+        _ => None,
+    };
+    if let Some(allow) = allow {
+        if !allow.contains(request.method()) {
+            return Ok(method_not_allowed(allow));
+        }
+    }
+    match (path, upgrade) {
         // Request for / as websocket
         ("/", true) => {
             debug!("websocket with upgrade request");
+            if ctx.bans.is_ip_banned(&remote_addr.ip()) {
+                info!("refusing connection from banned address {}", remote_addr);
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Forbidden."))
+                    .unwrap());
+            }
+            // clients reconnecting in a loop, though not a proxy that
+            // did not say who its clients are, which would throttle
+            // all of them together
+            let rate = config::SETTINGS.read().unwrap().limits.connection_rate();
+            let throttled = if ctx.proxies.is_trusted(remote_addr.ip()) {
+                None
+            } else {
+                ctx.connection_rate.check(remote_addr.ip(), rate)
+            };
+            if let Some(wait) = throttled {
+                let throttled = METRICS.connection_throttled();
+                if throttled.is_power_of_two() {
+                    warn!(
+                        "throttled {} connection(s) at limits.connections_per_minute ({}), latest from {}",
+                        throttled, rate.per_minute, remote_addr
+                    );
+                }
+                // whole seconds, rounded up
+                let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::RETRY_AFTER, retry_after.max(1))
+                    .body(Body::from("Too many connections, try again later."))
+                    .unwrap());
+            }
+            // web pages the operator has not allowed
+            let origin = request.headers().get(header::ORIGIN);
+            let origin_allowed = {
+                let settings = config::SETTINGS.read().unwrap();
+                // a header that is not text matches nothing allowed
+                settings
+                    .network
+                    .origin_allowed(origin.map(|o| o.to_str().unwrap_or_default()))
+            };
+            if !origin_allowed {
+                info!(
+                    "refusing connection from {} with origin {:?}",
+                    remote_addr, origin
+                );
+                METRICS.origin_refused();
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from("Forbidden."))
+                    .unwrap());
+            }
+            // refuse before the handshake, so a reconnect storm costs
+            // little
+            let max_connections = config::SETTINGS.read().unwrap().limits.max_connections;
+            let slot = match ctx.registry.admit(max_connections) {
+                Some(slot) => slot,
+                None => {
+                    let refused = METRICS.connection_refused();
+                    // the first few, then ever more rarely
+                    if refused.is_power_of_two() {
+                        warn!(
+                            "refused {} connection(s) at limits.max_connections ({}), latest from {}",
+                            refused, max_connections, remote_addr
+                        );
+                    }
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(header::RETRY_AFTER, CONNECTION_RETRY_AFTER_SECS)
+                        .body(Body::from("Too many connections, try again later."))
+                        .unwrap());
+                }
+            };
+            // note what software the client is, for debugging
+            let headers = conn::ClientHeaders::from_headers(request.headers());
+            // compress messages, if the client can and it is allowed
+            let (limits, extension) = {
+                let settings = config::SETTINGS.read().unwrap();
+                let limits = deflate::Limits {
+                    max_message_bytes: settings.limits.max_ws_message_bytes,
+                    max_frame_bytes: settings.limits.max_ws_frame_bytes,
+                };
+                let extension = settings
+                    .network
+                    .enable_compression
+                    .then(|| deflate::negotiate(request.headers()))
+                    .flatten();
+                (limits, extension)
+            };
             //assume request is a handshake, so create the handshake response
             let response = match handshake::server::create_response_with_body(&request, || {
                 Body::empty()
             }) {
-                Ok(response) => {
+                Ok(mut response) => {
+                    let compression = extension.map(|extension| {
+                        response
+                            .headers_mut()
+                            .insert(header::SEC_WEBSOCKET_EXTENSIONS, extension);
+                        // limits apply to messages once inflated
+                        limits
+                    });
                     //in case the handshake response creation succeeds,
                     //spawn a task to handle the websocket connection
                     tokio::spawn(async move {
@@ -68,28 +317,41 @@ async fn handle_web_request(
                             Ok(upgraded) => {
                                 // set WebSocket configuration options
                                 let mut config = WebSocketConfig::default();
-                                {
-                                    let settings = config::SETTINGS.read().unwrap();
-                                    config.max_message_size = settings.limits.max_ws_message_bytes;
-                                    config.max_frame_size = settings.limits.max_ws_frame_bytes;
-                                }
+                                config.max_message_size = limits.max_message_bytes;
+                                config.max_frame_size = limits.max_frame_bytes;
                                 //create a websocket stream from the upgraded object
                                 let ws_stream = WebSocketStream::from_raw_socket(
                                     //pass the upgraded object
                                     //as the base layer stream of the Websocket
-                                    upgraded,
+                                    DeflateStream::new(upgraded, compression),
                                     tokio_tungstenite::tungstenite::protocol::Role::Server,
                                     Some(config),
                                 )
                                 .await;
-                                tokio::spawn(nostr_server(
-                                    ws_stream, broadcast, event_tx, shutdown,
-                                ));
+                                let tasks = ctx.tasks.clone();
+                                // everything logged for the connection
+                                // carries its id and address
+                                let span = tracing::info_span!(
+                                    "conn",
+                                    client_id = tracing::field::Empty,
+                                    remote = %remote_addr
+                                );
+                                tasks.spawn(
+                                    nostr_server(
+                                        ws_stream,
+                                        remote_addr,
+                                        headers,
+                                        ctx,
+                                        slot,
+                                        shutdown,
+                                    ),
+                                    span,
+                                );
                             }
-                            Err(e) => println!(
-                                "error when trying to upgrade connection \
-                                 from address {} to websocket connection. \
-                                 Error is: {}",
+                            // the client went away, or sent something
+                            // other than websocket frames
+                            Err(e) => warn!(
+                                "websocket upgrade failed for client at {}: {}",
                                 remote_addr, e
                             ),
                         }
@@ -107,9 +369,30 @@ async fn handle_web_request(
             };
             Ok::<_, Infallible>(response)
         }
+        // Every accepted event, for indexers
+        ("/firehose", true) => Ok(firehose_response(request, remote_addr, ctx, shutdown)),
+        // Prometheus metrics, unless served on their own address
+        ("/metrics", false) if metrics_on_relay_port() => Ok(metrics_response(&ctx).await),
+        // Operator API, unless served on its own address
+        (path, false) if path.starts_with("/admin/") && admin_on_relay_port() => {
+            Ok(admin_response(request, remote_addr, &ctx).await)
+        }
+        // Snapshot for dashboards and relay listings
+        ("/stats", false) => Ok(stats_response(&request, remote_addr, &ctx).await),
+        // What build is running, for anyone triaging an issue
+        ("/version", false) => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(version::BUILD_INFO_JSON.as_str()))
+            .unwrap()),
         // Request for Relay info
         ("/", false) => {
             // handle request at root with no upgrade header
+            let origin = request.headers().get(header::ORIGIN);
+            // browsers check before sending an Accept header
+            if request.method() == Method::OPTIONS {
+                let config = config::SETTINGS.read().unwrap();
+                return Ok(info::preflight_response(&config, origin));
+            }
             // Check if this is a nostr server info request
             let accept_header = &request.headers().get(ACCEPT);
             // check if application/nostr+json is included
@@ -119,19 +402,17 @@ async fn handle_web_request(
                         let config = config::SETTINGS.read().unwrap();
                         // build a relay info response
                         debug!("Responding to server info request");
-                        let rinfo = RelayInfo::from(config.info.clone());
-                        let b = Body::from(serde_json::to_string_pretty(&rinfo).unwrap());
-                        return Ok(Response::builder()
-                            .status(200)
-                            .header("Content-Type", "application/nostr+json")
-                            .body(b)
-                            .unwrap());
+                        return Ok(info::info_response(
+                            &config,
+                            request.headers(),
+                            ctx.relay_url.as_deref(),
+                        ));
                     }
                 }
             }
-            Ok(Response::new(Body::from(
-                "Please use a Nostr client to connect.",
-            )))
+            // people visiting in a browser
+            let config = config::SETTINGS.read().unwrap();
+            Ok(info::landing_response(&config, ctx.registry.len()))
         }
         (_, _) => {
             //handle any other url
@@ -143,236 +424,1875 @@ async fn handle_web_request(
     }
 }
 
-async fn shutdown_signal() {
-    // Wait for the CTRL+C signal
-    tokio::signal::ctrl_c()
+/// Handle a request as [`handle_web_request`] does, noting it in the
+/// access log if `log.access` is set.  Websocket traffic is not
+/// logged, beyond the handshake.  `load_balancer` is the peer that
+/// sent a PROXY header for the connection, if any.
+async fn logged_web_request(
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+    load_balancer: Option<SocketAddr>,
+    ctx: ClientContext,
+    shutdown: Receiver<()>,
+) -> Result<Response<Body>, Infallible> {
+    if !config::SETTINGS.read().unwrap().log.access {
+        return handle_web_request(request, remote_addr, ctx, shutdown).await;
+    }
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let client = ctx.proxies.client_addr(remote_addr, request.headers()).ip();
+    // the hop the client address came from, if not the client itself
+    let proxy = match load_balancer {
+        Some(load_balancer) => Some(load_balancer.ip()),
+        None => (client != remote_addr.ip()).then_some(remote_addr.ip()),
+    };
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(str::to_owned);
+    let response = handle_web_request(request, remote_addr, ctx, shutdown).await?;
+    let size = response.body().size_hint();
+    AccessRecord {
+        method: method.as_str(),
+        path: &path,
+        status: response.status().as_u16(),
+        bytes: size.exact(),
+        latency: started.elapsed(),
+        client,
+        proxy,
+        user_agent: user_agent.as_deref(),
+    }
+    .log();
+    Ok(response)
+}
+
+/// Whether metrics are served alongside the relay, rather than on
+/// their own address or not at all.
+fn metrics_on_relay_port() -> bool {
+    let metrics = &config::SETTINGS.read().unwrap().metrics;
+    metrics.enabled && metrics.address.is_none()
+}
+
+/// Whether the admin API is served alongside the relay, rather than
+/// on its own address.
+fn admin_on_relay_port() -> bool {
+    config::SETTINGS.read().unwrap().admin.address.is_none()
+}
+
+/// Answer a request to the admin API.
+async fn admin_response(
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+    ctx: &ClientContext,
+) -> Response<Body> {
+    let targets = AdminTargets {
+        registry: &ctx.registry,
+        storage: ctx.storage.as_ref(),
+        recent: &ctx.recent,
+        bans: &ctx.bans,
+    };
+    admin::handle_admin_request(request, remote_addr, &targets).await
+}
+
+/// Relay metrics in the Prometheus text format.
+async fn metrics_response(ctx: &ClientContext) -> Response<Body> {
+    let db_size_bytes = match ctx.storage.size_bytes().await {
+        Ok(size) => size,
+        Err(e) => {
+            warn!("could not read database size for metrics: {}", e);
+            None
+        }
+    };
+    let gauges = metrics::Gauges {
+        connections: ctx.registry.len(),
+        subscriptions: ctx.sub_budget.active(),
+        firehose_consumers: ctx.firehose.len(),
+        mirrors: ctx.mirrors.iter().map(|m| m.snapshot()).collect(),
+        broadcast_queue: ctx.broadcast.len(),
+        persist_queue: ctx.events.depth(),
+        db_size_bytes,
+        close_codes: ctx.registry.close_codes(),
+        throttled_addresses: ctx
+            .connection_rate
+            .most_throttled(THROTTLED_ADDRESSES_REPORTED),
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, metrics::CONTENT_TYPE)
+        .body(Body::from(METRICS.render(&gauges)))
+        .unwrap()
+}
+
+/// Live numbers for a stats snapshot.
+fn live_counts(ctx: &ClientContext) -> LiveCounts {
+    LiveCounts {
+        connections: ctx.registry.len(),
+        subscriptions: ctx.sub_budget.active(),
+        firehose_consumers: ctx.firehose.len(),
+    }
+}
+
+/// Answer a request for `/stats`, as `stats.access` allows.
+async fn stats_response(
+    request: &Request<Body>,
+    remote_addr: SocketAddr,
+    ctx: &ClientContext,
+) -> Response<Body> {
+    let (access, cache) = {
+        let settings = config::SETTINGS.read().unwrap();
+        let access = match settings.stats.access {
+            StatsAccess::Off => None,
+            StatsAccess::Public => Some(true),
+            StatsAccess::Admin => Some(admin::is_authorized(request, &settings.admin.token)),
+        };
+        (access, Duration::from_secs(settings.stats.cache_secs))
+    };
+    match access {
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Nothing here."))
+            .unwrap(),
+        Some(false) => {
+            warn!("unauthorized stats request from {}", remote_addr);
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::from("Unauthorized."))
+                .unwrap()
+        }
+        Some(true) => match ctx
+            .stats
+            .snapshot(ctx.storage.as_ref(), live_counts(ctx), cache)
+            .await
+        {
+            Ok(stats) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_string(&stats).unwrap()))
+                .unwrap(),
+            Err(e) => {
+                warn!("could not take stats snapshot: {}", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Stats unavailable."))
+                    .unwrap()
+            }
+        },
+    }
+}
+
+/// Answer a websocket handshake for `/firehose`, as `firehose.access`
+/// allows, and stream events to the consumer from a task of its own.
+fn firehose_response(
+    mut request: Request<Body>,
+    remote_addr: SocketAddr,
+    ctx: ClientContext,
+    shutdown: Receiver<()>,
+) -> Response<Body> {
+    let (access, max_consumers, max_message_bytes, send_timeout) = {
+        let settings = config::SETTINGS.read().unwrap();
+        let access = match settings.firehose.access {
+            StatsAccess::Off => None,
+            StatsAccess::Public => Some(true),
+            StatsAccess::Admin => Some(admin::is_authorized(&request, &settings.admin.token)),
+        };
+        (
+            access,
+            settings.firehose.max_consumers,
+            settings.limits.max_ws_message_bytes,
+            Duration::from_secs(settings.network.send_timeout_secs),
+        )
+    };
+    let refuse = |status: StatusCode, body: &str| {
+        Response::builder()
+            .status(status)
+            .body(Body::from(body.to_owned()))
+            .unwrap()
+    };
+    match access {
+        None => return refuse(StatusCode::NOT_FOUND, "Nothing here."),
+        Some(false) => {
+            warn!("unauthorized firehose request from {}", remote_addr);
+            return refuse(StatusCode::UNAUTHORIZED, "Unauthorized.");
+        }
+        Some(true) => {}
+    }
+    if ctx.bans.is_ip_banned(&remote_addr.ip()) {
+        info!("refusing firehose from banned address {}", remote_addr);
+        return refuse(StatusCode::FORBIDDEN, "Forbidden.");
+    }
+    let slot = match ctx.firehose.admit(max_consumers) {
+        Some(slot) => slot,
+        None => {
+            info!(
+                "refused firehose from {} at firehose.max_consumers ({})",
+                remote_addr, max_consumers
+            );
+            let mut response = refuse(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many firehose consumers, try again later.",
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, CONNECTION_RETRY_AFTER_SECS.into());
+            return response;
+        }
+    };
+    let response = match handshake::server::create_response_with_body(&request, Body::empty) {
+        Ok(response) => response,
+        Err(error) => {
+            return refuse(
+                StatusCode::BAD_REQUEST,
+                &format!("Failed to create websocket: {}", error),
+            )
+        }
+    };
+    // events broadcast from the handshake on
+    let events = ctx.broadcast.subscribe();
+    tokio::spawn(async move {
+        let upgraded = match upgrade::on(&mut request).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("could not upgrade firehose from {}: {}", remote_addr, e);
+                return;
+            }
+        };
+        // consumers have nothing to say, beyond closing
+        let mut config = WebSocketConfig::default();
+        config.max_message_size = max_message_bytes;
+        let ws_stream = WebSocketStream::from_raw_socket(
+            DeflateStream::new(upgraded, None),
+            tokio_tungstenite::tungstenite::protocol::Role::Server,
+            Some(config),
+        )
+        .await;
+        let span = tracing::info_span!("firehose", remote = %remote_addr);
+        ctx.tasks.spawn(
+            async move {
+                let _slot = slot;
+                info!("firehose consumer connected from {}", remote_addr);
+                let (sink, stream) = ws_stream.split();
+                match firehose::stream_events(sink, stream, events, send_timeout, shutdown).await {
+                    FirehoseExit::Lagged { skipped } => info!(
+                        "firehose consumer {} fell behind by {} event(s), disconnected",
+                        remote_addr, skipped
+                    ),
+                    FirehoseExit::SlowConsumer => {
+                        METRICS.write_timed_out();
+                        info!(
+                            "write timed out, disconnecting firehose consumer {}",
+                            remote_addr
+                        )
+                    }
+                    exit => debug!("firehose consumer {} gone: {:?}", remote_addr, exit),
+                }
+            },
+            span,
+        );
+    });
+    response
+}
+
+/// Log a stats snapshot every `interval` until the relay shuts down.
+async fn log_stats(ctx: ClientContext, interval: Duration, mut shutdown: Receiver<()>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+        // the same snapshot /stats would serve
+        let cache = Duration::from_secs(config::SETTINGS.read().unwrap().stats.cache_secs);
+        match ctx
+            .stats
+            .snapshot(ctx.storage.as_ref(), live_counts(&ctx), cache)
+            .await
+        {
+            Ok(stats) => info!("relay stats: {}", serde_json::to_string(&stats).unwrap()),
+            Err(e) => warn!("could not take stats snapshot: {}", e),
+        }
+    }
+}
+
+/// Log how websocket connections ended and what went wrong on them
+/// every `interval`, when anything did, until the relay shuts down.
+async fn log_websocket_summary(ctx: ClientContext, interval: Duration, mut shutdown: Receiver<()>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    let mut earlier = METRICS.websocket_counts(ctx.registry.close_codes());
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+        let now = METRICS.websocket_counts(ctx.registry.close_codes());
+        let counts = now.since(&earlier);
+        if !counts.is_empty() {
+            info!("websockets in the last {:?}: {}", interval, counts);
+        }
+        earlier = now;
+    }
+}
+
+/// Notify the systemd watchdog every `interval`, as long as the
+/// database answers a trivial query in time, so that a relay that has
+/// stopped responding is restarted.
+async fn notify_watchdog(
+    storage: Arc<dyn db::Storage>,
+    interval: Duration,
+    mut shutdown: Receiver<()>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = shutdown.recv() => return,
+        }
+        match tokio::time::timeout(interval, storage.ping()).await {
+            Ok(Ok(())) => systemd::watchdog(),
+            Ok(Err(e)) => warn!(
+                "not notifying the systemd watchdog, database check failed: {}",
+                e
+            ),
+            Err(_) => warn!(
+                "not notifying the systemd watchdog, database check took over {:?}",
+                interval
+            ),
+        }
+    }
+}
+
+/// Apply changes to the configuration file, as far as they can be
+/// applied while the relay runs.  `cli` holds the options given on
+/// the command line, which still take precedence.
+#[cfg(unix)]
+fn reload_config(cli: &GlobalOptions) {
+    match config::reload(|c| cli.apply(c)) {
+        Ok(ignored) => {
+            if let Err(e) = logging::configure(&config::SETTINGS.read().unwrap()) {
+                warn!("kept previous log settings: {}", e);
+            }
+            if let Err(e) = info::load_landing_page(&config::SETTINGS.read().unwrap()) {
+                warn!("kept previous landing page: {}", e);
+            }
+            if let Err(e) = whitelist::load(&config::SETTINGS.read().unwrap()) {
+                warn!("kept previous author whitelist: {}", e);
+            }
+            info!("reloaded configuration");
+            if !ignored.is_empty() {
+                warn!(
+                    "restart the relay to apply changes to: {}",
+                    ignored.join(", ")
+                );
+            }
+        }
+        Err(e) => warn!("kept previous configuration: {}", e),
+    }
+}
+
+/// Signals asking the relay to shut down: ctrl-c, and under a service
+/// manager SIGTERM or SIGQUIT.
+struct StopSignals {
+    #[cfg(unix)]
+    term: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    quit: tokio::signal::unix::Signal,
+}
+
+impl StopSignals {
+    /// Start listening for the signals, so they no longer end the
+    /// process immediately.
+    fn new() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let listen = |kind: SignalKind| {
+                signal(kind).map_err(|e| {
+                    Error::GenericError(format!("could not listen for signals: {}", e))
+                })
+            };
+            Ok(StopSignals {
+                term: listen(SignalKind::terminate())?,
+                quit: listen(SignalKind::quit())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(StopSignals {})
+    }
+
+    /// Wait for a signal, returning its name.
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = self.term.recv() => "SIGTERM",
+            _ = self.quit.recv() => "SIGQUIT",
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await.ok();
+            "ctrl-c"
+        }
+    }
+}
+
+/// Serve client requests on the connections from `incoming` until
+/// the relay shuts down.
+async fn serve(incoming: Incoming, ctx: ClientContext, stop: Sender<()>) -> hyper::Result<()> {
+    // A `Service` is needed for every connection, so this
+    // creates one from our `handle_request` function.
+    let make_svc = make_service_fn(|conn: &ClientConnection| {
+        let remote_addr = conn.remote_addr();
+        let load_balancer = conn.proxy_addr();
+        let ctx = ctx.clone();
+        let stop = stop.clone();
+        async move {
+            // service_fn converts our function into a `Service`
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                logged_web_request(
+                    request,
+                    remote_addr,
+                    load_balancer,
+                    ctx.clone(),
+                    stop.subscribe(),
+                )
+            }))
+        }
+    });
+    let mut server_shutdown = stop.subscribe();
+    Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            server_shutdown.recv().await.ok();
+        })
         .await
-        .expect("failed to install CTRL+C signal handler");
+}
+
+/// Serve only the operator interfaces, metrics and/or the admin API,
+/// on their own listener until the relay shuts down.
+async fn serve_operator(
+    incoming: Incoming,
+    ctx: ClientContext,
+    stop: Sender<()>,
+    services: OperatorServices,
+) -> hyper::Result<()> {
+    let make_svc = make_service_fn(|conn: &ClientConnection| {
+        let remote_addr = conn.remote_addr();
+        let ctx = ctx.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let ctx = ctx.clone();
+                async move {
+                    let limits = config::SETTINGS.read().unwrap().limits.request_limits();
+                    if let Err(response) = httplimit::check_headers(request.headers(), &limits) {
+                        return Ok(response);
+                    }
+                    let request = match httplimit::read_body(request, limits.max_body_bytes).await {
+                        Ok(request) => request,
+                        Err(response) => return Ok(response),
+                    };
+                    let response = match request.uri().path() {
+                        "/metrics"
+                            if services.metrics && !ALLOW_READ.contains(request.method()) =>
+                        {
+                            method_not_allowed(ALLOW_READ)
+                        }
+                        "/metrics" if services.metrics => metrics_response(&ctx).await,
+                        path if path.starts_with("/admin/") && services.admin => {
+                            admin_response(request, remote_addr, &ctx).await
+                        }
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from("Nothing here."))
+                            .unwrap(),
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    let mut server_shutdown = stop.subscribe();
+    Server::builder(incoming)
+        .serve(make_svc)
+        .with_graceful_shutdown(async move {
+            server_shutdown.recv().await.ok();
+        })
+        .await
+}
+
+/// What an operator listener serves.
+#[derive(Debug, Default, Clone, Copy)]
+struct OperatorServices {
+    metrics: bool,
+    admin: bool,
+}
+
+/// Addresses of the operator listeners, with what each serves.
+fn operator_listeners(settings: &config::Settings) -> Result<Vec<(SocketAddr, OperatorServices)>> {
+    let parse = |name: &str, address: &str| {
+        address
+            .parse::<SocketAddr>()
+            .map_err(|_| Error::GenericError(format!("invalid {} address {:?}", name, address)))
+    };
+    let mut listeners: Vec<(SocketAddr, OperatorServices)> = vec![];
+    let mut add = |addr: SocketAddr, set: fn(&mut OperatorServices)| match listeners
+        .iter_mut()
+        .find(|(a, _)| *a == addr)
+    {
+        Some((_, services)) => set(services),
+        None => {
+            let mut services = OperatorServices::default();
+            set(&mut services);
+            listeners.push((addr, services));
+        }
+    };
+    if let Some(address) = settings
+        .metrics
+        .address
+        .as_ref()
+        .filter(|_| settings.metrics.enabled)
+    {
+        add(parse("metrics", address)?, |s| s.metrics = true);
+    }
+    if let Some(address) = settings
+        .admin
+        .address
+        .as_ref()
+        .filter(|_| settings.admin.enabled)
+    {
+        let addr = parse("admin", address)?;
+        if !addr.ip().is_loopback() {
+            warn!(
+                "admin API is served on {}, which is not a loopback address",
+                addr
+            );
+        }
+        add(addr, |s| s.admin = true);
+    }
+    Ok(listeners)
+}
+
+/// Compact the SQLite database in `data_directory`, reporting the
+/// space saved.  The relay must not be running.
+fn compact_db(data_directory: &str) -> Result<(), Error> {
+    match db::compact(Path::new(data_directory)) {
+        Ok(report) => {
+            println!(
+                "compacted database from {} to {} bytes ({} saved)",
+                report.size_before,
+                report.size_after,
+                report.size_before.saturating_sub(report.size_after)
+            );
+            Ok(())
+        }
+        Err(e) => {
+            error!("compaction failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Log the progress of a backup.
+fn log_backup_progress(p: db::BackupProgress) {
+    debug!("backup: {} of {} pages remaining", p.remaining, p.pagecount);
+}
+
+/// Copy a snapshot of the database in `data_directory` to `dest`.
+/// Safe to run while the relay is running.
+fn backup_db(data_directory: &str, dest: &str) -> Result<(), Error> {
+    if Path::new(dest).exists() {
+        error!("backup destination {} already exists", dest);
+        return Err(Error::GenericError(format!("{} already exists", dest)));
+    }
+    match db::backup_to(
+        Path::new(data_directory),
+        Path::new(dest),
+        Some(log_backup_progress),
+    ) {
+        Ok(()) => {
+            println!("backed up database to {}", dest);
+            Ok(())
+        }
+        Err(e) => {
+            error!("backup failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Export events from the database in `data_directory` as
+/// line-delimited JSON, to `out` or stdout.  Progress is reported on
+/// stderr.
+fn export_db(data_directory: &str, filter: &db::ExportFilter, out: Option<&str>) -> Result<()> {
+    let progress = |n| eprintln!("exported {} events", n);
+    let result = match out {
+        Some(path) => {
+            if Path::new(path).exists() {
+                error!("export destination {} already exists", path);
+                return Err(Error::GenericError(format!("{} already exists", path)));
+            }
+            let file = std::fs::File::create(path)
+                .map_err(|e| Error::GenericError(format!("could not create {}: {}", path, e)))?;
+            let mut writer = std::io::BufWriter::new(file);
+            db::export_events(Path::new(data_directory), filter, &mut writer, progress)
+        }
+        None => {
+            let stdout = std::io::stdout();
+            let mut writer = std::io::BufWriter::new(stdout.lock());
+            db::export_events(Path::new(data_directory), filter, &mut writer, progress)
+        }
+    };
+    match result {
+        Ok(count) => {
+            eprintln!("exported {} events", count);
+            Ok(())
+        }
+        Err(e) => {
+            error!("export failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Import line-delimited JSON events from `src`, or stdin, into the
+/// database in `data_directory`.  Progress is reported on stderr.
+fn import_db(data_directory: &str, src: Option<&str>) -> Result<()> {
+    let progress = |r: &db::ImportReport| eprintln!("read {} events", r.total());
+    let result = match src {
+        Some(path) => {
+            let file = std::fs::File::open(path)
+                .map_err(|e| Error::GenericError(format!("could not open {}: {}", path, e)))?;
+            db::import_events(
+                Path::new(data_directory),
+                std::io::BufReader::new(file),
+                progress,
+            )
+        }
+        None => {
+            let stdin = std::io::stdin();
+            db::import_events(Path::new(data_directory), stdin.lock(), progress)
+        }
+    };
+    match result {
+        Ok(report) => {
+            eprintln!(
+                "imported {} events ({} duplicates, {} invalid, {} failed)",
+                report.imported, report.duplicates, report.invalid, report.failed
+            );
+            Ok(())
+        }
+        Err(e) => {
+            error!("import failed: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Verify every event in the database in `data_directory`, printing
+/// a summary.  Fails if any invalid event was found.
+fn verify_db(data_directory: &str, delete_invalid: bool) -> Result<()> {
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let report = match db::verify_events(Path::new(data_directory), delete_invalid, workers, |n| {
+        eprintln!("checked {} events", n)
+    }) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("verification failed: {}", e);
+            return Err(e);
+        }
+    };
+    for invalid in &report.invalid {
+        println!("invalid event {}: {}", invalid.event_hash, invalid.reason);
+    }
+    for author in &report.author_mismatches {
+        println!("author statistics out of date for {}", author);
+    }
+    println!(
+        "checked {} events, {} invalid, {} deleted",
+        report.checked,
+        report.invalid.len(),
+        report.deleted
+    );
+    if report.authors_rebuilt {
+        println!("rebuilt author statistics");
+    }
+    if !report.invalid.is_empty() {
+        Err(Error::GenericError(format!(
+            "{} invalid events found",
+            report.invalid.len()
+        )))
+    } else if !report.author_mismatches.is_empty() {
+        Err(Error::GenericError(format!(
+            "statistics for {} authors are out of date",
+            report.author_mismatches.len()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Delete events by id from the database in `data_directory`,
+/// recording tombstones so they are refused if submitted again.
+fn delete_db(data_directory: &str, ids: &[EventId]) -> Result<()> {
+    let outcomes = match db::delete_offline(Path::new(data_directory), ids) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            error!("delete failed: {}", e);
+            return Err(e);
+        }
+    };
+    for (id, outcome) in ids.iter().zip(outcomes) {
+        match (outcome.deleted, outcome.tombstoned) {
+            (true, _) => println!("deleted {}", id),
+            (false, true) => println!("{} was not stored, it will be refused", id),
+            (false, false) => println!("{} was already deleted", id),
+        }
+    }
+    Ok(())
+}
+
+/// Delete events matching `filter` from the database in
+/// `data_directory`, then compact it if `vacuum` is set.  The relay
+/// must not be running.
+fn prune_db(data_directory: &str, filter: &db::PruneFilter, vacuum: bool) -> Result<()> {
+    let report = match db::prune_offline(Path::new(data_directory), filter) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("prune failed: {}", e);
+            return Err(e);
+        }
+    };
+    println!(
+        "deleted {} events, {} pages free",
+        report.deleted, report.free_pages
+    );
+    if vacuum {
+        compact_db(data_directory)?;
+    }
+    Ok(())
+}
+
+/// Resolve `dir` against the working directory, so that messages
+/// name the directory actually used.
+fn absolute_path(dir: &Path) -> PathBuf {
+    if dir.is_absolute() {
+        dir.to_owned()
+    } else {
+        env::current_dir()
+            .map(|cwd| cwd.join(dir))
+            .unwrap_or_else(|_| dir.to_owned())
+    }
+}
+
+/// Check the data directory before anything is started, returning
+/// one message per problem found.
+///
+/// The directory is created, readable only by the relay user, when it
+/// is missing and `auto_create` is set.  When `writes` is set, the
+/// directory and any existing database files must be writable by the
+/// current user, and at least `min_free_mb` megabytes must be free.
+fn preflight_data_directory(
+    dir: &Path,
+    auto_create: bool,
+    writes: bool,
+    min_free_mb: u64,
+) -> Vec<String> {
+    let dir = absolute_path(dir);
+    let mut problems = Vec::new();
+    if !dir.exists() {
+        if !writes {
+            problems.push(format!(
+                "data directory {} does not exist; check the --db argument",
+                dir.display()
+            ));
+            return problems;
+        }
+        if !auto_create {
+            problems.push(format!(
+                "data directory {} does not exist; create it, or set database.auto_create_dir",
+                dir.display()
+            ));
+            return problems;
+        }
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        if let Err(e) = builder.create(&dir) {
+            problems.push(format!(
+                "could not create data directory {}: {}; check the permissions of its parent",
+                dir.display(),
+                e
+            ));
+            return problems;
+        }
+        info!("created data directory {}", dir.display());
+    } else if !dir.is_dir() {
+        problems.push(format!(
+            "data directory {} exists but is not a directory; point --db at a directory",
+            dir.display()
+        ));
+        return problems;
+    }
+    // database files left by another user (often root) cannot be
+    // opened by the writer
+    for name in db::DB_FILES {
+        let path = dir.join(name);
+        if !path.exists() {
+            continue;
+        }
+        let opened = std::fs::OpenOptions::new()
+            .read(true)
+            .write(writes)
+            .open(&path);
+        if let Err(e) = opened {
+            problems.push(format!(
+                "cannot open {} for {}: {}; check its owner and permissions",
+                path.display(),
+                if writes {
+                    "reading and writing"
+                } else {
+                    "reading"
+                },
+                e
+            ));
+        }
+    }
+    if !writes {
+        return problems;
+    }
+    let probe = dir.join(format!(".nostrd-write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            std::fs::remove_file(&probe).ok();
+        }
+        Err(e) => problems.push(format!(
+            "data directory {} is not writable: {}; check its owner and permissions",
+            dir.display(),
+            e
+        )),
+    }
+    match fs2::available_space(&dir) {
+        Ok(available) if available < min_free_mb * 1024 * 1024 => problems.push(format!(
+            "only {} MB free in data directory {}, at least {} MB are needed; free some space, \
+             or lower database.min_free_space_mb",
+            available / (1024 * 1024),
+            dir.display(),
+            min_free_mb
+        )),
+        Ok(_) => {}
+        Err(e) => warn!(
+            "could not check free space in data directory {}: {}",
+            dir.display(),
+            e
+        ),
+    }
+    problems
 }
 
 /// Start running a Nostr relay server.
-fn main() -> Result<(), Error> {
-    // setup logger
-    let _ = env_logger::try_init();
-    // get database directory from args
-    let args: Vec<String> = env::args().collect();
-    let db_dir: Option<String> = db_from_args(args);
+fn main() {
+    if let Err(e) = run() {
+        // report errors to the operator without a debug dump
+        eprintln!("nostrd: {}", e);
+        let status = match e {
+            Error::ShutdownAbandoned(_) => shutdown::EXIT_ABANDONED,
+            _ => 1,
+        };
+        std::process::exit(status);
+    }
+}
+
+/// Run the mode requested on the command line.
+fn run() -> Result<(), Error> {
+    // options for every command may appear anywhere; usage errors
+    // and --help are reported here, and exit
+    let Cli {
+        options: cli,
+        command,
+    } = Cli::parse();
+    // setup logger, with the command line overriding RUST_LOG; the
+    // log settings apply once they are read
+    logging::init(cli.log_level.as_deref())?;
+    if let Some(path) = &cli.config {
+        config::set_config_file(path);
+    }
     {
         let mut settings = config::SETTINGS.write().unwrap();
-        // replace default settings with those read from config.toml
-        let mut c = config::Settings::new();
-        // update with database location
-        if let Some(db) = db_dir {
-            c.database.data_directory = db;
-        }
+        // replace default settings with those read from the
+        // configuration file and environment, then the command line
+        let mut c = config::Settings::load()?;
+        cli.apply(&mut c);
+        config::set_read_only(c.relay.read_only);
+        logging::configure(&c)?;
         *settings = c;
     }
+    // talking to a running relay needs no database
+    if let Some(Command::Notice(notice)) = &command {
+        return notice_relay(notice.command());
+    }
+    // every mode opening the database needs the key
+    db::load_encryption_key()?;
+    config::SETTINGS
+        .read()
+        .unwrap()
+        .database
+        .pragmas
+        .validate()?;
 
     let config = config::SETTINGS.read().unwrap();
-    // do some config validation.
-    if !Path::new(&config.database.data_directory).is_dir() {
-        error!("Database directory does not exist");
+    // check the data directory before anything opens the database
+    let writes = command.as_ref().map_or(true, Command::writes);
+    let problems = preflight_data_directory(
+        Path::new(&config.database.data_directory),
+        config.database.auto_create_dir,
+        writes,
+        config.database.min_free_space_mb,
+    );
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("nostrd: {}", problem);
+        }
         return Err(Error::DatabaseDirError);
     }
+    let data_directory = &config.database.data_directory;
+    match command {
+        None => {}
+        Some(Command::Compact) => return compact_db(data_directory),
+        Some(Command::Backup { dest }) => return backup_db(data_directory, &dest),
+        Some(Command::Export(export)) => {
+            return export_db(data_directory, &export.filter(), export.out.as_deref())
+        }
+        Some(Command::Import { file }) => {
+            return import_db(data_directory, file.as_deref().filter(|f| *f != "-"))
+        }
+        Some(Command::Prune(prune)) => {
+            return prune_db(data_directory, &prune.filter(), prune.vacuum)
+        }
+        Some(Command::Verify { delete_invalid }) => {
+            return verify_db(data_directory, delete_invalid)
+        }
+        Some(Command::Delete { ids }) => return delete_db(data_directory, &ids),
+        Some(Command::EncryptDb) => return db::encrypt_db(Path::new(data_directory)),
+        Some(Command::Notice(_)) => unreachable!("handled before the database is checked"),
+    }
+    if let Err(e) = db::RetentionPolicy::from_config(&config.retention) {
+        error!("Invalid retention configuration: {}", e);
+        return Err(e);
+    }
+    config.runtime.check()?;
+    info!("{}", version::banner());
     debug!("config: {:?}", config);
-    let addr = format!("{}:{}", config.network.address.trim(), config.network.port);
-    let socket_addr = addr.parse().expect("listening address not valid");
-    // configure tokio runtime
-    let rt = Builder::new_multi_thread()
-        .enable_all()
-        .thread_name("tokio-ws")
+    let client_listeners = config.network.client_listeners()?;
+    config.network.trusted_proxies()?;
+    // configure tokio runtime, with its defaults for anything unset
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("tokio-ws");
+    if let Some(threads) = config.runtime.worker_threads {
+        builder.worker_threads(threads);
+    }
+    let max_blocking_threads = config
+        .runtime
+        .max_blocking_threads
+        .unwrap_or(config::DEFAULT_MAX_BLOCKING_THREADS);
+    builder.max_blocking_threads(max_blocking_threads);
+    let rt = builder
         .build()
-        .unwrap();
+        .map_err(|e| Error::GenericError(format!("could not start the runtime: {}", e)))?;
+    info!(
+        "runtime has {} worker thread(s) and up to {} blocking thread(s)",
+        rt.metrics().num_workers(),
+        max_blocking_threads
+    );
+    if let Some(warning) = config
+        .runtime
+        .blocking_threads_warning(config.limits.max_total_subscriptions)
+    {
+        warn!("{}", warning);
+    }
+    // settings can be reloaded once the relay is running
+    drop(config);
     // start tokio
     rt.block_on(async {
+        // take what startup needs from the settings before anything
+        // awaits: a reload waiting on the lock would otherwise hold up
+        // every other reader until the relay finished starting
         let settings = config::SETTINGS.read().unwrap();
-        info!("listening on: {}", socket_addr);
+        info::load_landing_page(&settings)?;
+        whitelist::load(&settings)?;
+        let write_batch_size = settings.database.write_batch_size;
+        let broadcast_buffer = settings.limits.broadcast_buffer;
+        let event_persist_buffer = settings.limits.event_persist_buffer;
+        let max_total_subscriptions = settings.limits.max_total_subscriptions;
+        let data_directory = settings.database.data_directory.clone();
+        let proxies = Arc::new(settings.network.trusted_proxies()?);
+        let connection_hooks = hooks::from_config(&settings.hooks)?;
+        let mirror_config = settings.mirror.clone();
+        let log_interval_secs = settings.stats.log_interval_secs;
+        let proxy_protocol = settings.network.proxy_protocol;
+        let ipv6_only = settings.network.ipv6_only;
+        let bind_retry = settings.network.bind_retry;
+        let operators = operator_listeners(&settings)?;
+        let drain = Duration::from_secs(settings.network.shutdown_grace_secs);
+        let grace = Duration::from_secs(settings.database.shutdown_grace_seconds);
+        drop(settings);
+        // open the storage backend and bring its schema up to date
+        let storage = db::storage_from_settings()?;
+        storage.migrate().await?;
+        // limit on database size, enforced by maintenance
+        let budget = Arc::new(db::SizeBudget::from_settings());
+        // overflow for events the writer cannot keep up with; anything
+        // left from the last run is stored before clients connect
+        let spool = db::Spool::from_settings()?.map(Arc::new);
+        if let Some(spool) = &spool {
+            db::replay_spool(storage.as_ref(), spool, write_batch_size).await?;
+        }
+        // ids of recently stored events, for cheap duplicate checks
+        let recent = Arc::new(db::RecentIds::from_settings());
+        recent.load(storage.as_ref()).await?;
+        if config::is_read_only() {
+            info!("relay is read-only, new events will be refused");
+        }
         // all client-submitted valid events are broadcast to every
         // other client on this channel.  This should be large enough
         // to accomodate slower readers (messages are dropped if
         // clients can not keep up).
-        let (bcast_tx, _) = broadcast::channel::<Event>(settings.limits.broadcast_buffer);
+        let (bcast_tx, _) = broadcast::channel::<BroadcastEvent>(broadcast_buffer);
+        info!(
+            "broadcast buffer holds {} events (limits.broadcast_buffer)",
+            broadcast_buffer
+        );
         // validated events that need to be persisted are sent to the
         // database on via this channel.
-        let (event_tx, event_rx) = mpsc::channel::<Event>(settings.limits.event_persist_buffer);
+        let (event_tx, event_rx) = mpsc::channel::<db::SubmittedEvent>(event_persist_buffer);
         // establish a channel for letting all threads now about a
         // requested server shutdown.
         let (invoke_shutdown, _) = broadcast::channel::<()>(1);
-        let ctrl_c_shutdown = invoke_shutdown.clone();
-        // // listen for ctrl-c interruupts
+        // shut down when asked to by a signal, or right away if asked
+        // again while shutting down
+        let mut stop_signals = StopSignals::new()?;
+        let signal_shutdown = invoke_shutdown.clone();
         tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.unwrap();
-            info!("shutting down due to SIGINT");
-            ctrl_c_shutdown.send(()).ok();
+            let signal = stop_signals.recv().await;
+            info!("shutting down due to {}", signal);
+            systemd::stopping();
+            signal_shutdown.send(()).ok();
+            let signal = stop_signals.recv().await;
+            warn!("{} received while shutting down, exiting now", signal);
+            std::process::exit(shutdown::EXIT_ABANDONED);
         });
+        // the database writer is stopped separately, once clients
+        // have been told about a shutdown.
+        let (writer_shutdown, _) = broadcast::channel::<()>(1);
         // start the database writer thread.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
-        db::db_writer(event_rx, bcast_tx.clone(), invoke_shutdown.subscribe()).await;
+        let mut writer = db::db_writer(
+            storage.clone(),
+            event_rx,
+            spool.clone(),
+            recent.clone(),
+            bcast_tx.clone(),
+            budget.clone(),
+            writer_shutdown.subscribe(),
+        )
+        .await;
         info!("db writer created");
-        // A `Service` is needed for every connection, so this
-        // creates one from our `handle_request` function.
-        let make_svc = make_service_fn(|conn: &AddrStream| {
-            let remote_addr = conn.remote_addr();
-            let bcast = bcast_tx.clone();
-            let event = event_tx.clone();
-            let stop = invoke_shutdown.clone();
-            async move {
-                // service_fn converts our function into a `Service`
-                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                    handle_web_request(
-                        request,
-                        remote_addr,
-                        bcast.clone(),
-                        event.clone(),
-                        stop.subscribe(),
-                    )
-                }))
+        let events = db::EventQueue::new(event_tx, spool);
+        // periodically prune old events and tidy the database.
+        db::db_maintenance(
+            storage.clone(),
+            budget.clone(),
+            recent.clone(),
+            invoke_shutdown.subscribe(),
+        )
+        .await;
+        // check the NIP-05 identifiers of authors, if enabled
+        if let Some(verifier) = nostrd::nip05::Verifier::from_settings(storage.clone()) {
+            tokio::spawn(verifier.run(bcast_tx.subscribe(), invoke_shutdown.subscribe()));
+        }
+        // subscriptions allowed across all connections
+        let sub_budget = Arc::new(conn::SubscriptionBudget::new(max_total_subscriptions));
+        let registry = Arc::new(ConnectionRegistry::new());
+        // dump the connection table to the log on request
+        #[cfg(unix)]
+        {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::user_defined1()) {
+                    Ok(mut usr1) => {
+                        while usr1.recv().await.is_some() {
+                            registry.log_table();
+                        }
+                    }
+                    Err(e) => warn!("could not listen for SIGUSR1: {}", e),
+                }
+            });
+        }
+        let bans = Arc::new(Bans::load(Path::new(&data_directory))?);
+        let ctx = ClientContext {
+            broadcast: bcast_tx.clone(),
+            events: events.clone(),
+            storage: storage.clone(),
+            sub_budget,
+            registry: registry.clone(),
+            tasks: Arc::new(ConnectionTasks::default()),
+            proxies,
+            recent,
+            bans: bans.clone(),
+            connection_rate: Arc::new(ConnectionRateLimiter::default()),
+            stats: Arc::new(StatsReporter::new()),
+            hooks: connection_hooks,
+            firehose: Arc::new(FirehoseConsumers::default()),
+            mirrors: mirror::start(&mirror_config, &events, &bans, &invoke_shutdown)?.into(),
+            relay_url: None,
+        };
+        // a snapshot in the log, for operators who do not scrape /stats
+        if log_interval_secs > 0 {
+            tokio::spawn(log_stats(
+                ctx.clone(),
+                Duration::from_secs(log_interval_secs),
+                invoke_shutdown.subscribe(),
+            ));
+        }
+        tokio::spawn(log_websocket_summary(
+            ctx.clone(),
+            WEBSOCKET_SUMMARY_INTERVAL,
+            invoke_shutdown.subscribe(),
+        ));
+        // serve plain HTTP, TLS, or both
+        let mut servers = vec![];
+        if proxy_protocol != ProxyProtocol::Off {
+            info!("expecting PROXY protocol {:?} headers", proxy_protocol);
+        }
+        // bind, logging the address as resolved, with the port chosen
+        // for port 0, and whether IPv6 listeners take IPv4 clients too
+        let listen = |addr: SocketAddr, what: &'static str| async move {
+            let listener = listener::bind_with_retry(addr, ipv6_only, bind_retry).await?;
+            let local = listener.local_addr().unwrap_or(addr);
+            let stack = match addr {
+                SocketAddr::V6(_) if ipv6_only => " (IPv6 only)",
+                SocketAddr::V6(_) => " (IPv6 and IPv4)",
+                SocketAddr::V4(_) => "",
+            };
+            info!("{} on: {}{}", what, local, stack);
+            Ok::<_, Error>(listener)
+        };
+        // one acceptor for each certificate, however many listeners
+        // serve it
+        let mut acceptors: Vec<(config::TlsFiles, Arc<ReloadableAcceptor>)> = vec![];
+        for files in client_listeners.iter().filter_map(|l| l.tls.as_ref()) {
+            if !acceptors.iter().any(|(loaded, _)| loaded == files) {
+                let acceptor = ReloadableAcceptor::new(
+                    Path::new(&files.cert_path),
+                    Path::new(&files.key_path),
+                )?;
+                acceptors.push((files.clone(), Arc::new(acceptor)));
             }
-        });
-        let server = Server::bind(&socket_addr)
-            .serve(make_svc)
-            .with_graceful_shutdown(shutdown_signal());
-        // run hyper
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
-        }
-        // our code
-    });
-    Ok(())
+        }
+        // pick up configuration changes and renewed certificates on
+        // request
+        #[cfg(unix)]
+        {
+            let acceptors = acceptors.clone();
+            let cli = cli.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::hangup()) {
+                    Ok(mut hup) => {
+                        while hup.recv().await.is_some() {
+                            reload_config(&cli);
+                            for (files, acceptor) in &acceptors {
+                                match acceptor.reload() {
+                                    Ok(()) => {
+                                        info!("reloaded TLS certificate {}", files.cert_path)
+                                    }
+                                    Err(e) => warn!("kept previous TLS certificate: {}", e),
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => warn!("could not listen for SIGHUP: {}", e),
+                }
+            });
+        }
+        // every listener shares the relay's channels and state,
+        // differing only in TLS and the relay URL announced
+        for client_listener in &client_listeners {
+            let acceptor = client_listener.tls.as_ref().and_then(|files| {
+                acceptors
+                    .iter()
+                    .find(|(loaded, _)| loaded == files)
+                    .map(|(_, acceptor)| acceptor.clone())
+            });
+            let what = match acceptor {
+                Some(_) => "listening for TLS",
+                None => "listening",
+            };
+            let listener = listen(client_listener.addr, what).await?;
+            let options = ListenerOptions {
+                proxy_protocol,
+                tls: acceptor,
+            };
+            let incoming = listener::incoming(listener, options);
+            let ctx = ClientContext {
+                relay_url: client_listener.relay_url.as_deref().map(Arc::from),
+                ..ctx.clone()
+            };
+            servers.push(serve(incoming, ctx, invoke_shutdown.clone()).boxed());
+        }
+        // metrics and the admin API can be kept off the public listeners
+        for (addr, services) in operators {
+            let what = match (services.metrics, services.admin) {
+                (true, true) => "serving metrics and admin API",
+                (true, false) => "serving metrics",
+                _ => "serving admin API",
+            };
+            let listener = listen(addr, what).await?;
+            let options = ListenerOptions {
+                proxy_protocol: ProxyProtocol::Off,
+                tls: None,
+            };
+            let incoming = listener::incoming(listener, options);
+            servers.push(
+                serve_operator(incoming, ctx.clone(), invoke_shutdown.clone(), services).boxed(),
+            );
+        }
+        // the database is up and every listener is bound
+        systemd::ready();
+        if let Some(interval) = systemd::watchdog_interval() {
+            info!("notifying the systemd watchdog every {:?}", interval);
+            tokio::spawn(notify_watchdog(
+                storage.clone(),
+                interval,
+                invoke_shutdown.subscribe(),
+            ));
+        }
+        // every listener finishes its graceful shutdown
+        let server = futures::future::join_all(servers);
+        tokio::pin!(server);
+        let report_errors = |results: Vec<hyper::Result<()>>| {
+            for e in results.into_iter().filter_map(|res| res.err()) {
+                eprintln!("server error: {}", e);
+            }
+        };
+        // run hyper, stopping if the database writer gives up
+        let mut writer_result = None;
+        tokio::select! {
+            results = &mut server => report_errors(results),
+            res = &mut writer => {
+                error!("database writer stopped, shutting down the relay");
+                systemd::stopping();
+                invoke_shutdown.send(()).ok();
+                writer_result = Some(res);
+                report_errors(server.await);
+            },
+        }
+        // let connections say goodbye to their clients
+        invoke_shutdown.send(()).ok();
+        if !registry.is_empty() {
+            info!("closing {} connection(s)", registry.len());
+        }
+        // anything still running after the grace period is aborted,
+        // cancelling its queries
+        let abandoned_before = db::abandoned_queries();
+        let aborted = ctx.tasks.drain(drain).await;
+        let mut report = ShutdownReport {
+            aborted_connections: aborted,
+            abandoned_queries: db::abandoned_queries() - abandoned_before,
+            unstored_events: None,
+        };
+        if aborted > 0 {
+            warn!(
+                "aborted {} connection(s) still open after {:?}",
+                aborted, drain
+            );
+        }
+        // let the writer store queued events, then flush and close
+        // the database, within the grace period
+        writer_shutdown.send(()).ok();
+        info!("stopped accepting events, shutting down within {:?}", grace);
+        let teardown = async {
+            let writer_result = match writer_result {
+                Some(res) => res,
+                None => writer.await,
+            };
+            info!("closing database");
+            storage.close().await?;
+            Ok::<_, Error>(writer_result)
+        };
+        let writer_result = match tokio::time::timeout(grace, teardown).await {
+            Ok(res) => res?,
+            Err(_) => {
+                error!("database did not shut down within {:?}", grace);
+                report.unstored_events = Some(ctx.events.in_memory());
+                warn!("shutdown abandoned work: {}", report);
+                return Err(Error::ShutdownAbandoned(report));
+            }
+        };
+        info!("database closed");
+        if report.abandoned() {
+            warn!("shutdown abandoned work: {}", report);
+        }
+        match writer_result {
+            Ok(Ok(())) if report.abandoned() => Err(Error::ShutdownAbandoned(report)),
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(Error::GenericError(format!(
+                "database writer failed: {}",
+                e
+            ))),
+        }
+    })
 }
 
 /// Handle new client connections.  This runs through an event loop
 /// for all client communication.
 async fn nostr_server(
-    ws_stream: WebSocketStream<Upgraded>,
-    broadcast: Sender<Event>,
-    event_tx: tokio::sync::mpsc::Sender<Event>,
+    ws_stream: protostream::WsStream,
+    remote_addr: SocketAddr,
+    headers: conn::ClientHeaders,
+    ctx: ClientContext,
+    _slot: ConnectionSlot,
     mut shutdown: Receiver<()>,
 ) {
+    let ClientContext {
+        broadcast,
+        events,
+        storage,
+        sub_budget,
+        registry,
+        bans,
+        hooks,
+        relay_url,
+        ..
+    } = ctx;
     // get a broadcast channel for clients to communicate on
     let mut bcast_rx = broadcast.subscribe();
+    // size of the broadcast channel, suggested for raising when
+    // clients fall behind it
+    let broadcast_buffer = config::SETTINGS.read().unwrap().limits.broadcast_buffer;
     // upgrade the TCP connection to WebSocket
     //let conn = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await;
     //let ws_stream = conn.expect("websocket handshake error");
-    // wrap websocket into a stream & sink of Nostr protocol messages
-    let mut nostr_stream = protostream::wrap_ws_in_nostr(ws_stream);
+    // wrap websocket into a stream of Nostr protocol messages, with
+    // responses written from a queue by a separate task
+    let (ws_sink, mut nostr_stream) = protostream::wrap_ws_in_nostr(ws_stream);
+    let (send_queue_size, send_timeout) = {
+        let network = &config::SETTINGS.read().unwrap().network;
+        (
+            network.send_queue_size,
+            Duration::from_secs(network.send_timeout_secs),
+        )
+    };
+    let outbound = Arc::new(OutboundQueue::new(send_queue_size));
+    let mut writer = {
+        let outbound = outbound.clone();
+        tokio::spawn(
+            async move { write_outbound(&outbound, ws_sink, send_timeout).await }.in_current_span(),
+        )
+    };
+    // the writer stops however this task ends
+    let _finish_outbound = FinishOnDrop(outbound.clone());
     // Track internal client state
-    let mut conn = conn::ClientConn::new();
+    let (max_subs, max_filters, sub_rate, sub_ttl, enqueue_wait, protocol_errors, mut inbound) = {
+        let limits = &config::SETTINGS.read().unwrap().limits;
+        (
+            limits.max_subscriptions_per_connection,
+            limits.max_filters,
+            (limits.subscriptions_per_min, limits.subscription_burst),
+            Duration::from_secs(limits.subscription_ttl_secs),
+            Duration::from_millis(limits.persist_enqueue_timeout_ms),
+            limits.protocol_errors,
+            conn::InboundLimiter::new(
+                limits.conn_events_per_sec,
+                limits.conn_requests_per_sec,
+                limits.conn_throttle_disconnect,
+            ),
+        )
+    };
+    let mut conn = conn::ClientConn::with_limits(max_subs, max_filters)
+        .with_subscription_rate(sub_rate.0, sub_rate.1)
+        .with_subscription_ttl(sub_ttl)
+        .with_remote_addr(remote_addr)
+        .with_headers(headers)
+        .with_budget(sub_budget);
     let cid = conn.get_client_prefix();
+    tracing::Span::current().record("client_id", cid.as_str());
+    let hook_info = ConnectionInfo {
+        client_id: cid.clone(),
+        address: Some(remote_addr.ip()),
+        headers: conn.headers().clone(),
+    };
+    hooks.on_connect(&hook_info);
+    // when only their own events are accepted, clients are challenged
+    // to authenticate, signing for the URL they connected to
+    let (publish_own_only, relay_url) = {
+        let settings = config::SETTINGS.read().unwrap();
+        (
+            settings.authorization.publish_own_only,
+            relay_url
+                .as_deref()
+                .map(str::to_owned)
+                .or_else(|| settings.info.relay_url.clone()),
+        )
+    };
+    if publish_own_only {
+        outbound.send(NostrResponse::new_auth(conn.auth_challenge()));
+    }
+    // remote address recorded with submitted events, if enabled
+    let source_ip = config::SETTINGS
+        .read()
+        .unwrap()
+        .database
+        .log_event_source
+        .then(|| remote_addr.ip().to_string());
     // Create a channel for receiving query results from the database.
     // we will send out the tx handle to any query we generate.
-    let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(256);
-    // maintain a hashmap of a oneshot channel for active subscriptions.
-    // when these subscriptions are cancelled, make a message
-    // available to the executing query so it knows to stop.
-    //let (abandon_query_tx, _) = oneshot::channel::<()>();
-    let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
-    // for stats, keep track of how many events the client published,
-    // and how many it received from queries.
-    let mut client_published_event_count: usize = 0;
-    let mut client_received_event_count: usize = 0;
-    info!("new connection for client: {}", cid);
+    let query_result_buffer = config::SETTINGS
+        .read()
+        .unwrap()
+        .limits
+        .query_result_buffer
+        .max(1);
+    let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(query_result_buffer);
+    let mut query_backlog = conn::QueryBacklog::new(query_result_buffer);
+    // Create a channel for receiving the outcome of events this
+    // client submitted, keyed by event id.
+    let (write_result_tx, mut write_result_rx) = mpsc::channel::<(String, WriteResult)>(256);
+    // keep track of the queries for active subscriptions.  when
+    // these subscriptions are cancelled or replaced, the executing
+    // query is told to stop.
+    let mut running_queries = db::RunningQueries::default();
+    // connections that stay silent for too long are closed
+    let (idle_timeout, idle_counts_outbound) = {
+        let network = &config::SETTINGS.read().unwrap().network;
+        (
+            Duration::from_secs(network.idle_timeout_secs),
+            network.idle_counts_outbound,
+        )
+    };
+    let mut idle_check =
+        tokio::time::interval(Duration::from_secs((idle_timeout.as_secs() / 4).max(1)));
+    let mut last_sent = Instant::now();
+    // clients are pinged so quiet connections stay open, and dead
+    // peers are noticed
+    let (ping_interval, max_missed_pongs) = {
+        let network = &config::SETTINGS.read().unwrap().network;
+        (
+            Duration::from_secs(network.ping_interval_secs),
+            network.max_missed_pongs,
+        )
+    };
+    let mut ping_tick = tokio::time::interval_at(
+        tokio::time::Instant::now() + ping_interval,
+        ping_interval.max(Duration::from_secs(1)),
+    );
+    let mut keepalive = conn::Keepalive::new(max_missed_pongs);
+    // subscriptions can be closed once they are old enough
+    let mut sub_expiry =
+        tokio::time::interval(Duration::from_secs((sub_ttl.as_secs() / 4).clamp(1, 60)));
+    // long-lived connections can have their statistics logged
+    let stats_interval = Duration::from_secs(
+        config::SETTINGS
+            .read()
+            .unwrap()
+            .network
+            .stats_log_interval_secs,
+    );
+    let mut stats_log = tokio::time::interval_at(
+        tokio::time::Instant::now() + stats_interval,
+        stats_interval.max(Duration::from_secs(1)),
+    );
+    // connections can be limited in how much they transfer
+    let mut bandwidth_cap = conn::BandwidthCap::new(
+        config::SETTINGS
+            .read()
+            .unwrap()
+            .limits
+            .max_bytes_per_connection_per_hour,
+    );
+    let mut bandwidth_check = tokio::time::interval(Duration::from_secs(1));
+    // list the connection for operators while it is open
+    let mut registration = registry.register(
+        conn.client_id(),
+        remote_addr,
+        conn.headers().clone(),
+        outbound.clone(),
+    );
+    let mut registry_refresh = tokio::time::interval(REGISTRY_REFRESH);
+    let mut shutting_down = false;
+    info!("new connection for {}{}", conn.log_label(), conn.headers());
     loop {
         tokio::select! {
             _ = shutdown.recv() => {
-                // server shutting down, exit loop
+                // server shutting down, tell the client and exit loop
+                conn.stats_mut().notices_sent += 1;
+                outbound.send(NostrResponse::new_notice(SHUTDOWN_MESSAGE));
+                outbound.finish(Some(SHUTDOWN_MESSAGE));
+                shutting_down = true;
+                break;
+            },
+            _ = sub_expiry.tick(), if !sub_ttl.is_zero() => {
+                for id in conn.expire_subscriptions() {
+                    let id = id.to_string();
+                    debug!("subscription {} expired for client: {}", id, cid);
+                    running_queries.cancel(&id);
+                    outbound.send(NostrResponse::new_closed(&id, "error: subscription expired, please re-subscribe"));
+                }
+            },
+            _ = idle_check.tick(), if !idle_timeout.is_zero() => {
+                let mut idle = nostr_stream.idle_for();
+                if idle_counts_outbound {
+                    idle = idle.min(last_sent.elapsed());
+                }
+                if idle >= idle_timeout {
+                    info!("closing idle connection for {} (no activity for {}s)", conn.log_label(), idle.as_secs());
+                    outbound.finish(Some("idle timeout"));
+                    break;
+                }
+            },
+            _ = ping_tick.tick(), if !ping_interval.is_zero() => {
+                if let Some((payload, at)) = nostr_stream.take_pong() {
+                    keepalive.pong(&payload, at);
+                    conn.stats_mut().ping_rtt_ms = keepalive.rtt().map(|rtt| rtt.as_millis() as u64);
+                }
+                match keepalive.tick() {
+                    conn::PingAction::Send(payload) => outbound.ping(payload),
+                    conn::PingAction::Dead => {
+                        info!("closing connection for {} after {} unanswered pings", conn.log_label(), max_missed_pongs);
+                        outbound.finish(Some("ping timeout"));
+                        break;
+                    }
+                }
+            },
+            _ = bandwidth_check.tick(), if bandwidth_cap.is_enabled() => {
+                let total = nostr_stream.bytes_received() + outbound.bytes_written();
+                if bandwidth_cap.exceeded(total) {
+                    info!("closing connection for {} over bandwidth limit ({} bytes transferred)", conn.log_label(), total);
+                    conn.stats_mut().notices_sent += 1;
+                    outbound.send(NostrResponse::new_notice("bandwidth limit exceeded"));
+                    outbound.finish(Some("bandwidth limit exceeded"));
+                    break;
+                }
+            },
+            reason = registration.disconnect_requested() => {
+                info!("disconnecting {} at operator request: {}", conn.log_label(), reason);
+                outbound.finish(Some(&reason));
                 break;
             },
-            Some(query_result) = query_rx.recv() => {
-                // database informed us of a query result we asked for
+            _ = registry_refresh.tick() => {
+                let stats = conn.stats_mut();
+                stats.bytes_in = nostr_stream.bytes_received();
+                stats.bytes_out = outbound.bytes_written();
+                stats.bytes_saved_by_compression = nostr_stream.bytes_saved_by_compression();
+                registration.update(conn.subscription_count(), conn.stats());
+            },
+            _ = stats_log.tick(), if !stats_interval.is_zero() => {
+                let stats = conn.stats_mut();
+                stats.bytes_in = nostr_stream.bytes_received();
+                stats.bytes_out = outbound.bytes_written();
+                stats.bytes_saved_by_compression = nostr_stream.bytes_saved_by_compression();
+                info!("connection stats for {}: {}", conn.log_label(), conn.stats());
+            },
+            exit = &mut writer => {
+                // responses can no longer be written
+                match exit {
+                    Ok(WriterExit::SlowClient { depth }) => {
+                        info!("write timed out, disconnecting slow client: {} (queue depth {})", conn.log_label(), depth);
+                    },
+                    Ok(WriterExit::QueueFull { depth }) => {
+                        info!("send queue full, disconnecting slow client: {} (queue depth {})", conn.log_label(), depth);
+                    },
+                    Ok(_) => debug!("connection writer stopped for client: {}", cid),
+                    Err(e) => warn!("connection writer failed for client: {}: {}", cid, e),
+                }
+                break;
+            },
+            // query results wait while the client catches up
+            Some(query_result) = query_rx.recv(), if outbound.has_room() => {
+                // database informed us of a query result we asked for.
+                // results queued by a superseded query are dropped.
+                let depth = query_rx.len() + 1;
+                conn.stats_mut().record_query_depth(depth);
+                if query_backlog.observe(depth) {
+                    info!("client {} is reading query results too slowly", cid);
+                    conn.stats_mut().notices_sent += 1;
+                    outbound.send(NostrResponse::new_notice("query results are backing up, read events faster"));
+                }
+                if !running_queries.is_current(&query_result) {
+                    continue;
+                }
                 let res = NostrResponse::new_event(&query_result.sub_id, &query_result.event);
-                client_received_event_count += 1;
-                nostr_stream.send(res).await.ok();
+                conn.stats_mut().query_events_sent += 1;
+                last_sent = Instant::now();
+                outbound.send(res);
+            },
+            Some((event_id, result)) = write_result_rx.recv() => {
+                // the database writer finished with an event we submitted
+                conn.stats_mut().record_write(&result);
+                METRICS.record_write(&result);
+                outbound.send(NostrResponse::new_ok(&event_id, result.is_accepted(), &result.message()));
             },
-            Ok(global_event) = bcast_rx.recv() => {
+            received = bcast_rx.recv() => {
                 // an event has been broadcast to all clients
+                let global_event = match received {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        info!("client {} fell behind and missed {} broadcast event(s)", cid, skipped);
+                        let lags = METRICS.broadcast_lagged(skipped);
+                        if lags >= LAG_WARNING_THRESHOLD && lags.is_power_of_two() {
+                            warn!("connections have fallen behind the broadcast channel {} times; consider raising limits.broadcast_buffer (currently {})", lags, broadcast_buffer);
+                        }
+                        let notice = conn.stats_mut().record_broadcast_lag(skipped);
+                        outbound.send(NostrResponse::new_notice(&notice));
+                        continue;
+                    },
+                    // the relay is stopping
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
                 // first check if there is a subscription for this event.
                 let matching_subs = conn.get_matching_subscriptions(&global_event);
+                let mut broadcast_sent = 0;
                 for s in matching_subs {
-                    // TODO: serialize at broadcast time, instead of
-                    // once for each consumer.
-                    if let Ok(event_str) = serde_json::to_string(&global_event) {
-                        debug!("sub match: client: {}, sub: {}, event: {}",
-                               cid, s,
-                               global_event.get_short_event_id());
-                        // create an event response and send it
-                        let event = Event::from_str(&event_str).unwrap();
-                        nostr_stream.send(NostrResponse::new_event(s.to_string().as_ref(), &event)).await.ok();
-                    } else {
-                        warn!("could not convert event to string");
+                    debug!("sub match: client: {}, sub: {}, event: {}",
+                           cid, s,
+                           global_event.get_short_event_id());
+                    // the event is serialized once, by whichever
+                    // connection needs it first
+                    last_sent = Instant::now();
+                    if outbound.send_broadcast(NostrResponse::new_serialized_event(s.to_string().as_ref(), &global_event)) {
+                        broadcast_sent += 1;
                     }
                 }
+                conn.stats_mut().broadcast_events_sent += broadcast_sent;
             },
             // check if this client has a subscription
             proto_next = nostr_stream.next() => {
+                // apply this client's message rate limits
+                let kind = match &proto_next {
+                    Some(Ok(NostrMessage::Event(_))) => Some(conn::MessageKind::Event),
+                    Some(Ok(_)) => Some(conn::MessageKind::Request),
+                    _ => None,
+                };
+                match kind.map(|k| inbound.check(k)) {
+                    Some(conn::Throttle::Drop { notify }) => {
+                        if notify {
+                            conn.stats_mut().notices_sent += 1;
+                            outbound.send(NostrResponse::new_notice("rate-limited: slow down, messages are being dropped"));
+                        }
+                        continue;
+                    },
+                    Some(conn::Throttle::Disconnect) => {
+                        info!("disconnecting {} for exceeding message rate limits", conn.log_label());
+                        outbound.finish(Some("rate limit exceeded"));
+                        break;
+                    },
+                    _ => {},
+                }
+                // messages the relay could not handle, counted
+                // against limits.protocol_errors once answered
+                let protocol_error = match &proto_next {
+                    Some(Ok(_)) => {
+                        conn.stats_mut().record_valid_message();
+                        None
+                    },
+                    Some(Err(Error::EventMaxLengthError(_))) => Some(conn::ProtocolErrorKind::Oversized),
+                    Some(Err(Error::BinaryMessage)) => Some(conn::ProtocolErrorKind::Binary),
+                    Some(Err(Error::ClientClosed(_) | Error::ConnError | Error::MessageTooBig(_))) | None => None,
+                    Some(Err(_)) => Some(conn::ProtocolErrorKind::Malformed),
+                };
                 match proto_next {
                     Some(Ok(NostrMessage::Event(ec))) => {
                         // If we successfully parse an EventCmd, we have the correct Event
                         let e = Event::from(ec);
                         let id_prefix:String = e.get_short_event_id();
                         debug!("successfully parsed/validated event: {} from client: {}", id_prefix, cid);
-                        // Write this to the database
-                        event_tx.send(e.clone()).await.ok();
-                        client_published_event_count += 1;
+                        // Write this to the database, and report the
+                        // outcome once the writer is done with it.
+                        let event_id = e.get_event_id().to_string();
+                        conn.stats_mut().events_published += 1;
+                        METRICS.event_received();
+                        let refused = if config::is_read_only() {
+                            Some(WriteResult::Rejected("relay is read-only".to_owned()))
+                        } else if bans.is_pubkey_banned(e.get_pubkey()) {
+                            Some(WriteResult::Rejected("pubkey is banned".to_owned()))
+                        } else if e.is_auth() {
+                            Some(WriteResult::Rejected("authentication events are sent with AUTH".to_owned()))
+                        } else if publish_own_only && conn.auth_pubkey().is_none() {
+                            Some(WriteResult::AuthRequired("publishing requires authentication".to_owned()))
+                        } else if publish_own_only && !conn.is_own_event(&e) {
+                            Some(WriteResult::Restricted("can only publish your own events".to_owned()))
+                        } else if whitelist::current().map_or(false, |whitelist| !whitelist.accepts(&e)) {
+                            Some(WriteResult::Restricted(whitelist::NOT_ACCEPTED.to_owned()))
+                        } else {
+                            None
+                        };
+                        if let Some(result) = refused {
+                            conn.stats_mut().record_write(&result);
+                            METRICS.record_write(&result);
+                            outbound.send(NostrResponse::new_ok(&event_id, false, &result.message()));
+                            continue;
+                        }
+                        let source = source_ip.clone().map(|ip| db::EventSource::new(ip, cid.clone()));
+                        match events.submit(e, source, enqueue_wait).await {
+                            db::Submission::Done(result) => {
+                                conn.stats_mut().record_write(&result);
+                                METRICS.record_write(&result);
+                                outbound.send(NostrResponse::new_ok(&event_id, result.is_accepted(), &result.message()));
+                            },
+                            db::Submission::Pending(notice_rx) => {
+                                let result_tx = write_result_tx.clone();
+                                tokio::spawn(async move {
+                                    let result = match tokio::time::timeout(WRITE_RESULT_TIMEOUT, notice_rx).await {
+                                        Ok(Ok(result)) => result,
+                                        Ok(Err(_)) => WriteResult::Error("event was not saved".to_owned()),
+                                        Err(_) => WriteResult::Error("timed out saving event".to_owned()),
+                                    };
+                                    result_tx.send((event_id, result)).await.ok();
+                                });
+                            },
+                        }
                     },
                     Some(Ok(NostrMessage::Req(s))) => {
                         debug!("client {} requesting a subscription", cid);
                         // subscription handling consists of:
                         // * registering the subscription so future events can be matched
+                        // * cancelling any query for a subscription it replaces
                         // * making a channel to cancel to request later
                         // * sending a request for a SQL query
-                        let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
                         match conn.subscribe(s.clone()) {
-                            Ok(()) => {
-                                running_queries.insert(s.get_id().to_string(), abandon_query_tx);
+                            Ok(replaced) if !replaced.needs_query() => {
+                                // the client already has the stored
+                                // events, and live matching is
+                                // unchanged, so nothing is sent.  the
+                                // relay sends no EOSE, so a new
+                                // subscription gets no reply of its
+                                // own either.
+                                debug!("client {} repeated an identical subscription", cid);
+                            },
+                            Ok(_) => {
+                                let (generation, abandon_query_rx) = running_queries.start(&s.get_id().to_string());
                                 // start a database query
-                                db::db_query(s, query_tx.clone(), abandon_query_rx).await;
+                                db::db_query(s, generation, storage.clone(), query_tx.clone(), abandon_query_rx).await;
                             },
                             Err(e) => {
-                                info!("Subscription error: {}", e);
-                                nostr_stream.send(NostrResponse::new_notice(&e.to_string())).await.ok();
-
+                                info!("client {} subscription refused: {}", cid, e);
+                                let message = match e {
+                                    Error::SubMaxExceededError => format!("error: too many subscriptions (max {})", max_subs),
+                                    Error::SubRateLimitedError => format!("rate-limited: {}", e),
+                                    e => format!("error: {}", e),
+                                };
+                                outbound.send(NostrResponse::new_closed(&s.get_id().to_string(), &message));
                             }
                         }
                     },
                     Some(Ok(NostrMessage::Close(close))) => {
-                        // closing a request simply removes the subscription.
-                        // check if a query is currently
-                        // running, and remove it if so.
-                        let stop_tx = running_queries.remove(&close.id.to_string());
-                        if let Some(tx) = stop_tx {
-                            tx.send(()).ok();
+                        // closing a request removes the subscription,
+                        // so new events are no longer checked against it.
+                        if conn.unsubscribe(&close) {
+                            // check if a query is currently
+                            // running, and stop it if so.
+                            running_queries.cancel(&close.id.to_string());
+                        } else {
+                            info!("client {} closed unknown subscription {}", cid, close.id);
+                            if conn.allow_unknown_close_notice() {
+                                let message = format!("unknown subscription id: {}", close.id);
+                                conn.stats_mut().notices_sent += 1;
+                                outbound.send(NostrResponse::new_notice(&message));
+                            }
+                        }
+                    },
+                    Some(Ok(NostrMessage::Auth(ac))) => {
+                        // the client answers its challenge, proving
+                        // which key it holds
+                        let e = Event::from(ac);
+                        let event_id = e.get_event_id().to_string();
+                        match conn.authenticate(&e, relay_url.as_deref()) {
+                            Ok(pubkey) => {
+                                info!("client {} authenticated as {}", cid, pubkey);
+                                hooks.on_auth(&hook_info, &pubkey.to_string());
+                                outbound.send(NostrResponse::new_ok(&event_id, true, ""));
+                            },
+                            Err(e) => {
+                                info!("client {} failed to authenticate: {}", cid, e);
+                                outbound.send(NostrResponse::new_ok(&event_id, false, &format!("invalid: {}", e)));
+                            },
                         }
-                        // stop checking new events against
-                        // the subscription
-                        conn.unsubscribe(close);
+                    },
+                    Some(Err(Error::ClientClosed(close))) => {
+                        registry.record_close(close.code);
+                        if close.is_normal() {
+                            debug!("normal websocket close from client: {} ({})", cid, close);
+                        } else {
+                            info!("websocket closed by client: {} with {}{}", cid, close, conn.headers());
+                        }
+                        break;
                     },
                     None => {
-                        debug!("normal websocket close from client: {}",cid);
+                        debug!("websocket ended without close frame from client: {}",cid);
+                        registry.record_close(CLOSE_ABNORMAL);
                         break;
                     },
                     Some(Err(Error::ConnError)) => {
                         debug!("got connection close/error, disconnecting client: {}",cid);
+                        registry.record_close(CLOSE_ABNORMAL);
+                        break;
+                    }
+                    Some(Err(Error::MessageTooBig(size))) => {
+                        info!("client {} sent a message larger ({} bytes) than max_ws_message_bytes{}", cid, size, conn.headers());
+                        registry.record_close(CLOSE_MESSAGE_TOO_BIG);
                         break;
                     }
                     Some(Err(Error::EventMaxLengthError(s))) => {
-                        info!("client {} sent event larger ({} bytes) than max size", cid, s);
-                        nostr_stream.send(NostrResponse::new_notice("event exceeded max size")).await.ok();
+                        info!("client {} sent event larger ({} bytes) than max size{}", cid, s, conn.headers());
+                        conn.stats_mut().notices_sent += 1;
+                        outbound.send(NostrResponse::new_notice("event exceeded max size"));
+                    },
+                    Some(Err(Error::SubIdInvalid(id))) => {
+                        info!("client {} requested invalid subscription id {:?}{}", cid, id, conn.headers());
+                        outbound.send(NostrResponse::new_closed(&id, "error: invalid subscription id"));
                     },
                     Some(Err(e)) => {
-                        info!("got non-fatal error from client: {}, error: {:?}", cid, e);
+                        info!("got non-fatal error from client: {}, error: {:?}{}", cid, e, conn.headers());
                     },
                 }
+                if let Some(kind) = protocol_error {
+                    if let Some(reason) = conn.stats_mut().record_protocol_error(kind, &protocol_errors) {
+                        info!("disconnecting {} after {}", conn.log_label(), reason);
+                        conn.stats_mut().notices_sent += 1;
+                        outbound.send(NostrResponse::new_notice(&format!("error: closing connection after {}", reason)));
+                        outbound.finish_with_code(CLOSE_POLICY_VIOLATION, &reason);
+                        break;
+                    }
+                }
             },
         }
     }
-    // connection cleanup - ensure any still running queries are terminated.
-    for (_, stop_tx) in running_queries.into_iter() {
-        stop_tx.send(()).ok();
+    // connection cleanup - ensure any still running queries are
+    // terminated, and stop the writer once it has sent what it can.
+    running_queries.cancel_all();
+    outbound.finish(None);
+    // during a shutdown, give the writer a moment to deliver the
+    // notice and close frame, without letting the client hold it up.
+    if shutting_down
+        && tokio::time::timeout(SHUTDOWN_CLOSE_TIMEOUT, &mut writer)
+            .await
+            .is_err()
+    {
+        debug!("client {} did not accept the shutdown close frame", cid);
+        writer.abort();
     }
+    let stats = conn.stats_mut();
+    stats.bytes_in = nostr_stream.bytes_received();
+    stats.bytes_out = outbound.bytes_written();
+    stats.bytes_saved_by_compression = nostr_stream.bytes_saved_by_compression();
     info!(
-        "stopping connection for client: {} (client sent {} event(s), received {})",
-        cid, client_published_event_count, client_received_event_count
+        "stopping connection for {} ({}; throttled {} event(s) and {} request(s), dropped {} broadcast(s))",
+        conn.log_label(),
+        conn.stats(),
+        inbound.throttled_events,
+        inbound.throttled_requests,
+        outbound.dropped_broadcasts()
     );
+    hooks.on_disconnect(&hook_info, conn.stats());
 }
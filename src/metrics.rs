@@ -0,0 +1,744 @@
+//! Relay metrics, served in the Prometheus text format
+use crate::db::WriteResult;
+use crate::mirror::UpstreamSnapshot;
+use crate::ratelimit::AddressBucket;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the query latency buckets
+const QUERY_SECONDS_BUCKETS: [f64; 10] =
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 2.5, 10.0];
+
+/// Upper bounds, in bytes, of the buckets oversized messages are
+/// counted in, with their labels
+const OVERSIZED_BUCKETS: [(usize, &str); 5] = [
+    (64 * 1024, "64KiB"),
+    (256 * 1024, "256KiB"),
+    (1024 * 1024, "1MiB"),
+    (4 * 1024 * 1024, "4MiB"),
+    (16 * 1024 * 1024, "16MiB"),
+];
+
+/// Label of messages larger than the last oversized bucket
+const OVERSIZED_BEYOND: &str = "over_16MiB";
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+lazy_static! {
+    /// Counters updated as the relay runs
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Durations counted into buckets by upper bound.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations in each bucket, not cumulative, with a final
+    /// bucket for anything beyond the last bound
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// Count one observation.
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Write the buckets, sum and count of the histogram `name`.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count).unwrap();
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+    }
+}
+
+/// Counters for the whole relay.
+#[derive(Debug)]
+pub struct Metrics {
+    events_received: AtomicU64,
+    events_persisted: AtomicU64,
+    events_duplicate: AtomicU64,
+    /// Refused events, by reason
+    events_rejected: Mutex<BTreeMap<&'static str, u64>>,
+    /// Events connections missed because they fell behind the
+    /// broadcast channel
+    broadcast_lagged: AtomicU64,
+    /// Times a connection fell behind the broadcast channel
+    broadcast_lags: AtomicU64,
+    /// Websocket upgrades refused at the connection limit
+    connections_refused: AtomicU64,
+    /// Websocket upgrades refused for their Origin header
+    origins_refused: AtomicU64,
+    /// Websocket upgrades refused for coming too often from an address
+    connections_throttled: AtomicU64,
+    /// Connection tasks ended by a panic
+    connections_panicked: AtomicU64,
+    /// Connections closed because a write to them timed out
+    write_timeouts: AtomicU64,
+    /// Close frames the relay sent, by code
+    closes_sent: Mutex<BTreeMap<u16, u64>>,
+    /// Websocket errors reading from clients, by kind
+    ws_errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// Messages refused for their size, by the limit exceeded and
+    /// size bucket
+    oversized: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    queries: AtomicU64,
+    query_seconds: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            events_received: AtomicU64::new(0),
+            events_persisted: AtomicU64::new(0),
+            events_duplicate: AtomicU64::new(0),
+            events_rejected: Mutex::new(BTreeMap::new()),
+            broadcast_lagged: AtomicU64::new(0),
+            broadcast_lags: AtomicU64::new(0),
+            connections_refused: AtomicU64::new(0),
+            origins_refused: AtomicU64::new(0),
+            connections_throttled: AtomicU64::new(0),
+            connections_panicked: AtomicU64::new(0),
+            write_timeouts: AtomicU64::new(0),
+            closes_sent: Mutex::new(BTreeMap::new()),
+            ws_errors: Mutex::new(BTreeMap::new()),
+            oversized: Mutex::new(BTreeMap::new()),
+            queries: AtomicU64::new(0),
+            query_seconds: Histogram::new(&QUERY_SECONDS_BUCKETS),
+        }
+    }
+}
+
+/// Values read from the relay's state when metrics are scraped.
+#[derive(Debug, Default)]
+pub struct Gauges {
+    pub connections: usize,
+    pub subscriptions: usize,
+    /// Consumers connected to `/firehose`
+    pub firehose_consumers: usize,
+    /// Relays events are copied from
+    pub mirrors: Vec<UpstreamSnapshot>,
+    /// Events waiting in the broadcast channel for the slowest
+    /// connection
+    pub broadcast_queue: usize,
+    /// Events waiting for the database writer, including any spooled
+    pub persist_queue: u64,
+    /// Bytes used by stored data, if the backend tracks it
+    pub db_size_bytes: Option<u64>,
+    /// Websocket terminations, by close code
+    pub close_codes: BTreeMap<u16, u64>,
+    /// Connection attempts refused, for the addresses refused most
+    pub throttled_addresses: Vec<(AddressBucket, u64)>,
+}
+
+/// A short label for why an event was refused, so that free-form
+/// reasons do not create a series each.
+pub fn rejection_reason(result: &WriteResult) -> Option<&'static str> {
+    let reason = match result {
+        WriteResult::Persisted | WriteResult::Duplicate | WriteResult::Queued => return None,
+        WriteResult::Rejected(reason) => match reason.as_str() {
+            "relay is read-only" => "read_only",
+            "event was deleted by the relay operator" => "deleted",
+            "pubkey is banned" => "banned",
+            _ => "blocked",
+        },
+        WriteResult::Restricted(_) => "restricted",
+        WriteResult::AuthRequired(_) => "auth_required",
+        WriteResult::Overloaded => "overloaded",
+        WriteResult::Error(message) => match message.as_str() {
+            "relay is overloaded" => "overloaded",
+            "relay is shutting down" => "shutting_down",
+            "relay storage full" => "storage_full",
+            "storage unavailable" => "storage_unavailable",
+            "timed out saving event" => "timeout",
+            _ => "error",
+        },
+    };
+    Some(reason)
+}
+
+/// The bucket a message of `bytes` is counted in when it is refused
+/// for its size.
+pub fn size_bucket(bytes: usize) -> &'static str {
+    OVERSIZED_BUCKETS
+        .iter()
+        .find(|(bound, _)| bytes <= *bound)
+        .map_or(OVERSIZED_BEYOND, |(_, label)| label)
+}
+
+/// Why connections ended and what went wrong on them, as counted so
+/// far, for the periodic summary in the log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WebsocketCounts {
+    /// Connections ended by clients, by close code, with 1006 for
+    /// those that ended without a close frame
+    pub closes_received: BTreeMap<u16, u64>,
+    /// Close frames sent by the relay, by code
+    pub closes_sent: BTreeMap<u16, u64>,
+    /// Websocket errors reading from clients, by kind
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Messages refused for their size, by limit and size bucket
+    pub oversized: BTreeMap<(&'static str, &'static str), u64>,
+    pub write_timeouts: u64,
+}
+
+/// Counts in `now` beyond those in `earlier`, leaving out any that
+/// did not grow.
+fn growth<K: Ord + Clone>(now: &BTreeMap<K, u64>, earlier: &BTreeMap<K, u64>) -> BTreeMap<K, u64> {
+    now.iter()
+        .map(|(key, n)| (key, n - earlier.get(key).copied().unwrap_or(0).min(*n)))
+        .filter(|(_, n)| *n > 0)
+        .map(|(key, n)| (key.clone(), n))
+        .collect()
+}
+
+impl WebsocketCounts {
+    /// What was counted after `earlier` was taken.
+    pub fn since(&self, earlier: &WebsocketCounts) -> WebsocketCounts {
+        WebsocketCounts {
+            closes_received: growth(&self.closes_received, &earlier.closes_received),
+            closes_sent: growth(&self.closes_sent, &earlier.closes_sent),
+            errors: growth(&self.errors, &earlier.errors),
+            oversized: growth(&self.oversized, &earlier.oversized),
+            write_timeouts: self.write_timeouts.saturating_sub(earlier.write_timeouts),
+        }
+    }
+
+    /// Whether nothing was counted.
+    pub fn is_empty(&self) -> bool {
+        self.closes_received.is_empty()
+            && self.closes_sent.is_empty()
+            && self.errors.is_empty()
+            && self.oversized.is_empty()
+            && self.write_timeouts == 0
+    }
+}
+
+impl fmt::Display for WebsocketCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn list<K: fmt::Display>(
+            f: &mut fmt::Formatter<'_>,
+            counts: impl Iterator<Item = (K, u64)>,
+        ) -> fmt::Result {
+            let mut any = false;
+            for (key, n) in counts {
+                write!(f, "{}{}={}", if any { " " } else { "" }, key, n)?;
+                any = true;
+            }
+            if !any {
+                write!(f, "none")?;
+            }
+            Ok(())
+        }
+        write!(f, "closes from clients: ")?;
+        list(f, self.closes_received.iter().map(|(k, n)| (k, *n)))?;
+        write!(f, "; closes sent: ")?;
+        list(f, self.closes_sent.iter().map(|(k, n)| (k, *n)))?;
+        write!(f, "; errors: ")?;
+        list(f, self.errors.iter().map(|(k, n)| (k, *n)))?;
+        write!(f, "; oversized: ")?;
+        list(
+            f,
+            self.oversized
+                .iter()
+                .map(|((limit, size), n)| (format!("{}/{}", limit, size), *n)),
+        )?;
+        write!(f, "; write timeouts: {}", self.write_timeouts)
+    }
+}
+
+impl Metrics {
+    /// Count an event received from a client.
+    pub fn event_received(&self) {
+        self.events_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event written by the database writer.
+    pub fn event_persisted(&self) {
+        self.events_persisted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an event the database writer found already stored.
+    pub fn event_duplicate(&self) {
+        self.events_duplicate.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of an event submitted by a client, if it was
+    /// refused.
+    pub fn record_write(&self, result: &WriteResult) {
+        if let Some(reason) = rejection_reason(result) {
+            *self
+                .events_rejected
+                .lock()
+                .unwrap()
+                .entry(reason)
+                .or_default() += 1;
+        }
+    }
+
+    /// Count events a connection missed by lagging behind, returning
+    /// how many times connections have lagged, including this one.
+    pub fn broadcast_lagged(&self, skipped: u64) -> u64 {
+        self.broadcast_lagged.fetch_add(skipped, Ordering::Relaxed);
+        self.broadcast_lags.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a connection refused at the connection limit, returning
+    /// how many have been, including this one.
+    pub fn connection_refused(&self) -> u64 {
+        self.connections_refused.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a connection refused for the page it came from.
+    pub fn origin_refused(&self) {
+        self.origins_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection refused for coming too often from its
+    /// address, returning how many have been, including this one.
+    pub fn connection_throttled(&self) -> u64 {
+        self.connections_throttled.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Count a connection task that panicked.
+    pub fn connection_panicked(&self) {
+        self.connections_panicked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection whose writer gave up on a write that did
+    /// not complete within `network.send_timeout_secs`.
+    pub fn write_timed_out(&self) {
+        self.write_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a close frame sent to a client.
+    pub fn close_sent(&self, code: u16) {
+        *self.closes_sent.lock().unwrap().entry(code).or_default() += 1;
+    }
+
+    /// Count an error reading a client's websocket, of a kind named by
+    /// [`crate::protostream::ws_error_kind`].
+    pub fn ws_error(&self, kind: &'static str) {
+        *self.ws_errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// Count a message of `bytes` refused for exceeding the setting
+    /// `limit`.
+    pub fn message_oversized(&self, limit: &'static str, bytes: usize) {
+        *self
+            .oversized
+            .lock()
+            .unwrap()
+            .entry((limit, size_bucket(bytes)))
+            .or_default() += 1;
+    }
+
+    /// The websocket counts so far, with `closes_received` as kept by
+    /// the connection registry.
+    pub fn websocket_counts(&self, closes_received: BTreeMap<u16, u64>) -> WebsocketCounts {
+        WebsocketCounts {
+            closes_received,
+            closes_sent: self.closes_sent.lock().unwrap().clone(),
+            errors: self.ws_errors.lock().unwrap().clone(),
+            oversized: self.oversized.lock().unwrap().clone(),
+            write_timeouts: self.write_timeouts.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a finished query.
+    pub fn query_finished(&self, elapsed: Duration) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.query_seconds.observe(elapsed);
+    }
+
+    /// Write every metric in the text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            header(out, name, help, "counter");
+            writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+        };
+        let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+            header(out, name, help, "gauge");
+            writeln!(out, "{} {}", name, value).unwrap();
+        };
+        gauge(
+            &mut out,
+            "nostrd_connections",
+            "Open websocket connections.",
+            gauges.connections as u64,
+        );
+        gauge(
+            &mut out,
+            "nostrd_subscriptions",
+            "Active subscriptions across all connections.",
+            gauges.subscriptions as u64,
+        );
+        gauge(
+            &mut out,
+            "nostrd_firehose_consumers",
+            "Consumers connected to /firehose.",
+            gauges.firehose_consumers as u64,
+        );
+        counter(
+            &mut out,
+            "nostrd_connections_refused_total",
+            "Websocket connections refused because limits.max_connections were open.",
+            &self.connections_refused,
+        );
+        counter(
+            &mut out,
+            "nostrd_origins_refused_total",
+            "Websocket connections refused because their Origin was not in network.allowed_origins.",
+            &self.origins_refused,
+        );
+        counter(
+            &mut out,
+            "nostrd_connections_throttled_total",
+            "Websocket connections refused because their address exceeded limits.connections_per_minute.",
+            &self.connections_throttled,
+        );
+        counter(
+            &mut out,
+            "nostrd_connection_panics_total",
+            "Connections closed because their task panicked; each is logged as an error.",
+            &self.connections_panicked,
+        );
+        counter(
+            &mut out,
+            "nostrd_write_timeouts_total",
+            "Connections closed because a write did not complete within network.send_timeout_secs.",
+            &self.write_timeouts,
+        );
+        header(
+            &mut out,
+            "nostrd_connections_throttled",
+            "Websocket connections refused, for the addresses (IPv6 by /64) refused most among those tracked.",
+            "gauge",
+        );
+        for (address, count) in &gauges.throttled_addresses {
+            writeln!(
+                out,
+                "nostrd_connections_throttled{{address=\"{}\"}} {}",
+                address, count
+            )
+            .unwrap();
+        }
+        counter(
+            &mut out,
+            "nostrd_events_received_total",
+            "Events received from clients.",
+            &self.events_received,
+        );
+        counter(
+            &mut out,
+            "nostrd_events_persisted_total",
+            "Events written to the database.",
+            &self.events_persisted,
+        );
+        counter(
+            &mut out,
+            "nostrd_events_duplicate_total",
+            "Events that were already stored.",
+            &self.events_duplicate,
+        );
+        header(
+            &mut out,
+            "nostrd_events_rejected_total",
+            "Events refused, by reason.",
+            "counter",
+        );
+        for (reason, count) in self.events_rejected.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "nostrd_events_rejected_total{{reason=\"{}\"}} {}",
+                reason, count
+            )
+            .unwrap();
+        }
+        gauge(
+            &mut out,
+            "nostrd_broadcast_queue",
+            "Events in the broadcast channel not yet read by the slowest connection.",
+            gauges.broadcast_queue as u64,
+        );
+        counter(
+            &mut out,
+            "nostrd_broadcast_lagged_events_total",
+            "Broadcast events skipped by connections that fell behind.",
+            &self.broadcast_lagged,
+        );
+        counter(
+            &mut out,
+            "nostrd_broadcast_lags_total",
+            "Times a connection fell behind the broadcast channel.",
+            &self.broadcast_lags,
+        );
+        gauge(
+            &mut out,
+            "nostrd_persist_queue",
+            "Events waiting for the database writer, including spooled events.",
+            gauges.persist_queue,
+        );
+        counter(
+            &mut out,
+            "nostrd_queries_total",
+            "Subscription queries run against the database.",
+            &self.queries,
+        );
+        self.query_seconds.render(
+            &mut out,
+            "nostrd_query_duration_seconds",
+            "Time taken to run subscription queries.",
+        );
+        if let Some(size) = gauges.db_size_bytes {
+            gauge(
+                &mut out,
+                "nostrd_db_size_bytes",
+                "Bytes used by stored data.",
+                size,
+            );
+        }
+        if !gauges.mirrors.is_empty() {
+            header(
+                &mut out,
+                "nostrd_mirror_connected",
+                "Whether a connection to each mirrored relay is open.",
+                "gauge",
+            );
+            for mirror in &gauges.mirrors {
+                writeln!(
+                    out,
+                    "nostrd_mirror_connected{{upstream=\"{}\"}} {}",
+                    mirror.url,
+                    u8::from(mirror.connected)
+                )
+                .unwrap();
+            }
+            header(
+                &mut out,
+                "nostrd_mirror_events_total",
+                "Events received from each mirrored relay, by outcome.",
+                "counter",
+            );
+            for mirror in &gauges.mirrors {
+                for (outcome, count) in [
+                    ("stored", mirror.events_stored),
+                    ("duplicate", mirror.events_duplicate),
+                    ("rejected", mirror.events_rejected),
+                    ("invalid", mirror.events_invalid),
+                ] {
+                    writeln!(
+                        out,
+                        "nostrd_mirror_events_total{{upstream=\"{}\",outcome=\"{}\"}} {}",
+                        mirror.url, outcome, count
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        header(
+            &mut out,
+            "nostrd_ws_closes_total",
+            "Websocket connections ended, by close code.",
+            "counter",
+        );
+        for (code, count) in &gauges.close_codes {
+            writeln!(out, "nostrd_ws_closes_total{{code=\"{}\"}} {}", code, count).unwrap();
+        }
+        header(
+            &mut out,
+            "nostrd_ws_closes_sent_total",
+            "Close frames sent by the relay, by close code.",
+            "counter",
+        );
+        for (code, count) in self.closes_sent.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "nostrd_ws_closes_sent_total{{code=\"{}\"}} {}",
+                code, count
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "nostrd_ws_errors_total",
+            "Websocket errors reading from clients, by kind.",
+            "counter",
+        );
+        for (kind, count) in self.ws_errors.lock().unwrap().iter() {
+            writeln!(out, "nostrd_ws_errors_total{{kind=\"{}\"}} {}", kind, count).unwrap();
+        }
+        header(
+            &mut out,
+            "nostrd_oversized_messages_total",
+            "Messages refused for their size, by the limit exceeded and size rounded up to 64KiB, 256KiB, 1MiB, 4MiB or 16MiB.",
+            "counter",
+        );
+        for ((limit, size), count) in self.oversized.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "nostrd_oversized_messages_total{{limit=\"{}\",size=\"{}\"}} {}",
+                limit, size, count
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_cumulative() {
+        let histogram = Histogram::new(&[0.01, 0.1]);
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_secs(1));
+        let mut out = String::new();
+        histogram.render(&mut out, "q", "Queries.");
+        assert!(out.contains("q_bucket{le=\"0.01\"} 1\n"));
+        assert!(out.contains("q_bucket{le=\"0.1\"} 3\n"));
+        assert!(out.contains("q_bucket{le=\"+Inf\"} 4\n"));
+        assert!(out.contains("q_sum 1.105\n"));
+        assert!(out.contains("q_count 4\n"));
+    }
+
+    #[test]
+    fn sizes_bucketed() {
+        assert_eq!(size_bucket(1), "64KiB");
+        assert_eq!(size_bucket(64 * 1024), "64KiB");
+        assert_eq!(size_bucket(64 * 1024 + 1), "256KiB");
+        assert_eq!(size_bucket(5 * 1024 * 1024), "16MiB");
+        assert_eq!(size_bucket(usize::MAX), OVERSIZED_BEYOND);
+    }
+
+    #[test]
+    fn websocket_counts_summarized() {
+        let metrics = Metrics::default();
+        metrics.close_sent(1001);
+        let earlier = metrics.websocket_counts([(1000, 4)].into_iter().collect());
+        assert!(earlier.since(&earlier).is_empty());
+        metrics.close_sent(1008);
+        metrics.ws_error("protocol");
+        metrics.message_oversized("max_ws_message_bytes", 300_000);
+        metrics.write_timed_out();
+        let now = metrics.websocket_counts([(1000, 6), (1006, 1)].into_iter().collect());
+        let hour = now.since(&earlier);
+        assert_eq!(
+            hour.closes_received,
+            [(1000, 2), (1006, 1)].into_iter().collect()
+        );
+        assert_eq!(hour.closes_sent, [(1008, 1)].into_iter().collect());
+        assert_eq!(
+            hour.to_string(),
+            "closes from clients: 1000=2 1006=1; closes sent: 1008=1; errors: protocol=1; \
+             oversized: max_ws_message_bytes/1MiB=1; write timeouts: 1"
+        );
+        assert_eq!(
+            WebsocketCounts::default().to_string(),
+            "closes from clients: none; closes sent: none; errors: none; oversized: none; \
+             write timeouts: 0"
+        );
+    }
+
+    #[test]
+    fn metrics_rendered() {
+        let metrics = Metrics::default();
+        metrics.event_received();
+        metrics.event_received();
+        metrics.event_persisted();
+        metrics.record_write(&WriteResult::Persisted);
+        metrics.record_write(&WriteResult::Rejected("relay is read-only".to_owned()));
+        metrics.record_write(&WriteResult::Rejected("spam".to_owned()));
+        metrics.record_write(&WriteResult::Error("disk I/O error".to_owned()));
+        metrics.record_write(&WriteResult::Overloaded);
+        metrics.record_write(&WriteResult::Restricted(
+            "not an accepted author".to_owned(),
+        ));
+        metrics.close_sent(1008);
+        metrics.ws_error("protocol");
+        metrics.message_oversized("max_event_bytes", 100_000);
+        let gauges = Gauges {
+            connections: 3,
+            db_size_bytes: Some(4096),
+            close_codes: [(1000, 2), (1006, 1)].into_iter().collect(),
+            mirrors: vec![UpstreamSnapshot {
+                url: "wss://relay.example.com".to_owned(),
+                connected: true,
+                events_stored: 5,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let out = metrics.render(&gauges);
+        assert!(out.contains("# TYPE nostrd_connections gauge\nnostrd_connections 3\n"));
+        assert!(out.contains("nostrd_events_received_total 2\n"));
+        assert!(out.contains("nostrd_events_persisted_total 1\n"));
+        assert!(out.contains("nostrd_events_rejected_total{reason=\"read_only\"} 1\n"));
+        assert!(out.contains("nostrd_events_rejected_total{reason=\"blocked\"} 1\n"));
+        assert!(out.contains("nostrd_events_rejected_total{reason=\"error\"} 1\n"));
+        assert!(out.contains("nostrd_events_rejected_total{reason=\"overloaded\"} 1\n"));
+        assert!(out.contains("nostrd_events_rejected_total{reason=\"restricted\"} 1\n"));
+        assert!(out.contains("nostrd_db_size_bytes 4096\n"));
+        assert!(out.contains("nostrd_ws_closes_total{code=\"1006\"} 1\n"));
+        assert!(out.contains("nostrd_ws_closes_sent_total{code=\"1008\"} 1\n"));
+        assert!(out.contains("nostrd_ws_errors_total{kind=\"protocol\"} 1\n"));
+        assert!(out.contains(
+            "nostrd_oversized_messages_total{limit=\"max_event_bytes\",size=\"256KiB\"} 1\n"
+        ));
+        assert!(out.contains("nostrd_mirror_connected{upstream=\"wss://relay.example.com\"} 1\n"));
+        assert!(out.contains(
+            "nostrd_mirror_events_total{upstream=\"wss://relay.example.com\",outcome=\"stored\"} 5\n"
+        ));
+        // every sample line belongs to a declared metric
+        for line in out.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = name
+                .trim_end_matches("_bucket")
+                .trim_end_matches("_sum")
+                .trim_end_matches("_count");
+            assert!(out.contains(&format!("# TYPE {} ", family)), "{}", line);
+        }
+    }
+}
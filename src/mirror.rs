@@ -0,0 +1,473 @@
+//! Copying events from other relays into this one
+use crate::bans::Bans;
+use crate::config::{self, Upstream};
+use crate::db::{EventQueue, EventSource, Submission, WriteResult};
+use crate::error::{Error, Result};
+use crate::nip05::backoff;
+use crate::protocol::{Event, ReqFilter};
+use futures::{SinkExt, StreamExt};
+use hyper::Uri;
+use log::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tungstenite::protocol::Message;
+
+/// Subscription id the relay uses with its upstreams
+const MIRROR_SUBSCRIPTION_ID: &str = "nostrd-mirror";
+
+/// Client id recorded as the source of mirrored events, when
+/// `database.log_event_source` is set
+pub const MIRROR_CLIENT_ID: &str = "mirror";
+
+/// Wait before the first reconnection to a failing upstream
+const RETRY_BASE: Duration = Duration::from_secs(1);
+
+/// A connection that stays open this long worked, so that
+/// reconnecting after it starts from [`RETRY_BASE`] again
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// How long to wait for an upstream to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a mirrored event waits for room in the writer's queue.
+/// Longer than clients wait, since an upstream can simply be read
+/// more slowly.
+const ENQUEUE_WAIT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the writer to store a mirrored event
+const WRITE_RESULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counters for one upstream relay.
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    url: String,
+    connected: AtomicBool,
+    connects: AtomicU64,
+    events_stored: AtomicU64,
+    events_duplicate: AtomicU64,
+    events_rejected: AtomicU64,
+    events_invalid: AtomicU64,
+    newest_created_at: AtomicU64,
+}
+
+/// Counters for one upstream relay, as of when they were read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamSnapshot {
+    pub url: String,
+    /// Whether a connection is open
+    pub connected: bool,
+    /// Connections opened, including the current one
+    pub connects: u64,
+    /// Events stored, or queued for storage
+    pub events_stored: u64,
+    /// Events that were already stored
+    pub events_duplicate: u64,
+    /// Events refused by relay policy, or that could not be stored
+    pub events_rejected: u64,
+    /// Messages that were not valid events, or were too large
+    pub events_invalid: u64,
+    /// `created_at` of the newest event copied, which reconnections
+    /// ask for events since
+    pub newest_created_at: u64,
+}
+
+impl UpstreamStats {
+    fn new(url: &str) -> Self {
+        UpstreamStats {
+            url: url.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Read the counters.
+    pub fn snapshot(&self) -> UpstreamSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        UpstreamSnapshot {
+            url: self.url.clone(),
+            connected: self.connected.load(Ordering::Relaxed),
+            connects: load(&self.connects),
+            events_stored: load(&self.events_stored),
+            events_duplicate: load(&self.events_duplicate),
+            events_rejected: load(&self.events_rejected),
+            events_invalid: load(&self.events_invalid),
+            newest_created_at: load(&self.newest_created_at),
+        }
+    }
+}
+
+/// A message from an upstream relay.
+#[derive(Debug, PartialEq)]
+enum Received {
+    /// An event for the mirror's subscription, validated
+    Event(Box<Event>),
+    /// The upstream has sent all its stored events
+    EndOfStored,
+    Notice(String),
+    /// The upstream ended the subscription, with a reason
+    Closed(String),
+    /// Anything else, such as a message for another subscription
+    Other,
+}
+
+/// Parse a message from an upstream, validating an event in it as one
+/// submitted by a client would be: its id, its signature, and the
+/// size of the message against `limits.max_event_bytes`.
+fn parse_message(text: &str, max_event_bytes: Option<usize>) -> Result<Received> {
+    let message: Vec<Value> = serde_json::from_str(text)?;
+    let field = |n: usize| message.get(n).and_then(Value::as_str);
+    let received = match field(0) {
+        Some("EVENT") if field(1) == Some(MIRROR_SUBSCRIPTION_ID) => {
+            if let Some(max) = max_event_bytes.filter(|&max| max > 0) {
+                if text.len() > max {
+                    return Err(Error::EventMaxLengthError(text.len()));
+                }
+            }
+            let event = message.get(2).ok_or(Error::ProtoParseError)?;
+            Received::Event(Box::new(Event::from_str(&event.to_string())?))
+        }
+        Some("EOSE") if field(1) == Some(MIRROR_SUBSCRIPTION_ID) => Received::EndOfStored,
+        Some("CLOSED") if field(1) == Some(MIRROR_SUBSCRIPTION_ID) => {
+            Received::Closed(field(2).unwrap_or_default().to_owned())
+        }
+        Some("NOTICE") => Received::Notice(field(1).unwrap_or_default().to_owned()),
+        _ => Received::Other,
+    };
+    Ok(received)
+}
+
+/// `filter` asking only for events created at `newest` or later, if
+/// any have been copied, so that a reconnection does not fetch
+/// everything again.  The event at `newest` itself is asked for
+/// again, since others may share its timestamp.
+fn resume_filter(filter: &Value, newest: u64) -> Value {
+    let mut filter = filter.clone();
+    if newest > 0 {
+        let since = filter.get("since").and_then(Value::as_u64).unwrap_or(0);
+        filter["since"] = since.max(newest).into();
+    }
+    filter
+}
+
+/// Copies events from one upstream relay.
+struct Mirror {
+    url: String,
+    /// Host of the upstream, recorded as the source of its events
+    host: String,
+    filter: Value,
+    stats: Arc<UpstreamStats>,
+    events: EventQueue,
+    bans: Arc<Bans>,
+    max_backoff: Duration,
+}
+
+/// Start copying events from every upstream in `mirror`, until
+/// `shutdown` fires, returning the counters for each.  Fails if an
+/// upstream's URL or filter is invalid.
+pub fn start(
+    mirror: &config::Mirror,
+    events: &EventQueue,
+    bans: &Arc<Bans>,
+    shutdown: &broadcast::Sender<()>,
+) -> Result<Vec<Arc<UpstreamStats>>> {
+    let mut all_stats = vec![];
+    for upstream in mirror.upstreams.iter().flatten() {
+        let mirror = Mirror::new(upstream, mirror, events.clone(), bans.clone())?;
+        all_stats.push(mirror.stats.clone());
+        tokio::spawn(mirror.run(shutdown.subscribe()));
+    }
+    Ok(all_stats)
+}
+
+impl Mirror {
+    fn new(
+        upstream: &Upstream,
+        settings: &config::Mirror,
+        events: EventQueue,
+        bans: Arc<Bans>,
+    ) -> Result<Self> {
+        let invalid = |why: String| {
+            Error::GenericError(format!(
+                "invalid mirror upstream {:?}: {}",
+                upstream.url, why
+            ))
+        };
+        let uri: Uri = upstream
+            .url
+            .parse()
+            .map_err(|e| invalid(format!("{}", e)))?;
+        if !matches!(uri.scheme_str(), Some("ws" | "wss")) {
+            return Err(invalid(
+                "the URL must start with ws:// or wss://".to_owned(),
+            ));
+        }
+        let filter: Value = match &upstream.filter {
+            Some(filter) => serde_json::from_str(filter).map_err(|e| invalid(e.to_string()))?,
+            None => Value::Object(Default::default()),
+        };
+        if !filter.is_object() {
+            return Err(invalid("the filter must be a JSON object".to_owned()));
+        }
+        serde_json::from_value::<ReqFilter>(filter.clone())
+            .map_err(|e| invalid(format!("filter: {}", e)))?;
+        Ok(Mirror {
+            url: upstream.url.clone(),
+            host: uri.host().unwrap_or_default().to_owned(),
+            filter,
+            stats: Arc::new(UpstreamStats::new(&upstream.url)),
+            events,
+            bans,
+            max_backoff: Duration::from_secs(settings.max_backoff_secs),
+        })
+    }
+
+    /// Copy events until `shutdown` fires, reconnecting whenever the
+    /// connection ends.
+    async fn run(self, mut shutdown: broadcast::Receiver<()>) {
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            let ended = tokio::select! {
+                ended = self.session() => ended,
+                _ = shutdown.recv() => break,
+            };
+            self.stats.connected.store(false, Ordering::Relaxed);
+            match ended {
+                Ok(()) => info!("mirror {} closed the connection", self.url),
+                Err(e) => warn!("mirror {} failed: {}", self.url, e),
+            }
+            if started.elapsed() >= STABLE_CONNECTION {
+                failures = 0;
+            }
+            failures += 1;
+            let wait = backoff(RETRY_BASE, failures, self.max_backoff);
+            info!(
+                "mirror {} reconnecting in {}s ({:?})",
+                self.url,
+                wait.as_secs(),
+                self.stats.snapshot()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {},
+                _ = shutdown.recv() => break,
+            }
+        }
+        debug!("mirror {} stopped", self.url);
+    }
+
+    /// Connect to the upstream, subscribe, and store what it sends
+    /// until the connection ends.
+    async fn session(&self) -> Result<()> {
+        let (ws, _) = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            tokio_tungstenite::connect_async(self.url.as_str()),
+        )
+        .await
+        .map_err(|_| Error::GenericError("timed out connecting".to_owned()))??;
+        self.stats.connected.store(true, Ordering::Relaxed);
+        self.stats.connects.fetch_add(1, Ordering::Relaxed);
+        let filter = resume_filter(
+            &self.filter,
+            self.stats.newest_created_at.load(Ordering::Relaxed),
+        );
+        info!("mirroring {} from {}", filter, self.url);
+        let (mut sink, mut stream) = ws.split();
+        let request = serde_json::json!(["REQ", MIRROR_SUBSCRIPTION_ID, filter]);
+        sink.send(Message::Text(request.to_string())).await?;
+        while let Some(message) = stream.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                // tungstenite answers pings as the stream is read
+                _ => continue,
+            };
+            let max_event_bytes = config::SETTINGS.read().unwrap().limits.max_event_bytes;
+            match parse_message(&text, max_event_bytes) {
+                Ok(Received::Event(event)) => self.store(*event).await,
+                Ok(Received::EndOfStored) => info!("mirror {} sent all stored events", self.url),
+                Ok(Received::Notice(notice)) => info!("mirror {} notice: {:?}", self.url, notice),
+                Ok(Received::Closed(reason)) => {
+                    return Err(Error::GenericError(format!(
+                        "subscription closed: {:?}",
+                        reason
+                    )))
+                }
+                Ok(Received::Other) => {}
+                Err(e) => {
+                    self.stats.events_invalid.fetch_add(1, Ordering::Relaxed);
+                    debug!("invalid message from mirror {}: {}", self.url, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store an event through the writer, as the relay stores events
+    /// clients submit, and count the outcome.
+    async fn store(&self, event: Event) {
+        let created_at = event.created_at;
+        let refused = if config::is_read_only() {
+            Some("relay is read-only")
+        } else if self.bans.is_pubkey_banned(event.get_pubkey()) {
+            Some("pubkey is banned")
+        } else {
+            None
+        };
+        let result = match refused {
+            Some(reason) => WriteResult::Rejected(reason.to_owned()),
+            None => {
+                let source = config::SETTINGS
+                    .read()
+                    .unwrap()
+                    .database
+                    .log_event_source
+                    .then(|| EventSource::new(self.host.clone(), MIRROR_CLIENT_ID.to_owned()));
+                match self.events.submit(event, source, ENQUEUE_WAIT).await {
+                    Submission::Done(result) => result,
+                    Submission::Pending(notice_rx) => {
+                        match tokio::time::timeout(WRITE_RESULT_TIMEOUT, notice_rx).await {
+                            Ok(Ok(result)) => result,
+                            Ok(Err(_)) => WriteResult::Error("event was not saved".to_owned()),
+                            Err(_) => WriteResult::Error("timed out saving event".to_owned()),
+                        }
+                    }
+                }
+            }
+        };
+        let stats = &self.stats;
+        match result {
+            WriteResult::Persisted | WriteResult::Queued => {
+                stats.events_stored.fetch_add(1, Ordering::Relaxed);
+            }
+            WriteResult::Duplicate => {
+                let duplicates = stats.events_duplicate.fetch_add(1, Ordering::Relaxed) + 1;
+                // a few are expected on reconnecting; many may mean
+                // the upstream mirrors this relay
+                if duplicates.is_power_of_two() {
+                    info!(
+                        "mirror {} has sent {} event(s) already stored; if it mirrors this relay, \
+                         they are events copied back",
+                        self.url, duplicates
+                    );
+                }
+            }
+            result => {
+                stats.events_rejected.fetch_add(1, Ordering::Relaxed);
+                debug!("mirrored event refused: {}", result.message());
+                return;
+            }
+        }
+        stats
+            .newest_created_at
+            .fetch_max(created_at, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::testvec::event::VALID_EVENT;
+    use crate::test_util::{temp_db_dir, valid_event};
+
+    fn event_message(subscription_id: &str) -> String {
+        let event: Value = serde_json::from_str(VALID_EVENT).unwrap();
+        serde_json::json!(["EVENT", subscription_id, event]).to_string()
+    }
+
+    #[test]
+    fn upstream_messages_parsed() {
+        let message = event_message(MIRROR_SUBSCRIPTION_ID);
+        let expected = valid_event();
+        assert_eq!(
+            parse_message(&message, None).unwrap(),
+            Received::Event(Box::new(expected))
+        );
+        // events for other subscriptions are not ours
+        assert_eq!(
+            parse_message(&event_message("other"), None).unwrap(),
+            Received::Other
+        );
+        assert_eq!(
+            parse_message(r#"["EOSE","nostrd-mirror"]"#, None).unwrap(),
+            Received::EndOfStored
+        );
+        assert_eq!(
+            parse_message(r#"["CLOSED","nostrd-mirror","blocked"]"#, None).unwrap(),
+            Received::Closed("blocked".to_owned())
+        );
+        assert_eq!(
+            parse_message(r#"["NOTICE","hello"]"#, None).unwrap(),
+            Received::Notice("hello".to_owned())
+        );
+        assert!(parse_message("not json", None).is_err());
+    }
+
+    #[test]
+    fn upstream_events_validated() {
+        let message = event_message(MIRROR_SUBSCRIPTION_ID);
+        // too large
+        assert!(matches!(
+            parse_message(&message, Some(64)),
+            Err(Error::EventMaxLengthError(_))
+        ));
+        // with a signature that does not match
+        let mut event: Value = serde_json::from_str(VALID_EVENT).unwrap();
+        event["content"] = "changed".into();
+        let message = serde_json::json!(["EVENT", MIRROR_SUBSCRIPTION_ID, event]).to_string();
+        assert!(parse_message(&message, None).is_err());
+    }
+
+    #[test]
+    fn filter_resumed_from_newest() {
+        let filter = serde_json::json!({"kinds": [1], "since": 100});
+        assert_eq!(resume_filter(&filter, 0), filter);
+        assert_eq!(resume_filter(&filter, 50), filter);
+        assert_eq!(
+            resume_filter(&filter, 200),
+            serde_json::json!({"kinds": [1], "since": 200})
+        );
+        assert_eq!(
+            resume_filter(&serde_json::json!({}), 200),
+            serde_json::json!({"since": 200})
+        );
+    }
+
+    #[test]
+    fn invalid_upstreams_refused() {
+        let settings = config::Mirror {
+            upstreams: None,
+            max_backoff_secs: 60,
+        };
+        let dir = temp_db_dir();
+        let bans = Arc::new(Bans::load(&dir).unwrap());
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let events = EventQueue::new(tx, None);
+        let upstream = |url: &str, filter: Option<&str>| Upstream {
+            url: url.to_owned(),
+            filter: filter.map(str::to_owned),
+        };
+        let check = |upstream: Upstream| {
+            Mirror::new(&upstream, &settings, events.clone(), bans.clone()).map(|m| m.host)
+        };
+        assert_eq!(
+            check(upstream(
+                "wss://relay.example.com",
+                Some(r#"{"kinds":[1]}"#)
+            ))
+            .unwrap(),
+            "relay.example.com"
+        );
+        assert!(check(upstream("https://relay.example.com", None)).is_err());
+        assert!(check(upstream("wss://relay.example.com", Some("[1]"))).is_err());
+        assert!(check(upstream(
+            "wss://relay.example.com",
+            Some(r#"{"kinds":"x"}"#)
+        ))
+        .is_err());
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,365 @@
+//! Verification of NIP-05 identifiers found in author metadata
+use crate::config::{Verification, SETTINGS};
+use crate::db::{retention, Storage, UserVerification};
+use crate::error::{Error, Result};
+use crate::protocol::{BroadcastEvent, Event};
+use bitcoin_hashes::hex::ToHex;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+/// How often identifiers due for a check are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Identifiers checked in each sweep.
+const SWEEP_BATCH: u64 = 100;
+
+/// Largest well-known document read from a domain.
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// A NIP-05 identifier, `local@domain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nip05Name {
+    local: String,
+    domain: String,
+}
+
+impl Nip05Name {
+    /// Domain that vouches for the identifier.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Location of the well-known document listing the identifier.
+    fn url(&self) -> String {
+        format!(
+            "https://{}/.well-known/nostr.json?name={}",
+            self.domain, self.local
+        )
+    }
+}
+
+impl FromStr for Nip05Name {
+    type Err = Error;
+
+    /// Parse an identifier, allowing only the characters NIP-05
+    /// permits in the local part, and hostname characters in the
+    /// domain.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::GenericError(format!("invalid NIP-05 identifier: {:?}", s));
+        let (local, domain) = s.split_once('@').ok_or_else(invalid)?;
+        let local = local.to_lowercase();
+        let domain = domain.to_lowercase();
+        let local_ok = !local.is_empty()
+            && local
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        let domain_ok = domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.')
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.".contains(c));
+        if local_ok && domain_ok {
+            Ok(Nip05Name { local, domain })
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl fmt::Display for Nip05Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.local, self.domain)
+    }
+}
+
+/// The NIP-05 identifier claimed by a metadata event, if it has a
+/// valid one.
+pub fn nip05_from_metadata(event: &Event) -> Option<Nip05Name> {
+    if !event.is_metadata() {
+        return None;
+    }
+    let metadata: serde_json::Value = serde_json::from_str(&event.content).ok()?;
+    metadata.get("nip05")?.as_str()?.parse().ok()
+}
+
+/// Check that a well-known document maps `name` to `pubkey`.
+fn document_lists(body: &[u8], name: &Nip05Name, pubkey: &XOnlyPublicKey) -> bool {
+    let document: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(document) => document,
+        Err(_) => return false,
+    };
+    document
+        .get("names")
+        .and_then(|names| names.get(&name.local))
+        .and_then(|key| key.as_str())
+        .is_some_and(|key| key.eq_ignore_ascii_case(&pubkey.to_hex()))
+}
+
+/// How long to wait before contacting a domain again after
+/// `failures` consecutive failures, doubling from `base` up to `max`.
+pub(crate) fn backoff(base: Duration, failures: u32, max: Duration) -> Duration {
+    let factor = 1u32
+        .checked_shl(failures.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    base.checked_mul(factor).map_or(max, |d| d.min(max))
+}
+
+/// When a domain may next be contacted.
+#[derive(Debug)]
+struct DomainState {
+    /// Earliest time for the next request
+    next_request: Instant,
+    /// Requests failed in a row
+    failures: u32,
+}
+
+/// Checks the NIP-05 identifiers of authors in the background,
+/// recording the results with the [`Storage`] backend.
+///
+/// Identifiers are checked when new metadata events are stored, and
+/// again once `verification.reverify_interval_seconds` has passed.
+/// Requests to a single domain are spaced out, and domains that fail
+/// to respond are backed off exponentially.
+pub struct Verifier {
+    storage: Arc<dyn Storage>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    settings: Verification,
+    domains: HashMap<String, DomainState>,
+}
+
+impl Verifier {
+    /// Create a verifier if `verification.enabled` is set.
+    pub fn from_settings(storage: Arc<dyn Storage>) -> Option<Self> {
+        let settings = SETTINGS.read().unwrap().verification.clone();
+        if !settings.enabled {
+            return None;
+        }
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_only()
+            .enable_http1()
+            .build();
+        Some(Verifier {
+            storage,
+            client: Client::builder().build(https),
+            settings,
+            domains: HashMap::new(),
+        })
+    }
+
+    /// Check identifiers until shutdown, watching `events` for newly
+    /// stored metadata.
+    pub async fn run(
+        mut self,
+        mut events: broadcast::Receiver<BroadcastEvent>,
+        mut shutdown: broadcast::Receiver<()>,
+    ) {
+        info!("NIP-05 verification enabled");
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => break,
+                received = events.recv() => match received {
+                    Ok(event) if event.is_metadata() => {
+                        if let Err(e) = self.metadata_stored(&event).await {
+                            warn!("could not record NIP-05 identifier: {}", e);
+                        }
+                    }
+                    Ok(_) => {}
+                    // missed metadata is picked up when it is next updated
+                    Err(RecvError::Lagged(n)) => debug!("verifier skipped {} events", n),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = sweep.tick() => {
+                    if let Err(e) = self.check_due().await {
+                        warn!("could not check NIP-05 identifiers: {}", e);
+                    }
+                }
+            }
+        }
+        info!("NIP-05 verification stopped");
+    }
+
+    /// Track the identifier in a newly stored metadata event, checking
+    /// it at once if it changed.
+    async fn metadata_stored(&mut self, event: &Event) -> Result<()> {
+        let name = match nip05_from_metadata(event) {
+            Some(name) => name,
+            None => return self.storage.delete_verification(event.pubkey).await,
+        };
+        let known = self.storage.verification(event.pubkey).await?;
+        if known.is_some_and(|v| v.name == name.to_string()) {
+            return Ok(());
+        }
+        let verification = UserVerification::new(event.pubkey, name.to_string());
+        self.check(verification).await
+    }
+
+    /// Check identifiers that were never checked, or not recently.
+    async fn check_due(&mut self) -> Result<()> {
+        let before = retention::now().saturating_sub(self.settings.reverify_interval_seconds);
+        for verification in self.storage.verifications_due(before, SWEEP_BATCH).await? {
+            self.check(verification).await?;
+        }
+        Ok(())
+    }
+
+    /// Check an identifier against its domain and record the result.
+    /// Domains in back-off are skipped, leaving the identifier due.
+    async fn check(&mut self, mut verification: UserVerification) -> Result<()> {
+        let name: Nip05Name = verification.name.parse()?;
+        let now = Instant::now();
+        let state = self
+            .domains
+            .entry(name.domain.clone())
+            .or_insert(DomainState {
+                next_request: now,
+                failures: 0,
+            });
+        if state.failures > 0 && state.next_request > now {
+            debug!("skipping {}, domain is backing off", name);
+            self.storage.save_verification(verification).await?;
+            return Ok(());
+        }
+        tokio::time::sleep_until(state.next_request).await;
+        let fetched = self.fetch(&name).await;
+        let state = self.domains.get_mut(&name.domain).expect("domain state");
+        verification.last_checked = Some(retention::now());
+        match fetched {
+            Ok(body) => {
+                state.failures = 0;
+                state.next_request =
+                    Instant::now() + Duration::from_millis(self.settings.domain_interval_ms);
+                if document_lists(&body, &name, &verification.pubkey) {
+                    debug!("verified {}", name);
+                    verification.last_success = verification.last_checked;
+                    verification.failure_count = 0;
+                } else {
+                    debug!("{} does not list this author", name);
+                    verification.failure_count += 1;
+                }
+            }
+            Err(e) => {
+                state.failures += 1;
+                let wait = backoff(
+                    Duration::from_millis(self.settings.domain_interval_ms),
+                    state.failures,
+                    Duration::from_secs(self.settings.max_backoff_seconds),
+                );
+                state.next_request = Instant::now() + wait;
+                info!(
+                    "could not check {} ({}), retrying domain in {:?}",
+                    name, e, wait
+                );
+                verification.failure_count += 1;
+            }
+        }
+        self.storage.save_verification(verification).await
+    }
+
+    /// Fetch the well-known document for `name`, within the configured
+    /// timeout.  Redirects are not followed.
+    async fn fetch(&self, name: &Nip05Name) -> Result<Vec<u8>> {
+        let uri: Uri = name
+            .url()
+            .parse()
+            .map_err(|e| Error::GenericError(format!("invalid URL: {}", e)))?;
+        let request = Request::get(uri)
+            .body(Body::empty())
+            .map_err(|e| Error::GenericError(e.to_string()))?;
+        let timeout = Duration::from_secs(self.settings.fetch_timeout_seconds);
+        let response = async {
+            let response = self.client.request(request).await.map_err(http_error)?;
+            if response.status() != StatusCode::OK {
+                return Err(Error::GenericError(format!(
+                    "unexpected status {}",
+                    response.status()
+                )));
+            }
+            let mut body = response.into_body();
+            let mut document = Vec::new();
+            while let Some(chunk) = body.data().await {
+                document.extend_from_slice(&chunk.map_err(http_error)?);
+                if document.len() > MAX_RESPONSE_BYTES {
+                    return Err(Error::GenericError("response too large".to_owned()));
+                }
+            }
+            Ok(document)
+        };
+        tokio::time::timeout(timeout, response)
+            .await
+            .map_err(|_| Error::GenericError("request timed out".to_owned()))?
+    }
+}
+
+fn http_error(e: hyper::Error) -> Error {
+    Error::GenericError(format!("HTTP error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::testvec::event::VALID_EVENT;
+
+    #[test]
+    fn parse_names() {
+        let name: Nip05Name = "Bob_1@Example.com".parse().unwrap();
+        assert_eq!(name.to_string(), "bob_1@example.com");
+        assert_eq!(
+            name.url(),
+            "https://example.com/.well-known/nostr.json?name=bob_1"
+        );
+        for invalid in [
+            "bob",
+            "@example.com",
+            "bob@localhost",
+            "b ob@example.com",
+            "bob@ex/ample.com",
+        ] {
+            assert!(invalid.parse::<Nip05Name>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn metadata_identifier() {
+        let mut event: serde_json::Value = serde_json::from_str(VALID_EVENT).unwrap();
+        event["kind"] = 0.into();
+        event["content"] = r#"{"name":"bob","nip05":"bob@example.com"}"#.into();
+        let metadata: Event = serde_json::from_value(event.clone()).unwrap();
+        let name = nip05_from_metadata(&metadata).unwrap();
+        assert_eq!(name.to_string(), "bob@example.com");
+
+        let pubkey = metadata.pubkey;
+        let document = format!(r#"{{"names":{{"bob":"{}"}}}}"#, pubkey.to_hex());
+        assert!(document_lists(document.as_bytes(), &name, &pubkey));
+        let other: Nip05Name = "alice@example.com".parse().unwrap();
+        assert!(!document_lists(document.as_bytes(), &other, &pubkey));
+        assert!(!document_lists(b"not json", &name, &pubkey));
+
+        event["kind"] = 1.into();
+        let note: Event = serde_json::from_value(event).unwrap();
+        assert!(nip05_from_metadata(&note).is_none());
+    }
+
+    #[test]
+    fn backoff_doubles_to_max() {
+        let base = Duration::from_secs(1);
+        let max = Duration::from_secs(60);
+        assert_eq!(backoff(base, 1, max), Duration::from_secs(1));
+        assert_eq!(backoff(base, 3, max), Duration::from_secs(4));
+        assert_eq!(backoff(base, 40, max), max);
+    }
+}
@@ -0,0 +1,446 @@
+//! Bounded queue of responses waiting to be written to a client
+use crate::metrics::METRICS;
+use crate::protostream::{close_message, response_message, NostrResponse};
+use futures::{Sink, SinkExt};
+use log::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+/// Responses queued for each connection, unless configured
+pub const DEFAULT_SEND_QUEUE_SIZE: usize = 1024;
+
+/// Responses not yet written.
+#[derive(Default)]
+struct Pending {
+    /// Replies to the client's own messages, including query results
+    responses: VecDeque<NostrResponse>,
+    /// Events broadcast to matching subscriptions
+    broadcasts: VecDeque<NostrResponse>,
+    /// Payload of a keepalive ping to send ahead of any responses
+    ping: Option<Vec<u8>>,
+    /// Set once a reply found the queue full of other replies
+    overflowed: bool,
+    /// Set once nothing more will be queued, with the code and reason
+    /// to send in a close frame, if any
+    finished: Option<Option<(u16, String)>>,
+}
+
+impl Pending {
+    fn depth(&self) -> usize {
+        self.responses.len() + self.broadcasts.len()
+    }
+}
+
+/// The next thing for the writer to do.
+enum Next {
+    Overflowed,
+    Ping(Vec<u8>),
+    Send(NostrResponse),
+    Close(Option<(u16, String)>),
+}
+
+/// Responses waiting to be written to a client.
+///
+/// Queueing never waits.  When the queue is full, broadcast events
+/// are dropped first, since the client can query for them again.
+/// Once it is full of replies, the client is disconnected as too slow
+/// rather than more being held for it.
+pub struct OutboundQueue {
+    pending: Mutex<Pending>,
+    ready: Notify,
+    capacity: usize,
+    dropped_broadcasts: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+/// Why a connection writer stopped.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WriterExit {
+    /// The queue was finished and everything in it written
+    Finished,
+    /// A write did not complete within the send timeout, with
+    /// `depth` responses still queued
+    SlowClient { depth: usize },
+    /// A reply found the queue full of replies not yet written, with
+    /// `depth` responses queued
+    QueueFull { depth: usize },
+    /// The websocket failed
+    Disconnected,
+}
+
+impl OutboundQueue {
+    /// Create a queue holding up to `capacity` responses.
+    pub fn new(capacity: usize) -> Self {
+        OutboundQueue {
+            pending: Mutex::new(Pending::default()),
+            ready: Notify::new(),
+            capacity: capacity.max(1),
+            dropped_broadcasts: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    /// The queue, even if a thread panicked while holding it.
+    fn pending(&self) -> MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Number of responses waiting to be written.
+    pub fn depth(&self) -> usize {
+        self.pending().depth()
+    }
+
+    /// Check whether a response can be queued without dropping a
+    /// broadcast event.
+    pub fn has_room(&self) -> bool {
+        self.depth() < self.capacity
+    }
+
+    /// Number of broadcast events dropped because the queue was full.
+    pub fn dropped_broadcasts(&self) -> u64 {
+        self.dropped_broadcasts.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the client, counting the payload of every
+    /// frame.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// Queue a reply to the client, dropping the oldest broadcast
+    /// event if the queue is full.  If there is none to drop, the
+    /// reply is dropped instead, and the writer stops.
+    pub fn send(&self, response: NostrResponse) {
+        let mut pending = self.pending();
+        if pending.depth() >= self.capacity {
+            if pending.broadcasts.pop_front().is_some() {
+                self.dropped_broadcasts.fetch_add(1, Ordering::Relaxed);
+            } else {
+                pending.overflowed = true;
+                self.ready.notify_one();
+                return;
+            }
+        }
+        pending.responses.push_back(response);
+        self.ready.notify_one();
+    }
+
+    /// Queue a broadcast event unless the queue is full, returning
+    /// whether it was queued.
+    pub fn send_broadcast(&self, response: NostrResponse) -> bool {
+        let mut pending = self.pending();
+        if pending.depth() >= self.capacity {
+            self.dropped_broadcasts.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        pending.broadcasts.push_back(response);
+        self.ready.notify_one();
+        true
+    }
+
+    /// Send a ping with `payload` before any queued responses,
+    /// replacing a ping not yet sent.
+    pub fn ping(&self, payload: Vec<u8>) {
+        let mut pending = self.pending();
+        pending.ping = Some(payload);
+        self.ready.notify_one();
+    }
+
+    /// Stop the writer once the queued responses are written, sending
+    /// a close frame with `reason` if one is given.
+    pub fn finish(&self, reason: Option<&str>) {
+        self.finish_closing(reason.map(|reason| (CloseCode::Away.into(), reason)));
+    }
+
+    /// Stop the writer once the queued responses are written, sending
+    /// a close frame with `code` and `reason`.
+    pub fn finish_with_code(&self, code: u16, reason: &str) {
+        self.finish_closing(Some((code, reason)));
+    }
+
+    fn finish_closing(&self, close: Option<(u16, &str)>) {
+        let mut pending = self.pending();
+        if pending.finished.is_none() {
+            pending.finished = Some(close.map(|(code, reason)| (code, reason.to_owned())));
+        }
+        self.ready.notify_one();
+    }
+
+    /// Wait for the next response, or for the queue to finish.
+    async fn next(&self) -> Next {
+        loop {
+            {
+                let mut pending = self.pending();
+                if pending.overflowed {
+                    return Next::Overflowed;
+                }
+                if let Some(payload) = pending.ping.take() {
+                    return Next::Ping(payload);
+                }
+                if let Some(response) = pending.responses.pop_front() {
+                    return Next::Send(response);
+                }
+                if let Some(response) = pending.broadcasts.pop_front() {
+                    return Next::Send(response);
+                }
+                if let Some(reason) = pending.finished.take() {
+                    return Next::Close(reason);
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+/// Finishes a queue when dropped, so that its writer stops and lets
+/// go of the connection however the task filling the queue ends,
+/// including by panicking.
+pub struct FinishOnDrop(pub Arc<OutboundQueue>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish(None);
+    }
+}
+
+/// Write queued responses to `sink` until the queue is finished, the
+/// sink fails, or a single write takes longer than `send_timeout`.
+/// Every frame is subject to the timeout, including pings and the
+/// final close frame, so a peer that stops reading cannot hold the
+/// writer forever.
+pub async fn write_outbound<S>(
+    queue: &OutboundQueue,
+    mut sink: S,
+    send_timeout: Duration,
+) -> WriterExit
+where
+    S: Sink<Message> + Unpin,
+{
+    loop {
+        let message = match queue.next().await {
+            Next::Overflowed => {
+                return WriterExit::QueueFull {
+                    depth: queue.depth(),
+                }
+            }
+            Next::Ping(payload) => Message::Ping(payload),
+            Next::Send(response) => match response_message(&response) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("could not serialize response: {}", e);
+                    continue;
+                }
+            },
+            Next::Close(reason) => {
+                if let Some((code, reason)) = reason {
+                    let message = close_message(code, &reason);
+                    let len = message.len() as u64;
+                    match tokio::time::timeout(send_timeout, sink.send(message)).await {
+                        Ok(Ok(())) => {
+                            queue.bytes_written.fetch_add(len, Ordering::Relaxed);
+                            METRICS.close_sent(code);
+                        }
+                        Ok(Err(_)) => return WriterExit::Disconnected,
+                        Err(_) => {
+                            METRICS.write_timed_out();
+                            debug!("timed out sending close frame ({})", reason);
+                            return WriterExit::SlowClient { depth: 0 };
+                        }
+                    }
+                }
+                return WriterExit::Finished;
+            }
+        };
+        let len = message.len() as u64;
+        match tokio::time::timeout(send_timeout, sink.send(message)).await {
+            Ok(Ok(())) => {
+                queue.bytes_written.fetch_add(len, Ordering::Relaxed);
+            }
+            Ok(Err(_)) => return WriterExit::Disconnected,
+            Err(_) => {
+                METRICS.write_timed_out();
+                return WriterExit::SlowClient {
+                    depth: queue.depth(),
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn notice(n: usize) -> NostrResponse {
+        NostrResponse::new_notice(&n.to_string())
+    }
+
+    #[tokio::test]
+    async fn broadcasts_dropped_first() {
+        let queue = OutboundQueue::new(2);
+        assert!(queue.send_broadcast(notice(1)));
+        queue.send(notice(2));
+        // a full queue evicts a broadcast for a reply
+        queue.send(notice(3));
+        assert_eq!(queue.dropped_broadcasts(), 1);
+        // and has no room for more broadcasts
+        assert!(!queue.send_broadcast(notice(4)));
+        assert_eq!(queue.dropped_broadcasts(), 2);
+        assert!(!queue.has_room());
+
+        queue.finish(None);
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let exit = write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        assert_eq!(exit, WriterExit::Finished);
+        assert_eq!(queue.bytes_written(), 28);
+        let written: Vec<Message> = rx.collect().await;
+        assert_eq!(
+            written,
+            vec![
+                Message::Text(r#"["NOTICE","2"]"#.to_owned()),
+                Message::Text(r#"["NOTICE","3"]"#.to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_capped() {
+        let queue = OutboundQueue::new(2);
+        for n in 0..3 {
+            queue.send(notice(n));
+        }
+        assert_eq!(queue.depth(), 2);
+        // the client is given up on, rather than sent a partial reply
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let exit = write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        assert_eq!(exit, WriterExit::QueueFull { depth: 2 });
+        let written: Vec<Message> = rx.collect().await;
+        assert!(written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_client_released() {
+        let queue = OutboundQueue::new(16);
+        for n in 0..4 {
+            queue.send(notice(n));
+        }
+        // a client that stops reading after the first message
+        let (tx, mut rx) = futures::channel::mpsc::channel(0);
+        let exit = write_outbound(&queue, tx, Duration::from_millis(100)).await;
+        assert!(matches!(exit, WriterExit::SlowClient { depth } if depth > 0));
+        // the writer has let go of the connection
+        let mut received = 0;
+        while rx.next().await.is_some() {
+            received += 1;
+        }
+        assert!(received < 4);
+    }
+
+    #[tokio::test]
+    async fn close_frame_counted() {
+        let queue = OutboundQueue::new(4);
+        queue.send(notice(7));
+        queue.finish(Some("bye"));
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let exit = write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        assert_eq!(exit, WriterExit::Finished);
+        let written: Vec<Message> = rx.collect().await;
+        assert_eq!(written.len(), 2);
+        // the notice text, then the close reason
+        assert_eq!(queue.bytes_written(), 14 + 3);
+    }
+
+    #[tokio::test]
+    async fn close_frame_going_away() {
+        let queue = OutboundQueue::new(4);
+        queue.finish(Some("relay shutting down"));
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        let written: Vec<Message> = rx.collect().await;
+        match &written[..] {
+            [Message::Close(Some(frame))] => {
+                assert_eq!(u16::from(frame.code), 1001);
+                assert_eq!(frame.reason, "relay shutting down");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_frame_code_given() {
+        let queue = OutboundQueue::new(4);
+        queue.finish_with_code(1008, "too many errors");
+        // the first reason given is kept
+        queue.finish(Some("relay shutting down"));
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        let written: Vec<Message> = rx.collect().await;
+        match &written[..] {
+            [Message::Close(Some(frame))] => {
+                assert_eq!(u16::from(frame.code), 1008);
+                assert_eq!(frame.reason, "too many errors");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_sent_first() {
+        let queue = OutboundQueue::new(4);
+        queue.send(notice(1));
+        queue.ping(vec![1]);
+        queue.ping(vec![2]);
+        queue.finish(None);
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        write_outbound(&queue, tx, Duration::from_secs(1)).await;
+        let written: Vec<Message> = rx.collect().await;
+        assert_eq!(
+            written,
+            vec![
+                Message::Ping(vec![2]),
+                Message::Text(r#"["NOTICE","1"]"#.to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn wedged_socket_times_out() {
+        // a peer that never reads, behind small kernel buffers
+        let listener = tokio::net::TcpSocket::new_v4().unwrap();
+        listener.set_recv_buffer_size(4096).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = listener.listen(1).unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_send_buffer_size(4096).unwrap();
+        let stream = socket.connect(addr).await.unwrap();
+        let (_peer, _) = listener.accept().await.unwrap();
+        let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+            stream,
+            tungstenite::protocol::Role::Server,
+            None,
+        )
+        .await;
+
+        let queue = OutboundQueue::new(1024);
+        let text = "x".repeat(16 * 1024);
+        for _ in 0..1024 {
+            queue.send(NostrResponse::new_notice(&text));
+        }
+        queue.finish(Some("bye"));
+        let exit = tokio::time::timeout(
+            Duration::from_secs(10),
+            write_outbound(&queue, ws, Duration::from_millis(200)),
+        )
+        .await
+        .expect("the writer gave up on the wedged socket");
+        assert!(matches!(exit, WriterExit::SlowClient { depth } if depth > 0));
+        assert!(queue.bytes_written() < 1024 * 16 * 1024);
+    }
+}
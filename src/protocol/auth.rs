@@ -0,0 +1,153 @@
+//! Client authentication
+//!
+//! Reference specification NIP42: https://github.com/nostr-protocol/nips/blob/master/42.md
+//!
+use super::event::{Event, EventKind};
+use super::tags::TagType;
+use crate::error::Error;
+use secp256k1::XOnlyPublicKey;
+use uuid::Uuid;
+
+/// Furthest from the present an authentication event may have been
+/// created, in seconds
+pub const AUTH_WINDOW_SECS: u64 = 600;
+
+/// Make a challenge for a client to sign, different for every
+/// connection.
+pub fn challenge() -> String {
+    Uuid::new_v4().to_simple().to_string()
+}
+
+/// Check that `event` answers `challenge`, for the relay at
+/// `relay_url` if it is known, and was created within
+/// [`AUTH_WINDOW_SECS`] of `now`.  Returns the key the client proved
+/// it holds.
+pub fn authenticated_pubkey(
+    event: &Event,
+    challenge: &str,
+    relay_url: Option<&str>,
+    now: u64,
+) -> Result<XOnlyPublicKey, Error> {
+    let failed = |reason: &str| Error::AuthFailed(reason.to_owned());
+    if event.kind != EventKind::ClientAuth {
+        return Err(failed("not an authentication event"));
+    }
+    if event.created_at.abs_diff(now) > AUTH_WINDOW_SECS {
+        return Err(failed("event is too old or too far in future"));
+    }
+    let tag = |tag_type| {
+        event
+            .tags
+            .iter()
+            .find(|tag| tag.get_type() == tag_type)
+            .and_then(|tag| tag.get_text())
+    };
+    if tag(TagType::Challenge) != Some(challenge) {
+        return Err(failed("wrong challenge"));
+    }
+    let relay = tag(TagType::Relay).ok_or_else(|| failed("missing relay tag"))?;
+    if let Some(url) = relay_url {
+        if !same_relay(relay, url) {
+            return Err(failed("wrong relay"));
+        }
+    }
+    event.verify().map_err(|_| failed("bad signature"))?;
+    Ok(event.pubkey)
+}
+
+/// Whether two relay URLs name the same relay, ignoring case and a
+/// trailing slash.
+fn same_relay(a: &str, b: &str) -> bool {
+    let normalize = |url: &str| url.trim_end_matches('/').to_ascii_lowercase();
+    normalize(a) == normalize(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin_hashes::{hex::ToHex, sha256, Hash};
+    use secp256k1::{KeyPair, Secp256k1};
+    use serde_json::{json, Value};
+
+    const RELAY: &str = "wss://relay.example.com/";
+    const NOW: u64 = 1_700_000_000;
+
+    /// An authentication event signed with a key of `secret` bytes.
+    fn auth_event(secret: u8, kind: u64, created_at: u64, tags: Value) -> Event {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_slice(&secp, &[secret; 32]).unwrap();
+        let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_hex();
+        let canonical = json!([0, pubkey, created_at, kind, tags, ""]).to_string();
+        let id = sha256::Hash::hash(canonical.as_bytes());
+        let msg = secp256k1::Message::from_slice(id.as_inner()).unwrap();
+        let sig = secp.sign_schnorr(&msg, &keypair);
+        serde_json::from_value(json!({
+            "id": id.to_hex(),
+            "pubkey": pubkey,
+            "created_at": created_at,
+            "kind": kind,
+            "tags": tags,
+            "content": "",
+            "sig": sig.to_string(),
+        }))
+        .unwrap()
+    }
+
+    fn failure(result: Result<XOnlyPublicKey, Error>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn challenge_answered() {
+        let tags = json!([["relay", RELAY], ["challenge", "c1"]]);
+        let event = auth_event(1, 22242, NOW - 30, tags);
+        let pubkey = authenticated_pubkey(&event, "c1", Some(RELAY), NOW).unwrap();
+        assert_eq!(&pubkey, event.get_pubkey());
+        // the relay may be named loosely, or be unknown
+        let loose = Some("WSS://Relay.Example.com");
+        assert!(authenticated_pubkey(&event, "c1", loose, NOW).is_ok());
+        assert!(authenticated_pubkey(&event, "c1", None, NOW).is_ok());
+        assert_ne!(challenge(), challenge());
+    }
+
+    #[test]
+    fn wrong_answers_refused() {
+        let tags = json!([["relay", RELAY], ["challenge", "c1"]]);
+        let event = auth_event(1, 22242, NOW, tags.clone());
+        assert_eq!(
+            failure(authenticated_pubkey(&event, "c2", Some(RELAY), NOW)),
+            "authentication failed: wrong challenge"
+        );
+        let other = Some("wss://other.example.com/");
+        assert_eq!(
+            failure(authenticated_pubkey(&event, "c1", other, NOW)),
+            "authentication failed: wrong relay"
+        );
+        let stale = NOW + AUTH_WINDOW_SECS + 1;
+        assert_eq!(
+            failure(authenticated_pubkey(&event, "c1", Some(RELAY), stale)),
+            "authentication failed: event is too old or too far in future"
+        );
+        let note = auth_event(1, 1, NOW, tags);
+        assert_eq!(
+            failure(authenticated_pubkey(&note, "c1", Some(RELAY), NOW)),
+            "authentication failed: not an authentication event"
+        );
+        let unnamed = auth_event(1, 22242, NOW, json!([["challenge", "c1"]]));
+        assert_eq!(
+            failure(authenticated_pubkey(&unnamed, "c1", None, NOW)),
+            "authentication failed: missing relay tag"
+        );
+    }
+
+    #[test]
+    fn forged_signature_refused() {
+        let tags = json!([["relay", RELAY], ["challenge", "c1"]]);
+        let mut event = auth_event(1, 22242, NOW, tags);
+        event.pubkey = *auth_event(2, 22242, NOW, json!([])).get_pubkey();
+        assert_eq!(
+            failure(authenticated_pubkey(&event, "c1", Some(RELAY), NOW)),
+            "authentication failed: bad signature"
+        );
+    }
+}
@@ -129,13 +129,65 @@ impl From<EventCmd> for Event {
     }
 }
 
+/// Auth command in network format, answering the relay's challenge
+/// with a signed event (NIP-42)
+#[derive(PartialEq, Debug, Clone)]
+pub struct AuthCmd {
+    event: Event,
+}
+
+impl Serialize for AuthCmd {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element("AUTH")?;
+        seq.serialize_element(&self.event)?;
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthCmd {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let received: Value = Deserialize::deserialize(deserializer)?;
+
+        let items = received.as_array().ok_or(serde::de::Error::invalid_type(
+            Unexpected::Other("json type in auth message"),
+            &"a json array",
+        ))?;
+
+        if items.len() != 2 || items[0] != "AUTH" {
+            return Err(serde::de::Error::invalid_value(
+                Unexpected::Other("auth message"),
+                &"AUTH and an event",
+            ));
+        }
+        // the signature is checked along with the challenge
+        let event = serde_json::from_value(items[1].clone())
+            .map_err(|e| serde::de::Error::custom(e.to_string()))?;
+        Ok(Self { event })
+    }
+}
+
+impl From<AuthCmd> for Event {
+    fn from(ac: AuthCmd) -> Self {
+        ac.event
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::super::testvec::event::VALID_EVENT;
     use super::*;
 
     #[test]
     fn close_command() {
-        let close = r#"["CLOSE","5436cab31e64e4f2cbd6216a68d95369210174fa4a82e77d09184aa51806de60"]"#;
+        let close =
+            r#"["CLOSE","5436cab31e64e4f2cbd6216a68d95369210174fa4a82e77d09184aa51806de60"]"#;
         let close1: Close = serde_json::from_str(close).unwrap();
         let ser_close1 = serde_json::to_string(&close1).unwrap();
         assert_eq!(ser_close1, close);
@@ -187,4 +239,15 @@ mod test {
         let event_cmd_2: EventCmd = serde_json::from_str(&ser_event_cmd).unwrap();
         assert_eq!(event_cmd, event_cmd_2);
     }
+
+    #[test]
+    fn auth_command() {
+        let event = serde_json::to_string(&Event::from_str(VALID_EVENT).unwrap()).unwrap();
+        let auth = format!(r#"["AUTH",{}]"#, event);
+        let auth_cmd: AuthCmd = serde_json::from_str(&auth).unwrap();
+        assert_eq!(serde_json::to_string(&auth_cmd).unwrap(), auth);
+        let event = format!(r#"["EVENT",{}]"#, event);
+        assert!(serde_json::from_str::<AuthCmd>(&event).is_err());
+        assert!(serde_json::from_str::<AuthCmd>(r#"["AUTH","challenge"]"#).is_err());
+    }
 }
@@ -0,0 +1,128 @@
+//! Delegated event signing
+//!
+//! Reference specification NIP26: https://github.com/nostr-protocol/nips/blob/master/26.md
+//!
+use super::event::{Event, SECP};
+use super::tags::TagType;
+use crate::error::Error;
+use bitcoin_hashes::{hex::ToHex, sha256, Hash};
+use secp256k1::XOnlyPublicKey;
+use std::str::FromStr;
+
+/// What a delegator allows the events signed on its behalf to be.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Conditions {
+    /// Kinds allowed, any of them if empty
+    kinds: Vec<u64>,
+    /// Events must be created after this time
+    created_after: Option<u64>,
+    /// Events must be created before this time
+    created_before: Option<u64>,
+}
+
+impl FromStr for Conditions {
+    type Err = Error;
+
+    /// Parse a query string of conditions, such as
+    /// `kind=1&created_at>1674834236&created_at<1677426236`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conditions = Conditions::default();
+        let invalid = |clause: &str| {
+            Error::EventInvalid(format!("invalid delegation condition {:?}", clause))
+        };
+        for clause in s.split('&').filter(|clause| !clause.is_empty()) {
+            if let Some(kind) = clause.strip_prefix("kind=") {
+                conditions
+                    .kinds
+                    .push(kind.parse().map_err(|_| invalid(clause))?);
+            } else if let Some(time) = clause.strip_prefix("created_at>") {
+                let time = time.parse().map_err(|_| invalid(clause))?;
+                conditions.created_after = conditions.created_after.max(Some(time));
+            } else if let Some(time) = clause.strip_prefix("created_at<") {
+                let time: u64 = time.parse().map_err(|_| invalid(clause))?;
+                conditions.created_before =
+                    Some(conditions.created_before.map_or(time, |t| t.min(time)));
+            } else {
+                return Err(invalid(clause));
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+impl Conditions {
+    /// Check whether an event of `kind` created at `created_at` is
+    /// allowed.
+    pub fn allow(&self, kind: u64, created_at: u64) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&kind))
+            && self.created_after.map_or(true, |after| created_at > after)
+            && self
+                .created_before
+                .map_or(true, |before| created_at < before)
+    }
+}
+
+/// The hash a delegator signs to let `delegatee` publish events
+/// meeting `conditions`.
+pub fn delegation_token(delegatee: &XOnlyPublicKey, conditions: &str) -> sha256::Hash {
+    let token = format!("nostr:delegation:{}:{}", delegatee.to_hex(), conditions);
+    sha256::Hash::hash(token.as_bytes())
+}
+
+/// The author who delegated signing `event`, if it carries a
+/// delegation tag with a valid signature, whose conditions it meets.
+pub fn delegator(event: &Event) -> Option<XOnlyPublicKey> {
+    let tag = event
+        .tags
+        .iter()
+        .find(|tag| tag.get_type() == TagType::Delegation)?;
+    let (delegator, conditions, sig) = tag.get_delegation()?;
+    let token = delegation_token(&event.pubkey, conditions);
+    let msg = secp256k1::Message::from_slice(token.as_inner()).ok()?;
+    SECP.verify_schnorr(sig, &msg, delegator).ok()?;
+    let allowed = Conditions::from_str(conditions)
+        .ok()?
+        .allow(event.kind.as_u64(), event.created_at);
+    allowed.then_some(*delegator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example from NIP-26
+    const DELEGATEE: &str = "477318cfb5427b9cfc66a9fa376150c1ddbc62115ae27cef72417eb959691396";
+    const DELEGATOR: &str = "8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd";
+    const CONDITIONS: &str = "kind=1&created_at>1674834236&created_at<1677426236";
+    const SIG: &str = "6f44d7fe4f1c09f3954640fb58bd12bae8bb8ff4120853c4693106c82e920e2b898f1f9ba9bd65449a987c39c0423426ab7b53910c0c6abfb41b30bc16e5f524";
+
+    #[test]
+    fn conditions_parsed() {
+        let conditions = Conditions::from_str(CONDITIONS).unwrap();
+        assert!(conditions.allow(1, 1674834237));
+        assert!(!conditions.allow(0, 1674834237));
+        assert!(!conditions.allow(1, 1674834236));
+        assert!(!conditions.allow(1, 1677426236));
+        let any = Conditions::from_str("").unwrap();
+        assert!(any.allow(2, 0));
+        let kinds = Conditions::from_str("kind=0&kind=2").unwrap();
+        assert!(kinds.allow(2, 0));
+        assert!(!kinds.allow(1, 0));
+        assert!(Conditions::from_str("kind=x").is_err());
+        assert!(Conditions::from_str("tag=p").is_err());
+    }
+
+    #[test]
+    fn token_signed() {
+        let delegatee = XOnlyPublicKey::from_str(DELEGATEE).unwrap();
+        let delegator = XOnlyPublicKey::from_str(DELEGATOR).unwrap();
+        let sig = secp256k1::schnorr::Signature::from_str(SIG).unwrap();
+        let token = delegation_token(&delegatee, CONDITIONS);
+        let msg = secp256k1::Message::from_slice(token.as_inner()).unwrap();
+        assert!(SECP.verify_schnorr(&sig, &msg, &delegator).is_ok());
+        // the signature covers the conditions
+        let token = delegation_token(&delegatee, "kind=1");
+        let msg = secp256k1::Message::from_slice(token.as_inner()).unwrap();
+        assert!(SECP.verify_schnorr(&sig, &msg, &delegator).is_err());
+    }
+}
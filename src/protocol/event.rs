@@ -9,6 +9,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::value::Value;
 use std::collections::HashSet;
 use std::fmt::Display;
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock};
 use std::time::SystemTime;
 
 use super::tags::{Tag, TagType};
@@ -22,11 +24,13 @@ lazy_static! {
 pub type EventId = sha256::Hash;
 
 /// An event kind as per NIP01 https://github.com/fiatjaf/nostr/blob/master/nips/01.md#basic-event-kinds
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum EventKind {
     SetMetadata,
     TextNote,
     RecommendedServer,
+    /// Proof that a client holds a key, sent with `AUTH` (NIP-42)
+    ClientAuth,
 }
 
 impl Serialize for EventKind {
@@ -34,10 +38,18 @@ impl Serialize for EventKind {
     where
         S: serde::Serializer,
     {
+        serializer.serialize_u64(self.as_u64())
+    }
+}
+
+impl EventKind {
+    /// The kind's number in the protocol.
+    pub fn as_u64(&self) -> u64 {
         match self {
-            Self::SetMetadata => serializer.serialize_u64(0),
-            Self::TextNote => serializer.serialize_u64(1),
-            Self::RecommendedServer => serializer.serialize_u64(2),
+            Self::SetMetadata => 0,
+            Self::TextNote => 1,
+            Self::RecommendedServer => 2,
+            Self::ClientAuth => 22242,
         }
     }
 }
@@ -63,9 +75,10 @@ impl<'de> Deserialize<'de> for EventKind {
             0 => Ok(Self::SetMetadata),
             1 => Ok(Self::TextNote),
             2 => Ok(Self::RecommendedServer),
+            22242 => Ok(Self::ClientAuth),
             _ => Err(serde::de::Error::invalid_value(
                 Unexpected::Unsigned(value),
-                &"0, 1 or 2",
+                &"0, 1, 2 or 22242",
             )),
         }
     }
@@ -116,6 +129,33 @@ impl std::str::FromStr for Event {
 }
 
 impl Event {
+    /// Get the event identifier.
+    pub fn get_event_id(&self) -> EventId {
+        self.id
+    }
+
+    /// Get the public key of the author.
+    pub fn get_pubkey(&self) -> &XOnlyPublicKey {
+        &self.pubkey
+    }
+
+    /// Get the public key of the author who delegated signing this
+    /// event to its pubkey, if it carries a valid NIP-26 delegation.
+    pub fn get_delegator(&self) -> Option<XOnlyPublicKey> {
+        super::delegation::delegator(self)
+    }
+
+    /// Check if this is a metadata (kind 0) event.
+    pub fn is_metadata(&self) -> bool {
+        self.kind == EventKind::SetMetadata
+    }
+
+    /// Check if this is a client authentication (kind 22242) event,
+    /// which is never stored or broadcast.
+    pub fn is_auth(&self) -> bool {
+        self.kind == EventKind::ClientAuth
+    }
+
     /// Create a short event identifier, suitable for logging.
     pub fn get_short_event_id(&self) -> String {
         self.id.to_string()[..8].to_string()
@@ -139,7 +179,12 @@ impl Event {
                 )));
             }
         }
+        self.verify()
+    }
 
+    /// Check that the event id matches the event contents, and that
+    /// the signature is valid for it.
+    pub fn verify(&self) -> Result<(), Error> {
         // Check if correct EventId is provided
         if self.id != self.compute_event_id()? {
             return Err(Error::EventInvalid("Event has wrong event id".to_string()));
@@ -268,6 +313,48 @@ impl Event {
     }
 }
 
+/// A stored event as broadcast to every connection, shared between
+/// them along with its JSON, which is only serialized once.
+#[derive(Debug, Clone)]
+pub struct BroadcastEvent(Arc<SharedEvent>);
+
+#[derive(Debug)]
+struct SharedEvent {
+    event: Event,
+    json: OnceLock<Arc<str>>,
+}
+
+impl BroadcastEvent {
+    pub fn new(event: Event) -> Self {
+        BroadcastEvent(Arc::new(SharedEvent {
+            event,
+            json: OnceLock::new(),
+        }))
+    }
+
+    /// The event serialized as JSON, made the first time it is needed.
+    pub fn json(&self) -> &Arc<str> {
+        self.0.json.get_or_init(|| {
+            serde_json::to_string(&self.0.event)
+                .expect("events serialize to JSON")
+                .into()
+        })
+    }
+}
+
+impl Deref for BroadcastEvent {
+    type Target = Event;
+    fn deref(&self) -> &Event {
+        &self.0.event
+    }
+}
+
+impl PartialEq for BroadcastEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.event == other.0.event
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::testvec::event::*;
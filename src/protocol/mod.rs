@@ -1,11 +1,16 @@
+pub mod auth;
 mod commands;
+mod delegation;
 mod event;
 mod responses;
 mod subscription;
 mod tags;
-mod testvec;
+pub(crate) mod testvec;
 
-pub use commands::{Close, EventCmd};
-pub use event::Event;
-pub use responses::{EventResp, NoticeResp};
-pub use subscription::{Subscription, SubscriptionId};
+pub use commands::{AuthCmd, Close, EventCmd};
+pub use event::{BroadcastEvent, Event, EventId};
+pub use responses::{AuthResp, ClosedResp, EventResp, NoticeResp, OkResp, SerializedEventResp};
+pub use subscription::{
+    is_valid_subscription_id, ReqFilter, Subscription, SubscriptionId, SubscriptionIndex,
+    DEFAULT_MAX_SUBID_LENGTH,
+};
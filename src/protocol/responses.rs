@@ -1,4 +1,4 @@
-use super::event::Event;
+use super::event::{BroadcastEvent, Event};
 use serde::ser::SerializeSeq;
 use serde::Serialize;
 
@@ -32,6 +32,144 @@ impl EventResp {
     }
 }
 
+/// An Event Response for an event already serialized, so that an
+/// event broadcast to many subscriptions is serialized only once
+#[derive(Debug, PartialEq, Clone)]
+pub struct SerializedEventResp {
+    subscription_id: String,
+    event: BroadcastEvent,
+}
+
+impl Serialize for SerializedEventResp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element("EVENT")?;
+        seq.serialize_element(&self.subscription_id)?;
+        seq.serialize_element(&*self.event)?;
+        seq.end()
+    }
+}
+
+impl SerializedEventResp {
+    /// Create new Event response for a broadcast event
+    pub fn new(subscription_id: &str, event: &BroadcastEvent) -> Self {
+        Self {
+            subscription_id: subscription_id.to_owned(),
+            event: event.clone(),
+        }
+    }
+
+    /// The response as JSON, framing the serialized event without
+    /// serializing it again.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let id = serde_json::to_string(&self.subscription_id)?;
+        let event = self.event.json();
+        let mut json = String::with_capacity(id.len() + event.len() + 12);
+        json.push_str(r#"["EVENT","#);
+        json.push_str(&id);
+        json.push(',');
+        json.push_str(event);
+        json.push(']');
+        Ok(json)
+    }
+}
+
+/// An OK Response Message telling the client whether an event it
+/// submitted was accepted
+#[derive(Debug, PartialEq, Clone)]
+pub struct OkResp {
+    event_id: String,
+    accepted: bool,
+    message: String,
+}
+
+impl Serialize for OkResp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element("OK")?;
+        seq.serialize_element(&self.event_id)?;
+        seq.serialize_element(&self.accepted)?;
+        seq.serialize_element(&self.message)?;
+        seq.end()
+    }
+}
+
+impl OkResp {
+    /// Create new OK response
+    pub fn new(event_id: &str, accepted: bool, message: &str) -> Self {
+        Self {
+            event_id: event_id.to_owned(),
+            accepted,
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// A Closed Response Message telling the client that the relay ended,
+/// or refused, one of its subscriptions
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClosedResp {
+    subscription_id: String,
+    message: String,
+}
+
+impl Serialize for ClosedResp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element("CLOSED")?;
+        seq.serialize_element(&self.subscription_id)?;
+        seq.serialize_element(&self.message)?;
+        seq.end()
+    }
+}
+
+impl ClosedResp {
+    /// Create new CLOSED response
+    pub fn new(subscription_id: &str, message: &str) -> Self {
+        Self {
+            subscription_id: subscription_id.to_owned(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+/// An Auth Response Message challenging the client to authenticate
+/// (NIP-42)
+#[derive(Debug, PartialEq, Clone)]
+pub struct AuthResp {
+    challenge: String,
+}
+
+impl Serialize for AuthResp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut seq = serializer.serialize_seq(None)?;
+        seq.serialize_element("AUTH")?;
+        seq.serialize_element(&self.challenge)?;
+        seq.end()
+    }
+}
+
+impl AuthResp {
+    /// Create new AUTH response
+    pub fn new(challenge: &str) -> Self {
+        Self {
+            challenge: challenge.to_owned(),
+        }
+    }
+}
+
 /// A Notice Response Message send to the client from the relay
 #[derive(Debug, PartialEq, Clone)]
 pub struct NoticeResp {
@@ -58,3 +196,58 @@ impl NoticeResp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::testvec::event::VALID_EVENT;
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[test]
+    fn ok_serialize() {
+        let ok = OkResp::new("abcd", false, "duplicate: already have this event");
+        assert_eq!(
+            serde_json::to_string(&ok).unwrap(),
+            r#"["OK","abcd",false,"duplicate: already have this event"]"#
+        );
+    }
+
+    #[test]
+    fn closed_serialize() {
+        let closed = ClosedResp::new("sub1", "error: too many subscriptions");
+        assert_eq!(
+            serde_json::to_string(&closed).unwrap(),
+            r#"["CLOSED","sub1","error: too many subscriptions"]"#
+        );
+    }
+
+    #[test]
+    fn auth_serialize() {
+        let auth = AuthResp::new("3f1b2c");
+        assert_eq!(
+            serde_json::to_string(&auth).unwrap(),
+            r#"["AUTH","3f1b2c"]"#
+        );
+    }
+
+    #[test]
+    fn serialized_event_framed() {
+        let event = Event::from_str(VALID_EVENT).unwrap();
+        let broadcast = BroadcastEvent::new(event.clone());
+        for id in ["sub1", "quote\"d", "ünïcödé \\ ✓"] {
+            let framed = SerializedEventResp::new(id, &broadcast).to_json().unwrap();
+            // the same bytes as serializing the event for each response
+            let expected = serde_json::to_string(&EventResp::new(id, &event)).unwrap();
+            assert_eq!(framed, expected);
+            let serialized = SerializedEventResp::new(id, &broadcast);
+            assert_eq!(serde_json::to_string(&serialized).unwrap(), expected);
+        }
+        // the event is serialized once, however many responses frame it
+        let json = broadcast.json().clone();
+        SerializedEventResp::new("sub2", &broadcast)
+            .to_json()
+            .unwrap();
+        assert!(Arc::ptr_eq(&json, broadcast.json()));
+    }
+}
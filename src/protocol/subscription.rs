@@ -1,14 +1,15 @@
 //! Subscription and filter parsing
 use super::event::{Event, EventId, EventKind};
 use super::tags::{Tag, TagType};
-use crate::error::Result;
+use crate::config;
+use crate::error::{Error, Result};
 use bitcoin_hashes::{sha256, Hash};
 use secp256k1::XOnlyPublicKey;
 use serde::de::Unexpected;
 use serde::ser::SerializeSeq;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
 /// A Request Filter as per NIP01 https://github.com/fiatjaf/nostr/blob/master/nips/01.md#communication-between-clients-and-relays
@@ -35,8 +36,92 @@ pub struct ReqFilter {
     pub authors: Option<Vec<XOnlyPublicKey>>,
 }
 
-// A sha256 hash denoting unique identifier for a subscription request
-pub type SubscriptionId = sha256::Hash;
+impl ReqFilter {
+    /// This filter with the values of each field sorted and without
+    /// duplicates, so filters written differently but matching the
+    /// same events compare equal.
+    fn canonical(&self) -> ReqFilter {
+        fn sorted<T: Clone, K: Ord>(
+            values: &Option<Vec<T>>,
+            key: impl Fn(&T) -> K,
+        ) -> Option<Vec<T>> {
+            values.as_ref().map(|values| {
+                let mut values = values.clone();
+                values.sort_by_key(&key);
+                values.dedup_by_key(|v| key(v));
+                values
+            })
+        }
+        ReqFilter {
+            ids: sorted(&self.ids, |id| *id),
+            kinds: sorted(&self.kinds, |kind| kind.clone()),
+            events: sorted(&self.events, |id| *id),
+            pubkeys: sorted(&self.pubkeys, |pk| pk.serialize()),
+            since: self.since,
+            until: self.until,
+            authors: sorted(&self.authors, |pk| pk.serialize()),
+        }
+    }
+}
+
+/// Subscription identifiers longer than this are refused, unless
+/// configured
+pub const DEFAULT_MAX_SUBID_LENGTH: usize = 64;
+
+/// Check that a subscription identifier is non-empty, contains only
+/// printable characters, and is at most `max_len` characters long
+/// (any length if zero).
+pub fn is_valid_subscription_id(id: &str, max_len: usize) -> bool {
+    !id.is_empty()
+        && !id.chars().any(char::is_control)
+        && (max_len == 0 || id.chars().count() <= max_len)
+}
+
+/// A subscription identifier chosen by the client, checked with
+/// [`is_valid_subscription_id`] against `limits.max_subid_length`.
+#[derive(Serialize, PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone)]
+#[serde(transparent)]
+pub struct SubscriptionId(String);
+
+impl SubscriptionId {
+    /// An identifier, if it is valid and at most `max_len` characters
+    /// long (any length if zero).
+    pub fn new(id: &str, max_len: usize) -> Option<Self> {
+        is_valid_subscription_id(id, max_len).then(|| SubscriptionId(id.to_owned()))
+    }
+
+    /// The identifier as the client sent it.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for SubscriptionId {
+    type Err = Error;
+
+    /// Parse an identifier, within the configured length limit.
+    fn from_str(s: &str) -> Result<Self> {
+        let max_len = config::SETTINGS.read().unwrap().limits.max_subid_length;
+        SubscriptionId::new(s, max_len).ok_or_else(|| Error::SubIdInvalid(s.to_owned()))
+    }
+}
+
+impl std::fmt::Display for SubscriptionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SubscriptionId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let id = String::deserialize(deserializer)?;
+        SubscriptionId::from_str(&id)
+            .map_err(|_| serde::de::Error::custom("invalid subscription id"))
+    }
+}
 
 /// Subscription defining a set of [`ReqFilter`]
 #[derive(PartialEq, Debug, Clone)]
@@ -67,7 +152,7 @@ impl<'de> Deserialize<'de> for Subscription {
     {
         let received: Vec<Value> = Deserialize::deserialize(deserializer)?;
         // We should never get a subscription message of smaller length.
-        if received.len() < 3 {
+        if received.len() < 2 {
             return Err(serde::de::Error::custom(
                 "Not enough data in subscription message",
             ));
@@ -91,11 +176,13 @@ impl<'de> Deserialize<'de> for Subscription {
 
         // Try parsing the rest of the data, or emit error
         if let Value::String(id) = &received[1] {
+            let id = SubscriptionId::from_str(id)
+                .map_err(|_| serde::de::Error::custom("invalid subscription id"))?;
+            // a subscription without filters is refused when it is
+            // made, so that the client is told which one it was
             let filters: Vec<ReqFilter> =
                 serde_json::from_value(Value::Array(received[2..].to_vec()))
                     .map_err(|e| serde::de::Error::custom(e.to_string()))?;
-            let id = SubscriptionId::from_str(&id)
-                .map_err(|e| serde::de::Error::custom(e.to_string()))?;
             Ok(Self { id, filters })
         } else {
             Err(serde::de::Error::invalid_type(
@@ -107,6 +194,24 @@ impl<'de> Deserialize<'de> for Subscription {
 }
 
 impl Subscription {
+    /// A subscription to every event created at or after `since`.
+    pub fn created_since(since: u64) -> Subscription {
+        let filter = ReqFilter {
+            ids: None,
+            kinds: None,
+            events: None,
+            pubkeys: None,
+            since: Some(since),
+            until: None,
+            authors: None,
+        };
+        let id = SubscriptionId(format!("created_since:{}", since));
+        Subscription {
+            id,
+            filters: vec![filter],
+        }
+    }
+
     /// Get the subscription id
     pub fn get_id(&self) -> &SubscriptionId {
         &self.id
@@ -125,10 +230,101 @@ impl Subscription {
             .any(|filter| filter.interested_in_event(event))
     }
 
+    /// Check whether another subscription has the same filters,
+    /// ignoring the order of filters and of the values within them.
+    pub fn has_same_filters(&self, other: &Subscription) -> bool {
+        let ours: Vec<ReqFilter> = self.filters.iter().map(ReqFilter::canonical).collect();
+        let theirs: Vec<ReqFilter> = other.filters.iter().map(ReqFilter::canonical).collect();
+        ours.iter().all(|f| theirs.contains(f)) && theirs.iter().all(|f| ours.contains(f))
+    }
+
     /// Calculate unique Subscription ID for given subscription
     pub fn calculate_id(&self) -> Result<sha256::Hash> {
         let canonical_string = serde_json::to_string(&serde_json::to_value(&self.filters)?)?;
-        Ok(SubscriptionId::hash(canonical_string.as_bytes()))
+        Ok(sha256::Hash::hash(canonical_string.as_bytes()))
+    }
+}
+
+/// Subscriptions indexed by the authors or kinds their filters
+/// require, to find the few that may be interested in an event
+/// without checking every filter.
+///
+/// A filter listing authors is indexed by author, one listing only
+/// kinds is indexed by kind, and any other filter must always be
+/// checked.
+#[derive(Debug, Default)]
+pub struct SubscriptionIndex {
+    by_author: HashMap<XOnlyPublicKey, HashSet<SubscriptionId>>,
+    by_kind: HashMap<EventKind, HashSet<SubscriptionId>>,
+    unindexed: HashSet<SubscriptionId>,
+}
+
+impl SubscriptionIndex {
+    /// Add the filters of a subscription.
+    pub fn insert(&mut self, sub: &Subscription) {
+        for filter in &sub.filters {
+            if let Some(authors) = &filter.authors {
+                for author in authors {
+                    self.by_author
+                        .entry(*author)
+                        .or_default()
+                        .insert(sub.id.clone());
+                }
+            } else if let Some(kinds) = &filter.kinds {
+                for kind in kinds {
+                    self.by_kind
+                        .entry(kind.clone())
+                        .or_default()
+                        .insert(sub.id.clone());
+                }
+            } else {
+                self.unindexed.insert(sub.id.clone());
+            }
+        }
+    }
+
+    /// Remove the filters of a subscription previously inserted.
+    pub fn remove(&mut self, sub: &Subscription) {
+        for filter in &sub.filters {
+            if let Some(authors) = &filter.authors {
+                for author in authors {
+                    remove_entry(&mut self.by_author, author, &sub.id);
+                }
+            } else if let Some(kinds) = &filter.kinds {
+                for kind in kinds {
+                    remove_entry(&mut self.by_kind, kind, &sub.id);
+                }
+            } else {
+                self.unindexed.remove(&sub.id);
+            }
+        }
+    }
+
+    /// Subscriptions that may be interested in an event.  Each still
+    /// needs checking with [`Subscription::interested_in_event`].
+    pub fn candidates(&self, event: &Event) -> HashSet<&SubscriptionId> {
+        let mut candidates: HashSet<&SubscriptionId> = self.unindexed.iter().collect();
+        if let Some(ids) = self.by_author.get(&event.pubkey) {
+            candidates.extend(ids);
+        }
+        if let Some(ids) = self.by_kind.get(&event.kind) {
+            candidates.extend(ids);
+        }
+        candidates
+    }
+}
+
+/// Remove `id` from the set under `key`, dropping the set once empty.
+fn remove_entry<K: std::hash::Hash + Eq>(
+    index: &mut HashMap<K, HashSet<SubscriptionId>>,
+    key: &K,
+    id: &SubscriptionId,
+) {
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
     }
 }
 
@@ -285,4 +481,106 @@ mod tests {
         let pubkey_tags_subs: Subscription = serde_json::from_str(PUBKEY_TAGS_SUBS).unwrap();
         assert!(pubkey_tags_subs.interested_in_event(&event));
     }
+
+    #[test]
+    fn subscription_id_validated() {
+        assert!(is_valid_subscription_id("abc", 64));
+        assert!(!is_valid_subscription_id("", 64));
+        assert!(!is_valid_subscription_id("abc\ndef", 64));
+        assert!(!is_valid_subscription_id("abc\0", 64));
+        assert!(!is_valid_subscription_id(&"a".repeat(65), 64));
+        assert!(is_valid_subscription_id(&"a".repeat(65), 0));
+        assert_eq!(SubscriptionId::new("abc", 64).unwrap().as_str(), "abc");
+        assert_eq!(SubscriptionId::new(&"a".repeat(65), 64), None);
+        for id in ["", "abc\ndef"] {
+            let req = serde_json::json!(["REQ", id, {}]).to_string();
+            let err = serde_json::from_str::<Subscription>(&req).unwrap_err();
+            assert!(err.to_string().contains("invalid subscription id"));
+        }
+    }
+
+    #[test]
+    fn index_finds_interested_subscriptions() {
+        let event = Event::from_str(VALID_EVENT).unwrap();
+        let author = event.pubkey.to_string();
+        let req = |name: &str, filters: serde_json::Value| -> Subscription {
+            let mut req = vec![serde_json::json!("REQ"), serde_json::json!(name)];
+            req.extend(filters.as_array().unwrap().iter().cloned());
+            serde_json::from_value(serde_json::Value::Array(req)).unwrap()
+        };
+        let subs = vec![
+            req("author", serde_json::json!([{ "authors": [author] }])),
+            req("kind", serde_json::json!([{ "kinds": [1] }])),
+            req("other kind", serde_json::json!([{ "kinds": [0, 2] }])),
+            req("since", serde_json::json!([{ "since": 1 }])),
+            req(
+                "mixed",
+                serde_json::json!([{ "kinds": [0] }, { "since": 1 }]),
+            ),
+            req(
+                "late",
+                serde_json::json!([{ "kinds": [2], "since": u32::MAX }]),
+            ),
+        ];
+        let mut index = SubscriptionIndex::default();
+        for sub in &subs {
+            index.insert(sub);
+        }
+        let matching = |index: &SubscriptionIndex| -> HashSet<SubscriptionId> {
+            let candidates = index.candidates(&event);
+            subs.iter()
+                .filter(|s| candidates.contains(&s.id) && s.interested_in_event(&event))
+                .map(|s| s.id.clone())
+                .collect()
+        };
+        let brute_force: HashSet<SubscriptionId> = subs
+            .iter()
+            .filter(|s| s.interested_in_event(&event))
+            .map(|s| s.id.clone())
+            .collect();
+        assert_eq!(brute_force.len(), 4);
+        assert_eq!(matching(&index), brute_force);
+        // a filter for other kinds is not checked
+        assert!(!index.candidates(&event).contains(&subs[1].id));
+
+        for sub in &subs {
+            index.remove(sub);
+        }
+        assert!(index.candidates(&event).is_empty());
+        assert!(index.by_author.is_empty() && index.by_kind.is_empty());
+    }
+
+    #[test]
+    fn same_filters_ignore_order() {
+        let id = "abc";
+        let (a, b) = (EventId::hash(b"a"), EventId::hash(b"b"));
+        let req = |filters: serde_json::Value| -> Subscription {
+            let mut req = vec![serde_json::json!("REQ"), serde_json::json!(id.to_string())];
+            req.extend(filters.as_array().unwrap().iter().cloned());
+            serde_json::from_value(serde_json::Value::Array(req)).unwrap()
+        };
+        let sub = req(serde_json::json!([{ "ids": [a, b], "kinds": [0, 1] }, { "since": 5 }]));
+        let reordered =
+            req(serde_json::json!([{ "since": 5 }, { "kinds": [1, 0, 1], "ids": [b, a] }]));
+        assert!(sub.has_same_filters(&reordered));
+        assert!(reordered.has_same_filters(&sub));
+        let narrower = req(serde_json::json!([{ "ids": [a], "kinds": [0, 1] }, { "since": 5 }]));
+        assert!(!sub.has_same_filters(&narrower));
+        let fewer = req(serde_json::json!([{ "since": 5 }]));
+        assert!(!sub.has_same_filters(&fewer));
+    }
+
+    #[test]
+    fn filterless_parsed() {
+        let id = "abc";
+        let subs: Subscription = serde_json::from_str(&format!(r#"["REQ","{}"]"#, id)).unwrap();
+        assert_eq!(subs.get_id().as_str(), id);
+        assert!(subs.get_filters().is_empty());
+
+        // an empty filter object is allowed, and matches everything
+        let subs: Subscription =
+            serde_json::from_str(&format!(r#"["REQ","{}",{{}}]"#, id)).unwrap();
+        assert_eq!(subs.get_filters().len(), 1);
+        assert!(subs.interested_in_event(&Event::from_str(VALID_EVENT).unwrap()));
+    }
 }
@@ -4,7 +4,7 @@
 //!
 
 use bitcoin_hashes::hex::ToHex;
-use secp256k1::XOnlyPublicKey;
+use secp256k1::{schnorr, XOnlyPublicKey};
 use std::fmt::Display;
 use std::str::FromStr;
 
@@ -28,11 +28,22 @@ pub struct PubkeyTag {
     recommended_url: Option<String>,
 }
 
+/// A delegation of signing to the event's author, as in NIP-26
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct DelegationTag {
+    delegator: XOnlyPublicKey,
+    conditions: String,
+    sig: schnorr::Signature,
+}
+
 /// A Type denoting kind of a [`Tag`]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TagType {
     Event,
     Pubkey,
+    Delegation,
+    Relay,
+    Challenge,
 }
 
 /// A Tag struct used to reference other events or pubkeys in an [`crate::event::Event`]
@@ -40,11 +51,19 @@ pub enum TagType {
 pub enum Tag {
     Event(EventTag),
     Pubkey(PubkeyTag),
+    Delegation(DelegationTag),
+    /// The relay a client authenticates to, as in NIP-42
+    Relay(String),
+    /// The challenge a client authenticates with, as in NIP-42
+    Challenge(String),
 }
 
 // Custom json serialization into protocol format
 // Event tag : ["e", "<32 byte event-id>", "optional<url>"]
 // Pubkey tag : ["p", "<32 byte Xonly Pubkey>", "optional<url>"]
+// Delegation tag : ["delegation", "<32 byte Xonly Pubkey>", "<conditions>", "<signature>"]
+// Relay tag : ["relay", "<url>"]
+// Challenge tag : ["challenge", "<challenge>"]
 impl serde::Serialize for Tag {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -71,6 +90,23 @@ impl serde::Serialize for Tag {
                 }
                 seq.end()
             }
+            Self::Delegation(delegation_tag) => {
+                let mut seq = serializer.serialize_seq(Some(4))?;
+                seq.serialize_element("delegation")?;
+                seq.serialize_element(&delegation_tag.delegator.to_hex())?;
+                seq.serialize_element(&delegation_tag.conditions)?;
+                seq.serialize_element(&delegation_tag.sig.to_string())?;
+                seq.end()
+            }
+            Self::Relay(text) | Self::Challenge(text) => {
+                let mut seq = serializer.serialize_seq(Some(2))?;
+                seq.serialize_element(match self {
+                    Self::Relay(_) => "relay",
+                    _ => "challenge",
+                })?;
+                seq.serialize_element(text)?;
+                seq.end()
+            }
         }
     }
 }
@@ -109,6 +145,41 @@ impl<'de> serde::Deserialize<'de> for Tag {
             })
             .collect::<Result<Vec<_>, _>>()?;
 
+        // Delegation tags are the only ones with four elements
+        if values.first() == Some(&"delegation") {
+            if values.len() != 4 {
+                return Err(serde::de::Error::invalid_length(
+                    values.len(),
+                    &"delegation tag length is 4",
+                ));
+            }
+            let delegator = XOnlyPublicKey::from_str(values[1])
+                .map_err(|e| serde::de::Error::custom(e.to_string()))?;
+            let sig = schnorr::Signature::from_str(values[3])
+                .map_err(|e| serde::de::Error::custom(e.to_string()))?;
+            return Ok(Tag::Delegation(DelegationTag {
+                delegator,
+                conditions: values[2].to_owned(),
+                sig,
+            }));
+        }
+
+        // Authentication tags have a single value
+        if let Some(&name @ ("relay" | "challenge")) = values.first() {
+            if values.len() != 2 {
+                return Err(serde::de::Error::invalid_length(
+                    values.len(),
+                    &"relay and challenge tag length is 2",
+                ));
+            }
+            let text = values[1].to_owned();
+            return Ok(if name == "relay" {
+                Tag::Relay(text)
+            } else {
+                Tag::Challenge(text)
+            });
+        }
+
         // Check length is not more tha 3
         if values.len() > 3 {
             Err(serde::de::Error::invalid_length(
@@ -150,7 +221,7 @@ impl<'de> serde::Deserialize<'de> for Tag {
                 // Any other tag type is currently not supported
                 _ => Err(serde::de::Error::invalid_value(
                     Unexpected::Other("tag type flag"),
-                    &"'e' or 'p'",
+                    &"'e', 'p', 'delegation', 'relay' or 'challenge'",
                 )),
             }
         }
@@ -186,11 +257,34 @@ impl Tag {
                 pubkey: _,
                 recommended_url,
             }) => recommended_url.clone(),
+            Self::Delegation(_) | Self::Relay(_) | Self::Challenge(_) => None,
+        }
+    }
+
+    /// Get the delegator, conditions and signature of a delegation
+    /// tag, None if called on another kind of tag
+    pub fn get_delegation(&self) -> Option<(&XOnlyPublicKey, &str, &schnorr::Signature)> {
+        match self {
+            Self::Delegation(DelegationTag {
+                delegator,
+                conditions,
+                sig,
+            }) => Some((delegator, conditions, sig)),
+            _ => None,
+        }
+    }
+
+    /// Get the value of a relay or challenge tag, None if called on
+    /// another kind of tag
+    pub fn get_text(&self) -> Option<&str> {
+        match self {
+            Self::Relay(text) | Self::Challenge(text) => Some(text),
+            _ => None,
         }
     }
 
     /// Get [`EventId`] for this tag, errors if called on an incompatible tag
-    fn get_event_id(&self) -> Result<EventId, Error> {
+    pub fn get_event_id(&self) -> Result<EventId, Error> {
         match self {
             Self::Event(EventTag {
                 event_id,
@@ -201,7 +295,7 @@ impl Tag {
     }
 
     /// Get [`XOnlyPublicKey`] for this tag, errors if called on an incompatible tag
-    fn get_pubkey(&self) -> Result<XOnlyPublicKey, Error> {
+    pub fn get_pubkey(&self) -> Result<XOnlyPublicKey, Error> {
         match self {
             Self::Pubkey(PubkeyTag {
                 pubkey,
@@ -236,6 +330,9 @@ impl Tag {
         match self {
             Tag::Event(_) => TagType::Event,
             Tag::Pubkey(_) => TagType::Pubkey,
+            Tag::Delegation(_) => TagType::Delegation,
+            Tag::Relay(_) => TagType::Relay,
+            Tag::Challenge(_) => TagType::Challenge,
         }
     }
 }
@@ -298,7 +395,8 @@ mod test {
         let tag: Result<Tag, _> = serde_json::from_str(test_string);
         assert_eq!(
             tag.err().expect("expect error").to_string(),
-            "invalid value: tag type flag, expected 'e' or 'p'".to_string()
+            "invalid value: tag type flag, expected 'e', 'p', 'delegation', 'relay' or 'challenge'"
+                .to_string()
         );
     }
 
@@ -332,6 +430,44 @@ mod test {
         );
     }
 
+    #[test]
+    fn delegation_roundtrip() {
+        let test_string = r#"["delegation","8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd","kind=1&created_at<1675721813","6f44d7fe4f1c09f3954640fb58bd12bae8bb8ff4120853c4693106c82e920e2b898f1f9ba9bd65449a987c39c0423426ab7b53910c0c6abfb41b30bc16e5f524"]"#;
+        let tag: Tag = serde_json::from_str(test_string).unwrap();
+        assert_eq!(tag.get_type(), TagType::Delegation);
+        let (delegator, conditions, _) = tag.get_delegation().unwrap();
+        assert_eq!(
+            delegator.to_hex(),
+            "8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd"
+        );
+        assert_eq!(conditions, "kind=1&created_at<1675721813");
+        assert_eq!(serde_json::to_string(&tag).unwrap(), test_string);
+        let short = r#"["delegation","8e0d3d3eb2881ec137a11debe736a9086715a8c8beeeda615780064d68bc25dd","kind=1"]"#;
+        assert_eq!(
+            serde_json::from_str::<Tag>(short).unwrap_err().to_string(),
+            "invalid length 3, expected delegation tag length is 4"
+        );
+    }
+
+    #[test]
+    fn auth_tags_roundtrip() {
+        for test_string in [
+            r#"["relay","wss://relay.example.com/"]"#,
+            r#"["challenge","3f1b2c"]"#,
+        ] {
+            let tag: Tag = serde_json::from_str(test_string).unwrap();
+            assert_eq!(serde_json::to_string(&tag).unwrap(), test_string);
+        }
+        let tag: Tag = serde_json::from_str(r#"["challenge","3f1b2c"]"#).unwrap();
+        assert_eq!(tag.get_type(), TagType::Challenge);
+        assert_eq!(tag.get_text(), Some("3f1b2c"));
+        let long = r#"["relay","wss://relay.example.com/","extra"]"#;
+        assert_eq!(
+            serde_json::from_str::<Tag>(long).unwrap_err().to_string(),
+            "invalid length 3, expected relay and challenge tag length is 2"
+        );
+    }
+
     #[test]
     fn invalid_json_type() {
         let test_string = r#"{"type": "e","event": "ef537f25c895bfa782526529a9b63d97aa631564d5d789c2b765448c8635fb6c"}"#;
@@ -1,20 +1,29 @@
 //! Nostr protocol layered over WebSocket
 use crate::config;
+use crate::conn::sanitize_text;
+use crate::deflate::DeflateStream;
 use crate::error::{Error, Result};
-use crate::protocol::{Close, Event, EventCmd, Subscription};
+use crate::metrics::METRICS;
+use crate::protocol::{is_valid_subscription_id, AuthCmd, Close, Event, EventCmd, Subscription};
 use core::pin::Pin;
-use futures::sink::Sink;
-use futures::stream::Stream;
+use futures::stream::{SplitSink, SplitStream, Stream, StreamExt};
 use futures::task::Context;
 use futures::task::Poll;
 use hyper::upgrade::Upgraded;
 use log::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio_tungstenite::WebSocketStream;
-use tungstenite::error::Error as WsError;
-use tungstenite::protocol::Message;
+use tungstenite::error::{CapacityError, Error as WsError};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
 
-use super::protocol::{EventResp, NoticeResp};
+use super::protocol::{
+    AuthResp, BroadcastEvent, ClosedResp, EventResp, NoticeResp, OkResp, SerializedEventResp,
+};
 
 /// Nostr protocol messages from a client
 #[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
@@ -26,6 +35,8 @@ pub enum NostrMessage {
     Req(Subscription),
     /// A `CLOSE` message
     Close(Close),
+    /// An `AUTH` message
+    Auth(AuthCmd),
 }
 
 /// Nostr protocol messages from a relay/server
@@ -37,6 +48,16 @@ pub enum NostrResponse {
     /// An `EVENT` response, composed of the subscription identifier,
     /// and serialized event JSON
     Event(EventResp),
+    /// An `EVENT` response for a broadcast event, framing JSON
+    /// serialized once for every subscription it matches
+    SerializedEvent(SerializedEventResp),
+    /// An `OK` response, reporting whether a submitted event was
+    /// accepted
+    Ok(OkResp),
+    /// A `CLOSED` response, ending or refusing a subscription
+    Closed(ClosedResp),
+    /// An `AUTH` response, challenging the client to authenticate
+    Auth(AuthResp),
 }
 
 impl NostrResponse {
@@ -47,16 +68,186 @@ impl NostrResponse {
     pub fn new_event(subs_id: &str, event: &Event) -> Self {
         Self::Event(EventResp::new(subs_id, event))
     }
+
+    pub fn new_serialized_event(subs_id: &str, event: &BroadcastEvent) -> Self {
+        Self::SerializedEvent(SerializedEventResp::new(subs_id, event))
+    }
+
+    pub fn new_ok(event_id: &str, accepted: bool, message: &str) -> Self {
+        Self::Ok(OkResp::new(event_id, accepted, message))
+    }
+
+    pub fn new_closed(subs_id: &str, message: &str) -> Self {
+        Self::Closed(ClosedResp::new(subs_id, message))
+    }
+
+    pub fn new_auth(challenge: &str) -> Self {
+        Self::Auth(AuthResp::new(challenge))
+    }
+}
+
+/// Longest close reason kept from a client, in characters.  Close
+/// frames allow at most 123 bytes of reason.
+const MAX_CLOSE_REASON_CHARS: usize = 123;
+
+/// Close code recorded when a close frame has no status code
+pub const CLOSE_NO_STATUS: u16 = 1005;
+
+/// Close code recorded when a connection ends without a close frame
+pub const CLOSE_ABNORMAL: u16 = 1006;
+
+/// Close code for a client that broke the relay's rules
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// Close code recorded when a client sent a message over
+/// `limits.max_ws_message_bytes`
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A close frame sent by a client.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClientClose {
+    /// The status code, or 1005 if the frame had none
+    pub code: u16,
+    /// The reason given, made safe to log
+    pub reason: String,
 }
 
-/// A Nostr protocol stream is layered on top of a Websocket stream.
+impl ClientClose {
+    fn from_frame(frame: Option<CloseFrame>) -> Self {
+        match frame {
+            Some(frame) => ClientClose {
+                code: frame.code.into(),
+                reason: sanitize_text(frame.reason.as_bytes(), MAX_CLOSE_REASON_CHARS),
+            },
+            None => ClientClose {
+                code: CLOSE_NO_STATUS,
+                reason: String::new(),
+            },
+        }
+    }
+
+    /// Whether the client left deliberately, rather than the
+    /// connection being closed by an error or a proxy.
+    pub fn is_normal(&self) -> bool {
+        matches!(
+            CloseCode::from(self.code),
+            CloseCode::Normal | CloseCode::Away | CloseCode::Status
+        )
+    }
+}
+
+impl fmt::Display for ClientClose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "code {}", self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, " reason {:?}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// A client's websocket, compressed if the client asked.
+pub type WsStream = WebSocketStream<DeflateStream<Upgraded>>;
+
+/// The sending half of a client's websocket.
+pub type WsSink = SplitSink<WsStream, Message>;
+
+/// A Nostr protocol stream is layered on top of the receiving half of
+/// a Websocket stream.
 pub struct NostrStream {
-    ws_stream: WebSocketStream<Upgraded>,
+    ws_stream: SplitStream<WsStream>,
+    /// When a frame of any type was last received
+    last_received: Instant,
+    /// Bytes of frame payloads received
+    bytes_received: u64,
+    /// Bytes compression saved, counted by the connection
+    bytes_saved: Arc<AtomicU64>,
+    /// Payload of the last pong received, with when it arrived
+    last_pong: Option<(Vec<u8>, Instant)>,
+}
+
+/// Given a websocket, return its sending half and a protocol stream
+/// wrapping the receiving half.
+pub fn wrap_ws_in_nostr(ws: WsStream) -> (WsSink, NostrStream) {
+    let bytes_saved = ws.get_ref().bytes_saved();
+    let (sink, stream) = ws.split();
+    let nostr_stream = NostrStream {
+        ws_stream: stream,
+        last_received: Instant::now(),
+        bytes_received: 0,
+        bytes_saved,
+        last_pong: None,
+    };
+    (sink, nostr_stream)
+}
+
+impl NostrStream {
+    /// Time since the client last sent a frame.
+    pub fn idle_for(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    /// Bytes received from the client, counting the payload of every
+    /// frame.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Bytes permessage-deflate kept off the connection, in both
+    /// directions.
+    pub fn bytes_saved_by_compression(&self) -> u64 {
+        self.bytes_saved.load(Ordering::Relaxed)
+    }
+
+    /// The last pong received since this was last called, with when
+    /// it arrived.
+    pub fn take_pong(&mut self) -> Option<(Vec<u8>, Instant)> {
+        self.last_pong.take()
+    }
+}
+
+/// A websocket frame carrying a response.
+pub fn response_message(response: &NostrResponse) -> Result<Message> {
+    // TODO: do real escaping for these - at least on NOTICE,
+    // which surely has some problems if arbitrary text is sent.
+    let text = match response {
+        NostrResponse::SerializedEvent(resp) => resp.to_json()?,
+        _ => serde_json::to_string(response)?,
+    };
+    Ok(Message::Text(text))
+}
+
+/// A websocket close frame with `code` and `reason`.
+pub fn close_message(code: u16, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: code.into(),
+        reason: reason.to_owned().into(),
+    }))
+}
+
+/// A short label for a websocket error, for metrics.
+pub fn ws_error_kind(error: &WsError) -> &'static str {
+    match error {
+        WsError::Protocol(_) => "protocol",
+        WsError::Capacity(_) => "capacity",
+        WsError::Utf8 => "utf8",
+        WsError::Io(_) => "io",
+        _ => "other",
+    }
 }
 
-/// Given a websocket, return a protocol stream wrapper.
-pub fn wrap_ws_in_nostr(ws: WebSocketStream<Upgraded>) -> NostrStream {
-    NostrStream { ws_stream: ws }
+/// The subscription id of a `REQ` message that could not be parsed,
+/// if the id is the reason.
+fn invalid_req_id(msg: &str, max_len: usize) -> Option<String> {
+    let items: Vec<serde_json::Value> = serde_json::from_str(msg).ok()?;
+    match items.as_slice() {
+        [serde_json::Value::String(tag), serde_json::Value::String(id), ..]
+            if tag == "REQ" && !is_valid_subscription_id(id, max_len) =>
+        {
+            Some(id.clone())
+        }
+        _ => None,
+    }
 }
 
 /// Implement the [`Stream`] interface to produce Nostr messages.
@@ -74,6 +265,7 @@ impl Stream for NostrStream {
                         if let Some(max_size) = config.limits.max_event_bytes {
                             // check length, ensure that some max size is set.
                             if msg.len() > max_size && max_size > 0 {
+                                METRICS.message_oversized("max_event_bytes", msg.len());
                                 return Err(Error::EventMaxLengthError(msg.len()));
                             }
                         }
@@ -82,54 +274,93 @@ impl Stream for NostrStream {
                 }
                 Err(e) => {
                     debug!("proto parse error: {:?}", e);
-                    debug!("parse error on message: {}", msg.trim());
+                    debug!("parse error on message: {:?}", msg.trim());
+                    if let Some(id) = invalid_req_id(&msg, config.limits.max_subid_length) {
+                        return Err(Error::SubIdInvalid(id));
+                    }
                     Err(Error::ProtoParseError)
                 }
             }
         }
-        match Pin::new(&mut self.ws_stream).poll_next(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(v)) => match v {
+        loop {
+            let v = match Pin::new(&mut self.ws_stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(v)) => v,
+            };
+            // any frame from the client shows it is still there
+            if let Ok(msg) = &v {
+                self.last_received = Instant::now();
+                self.bytes_received += msg.len() as u64;
+            }
+            return match v {
                 Ok(Message::Text(vs)) => Poll::Ready(Some(convert(vs))),
-                Ok(Message::Binary(_)) => Poll::Ready(Some(Err(Error::ProtoParseError))),
-                Ok(Message::Pong(_)) | Ok(Message::Ping(_)) => Poll::Pending,
-                Ok(Message::Close(_)) => Poll::Ready(None),
+                Ok(Message::Binary(_)) => Poll::Ready(Some(Err(Error::BinaryMessage))),
+                // tungstenite queues the reply to a ping, and sends it
+                // when the stream is next read, so keep reading.
+                Ok(Message::Ping(_)) => continue,
+                Ok(Message::Pong(payload)) => {
+                    self.last_pong = Some((payload, Instant::now()));
+                    continue;
+                }
+                Ok(Message::Close(frame)) => Poll::Ready(Some(Err(Error::ClientClosed(
+                    ClientClose::from_frame(frame),
+                )))),
                 Err(WsError::AlreadyClosed) | Err(WsError::ConnectionClosed) => Poll::Ready(None),
-                Err(_) => Poll::Ready(Some(Err(Error::ConnError))),
-            },
+                Err(e) => {
+                    METRICS.ws_error(ws_error_kind(&e));
+                    match e {
+                        WsError::Capacity(CapacityError::MessageTooLong { size, .. }) => {
+                            METRICS.message_oversized("max_ws_message_bytes", size);
+                            Poll::Ready(Some(Err(Error::MessageTooBig(size))))
+                        }
+                        _ => Poll::Ready(Some(Err(Error::ConnError))),
+                    }
+                }
+            };
         }
     }
 }
 
-/// Implement the [`Sink`] interface to produce Nostr responses.
-impl Sink<NostrResponse> for NostrStream {
-    type Error = Error;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        // map the error type
-        match Pin::new(&mut self.ws_stream).poll_ready(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::ConnWriteError)),
-            Poll::Pending => Poll::Pending,
-        }
+    #[test]
+    fn invalid_req_id_found() {
+        let req = |id: &str| serde_json::json!(["REQ", id, {}]).to_string();
+        assert_eq!(invalid_req_id(&req("a\nb"), 64), Some("a\nb".to_owned()));
+        assert_eq!(invalid_req_id(&req(""), 64), Some("".to_owned()));
+        assert_eq!(invalid_req_id(&req("abc"), 64), None);
+        assert_eq!(invalid_req_id(r#"["CLOSE",""]"#, 64), None);
+        assert_eq!(invalid_req_id("not json", 64), None);
     }
 
-    fn start_send(mut self: Pin<&mut Self>, item: NostrResponse) -> Result<(), Self::Error> {
-        // TODO: do real escaping for these - at least on NOTICE,
-        // which surely has some problems if arbitrary text is sent.
-        let send_str = serde_json::to_string(&item)?;
-        match Pin::new(&mut self.ws_stream).start_send(Message::Text(send_str)) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(Error::ConnWriteError),
-        }
+    #[test]
+    fn ws_errors_labelled() {
+        let too_long = WsError::Capacity(CapacityError::MessageTooLong {
+            size: 2,
+            max_size: 1,
+        });
+        assert_eq!(ws_error_kind(&too_long), "capacity");
+        assert_eq!(ws_error_kind(&WsError::Utf8), "utf8");
+        assert_eq!(ws_error_kind(&WsError::AlreadyClosed), "other");
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
-    }
+    #[test]
+    fn client_close_sanitized() {
+        let close = ClientClose::from_frame(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: format!("blocked\n{}", "x".repeat(200)).into(),
+        }));
+        assert_eq!(close.code, 1008);
+        assert!(!close.is_normal());
+        assert!(close.reason.starts_with("blocked?x"));
+        assert_eq!(close.reason.chars().count(), MAX_CLOSE_REASON_CHARS);
 
-    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+        let close = ClientClose::from_frame(None);
+        assert_eq!(close.code, CLOSE_NO_STATUS);
+        assert!(close.is_normal());
+        assert_eq!(close.to_string(), "code 1005");
     }
 }
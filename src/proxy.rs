@@ -0,0 +1,599 @@
+//! Client addresses reported by trusted reverse proxies and load
+//! balancers
+use crate::error::{Error, Result};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest version 1 PROXY header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Signature starting a version 2 PROXY header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Length of the fixed part of a version 2 PROXY header
+const V2_FIXED_LEN: usize = 16;
+
+/// Which PROXY protocol header, if any, a load balancer sends ahead
+/// of each connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    #[default]
+    Off,
+    V1,
+    V2,
+}
+
+fn invalid_header(reason: &str) -> Error {
+    Error::ProxyHeaderInvalid(reason.to_owned())
+}
+
+fn read_failed(e: std::io::Error) -> Error {
+    Error::ProxyHeaderInvalid(format!("could not read: {}", e))
+}
+
+/// Parse a version 1 (text) PROXY header, including the CRLF.
+/// Returns the source address, or `None` for `UNKNOWN` connections,
+/// whose peer address should be used.
+pub fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = header
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid_header("missing CRLF"))?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid_header("not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip = IpAddr::from_str(src).map_err(|_| invalid_header("bad source address"))?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                return Err(invalid_header("address does not match protocol"));
+            }
+            let port = src_port
+                .parse()
+                .map_err(|_| invalid_header("bad source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_header("malformed line")),
+    }
+}
+
+/// Parse a complete version 2 (binary) PROXY header.  Returns the
+/// source address, or `None` for health checks from the proxy itself
+/// and connections without an IP source.
+pub fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>> {
+    if header.len() < V2_FIXED_LEN || header[..12] != V2_SIGNATURE {
+        return Err(invalid_header("missing signature"));
+    }
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+    let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+    let addrs = header
+        .get(V2_FIXED_LEN..V2_FIXED_LEN + len)
+        .ok_or_else(|| invalid_header("truncated"))?;
+    match command {
+        // LOCAL, sent by the proxy on its own behalf
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid_header("unsupported command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+    match header[13] >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        // AF_INET6
+        2 if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(octets).into(),
+                port(32),
+            )))
+        }
+        1 | 2 => Err(invalid_header("address block too short")),
+        // AF_UNSPEC and AF_UNIX carry no client IP
+        _ => Ok(None),
+    }
+}
+
+/// Read the PROXY header expected in `mode` from the start of a
+/// connection, leaving the stream at the first byte after it.
+/// Returns the client address, if the header gives one.
+pub async fn read_proxy_header<R>(stream: &mut R, mode: ProxyProtocol) -> Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = vec![];
+    match mode {
+        ProxyProtocol::Off => Ok(None),
+        ProxyProtocol::V1 => {
+            // read a byte at a time, so nothing after the line is
+            // consumed
+            while !header.ends_with(b"\r\n") {
+                if header.len() == V1_MAX_LEN {
+                    return Err(invalid_header("line too long"));
+                }
+                header.push(stream.read_u8().await.map_err(read_failed)?);
+            }
+            parse_v1(&header)
+        }
+        ProxyProtocol::V2 => {
+            header.resize(V2_FIXED_LEN, 0);
+            stream.read_exact(&mut header).await.map_err(read_failed)?;
+            if header[..12] != V2_SIGNATURE {
+                return Err(invalid_header("missing signature"));
+            }
+            let len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+            header.resize(V2_FIXED_LEN + len, 0);
+            stream
+                .read_exact(&mut header[V2_FIXED_LEN..])
+                .await
+                .map_err(read_failed)?;
+            parse_v2(&header)
+        }
+    }
+}
+
+/// A block of IP addresses, such as `10.0.0.0/8` or `::1/128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Check whether `ip` is in the block.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse a CIDR block, or a single address.
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::GenericError(format!("invalid address block: {:?}", s));
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        // clients are compared as IPv4 addresses, so a block of
+        // IPv4-mapped addresses is taken as the IPv4 block
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() => {
+                if prefix < 96 {
+                    return Err(invalid());
+                }
+                Ok(IpNet {
+                    addr: v4.into(),
+                    prefix: prefix - 96,
+                })
+            }
+            addr => Ok(IpNet { addr, prefix }),
+        }
+    }
+}
+
+/// Proxies whose forwarding headers are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Trust the proxies in the given blocks.
+    pub fn new<S: AsRef<str>>(blocks: &[S]) -> Result<Self> {
+        let nets = blocks
+            .iter()
+            .map(|b| b.as_ref().parse())
+            .collect::<Result<_>>()?;
+        Ok(TrustedProxies { nets })
+    }
+
+    /// Whether `ip` is a trusted proxy.
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// The address of the client behind `peer`.  If `peer` is a
+    /// trusted proxy, this is the rightmost `X-Forwarded-For` hop
+    /// that is not itself trusted, or else `X-Real-IP`.  Headers from
+    /// other peers, and malformed headers, are ignored.  The port of
+    /// the connection from the proxy is kept.
+    pub fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.is_trusted(peer.ip()) {
+            return peer;
+        }
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .map(|v| v.to_str().ok())
+            .collect::<Option<Vec<&str>>>();
+        let client = match forwarded {
+            Some(values) if !values.is_empty() => self.forwarded_client(&values.join(",")),
+            Some(_) => headers
+                .get("x-real-ip")
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_hop),
+            None => None,
+        };
+        client.map_or(peer, |ip| SocketAddr::new(ip, peer.port()))
+    }
+
+    /// The client in an `X-Forwarded-For` list, unless the list is
+    /// malformed before an untrusted hop is reached.
+    fn forwarded_client(&self, list: &str) -> Option<IpAddr> {
+        let mut leftmost = None;
+        for hop in list.rsplit(',') {
+            let ip = parse_hop(hop)?;
+            if !self.is_trusted(ip) {
+                return Some(ip);
+            }
+            leftmost = Some(ip);
+        }
+        // every hop is a trusted proxy, so the first is the client
+        leftmost
+    }
+}
+
+/// Parse a single forwarded address, which proxies may give with a
+/// port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    IpAddr::from_str(hop)
+        .ok()
+        .or_else(|| SocketAddr::from_str(hop).ok().map(|a| a.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(&["127.0.0.1", "10.0.0.0/8", "fd00::/8"]).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    fn client(peer: &str, pairs: &[(&'static str, &str)]) -> String {
+        let peer: SocketAddr = peer.parse().unwrap();
+        proxies().client_addr(peer, &headers(pairs)).to_string()
+    }
+
+    #[test]
+    fn v1_headers_parsed() {
+        let addr = |s: &str| Some(s.parse::<SocketAddr>().unwrap());
+        // the examples from the specification
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n").unwrap(),
+            addr("255.255.255.255:65535")
+        );
+        let tcp6 = b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff \
+                     ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        assert!(tcp6.len() <= V1_MAX_LEN);
+        assert_eq!(
+            parse_v1(tcp6).unwrap(),
+            addr("[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535")
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(
+            parse_v1(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n").unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n").unwrap(),
+            addr("192.168.0.1:56324")
+        );
+        for bad in [
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443".as_ref(),
+            b"PROXY TCP4 2001:db8::1 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 99999 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n",
+            b"PROXY  TCP4 192.168.0.1 192.168.0.11 56324 443\r\n",
+            b"GET / HTTP/1.1\r\n",
+        ] {
+            assert!(parse_v1(bad).is_err());
+        }
+    }
+
+    /// A version 2 header with the given command, family and
+    /// address block.
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend((addrs.len() as u16).to_be_bytes());
+        header.extend(addrs);
+        header
+    }
+
+    #[test]
+    fn v2_headers_parsed() {
+        // TCP over IPv4, 192.168.0.1:56324 to 192.168.0.11:443
+        let inet = [192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(
+            parse_v2(&v2(1, 0x11, &inet)).unwrap(),
+            Some("192.168.0.1:56324".parse().unwrap())
+        );
+        // trailing TLVs are ignored
+        let mut with_tlv = inet.to_vec();
+        with_tlv.extend([0x04, 0x00, 0x01, 0x00]);
+        assert!(parse_v2(&v2(1, 0x11, &with_tlv)).unwrap().is_some());
+        // TCP over IPv6, 2001:db8::1 port 443
+        let mut inet6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        inet6.extend([0; 11]);
+        inet6.push(1);
+        inet6.extend([0; 16]);
+        inet6.extend([0x01, 0xbb, 0x01, 0xbb]);
+        assert_eq!(
+            parse_v2(&v2(1, 0x21, &inet6)).unwrap(),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+        // LOCAL health checks and unspecified families use the peer
+        assert_eq!(parse_v2(&v2(0, 0x11, &inet)).unwrap(), None);
+        assert_eq!(parse_v2(&v2(1, 0x00, &[])).unwrap(), None);
+        // malformed headers
+        assert!(parse_v2(&v2(1, 0x11, &inet[..8])).is_err());
+        let mut truncated = v2(1, 0x11, &inet);
+        truncated.pop();
+        assert!(parse_v2(&truncated).is_err());
+        let mut version1 = v2(1, 0x11, &inet);
+        version1[12] = 0x11;
+        assert!(parse_v2(&version1).is_err());
+        assert!(parse_v2(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn header_read_without_overreading() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 1234 443\r\nGET /";
+        let addr = read_proxy_header(&mut stream, ProxyProtocol::V1)
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.1:1234".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        let mut bytes = v2(1, 0x11, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 0, 80]);
+        bytes.extend(b"GET /");
+        let mut stream = bytes.as_slice();
+        let addr = read_proxy_header(&mut stream, ProxyProtocol::V2)
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.1:80".parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+
+        // a client speaking HTTP directly is refused
+        let mut stream: &[u8] = &[b'A'; 200];
+        assert!(read_proxy_header(&mut stream, ProxyProtocol::V1)
+            .await
+            .is_err());
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: relay\r\n\r\n";
+        assert!(read_proxy_header(&mut stream, ProxyProtocol::V2)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn blocks_parsed() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.9".parse().unwrap()));
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.1".parse().unwrap()));
+        assert!(!any.contains("::1".parse().unwrap()));
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", ""] {
+            assert!(bad.parse::<IpNet>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn block_boundaries_checked() {
+        // every prefix length, at the first and last address of the
+        // block and either side of it
+        let base = u32::from(Ipv4Addr::new(198, 51, 100, 77));
+        for prefix in 0..=32u32 {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let (network, broadcast) = (base & mask, base | !mask);
+            let net: IpNet = format!("{}/{}", Ipv4Addr::from(base), prefix)
+                .parse()
+                .unwrap();
+            let v4 = |n: u32| IpAddr::V4(Ipv4Addr::from(n));
+            let mapped = |n: u32| IpAddr::V6(Ipv4Addr::from(n).to_ipv6_mapped());
+            for ip in [network, broadcast, base] {
+                assert!(net.contains(v4(ip)), "{}/{} {}", base, prefix, ip);
+                assert!(net.contains(mapped(ip)), "{}/{} {}", base, prefix, ip);
+            }
+            if let Some(below) = network.checked_sub(1) {
+                assert!(!net.contains(v4(below)), "/{} {}", prefix, below);
+                assert!(!net.contains(mapped(below)), "/{} {}", prefix, below);
+            }
+            if let Some(above) = broadcast.checked_add(1) {
+                assert!(!net.contains(v4(above)), "/{} {}", prefix, above);
+            }
+            // the same block written as IPv4-mapped addresses
+            let written: IpNet = format!("::ffff:{}/{}", Ipv4Addr::from(base), prefix + 96)
+                .parse()
+                .unwrap();
+            assert_eq!(written, net);
+        }
+        let base = u128::from("2001:db8:aaaa:bbbb::1234".parse::<Ipv6Addr>().unwrap());
+        for prefix in 0..=128u32 {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let (first, last) = (base & mask, base | !mask);
+            let net: IpNet = format!("{}/{}", Ipv6Addr::from(base), prefix)
+                .parse()
+                .unwrap();
+            let v6 = |n: u128| IpAddr::V6(Ipv6Addr::from(n));
+            assert!(net.contains(v6(first)), "/{}", prefix);
+            assert!(net.contains(v6(last)), "/{}", prefix);
+            if let Some(below) = first.checked_sub(1) {
+                assert!(!net.contains(v6(below)), "/{}", prefix);
+            }
+            if let Some(above) = last.checked_add(1) {
+                assert!(!net.contains(v6(above)), "/{}", prefix);
+            }
+        }
+        // IPv4 clients are not in IPv6 blocks, nor the reverse
+        let everything_v6: IpNet = "::/0".parse().unwrap();
+        assert!(!everything_v6.contains("192.0.2.1".parse().unwrap()));
+        assert!(!everything_v6.contains("::ffff:192.0.2.1".parse().unwrap()));
+        let everything_v4: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(!everything_v4.contains("2001:db8::1".parse().unwrap()));
+        // a mapped block must lie within the mapped addresses
+        assert!("::ffff:0.0.0.0/95".parse::<IpNet>().is_err());
+        assert!("::ffff:10.0.0.0/129".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn proxies_recognised() {
+        let proxies = proxies();
+        for trusted in [
+            "127.0.0.1",
+            "::ffff:127.0.0.1",
+            "10.0.0.0",
+            "10.255.255.255",
+            "fd00::",
+            "fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+        ] {
+            assert!(proxies.is_trusted(trusted.parse().unwrap()), "{}", trusted);
+        }
+        for untrusted in [
+            "127.0.0.2",
+            "9.255.255.255",
+            "11.0.0.0",
+            "fcff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+            "fe00::",
+            "::1",
+        ] {
+            assert!(
+                !proxies.is_trusted(untrusted.parse().unwrap()),
+                "{}",
+                untrusted
+            );
+        }
+        assert!(!TrustedProxies::default().is_trusted("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn rightmost_untrusted_hop_used() {
+        let xff = "x-forwarded-for";
+        assert_eq!(
+            client("127.0.0.1:4000", &[(xff, "203.0.113.7")]),
+            "203.0.113.7:4000"
+        );
+        // a client can prepend anything, so only the hop the proxies
+        // saw is believed
+        assert_eq!(
+            client(
+                "127.0.0.1:4000",
+                &[(xff, "1.2.3.4, 198.51.100.2, 10.0.0.5")]
+            ),
+            "198.51.100.2:4000"
+        );
+        // hops may be split across headers, and carry ports
+        assert_eq!(
+            client(
+                "127.0.0.1:4000",
+                &[(xff, "198.51.100.2:5555"), (xff, "10.0.0.5")]
+            ),
+            "198.51.100.2:4000"
+        );
+        assert_eq!(
+            client("[fd00::1]:4000", &[(xff, "2001:db8::1, fd00::2")]),
+            "[2001:db8::1]:4000"
+        );
+        // only trusted proxies in the chain
+        assert_eq!(
+            client("127.0.0.1:4000", &[(xff, "10.0.0.9, 10.0.0.5")]),
+            "10.0.0.9:4000"
+        );
+        // X-Forwarded-For is preferred
+        assert_eq!(
+            client(
+                "127.0.0.1:4000",
+                &[("x-real-ip", "192.0.2.1"), (xff, "192.0.2.2")]
+            ),
+            "192.0.2.2:4000"
+        );
+        assert_eq!(
+            client("127.0.0.1:4000", &[("x-real-ip", "192.0.2.1")]),
+            "192.0.2.1:4000"
+        );
+    }
+
+    #[test]
+    fn malformed_headers_ignored() {
+        let xff = "x-forwarded-for";
+        for value in [
+            "",
+            "unknown",
+            "198.51.100.2, garbage",
+            "1.2.3.4,,",
+            "999.1.1.1",
+        ] {
+            assert_eq!(
+                client("127.0.0.1:4000", &[(xff, value)]),
+                "127.0.0.1:4000",
+                "{:?}",
+                value
+            );
+        }
+        // garbage beyond the first untrusted hop does not matter
+        assert_eq!(
+            client("127.0.0.1:4000", &[(xff, "garbage, 198.51.100.2")]),
+            "198.51.100.2:4000"
+        );
+        assert_eq!(
+            client("127.0.0.1:4000", &[("x-real-ip", "nope")]),
+            "127.0.0.1:4000"
+        );
+    }
+
+    #[test]
+    fn untrusted_peers_not_believed() {
+        let spoofed = [("x-forwarded-for", "10.9.9.9"), ("x-real-ip", "10.9.9.9")];
+        assert_eq!(client("192.0.2.50:4000", &spoofed), "192.0.2.50:4000");
+        let nobody = TrustedProxies::default();
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(nobody.client_addr(peer, &headers(&spoofed)), peer);
+    }
+}
@@ -0,0 +1,214 @@
+//! Limits on how often each address may open websocket connections
+use governor::clock::{Clock, DefaultClock};
+use governor::state::{InMemoryState, NotKeyed};
+use governor::{Quota, RateLimiter};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+type DirectLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+/// The addresses that share a limit: one IPv4 address, or an IPv6 /64,
+/// as a single client is usually given a whole /64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AddressBucket(IpAddr);
+
+impl AddressBucket {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V6(v6) => {
+                let prefix = u128::from(v6) & !(u128::MAX >> 64);
+                AddressBucket(IpAddr::V6(Ipv6Addr::from(prefix)))
+            }
+            v4 => AddressBucket(v4),
+        }
+    }
+}
+
+impl fmt::Display for AddressBucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            IpAddr::V4(v4) => write!(f, "{}", v4),
+            IpAddr::V6(v6) => write!(f, "{}/64", v6),
+        }
+    }
+}
+
+/// How often each address may connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionRate {
+    /// Connections per minute, sustained; 0 for unlimited
+    pub per_minute: u32,
+    /// Connections allowed at once after a quiet spell; 0 for
+    /// `per_minute`
+    pub burst: u32,
+    /// Addresses tracked at once, forgetting the least recently seen
+    pub max_addresses: usize,
+}
+
+struct Entry {
+    limiter: DirectLimiter,
+    /// When the address was last seen, in attempts
+    last_seen: u64,
+    /// Attempts refused since the address was first tracked
+    throttled: u64,
+}
+
+#[derive(Default)]
+struct Tracked {
+    /// The rate the limiters were made for
+    rate: Option<(u32, u32)>,
+    entries: HashMap<AddressBucket, Entry>,
+    /// Addresses by when they were last seen, oldest first
+    by_age: BTreeMap<u64, AddressBucket>,
+    /// Attempts seen, for ordering
+    attempts: u64,
+}
+
+impl Tracked {
+    /// Forget addresses until there are fewer than `max`.
+    fn shrink_below(&mut self, max: usize) {
+        while self.entries.len() >= max.max(1) {
+            match self.by_age.pop_first() {
+                Some((_, bucket)) => {
+                    self.entries.remove(&bucket);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Connection attempts allowed per address, tracking a bounded number
+/// of recently seen addresses.
+#[derive(Default)]
+pub struct ConnectionRateLimiter {
+    tracked: Mutex<Tracked>,
+}
+
+impl ConnectionRateLimiter {
+    /// Record an attempt to connect from `ip`, returning how long it
+    /// should wait before trying again if it is over `rate`.
+    pub fn check(&self, ip: IpAddr, rate: ConnectionRate) -> Option<Duration> {
+        let per_minute = NonZeroU32::new(rate.per_minute)?;
+        let burst = NonZeroU32::new(rate.burst).unwrap_or(per_minute);
+        let bucket = AddressBucket::of(ip);
+        let mut tracked = self.tracked.lock().unwrap();
+        // limiters made for another rate are started afresh
+        if tracked.rate != Some((per_minute.get(), burst.get())) {
+            *tracked = Tracked {
+                rate: Some((per_minute.get(), burst.get())),
+                ..Default::default()
+            };
+        }
+        tracked.attempts += 1;
+        let now = tracked.attempts;
+        if let Some(entry) = tracked.entries.get_mut(&bucket) {
+            let last_seen = std::mem::replace(&mut entry.last_seen, now);
+            tracked.by_age.remove(&last_seen);
+        } else {
+            tracked.shrink_below(rate.max_addresses);
+            let quota = Quota::per_minute(per_minute).allow_burst(burst);
+            tracked.entries.insert(
+                bucket,
+                Entry {
+                    limiter: RateLimiter::direct(quota),
+                    last_seen: now,
+                    throttled: 0,
+                },
+            );
+        }
+        tracked.by_age.insert(now, bucket);
+        let entry = tracked.entries.get_mut(&bucket).unwrap();
+        match entry.limiter.check() {
+            Ok(_) => None,
+            Err(not_until) => {
+                entry.throttled += 1;
+                Some(not_until.wait_time_from(DefaultClock::default().now()))
+            }
+        }
+    }
+
+    /// The `n` tracked addresses with the most attempts refused, most
+    /// first.
+    pub fn most_throttled(&self, n: usize) -> Vec<(AddressBucket, u64)> {
+        let tracked = self.tracked.lock().unwrap();
+        let mut throttled: Vec<_> = tracked
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.throttled > 0)
+            .map(|(bucket, entry)| (*bucket, entry.throttled))
+            .collect();
+        throttled.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        throttled.truncate(n);
+        throttled
+    }
+
+    /// Addresses currently tracked.
+    pub fn tracked(&self) -> usize {
+        self.tracked.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn attempts_limited_per_address() {
+        let limiter = ConnectionRateLimiter::default();
+        let rate = ConnectionRate {
+            per_minute: 10,
+            burst: 3,
+            max_addresses: 2,
+        };
+        for _ in 0..3 {
+            assert!(limiter.check(ip("10.0.0.1"), rate).is_none());
+        }
+        let wait = limiter.check(ip("10.0.0.1"), rate).unwrap();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(6));
+        // others are not affected, including another IPv4 address
+        // mapped into IPv6
+        assert!(limiter.check(ip("10.0.0.2"), rate).is_none());
+        assert!(limiter.check(ip("::ffff:10.0.0.1"), rate).is_some());
+        // an IPv6 /64 shares a limit
+        for host in 1..=3 {
+            assert!(limiter
+                .check(ip(&format!("2001:db8::{}", host)), rate)
+                .is_none());
+        }
+        assert!(limiter.check(ip("2001:db8::ffff"), rate).is_some());
+        assert!(limiter.check(ip("2001:db8:0:1::1"), rate).is_none());
+
+        // only the most recently seen addresses are kept
+        assert_eq!(limiter.tracked(), 2);
+        assert_eq!(
+            limiter.most_throttled(5),
+            vec![(AddressBucket::of(ip("2001:db8::1")), 1)]
+        );
+        assert_eq!(
+            AddressBucket::of(ip("2001:db8::1")).to_string(),
+            "2001:db8::/64"
+        );
+
+        // unlimited, and a new rate starts afresh
+        let unlimited = ConnectionRate {
+            per_minute: 0,
+            ..rate
+        };
+        assert!(limiter.check(ip("10.0.0.1"), unlimited).is_none());
+        let faster = ConnectionRate {
+            per_minute: 60,
+            ..rate
+        };
+        assert!(limiter.check(ip("2001:db8::1"), faster).is_none());
+        assert_eq!(limiter.tracked(), 1);
+    }
+}
@@ -0,0 +1,568 @@
+//! Registry of the client connections currently open
+use crate::conn::{ClientHeaders, ConnStats};
+use crate::metrics::METRICS;
+use crate::outbound::OutboundQueue;
+use crate::protostream::NostrResponse;
+use futures::FutureExt;
+use log::*;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// A live connection, as last reported by its task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    /// Unique client identifier
+    pub client_id: String,
+    /// Address the client connected from
+    pub remote_addr: SocketAddr,
+    /// Headers identifying the client software
+    #[serde(flatten)]
+    pub headers: ClientHeaders,
+    /// When the connection opened, in seconds since the epoch
+    pub connected_at: u64,
+    /// Active subscriptions
+    pub subscriptions: usize,
+    /// Activity counters
+    pub stats: ConnStats,
+}
+
+/// A notice sent to every client repeatedly, until cancelled.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScheduledNotice {
+    pub id: u64,
+    pub message: String,
+    /// Seconds between each sending
+    pub every_secs: u64,
+}
+
+/// A registered connection.
+struct Entry {
+    info: ConnectionInfo,
+    /// Where responses to the client are queued
+    outbound: Arc<OutboundQueue>,
+    /// Tells the connection task to close, with the reason, until
+    /// used
+    disconnect_tx: Option<oneshot::Sender<String>>,
+}
+
+impl Entry {
+    /// Check whether `target` is the client's address, its full
+    /// identifier, or the identifier prefix used in logs.
+    fn matches(&self, target: &str) -> bool {
+        match target.parse::<IpAddr>() {
+            Ok(ip) => self.info.remote_addr.ip().to_canonical() == ip.to_canonical(),
+            Err(_) => target.len() >= 8 && self.info.client_id.starts_with(target),
+        }
+    }
+}
+
+/// Lock a mutex, ignoring poisoning.  A task that panicked while
+/// holding one of the registry's locks can not have left a table
+/// half-written, so it is still usable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Connections open across the relay.
+///
+/// Each connection task holds a [`Registration`] for as long as it
+/// runs, and its entry is removed when that is dropped, even if the
+/// task panics.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    conns: Mutex<HashMap<Uuid, Entry>>,
+    /// Repeating notices, with the tasks sending them
+    schedules: Mutex<HashMap<u64, (ScheduledNotice, JoinHandle<()>)>>,
+    next_schedule: AtomicU64,
+    /// Connections ended, by websocket close code
+    close_codes: Mutex<BTreeMap<u16, u64>>,
+    /// Connections admitted, including those still completing the
+    /// websocket handshake
+    admitted: Arc<AtomicUsize>,
+}
+
+impl ConnectionRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connection, whose responses are queued on `outbound`,
+    /// returning the registration that keeps it listed.
+    pub fn register(
+        self: &Arc<Self>,
+        client_id: Uuid,
+        remote_addr: SocketAddr,
+        headers: ClientHeaders,
+        outbound: Arc<OutboundQueue>,
+    ) -> Registration {
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let info = ConnectionInfo {
+            client_id: client_id.to_string(),
+            remote_addr,
+            headers,
+            connected_at,
+            subscriptions: 0,
+            stats: ConnStats::default(),
+        };
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let entry = Entry {
+            info,
+            outbound,
+            disconnect_tx: Some(disconnect_tx),
+        };
+        lock(&self.conns).insert(client_id, entry);
+        Registration {
+            registry: self.clone(),
+            client_id,
+            disconnect_rx,
+        }
+    }
+
+    /// Admit a connection if fewer than `max` are open or opening
+    /// (any number if zero), returning the slot it holds until it
+    /// closes.
+    pub fn admit(&self, max: usize) -> Option<ConnectionSlot> {
+        self.admitted
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (max == 0 || n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot {
+            admitted: self.admitted.clone(),
+        })
+    }
+
+    /// Number of open connections.
+    pub fn len(&self) -> usize {
+        lock(&self.conns).len()
+    }
+
+    /// Check whether no connections are open.
+    pub fn is_empty(&self) -> bool {
+        lock(&self.conns).is_empty()
+    }
+
+    /// Snapshot of the open connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut conns: Vec<ConnectionInfo> = lock(&self.conns)
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect();
+        conns.sort_by(|a, b| (a.connected_at, &a.client_id).cmp(&(b.connected_at, &b.client_id)));
+        conns
+    }
+
+    /// Write the table of open connections to the log.
+    pub fn log_table(&self) {
+        let conns = self.list();
+        info!("{} open connection(s)", conns.len());
+        for c in conns {
+            info!(
+                "client: {} addr: {}{} connected_at: {} subscriptions: {} ({})",
+                c.client_id, c.remote_addr, c.headers, c.connected_at, c.subscriptions, c.stats
+            );
+        }
+    }
+
+    /// Count a connection ending with a websocket close `code`.
+    pub fn record_close(&self, code: u16) {
+        *lock(&self.close_codes).entry(code).or_default() += 1;
+    }
+
+    /// Connections ended so far, by websocket close code.
+    pub fn close_codes(&self) -> BTreeMap<u16, u64> {
+        lock(&self.close_codes).clone()
+    }
+
+    /// Queue a notice for every open connection, returning how many
+    /// it was queued for.  This never waits for a client; one that is
+    /// far behind may lose broadcast events to make room.
+    pub fn send_notice(&self, message: &str) -> usize {
+        let conns = lock(&self.conns);
+        for entry in conns.values() {
+            entry.outbound.send(NostrResponse::new_notice(message));
+        }
+        conns.len()
+    }
+
+    /// Tell the connections matching `target`, a client identifier
+    /// or IP address, to close with `reason`.  Returns how many were
+    /// told.
+    pub fn disconnect(&self, target: &str, reason: &str) -> usize {
+        let mut conns = lock(&self.conns);
+        conns
+            .values_mut()
+            .filter(|entry| entry.matches(target))
+            .filter_map(|entry| entry.disconnect_tx.take())
+            .map(|tx| tx.send(reason.to_owned()))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Send a notice to every open connection every `every`, starting
+    /// one interval from now, until cancelled.  Returns the id to
+    /// cancel it with.
+    pub fn schedule_notice(self: &Arc<Self>, message: &str, every: Duration) -> u64 {
+        let id = self.next_schedule.fetch_add(1, Ordering::Relaxed) + 1;
+        let every = every.max(Duration::from_millis(1));
+        // the task must not keep the registry alive
+        let registry: Weak<Self> = Arc::downgrade(self);
+        let task_message = message.to_owned();
+        let start = tokio::time::Instant::now() + every;
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval_at(start, every);
+            loop {
+                interval.tick().await;
+                match registry.upgrade() {
+                    Some(registry) => {
+                        let sent = registry.send_notice(&task_message);
+                        debug!("sent scheduled notice {} to {} connection(s)", id, sent);
+                    }
+                    None => return,
+                }
+            }
+        });
+        let notice = ScheduledNotice {
+            id,
+            message: message.to_owned(),
+            every_secs: every.as_secs(),
+        };
+        lock(&self.schedules).insert(id, (notice, task));
+        id
+    }
+
+    /// Stop a repeating notice, returning whether it existed.
+    pub fn cancel_notice(&self, id: u64) -> bool {
+        match lock(&self.schedules).remove(&id) {
+            Some((_, task)) => {
+                task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The repeating notices, by id.
+    pub fn scheduled_notices(&self) -> Vec<ScheduledNotice> {
+        let mut notices: Vec<ScheduledNotice> = lock(&self.schedules)
+            .values()
+            .map(|(notice, _)| notice.clone())
+            .collect();
+        notices.sort_by_key(|n| n.id);
+        notices
+    }
+}
+
+/// The tasks serving client connections, so that a shutdown can
+/// wait for them to finish, and abort any that take too long.
+#[derive(Default)]
+pub struct ConnectionTasks {
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl ConnectionTasks {
+    /// Run a connection task in `span`.  A panic ends only that
+    /// connection: it is logged in the span and counted, and whatever
+    /// the task held, such as its registration, is dropped.
+    pub fn spawn<F>(&self, task: F, span: tracing::Span)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let task = async move {
+            if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+                error!("connection task panicked: {}", panic_message(&*panic));
+                METRICS.connection_panicked();
+            }
+        }
+        .instrument(span);
+        let mut tasks = lock(&self.tasks);
+        // forget tasks that have already finished
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    /// Wait up to `grace` for every task to finish, then abort the
+    /// rest, returning how many were aborted.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let mut tasks = std::mem::take(&mut *lock(&self.tasks));
+        let deadline = tokio::time::Instant::now() + grace;
+        while !tasks.is_empty() {
+            if tokio::time::timeout_at(deadline, tasks.join_next())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let remaining = tasks.len();
+        tasks.shutdown().await;
+        remaining
+    }
+}
+
+/// The message a panic was raised with.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("(no message)", String::as_str),
+    }
+}
+
+/// Room for one connection under the relay's limit, given back when
+/// dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    admitted: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.admitted.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A connection's place in the [`ConnectionRegistry`], removed when
+/// dropped.
+pub struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    client_id: Uuid,
+    disconnect_rx: oneshot::Receiver<String>,
+}
+
+impl Registration {
+    /// Wait until an operator asks for the connection to close,
+    /// returning the reason.  Must not be awaited again once it has
+    /// returned.
+    pub async fn disconnect_requested(&mut self) -> String {
+        match (&mut self.disconnect_rx).await {
+            Ok(reason) => reason,
+            // the registry never drops the sender while registered
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    /// Update the subscription count and statistics shown for the
+    /// connection.
+    pub fn update(&self, subscriptions: usize, stats: &ConnStats) {
+        if let Some(entry) = lock(&self.registry.conns).get_mut(&self.client_id) {
+            entry.info.subscriptions = subscriptions;
+            entry.info.stats = stats.clone();
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        lock(&self.registry.conns).remove(&self.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{write_outbound, FinishOnDrop, WriterExit};
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn entry_removed_on_panic() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let outbound = Arc::new(OutboundQueue::new(4));
+        let kept = registry.register(
+            Uuid::new_v4(),
+            addr(),
+            ClientHeaders::default(),
+            outbound.clone(),
+        );
+        let stats = ConnStats {
+            events_published: 3,
+            ..Default::default()
+        };
+        kept.update(2, &stats);
+        let task_registry = registry.clone();
+        let result = std::thread::spawn(move || {
+            let _registration =
+                task_registry.register(Uuid::new_v4(), addr(), ClientHeaders::default(), outbound);
+            assert_eq!(task_registry.len(), 2);
+            panic!("connection task failed");
+        })
+        .join();
+        assert!(result.is_err());
+        let listed = registry.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].subscriptions, 2);
+        assert_eq!(listed[0].stats, stats);
+    }
+
+    #[test]
+    fn connections_admitted_up_to_limit() {
+        let registry = ConnectionRegistry::new();
+        let first = registry.admit(2).unwrap();
+        let _second = registry.admit(2).unwrap();
+        assert!(registry.admit(2).is_none());
+        drop(first);
+        assert!(registry.admit(2).is_some());
+        // no limit
+        let unlimited: Vec<_> = (0..5).map(|_| registry.admit(0).unwrap()).collect();
+        assert_eq!(unlimited.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn slow_tasks_aborted_after_grace() {
+        let tasks = ConnectionTasks::default();
+        let (finished_tx, finished_rx) = oneshot::channel();
+        tasks.spawn(
+            async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                finished_tx.send(()).unwrap();
+            },
+            tracing::Span::none(),
+        );
+        let (dropped_tx, dropped_rx) = oneshot::channel::<()>();
+        tasks.spawn(
+            async move {
+                let _dropped_tx = dropped_tx;
+                std::future::pending::<()>().await;
+            },
+            tracing::Span::none(),
+        );
+        assert_eq!(tasks.drain(Duration::from_millis(200)).await, 1);
+        finished_rx.await.unwrap();
+        // the stuck task was aborted, dropping its sender
+        assert!(dropped_rx.await.is_err());
+        assert_eq!(tasks.drain(Duration::ZERO).await, 0);
+    }
+
+    #[tokio::test]
+    async fn panicking_task_isolated() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let tasks = ConnectionTasks::default();
+        let outbound = Arc::new(OutboundQueue::new(4));
+        let slot = registry.admit(1).unwrap();
+        let registration = registry.register(
+            Uuid::new_v4(),
+            addr(),
+            ClientHeaders::default(),
+            outbound.clone(),
+        );
+        let panics = || {
+            let rendered = METRICS.render(&Default::default());
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix("nostrd_connection_panics_total "))
+                .map(|n| n.parse::<u64>().unwrap())
+                .unwrap()
+        };
+        let before = panics();
+        let task_outbound = outbound.clone();
+        tasks.spawn(
+            async move {
+                let _held = (slot, registration, FinishOnDrop(task_outbound));
+                tokio::task::yield_now().await;
+                panic!("poisoned event");
+            },
+            tracing::Span::none(),
+        );
+        assert_eq!(tasks.drain(Duration::from_secs(5)).await, 0);
+        // what the task held was given back
+        assert!(registry.is_empty());
+        assert!(registry.admit(1).is_some());
+        let (tx, _rx) = futures::channel::mpsc::unbounded();
+        let exit = write_outbound(&outbound, tx, Duration::from_secs(1)).await;
+        assert_eq!(exit, WriterExit::Finished);
+        assert!(panics() > before);
+        // and other connections carry on
+        let (done_tx, done_rx) = oneshot::channel();
+        tasks.spawn(
+            async move { done_tx.send(()).unwrap() },
+            tracing::Span::none(),
+        );
+        done_rx.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnect_by_id_or_address() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let register = |ip: &str| {
+            let addr = SocketAddr::new(ip.parse().unwrap(), 4000);
+            registry.register(
+                Uuid::new_v4(),
+                addr,
+                ClientHeaders::default(),
+                Arc::new(OutboundQueue::new(4)),
+            )
+        };
+        let mut first = register("10.0.0.1");
+        let mut second = register("10.0.0.1");
+        let mut other = register("10.0.0.2");
+        let id = other.client_id.to_string();
+        assert_eq!(registry.disconnect(&id[..8], "misbehaving"), 1);
+        assert_eq!(other.disconnect_requested().await, "misbehaving");
+        // a connection is only told once
+        assert_eq!(registry.disconnect(&id, "again"), 0);
+        assert_eq!(registry.disconnect("10.0.0.1", "bye"), 2);
+        assert_eq!(first.disconnect_requested().await, "bye");
+        assert_eq!(second.disconnect_requested().await, "bye");
+        assert_eq!(registry.disconnect("10.0.0.3", "bye"), 0);
+        assert_eq!(registry.disconnect("abc", "bye"), 0);
+    }
+
+    // the clock only moves when advanced, so exactly one notice is
+    // sent per interval
+    #[tokio::test(start_paused = true)]
+    async fn notices_reach_every_connection() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        // one client has stopped reading, and its queue is full
+        let stuck = Arc::new(OutboundQueue::new(1));
+        stuck.send(NostrResponse::new_notice("unread"));
+        let reading = Arc::new(OutboundQueue::new(8));
+        let _stuck_reg = registry.register(
+            Uuid::new_v4(),
+            addr(),
+            ClientHeaders::default(),
+            stuck.clone(),
+        );
+        let _reading_reg = registry.register(
+            Uuid::new_v4(),
+            addr(),
+            ClientHeaders::default(),
+            reading.clone(),
+        );
+        assert_eq!(registry.send_notice("restarting soon"), 2);
+        assert_eq!(reading.depth(), 1);
+
+        let every = Duration::from_millis(50);
+        let id = registry.schedule_notice("restarting soon", every);
+        assert_eq!(registry.scheduled_notices()[0].id, id);
+        for sent in 1..=3 {
+            tokio::time::advance(every).await;
+            tokio::task::yield_now().await;
+            assert_eq!(reading.depth(), 1 + sent);
+        }
+        assert!(registry.cancel_notice(id));
+        assert!(!registry.cancel_notice(id));
+        tokio::time::advance(every * 3).await;
+        tokio::task::yield_now().await;
+        assert_eq!(reading.depth(), 4);
+        assert!(registry.scheduled_notices().is_empty());
+    }
+}
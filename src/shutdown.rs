@@ -0,0 +1,85 @@
+//! What a shutdown left undone, and how the process reports it
+use std::fmt;
+
+/// Exit status when a shutdown abandoned work, such as connections
+/// aborted after `network.shutdown_grace_secs`, or a second signal
+/// forcing the relay to exit; distinct from the status of 1 for
+/// errors, so supervisors can tell them apart.
+pub const EXIT_ABANDONED: i32 = 3;
+
+/// Work the relay gave up on while shutting down.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connection tasks aborted after the grace period
+    pub aborted_connections: usize,
+    /// Queries cancelled with the aborted connections
+    pub abandoned_queries: u64,
+    /// Events still queued in memory, if the database writer did not
+    /// finish within `database.shutdown_grace_seconds`
+    pub unstored_events: Option<u64>,
+}
+
+impl ShutdownReport {
+    /// Whether anything was abandoned.
+    pub fn abandoned(&self) -> bool {
+        self.aborted_connections > 0 || self.abandoned_queries > 0 || self.unstored_events.is_some()
+    }
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if self.aborted_connections > 0 {
+            parts.push(format!(
+                "{} connection(s) aborted",
+                self.aborted_connections
+            ));
+        }
+        if self.abandoned_queries > 0 {
+            parts.push(format!("{} query(ies) cancelled", self.abandoned_queries));
+        }
+        if let Some(events) = self.unstored_events {
+            parts.push(format!(
+                "database not closed, {} queued event(s) not stored",
+                events
+            ));
+        }
+        if parts.is_empty() {
+            write!(f, "nothing abandoned")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abandoned_work_summarized() {
+        let clean = ShutdownReport::default();
+        assert!(!clean.abandoned());
+        assert_eq!(clean.to_string(), "nothing abandoned");
+
+        let report = ShutdownReport {
+            aborted_connections: 2,
+            abandoned_queries: 1,
+            unstored_events: None,
+        };
+        assert!(report.abandoned());
+        assert_eq!(
+            report.to_string(),
+            "2 connection(s) aborted, 1 query(ies) cancelled"
+        );
+        let report = ShutdownReport {
+            unstored_events: Some(0),
+            ..Default::default()
+        };
+        assert!(report.abandoned());
+        assert_eq!(
+            report.to_string(),
+            "database not closed, 0 queued event(s) not stored"
+        );
+    }
+}
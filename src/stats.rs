@@ -0,0 +1,181 @@
+//! A JSON snapshot of the relay, served under `/stats` and logged
+//! periodically
+use crate::db::{DbStats, Storage};
+use crate::error::Result;
+use crate::protocol::Subscription;
+use crate::version::VERSION;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Who may fetch `/stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsAccess {
+    /// Anyone
+    #[default]
+    Public,
+    /// Only requests carrying the admin token
+    Admin,
+    /// Nobody; the route is not served
+    Off,
+}
+
+/// Numbers describing the relay as it runs, for dashboards and relay
+/// listings.
+///
+/// This is the body of `/stats`, and what is logged every
+/// `stats.log_interval_secs`.  Fields may be added, but existing ones
+/// keep their names and meanings, so other software can rely on them.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayStats {
+    /// Version of the relay software
+    pub version: String,
+    /// When the snapshot was taken, in seconds since the Unix epoch
+    pub generated_at: u64,
+    /// Seconds since the relay started
+    pub uptime_secs: u64,
+    /// Websocket connections open
+    pub connections: usize,
+    /// Subscriptions open across all connections
+    pub subscriptions: usize,
+    /// Consumers connected to `/firehose`
+    pub firehose_consumers: usize,
+    /// Stored events with a `created_at` in the last hour
+    pub events_last_hour: u64,
+    /// Stored events with a `created_at` in the last day
+    pub events_last_day: u64,
+    /// Contents of the database, as of `database.stats_cache_seconds`
+    /// ago at most
+    pub database: DbStats,
+}
+
+/// Numbers read from the running relay, rather than the database.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveCounts {
+    pub connections: usize,
+    pub subscriptions: usize,
+    pub firehose_consumers: usize,
+}
+
+/// Takes snapshots of the relay, reusing one for a few seconds so
+/// that frequent requests do not each query the database.
+#[derive(Debug)]
+pub struct StatsReporter {
+    started: Instant,
+    cached: tokio::sync::Mutex<Option<(Instant, RelayStats)>>,
+}
+
+impl Default for StatsReporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsReporter {
+    /// A reporter counting uptime from now.
+    pub fn new() -> Self {
+        StatsReporter {
+            started: Instant::now(),
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// A snapshot no older than `max_age`, taking a new one if needed.
+    /// Requests arriving while one is taken wait for it, rather than
+    /// taking their own.
+    pub async fn snapshot(
+        &self,
+        storage: &dyn Storage,
+        live: LiveCounts,
+        max_age: Duration,
+    ) -> Result<RelayStats> {
+        let mut cached = self.cached.lock().await;
+        if let Some((at, stats)) = &*cached {
+            if at.elapsed() < max_age {
+                return Ok(stats.clone());
+            }
+        }
+        let stats = self.take(storage, live).await?;
+        *cached = Some((Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+
+    async fn take(&self, storage: &dyn Storage, live: LiveCounts) -> Result<RelayStats> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let events_last_hour = storage
+            .count(Subscription::created_since(now.saturating_sub(60 * 60)))
+            .await?;
+        let events_last_day = storage
+            .count(Subscription::created_since(
+                now.saturating_sub(24 * 60 * 60),
+            ))
+            .await?;
+        Ok(RelayStats {
+            version: VERSION.to_owned(),
+            generated_at: now,
+            uptime_secs: self.started.elapsed().as_secs(),
+            connections: live.connections,
+            subscriptions: live.subscriptions,
+            firehose_consumers: live.firehose_consumers,
+            events_last_hour,
+            events_last_day,
+            database: storage.stats().await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::SqliteStorage;
+    use crate::test_util::temp_db_dir;
+
+    #[tokio::test]
+    async fn snapshots_reused() {
+        let dir = temp_db_dir();
+        let storage = SqliteStorage::open(&dir).unwrap();
+        storage.migrate().await.unwrap();
+        let reporter = StatsReporter::new();
+        let live = |connections| LiveCounts {
+            connections,
+            subscriptions: 2,
+            firehose_consumers: 1,
+        };
+        let minute = Duration::from_secs(60);
+
+        let stats = reporter.snapshot(&storage, live(1), minute).await.unwrap();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.subscriptions, 2);
+        assert_eq!(stats.firehose_consumers, 1);
+        assert_eq!(stats.events_last_day, 0);
+        assert_eq!(stats.database.event_count, 0);
+        assert_eq!(stats.version, VERSION);
+        // until it is too old
+        let cached = reporter.snapshot(&storage, live(5), minute).await.unwrap();
+        assert_eq!(cached, stats);
+        let fresh = reporter
+            .snapshot(&storage, live(5), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(fresh.connections, 5);
+
+        let json = serde_json::to_value(&fresh).unwrap();
+        for field in [
+            "version",
+            "generated_at",
+            "uptime_secs",
+            "connections",
+            "subscriptions",
+            "firehose_consumers",
+            "events_last_hour",
+            "events_last_day",
+            "database",
+        ] {
+            assert!(json.get(field).is_some(), "{} missing", field);
+        }
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
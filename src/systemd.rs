@@ -0,0 +1,141 @@
+//! Notifications for systemd services of `Type=notify`: when the relay
+//! is ready, when it starts stopping, and that it is still responsive,
+//! for the watchdog.  Nothing is sent unless systemd asked by setting
+//! `NOTIFY_SOCKET`.
+use log::*;
+use std::time::Duration;
+
+/// Socket systemd listens for notifications on
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Microseconds systemd waits between watchdog notifications before
+/// restarting the service
+const WATCHDOG_USEC: &str = "WATCHDOG_USEC";
+
+/// Process the watchdog applies to, if not every process started
+const WATCHDOG_PID: &str = "WATCHDOG_PID";
+
+/// Send `state` to systemd, returning whether it was sent.
+pub fn notify(state: &str) -> bool {
+    #[cfg(unix)]
+    {
+        let Some(socket) = std::env::var_os(NOTIFY_SOCKET) else {
+            return false;
+        };
+        match send(&socket, state) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("could not notify systemd of {}: {}", state, e);
+                false
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+        false
+    }
+}
+
+/// Send a datagram to the notification socket, a path or, starting
+/// with `@`, an abstract socket name.
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+    let datagram = UnixDatagram::unbound()?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        datagram.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    datagram.send_to(state.as_bytes(), socket)?;
+    Ok(())
+}
+
+/// Tell systemd the relay is serving clients.
+pub fn ready() {
+    if notify("READY=1") {
+        info!("notified systemd that the relay is ready");
+    }
+}
+
+/// Tell systemd the relay is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Tell systemd the relay is still responsive.
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// How often to notify the watchdog, if systemd enabled it for this
+/// process: twice within each timeout.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var(WATCHDOG_USEC).ok().as_deref(),
+        std::env::var(WATCHDOG_PID).ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn watchdog_interval_from(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    // meant for another process, such as a parent that started this
+    if pid.is_some_and(|pid| pid.trim().parse::<u32>().ok() != Some(own_pid)) {
+        return None;
+    }
+    let usec: u64 = usec?.trim().parse().ok().filter(|&usec| usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_read() {
+        assert_eq!(watchdog_interval_from(None, None, 7), None);
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), None, 7),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            watchdog_interval_from(Some("30000000"), Some("7"), 7),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(watchdog_interval_from(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(watchdog_interval_from(Some("0"), None, 7), None);
+        assert_eq!(watchdog_interval_from(Some("soon"), None, 7), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notifications_sent() {
+        use std::os::unix::net::UnixDatagram;
+        let path = std::env::temp_dir().join(format!("nostrd-notify-{}", uuid::Uuid::new_v4()));
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&path).ok();
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+            let name = format!("nostrd-notify-{}", uuid::Uuid::new_v4());
+            let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+            let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+            send(format!("@{}", name).as_ref(), "WATCHDOG=1").unwrap();
+            let n = systemd.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"WATCHDOG=1");
+        }
+    }
+}
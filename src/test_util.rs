@@ -0,0 +1,26 @@
+//! Helpers shared by unit tests
+use crate::protocol::testvec::event::VALID_EVENT;
+use crate::protocol::{Event, EventId};
+use bitcoin_hashes::Hash;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Create an empty, uniquely named directory for a test database.
+pub fn temp_db_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nostrd-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The test vector event.
+pub fn valid_event() -> Event {
+    Event::from_str(VALID_EVENT).unwrap()
+}
+
+/// Copy of the test vector event with an id of `n` repeated, so that
+/// copies are stored separately.  The copies no longer verify.
+pub fn event_with_id(n: u8) -> Event {
+    let mut event = valid_event();
+    event.id = EventId::from_inner([n; 32]);
+    event
+}
@@ -0,0 +1,102 @@
+//! What build of the relay is running, recorded at compile time by
+//! `build.rs`
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Version of the relay software
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the relay was built from, if built from a git checkout
+pub const COMMIT: Option<&str> = option_env!("NOSTRD_GIT_COMMIT");
+
+/// When the relay was built, as an RFC 3339 timestamp
+pub const BUILD_TIMESTAMP: Option<&str> = option_env!("NOSTRD_BUILD_TIMESTAMP");
+
+/// Version of the compiler that built the relay
+pub const RUSTC_VERSION: Option<&str> = option_env!("NOSTRD_RUSTC_VERSION");
+
+/// Optional cargo features compiled in.
+pub fn features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "postgres") {
+        features.push("postgres");
+    }
+    if cfg!(feature = "sqlcipher") {
+        features.push("sqlcipher");
+    }
+    features
+}
+
+/// Build information served under `/version`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+    pub build_timestamp: Option<&'static str>,
+    pub rustc: Option<&'static str>,
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            version: VERSION,
+            commit: COMMIT,
+            build_timestamp: BUILD_TIMESTAMP,
+            rustc: RUSTC_VERSION,
+            features: features(),
+        }
+    }
+}
+
+lazy_static! {
+    /// [`BuildInfo`] as JSON, which never changes while the relay runs
+    pub static ref BUILD_INFO_JSON: String = serde_json::to_string(&BuildInfo::current()).unwrap();
+}
+
+/// The version with the commit as build metadata, such as
+/// `0.3.3+1a2b3c4d5e6f`, as reported in relay information.
+pub fn full_version() -> String {
+    match COMMIT {
+        Some(commit) => format!("{}+{}", VERSION, commit),
+        None => VERSION.to_owned(),
+    }
+}
+
+/// One line describing the build, logged at startup.
+pub fn banner() -> String {
+    let features = features();
+    format!(
+        "nostrd {}, commit {}, built {} with {}, features: {}",
+        VERSION,
+        COMMIT.unwrap_or("unknown"),
+        BUILD_TIMESTAMP.unwrap_or("at an unknown time"),
+        RUSTC_VERSION.unwrap_or("an unknown rustc"),
+        if features.is_empty() {
+            "none".to_owned()
+        } else {
+            features.join(", ")
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_recorded() {
+        let json: serde_json::Value = serde_json::from_str(&BUILD_INFO_JSON).unwrap();
+        assert_eq!(json["version"], VERSION);
+        assert_eq!(json["commit"].as_str(), COMMIT);
+        assert_eq!(json["build_timestamp"].as_str(), BUILD_TIMESTAMP);
+        assert_eq!(json["rustc"].as_str(), RUSTC_VERSION);
+        assert!(json["features"].is_array());
+        // always known, whatever the checkout
+        let built = BUILD_TIMESTAMP.unwrap();
+        assert_eq!(built.len(), "2024-01-01T00:00:00Z".len(), "{}", built);
+        assert!(RUSTC_VERSION.unwrap().starts_with("rustc "));
+        assert!(full_version().starts_with(VERSION));
+        assert!(banner().contains(VERSION));
+    }
+}
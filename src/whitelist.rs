@@ -0,0 +1,148 @@
+//! Authors allowed to publish, when writes are restricted to them
+use crate::config;
+use crate::error::{Error, Result};
+use crate::protocol::Event;
+use lazy_static::lazy_static;
+use log::*;
+use secp256k1::XOnlyPublicKey;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// Why an event from an author not on the whitelist is refused
+pub const NOT_ACCEPTED: &str = "not an accepted author";
+
+lazy_static! {
+    /// Authors accepted, read at startup and on reload
+    static ref WHITELIST: RwLock<Option<Arc<Whitelist>>> = RwLock::new(None);
+}
+
+/// Authors whose events are accepted, along with events they
+/// delegated signing of.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Whitelist {
+    authors: HashSet<XOnlyPublicKey>,
+}
+
+impl Whitelist {
+    /// The authors listed in `config` and its file, if writes are
+    /// restricted.
+    pub fn from_config(config: &config::Authorization) -> Result<Option<Self>> {
+        if !config.is_restricted() {
+            return Ok(None);
+        }
+        let mut authors: HashSet<XOnlyPublicKey> =
+            config.pubkey_whitelist.iter().flatten().copied().collect();
+        if let Some(path) = &config.pubkey_whitelist_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| Error::GenericError(format!("could not read {}: {}", path, e)))?;
+            let listed = parse_list(&text)
+                .map_err(|e| Error::GenericError(format!("could not read {}: {}", path, e)))?;
+            authors.extend(listed);
+        }
+        Ok(Some(Whitelist { authors }))
+    }
+
+    /// Number of authors listed.
+    pub fn len(&self) -> usize {
+        self.authors.len()
+    }
+
+    /// Check whether no authors are listed.
+    pub fn is_empty(&self) -> bool {
+        self.authors.is_empty()
+    }
+
+    /// Whether `event` may be published: its author is listed, or it
+    /// carries a valid delegation from an author who is.
+    pub fn accepts(&self, event: &Event) -> bool {
+        self.authors.contains(event.get_pubkey())
+            || event
+                .get_delegator()
+                .map_or(false, |delegator| self.authors.contains(&delegator))
+    }
+}
+
+/// Pubkeys listed in hex one per line, ignoring blank lines and
+/// anything after a `#`.
+fn parse_list(text: &str) -> std::result::Result<Vec<XOnlyPublicKey>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| XOnlyPublicKey::from_str(line).map_err(|e| format!("line {}: {}", n, e)))
+        .collect()
+}
+
+/// Read the whitelist configured in `settings`, replacing the one in
+/// use.  If it can not be read, the one in use is kept.
+pub fn load(settings: &config::Settings) -> Result<()> {
+    let whitelist = Whitelist::from_config(&settings.authorization)?;
+    if let Some(whitelist) = &whitelist {
+        info!(
+            "accepting events only from {} whitelisted author(s) and their delegates",
+            whitelist.len()
+        );
+    }
+    *WHITELIST.write().unwrap() = whitelist.map(Arc::new);
+    Ok(())
+}
+
+/// The whitelist in use, if writes are restricted.
+pub fn current() -> Option<Arc<Whitelist>> {
+    WHITELIST.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::valid_event as event;
+    use bitcoin_hashes::hex::ToHex;
+
+    #[test]
+    fn unrestricted_without_config() {
+        let config = config::Authorization::default();
+        assert!(Whitelist::from_config(&config).unwrap().is_none());
+    }
+
+    #[test]
+    fn listed_authors_accepted() {
+        let event = event();
+        let mut config = config::Authorization {
+            pubkey_whitelist: Some(vec![]),
+            ..Default::default()
+        };
+        let whitelist = Whitelist::from_config(&config).unwrap().unwrap();
+        assert!(whitelist.is_empty());
+        assert!(!whitelist.accepts(&event));
+        config.pubkey_whitelist = Some(vec![*event.get_pubkey()]);
+        let whitelist = Whitelist::from_config(&config).unwrap().unwrap();
+        assert!(whitelist.accepts(&event));
+    }
+
+    #[test]
+    fn list_file_read() {
+        let event = event();
+        let path = std::env::temp_dir().join(format!("nostrd-whitelist-{}", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            format!(
+                "# editors\n\n{}  # the first\n",
+                event.get_pubkey().to_hex()
+            ),
+        )
+        .unwrap();
+        let config = config::Authorization {
+            pubkey_whitelist: None,
+            pubkey_whitelist_file: Some(path.display().to_string()),
+            ..Default::default()
+        };
+        let whitelist = Whitelist::from_config(&config).unwrap().unwrap();
+        assert_eq!(whitelist.len(), 1);
+        assert!(whitelist.accepts(&event));
+        std::fs::write(&path, "not a key\n").unwrap();
+        let error = Whitelist::from_config(&config).unwrap_err().to_string();
+        assert!(error.contains("line 1"), "{}", error);
+        std::fs::remove_file(&path).ok();
+    }
+}
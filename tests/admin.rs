@@ -0,0 +1,437 @@
+//! The admin API of a running relay, and its effect on clients
+mod common;
+
+use bitcoin_hashes::{sha256, Hash};
+use nostrd::db::{SqliteStorage, Storage};
+use nostrd::protocol::Event;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::Child;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+const TOKEN: &str = "s3cret";
+
+/// A relay process, killed when dropped.
+struct Relay {
+    process: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Relay {
+    /// Start a relay with the admin API enabled, and `config` appended
+    /// to its `[admin]` section.
+    fn start(config: &str) -> Self {
+        Self::start_with_env(config, &[])
+    }
+
+    /// Start a relay as [`Relay::start`] does, with `env` set in its
+    /// environment.
+    fn start_with_env(config: &str, env: &[(&str, &str)]) -> Self {
+        let dir = common::temp_db_dir();
+        let port = common::free_port();
+        let process = common::spawn_relay_with_env(
+            &dir,
+            port,
+            &format!("\n[admin]\nenabled = true\ntoken = {:?}\n{}", TOKEN, config),
+            env,
+        );
+        Relay { process, port, dir }
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.process.kill().ok();
+        self.process.wait().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Make an HTTP request of `port`, returning the status and the body
+/// parsed as JSON.
+fn call(port: u16, method: &str, path: &str, token: Option<&str>, body: Value) -> (u16, Value) {
+    let mut stream = common::connect(port);
+    let body = if body.is_null() {
+        String::new()
+    } else {
+        body.to_string()
+    };
+    let auth = token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        auth,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+    (status, serde_json::from_str(body).unwrap_or(Value::Null))
+}
+
+/// Make an authorized request of the admin API on `port`.
+fn admin(port: u16, method: &str, path: &str, body: Value) -> Value {
+    let (status, body) = call(port, method, path, Some(TOKEN), body);
+    assert_eq!(status, 200, "{} {}: {}", method, path, body);
+    body
+}
+
+/// Open a websocket to the relay.
+fn client(port: u16) -> tungstenite::Result<WebSocket<TcpStream>> {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client::client(url.as_str(), stream)
+        .map(|(socket, _)| socket)
+        .map_err(|e| match e {
+            tungstenite::HandshakeError::Failure(e) => e,
+            tungstenite::HandshakeError::Interrupted(_) => panic!("handshake interrupted"),
+        })
+}
+
+/// Read messages until one of type `kind` arrives.
+fn expect(socket: &mut WebSocket<TcpStream>, kind: &str) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message[0] == kind {
+                return message;
+            }
+        }
+    }
+}
+
+/// Publish an event, returning whether it was accepted and the
+/// relay's message.
+fn publish(socket: &mut WebSocket<TcpStream>, event: &Event) -> (bool, String) {
+    let command = json!(["EVENT", event]).to_string();
+    socket.write_message(Message::Text(command)).unwrap();
+    let ok = expect(socket, "OK");
+    (ok[2].as_bool().unwrap(), ok[3].as_str().unwrap().to_owned())
+}
+
+#[test]
+fn admin_requires_token() {
+    let admin_port = common::free_port();
+    let relay = Relay::start(&format!("address = \"127.0.0.1:{}\"\n", admin_port));
+    // the relay port no longer serves the API
+    common::connect(relay.port);
+    let (status, _) = call(
+        relay.port,
+        "GET",
+        "/admin/connections",
+        Some(TOKEN),
+        Value::Null,
+    );
+    assert_eq!(status, 404);
+
+    let (status, _) = call(admin_port, "GET", "/admin/connections", None, Value::Null);
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        admin_port,
+        "GET",
+        "/admin/connections",
+        Some("s3creT"),
+        Value::Null,
+    );
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        admin_port,
+        "POST",
+        "/admin/read-only",
+        Some("wrong"),
+        json!({ "read_only": true }),
+    );
+    assert_eq!(status, 401);
+    let (status, _) = call(admin_port, "GET", "/admin/ban-ip", Some(TOKEN), Value::Null);
+    assert_eq!(status, 405);
+    let (status, _) = call(
+        admin_port,
+        "POST",
+        "/admin/ban-pubkey",
+        Some(TOKEN),
+        json!({ "pubkey": "nope" }),
+    );
+    assert_eq!(status, 400);
+    assert_eq!(
+        admin(admin_port, "GET", "/admin/connections", Value::Null),
+        json!([])
+    );
+}
+
+#[test]
+fn stats_require_token_if_configured() {
+    let relay = Relay::start("\n[stats]\naccess = \"admin\"\ncache_secs = 0\n");
+    let port = relay.port;
+    let mut socket = client(port).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let event = common::signed_event(1, now, 1, json!([]), "recent");
+    assert!(publish(&mut socket, &event).0);
+
+    let (status, _) = call(port, "GET", "/stats", None, Value::Null);
+    assert_eq!(status, 401);
+    let stats = admin(port, "GET", "/stats", Value::Null);
+    assert_eq!(stats["connections"], 1);
+    assert_eq!(stats["events_last_hour"], 1);
+    assert_eq!(stats["events_last_day"], 1);
+    assert_eq!(stats["database"]["event_count"], 1);
+    assert!(stats["version"].is_string());
+}
+
+#[test]
+fn admin_actions_affect_clients() {
+    let relay = Relay::start("");
+    let port = relay.port;
+    let mut socket = client(port).unwrap();
+
+    let connections = admin(port, "GET", "/admin/connections", Value::Null);
+    assert_eq!(connections.as_array().unwrap().len(), 1);
+    let sent = admin(port, "POST", "/admin/notice", json!({ "message": "hello" }));
+    assert_eq!(sent["sent"], 1);
+    assert_eq!(expect(&mut socket, "NOTICE")[1], "hello");
+
+    // a deleted event is gone, and refused if published again
+    let event = common::signed_event(1, 1_000, 1, json!([]), "deleted");
+    let kept = common::signed_event(1, 1_001, 1, json!([]), "kept");
+    assert!(publish(&mut socket, &event).0);
+    assert!(publish(&mut socket, &kept).0);
+    let id = event.get_event_id().to_string();
+    let outcome = admin(port, "POST", "/admin/delete-event", json!({ "id": id }));
+    assert_eq!(outcome["deleted"], true);
+    // results come oldest first, so the deleted event would lead
+    let sub_id = sha256::Hash::hash(b"sub").to_string();
+    let ids = json!([id, kept.get_event_id().to_string()]);
+    let req = json!(["REQ", sub_id, { "ids": ids }]).to_string();
+    socket.write_message(Message::Text(req)).unwrap();
+    let first = expect(&mut socket, "EVENT");
+    assert_eq!(first[2]["content"], "kept");
+    let (accepted, message) = publish(&mut socket, &event);
+    assert!(!accepted);
+    assert!(message.contains("deleted"), "{}", message);
+
+    // storage used by an author, counting only what is still stored
+    let author = common::test_pubkey(1).to_string();
+    let path = format!("/admin/author-stats?pubkey={}", author);
+    let stats = admin(port, "GET", &path, Value::Null);
+    assert_eq!(stats["author"], author);
+    assert_eq!(stats["event_count"], 1);
+    let (status, _) = call(port, "GET", &path, None, Value::Null);
+    assert_eq!(status, 401);
+    let unknown = format!("/admin/author-stats?pubkey={}", common::test_pubkey(9));
+    let (status, _) = call(port, "GET", &unknown, Some(TOKEN), Value::Null);
+    assert_eq!(status, 404);
+
+    // a banned author's events are refused until unbanned
+    let pubkey = common::test_pubkey(2).to_string();
+    admin(
+        port,
+        "POST",
+        "/admin/ban-pubkey",
+        json!({ "pubkey": pubkey }),
+    );
+    assert_eq!(
+        admin(port, "GET", "/admin/bans", Value::Null)["pubkeys"],
+        json!([pubkey])
+    );
+    let (accepted, message) = publish(
+        &mut socket,
+        &common::signed_event(2, 1_002, 1, json!([]), "banned"),
+    );
+    assert!(!accepted);
+    assert!(message.contains("banned"), "{}", message);
+    admin(
+        port,
+        "POST",
+        "/admin/unban-pubkey",
+        json!({ "pubkey": pubkey }),
+    );
+    assert!(
+        publish(
+            &mut socket,
+            &common::signed_event(2, 1_003, 1, json!([]), "unbanned")
+        )
+        .0
+    );
+
+    // nothing is accepted while read-only
+    admin(
+        port,
+        "POST",
+        "/admin/read-only",
+        json!({ "read_only": true }),
+    );
+    let (accepted, message) = publish(
+        &mut socket,
+        &common::signed_event(3, 1_003, 1, json!([]), "read-only"),
+    );
+    assert!(!accepted);
+    assert!(message.contains("read-only"), "{}", message);
+    admin(
+        port,
+        "POST",
+        "/admin/read-only",
+        json!({ "read_only": false }),
+    );
+    assert!(
+        publish(
+            &mut socket,
+            &common::signed_event(3, 1_004, 1, json!([]), "writable")
+        )
+        .0
+    );
+
+    // banning an address closes its connections and refuses new ones
+    let banned = admin(port, "POST", "/admin/ban-ip", json!({ "ip": "127.0.0.1" }));
+    assert_eq!(banned["closed"], 1);
+    loop {
+        match socket.read_message() {
+            Ok(Message::Close(frame)) => {
+                assert_eq!(frame.unwrap().reason, "banned");
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => panic!("connection not closed: {}", e),
+        }
+    }
+    match client(port) {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+        other => panic!("banned address connected: {:?}", other.map(|_| ())),
+    }
+    admin(
+        port,
+        "POST",
+        "/admin/unban-ip",
+        json!({ "ip": "127.0.0.1" }),
+    );
+    assert!(client(port).is_ok());
+}
+
+#[test]
+fn oversized_bodies_refused() {
+    let relay = Relay::start("\n[limits]\nmax_http_body_bytes = 1024\n");
+    let port = relay.port;
+    let notice = json!({ "message": "x".repeat(2048) });
+    let (status, _) = call(port, "POST", "/admin/notice", Some(TOKEN), notice);
+    assert_eq!(status, 413);
+    // within the limit, the same request is handled
+    admin(port, "POST", "/admin/notice", json!({ "message": "hello" }));
+
+    // a body far larger than it claims is refused from its
+    // Content-Length alone, without waiting for the rest
+    let mut stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    write!(
+        stream,
+        "POST /admin/notice HTTP/1.1\r\nHost: localhost\r\n\
+         Authorization: Bearer {}\r\nContent-Type: application/json\r\n\
+         Content-Length: 10000000000\r\n\r\n{{}}",
+        TOKEN
+    )
+    .unwrap();
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 413");
+}
+
+#[test]
+fn compaction_notifies_clients() {
+    let relay = Relay::start("");
+    let port = relay.port;
+    let mut socket = client(port).unwrap();
+    let report = admin(port, "POST", "/admin/compact", Value::Null);
+    assert!(report["size_after"].is_u64(), "{}", report);
+    let notice = expect(&mut socket, "NOTICE");
+    assert!(
+        notice[1].as_str().unwrap().contains("read-only"),
+        "{}",
+        notice
+    );
+    // writes carry on afterwards
+    let event = common::signed_event(1, 1_000, 1, json!([]), "compacted");
+    assert!(publish(&mut socket, &event).0);
+}
+
+#[tokio::test]
+async fn backup_taken_while_running() {
+    let relay = Relay::start("");
+    let port = relay.port;
+    let mut socket = client(port).unwrap();
+    let event = common::signed_event(1, 1_000, 1, json!([]), "backed up");
+    assert!(publish(&mut socket, &event).0);
+
+    let dest = common::temp_db_dir();
+    let path = dest.join("nostr.db");
+    let query = format!("/admin/backup?path={}", path.display());
+    let (status, _) = call(port, "POST", &query, None, Value::Null);
+    assert_eq!(status, 401);
+    let (status, _) = call(port, "POST", "/admin/backup", Some(TOKEN), Value::Null);
+    assert_eq!(status, 400);
+    admin(port, "POST", &query, Value::Null);
+    // an existing file is never overwritten
+    let (status, _) = call(port, "POST", &query, Some(TOKEN), Value::Null);
+    assert_eq!(status, 409);
+
+    let backup = SqliteStorage::open(&dest).unwrap();
+    backup.migrate().await.unwrap();
+    assert_eq!(backup.stats().await.unwrap().event_count, 1);
+    backup.close().await.unwrap();
+    std::fs::remove_dir_all(&dest).ok();
+}
+
+#[test]
+fn event_sources_looked_up() {
+    let relay = Relay::start_with_env("", &[("NOSTRD_DATABASE__LOG_EVENT_SOURCE", "true")]);
+    let port = relay.port;
+    let mut socket = client(port).unwrap();
+    let event = common::signed_event(1, 1_000, 1, json!([]), "traced");
+    assert!(publish(&mut socket, &event).0);
+    let path = format!("/admin/event-source?id={}", event.get_event_id());
+
+    // the source is recorded just after the event is acknowledged
+    let started = std::time::Instant::now();
+    let source = loop {
+        let (status, body) = call(port, "GET", &path, Some(TOKEN), Value::Null);
+        match status {
+            200 => break body,
+            404 if started.elapsed() < Duration::from_secs(10) => {
+                std::thread::sleep(Duration::from_millis(50))
+            }
+            _ => panic!("{}: {}", status, body),
+        }
+    };
+    assert_eq!(source["ip"], "127.0.0.1");
+    assert!(source["client_id"].is_string());
+
+    let (status, _) = call(port, "GET", &path, None, Value::Null);
+    assert_eq!(status, 401);
+    let (status, _) = call(
+        port,
+        "GET",
+        "/admin/event-source?id=nope",
+        Some(TOKEN),
+        Value::Null,
+    );
+    assert_eq!(status, 400);
+    let unknown = format!("/admin/event-source?id={}", sha256::Hash::hash(b"unknown"));
+    let (status, _) = call(port, "GET", &unknown, Some(TOKEN), Value::Null);
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,142 @@
+//! Publishing restricted to authenticated clients' own events, by
+//! authorization.publish_own_only
+mod common;
+
+use nostrd::protocol::Event;
+use serde_json::{json, Value};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::{Message, WebSocket};
+
+/// Open a websocket to the relay.
+fn client(port: u16) -> WebSocket<TcpStream> {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client::client(url.as_str(), stream).unwrap().0
+}
+
+/// Read messages until one of type `kind` arrives.
+fn expect(socket: &mut WebSocket<TcpStream>, kind: &str) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message[0] == kind {
+                return message;
+            }
+        }
+    }
+}
+
+/// Send `event` as a message of type `kind`, returning whether the
+/// relay accepted it and its message.
+fn submit(socket: &mut WebSocket<TcpStream>, kind: &str, event: &Event) -> (bool, String) {
+    let command = json!([kind, event]).to_string();
+    socket.write_message(Message::Text(command)).unwrap();
+    let ok = expect(socket, "OK");
+    assert_eq!(ok[1], event.get_event_id().to_string());
+    (ok[2].as_bool().unwrap(), ok[3].as_str().unwrap().to_owned())
+}
+
+/// Answer the relay's challenge with the key of `secret` bytes,
+/// naming `relay`.
+fn authenticate(
+    socket: &mut WebSocket<TcpStream>,
+    secret: u8,
+    relay: &str,
+    challenge: &str,
+) -> (bool, String) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let tags = json!([["relay", relay], ["challenge", challenge]]);
+    let auth = common::signed_event(secret, now, 22242, tags, "");
+    submit(socket, "AUTH", &auth)
+}
+
+#[test]
+fn only_own_events_published() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let relay_url = format!("ws://127.0.0.1:{}", port);
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        &format!(
+            "\n[info]\nrelay_url = \"{}/\"\n\n[authorization]\npublish_own_only = true\n",
+            relay_url
+        ),
+    );
+    let mut socket = client(port);
+    let challenge = expect(&mut socket, "AUTH")[1].as_str().unwrap().to_owned();
+
+    // nothing is accepted before the client authenticates
+    let own = common::signed_event(1, 1_000, 1, json!([]), "own");
+    assert_eq!(
+        submit(&mut socket, "EVENT", &own),
+        (
+            false,
+            "auth-required: publishing requires authentication".to_owned()
+        )
+    );
+
+    // answers to another challenge, or for another relay, are refused
+    let (accepted, message) = authenticate(&mut socket, 1, &relay_url, "guess");
+    assert!(!accepted);
+    assert_eq!(message, "invalid: authentication failed: wrong challenge");
+    let (accepted, message) = authenticate(&mut socket, 1, "wss://other.example.com", &challenge);
+    assert!(!accepted);
+    assert_eq!(message, "invalid: authentication failed: wrong relay");
+    assert!(!submit(&mut socket, "EVENT", &own).0);
+
+    assert_eq!(
+        authenticate(&mut socket, 1, &relay_url, &challenge),
+        (true, String::new())
+    );
+    assert_eq!(submit(&mut socket, "EVENT", &own), (true, String::new()));
+
+    // someone else's event
+    let other = common::signed_event(2, 1_001, 1, json!([]), "other");
+    assert_eq!(
+        submit(&mut socket, "EVENT", &other),
+        (
+            false,
+            "restricted: can only publish your own events".to_owned()
+        )
+    );
+
+    // an event the authenticated key delegated signing of
+    let delegatee = common::test_pubkey(3);
+    let conditions = "kind=1&created_at>500&created_at<2000";
+    let tags = json!([common::delegation_tag(1, &delegatee, conditions)]);
+    let delegated = common::signed_event(3, 1_002, 1, tags, "delegated");
+    assert_eq!(
+        submit(&mut socket, "EVENT", &delegated),
+        (true, String::new())
+    );
+    // but not one delegated by another key
+    let tags = json!([common::delegation_tag(2, &delegatee, conditions)]);
+    let by_proxy = common::signed_event(3, 1_003, 1, tags, "by proxy");
+    assert!(!submit(&mut socket, "EVENT", &by_proxy).0);
+
+    // authentication events are not themselves published
+    let tags = json!([["relay", relay_url], ["challenge", challenge]]);
+    let auth = common::signed_event(1, 1_004, 22242, tags, "");
+    assert!(!submit(&mut socket, "EVENT", &auth).0);
+
+    // each connection is challenged, and authenticates, separately
+    let mut second = client(port);
+    let second_challenge = expect(&mut second, "AUTH")[1].as_str().unwrap().to_owned();
+    assert_ne!(second_challenge, challenge);
+    let later = common::signed_event(1, 1_005, 1, json!([]), "later");
+    assert!(submit(&mut second, "EVENT", &later)
+        .1
+        .starts_with("auth-required:"));
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
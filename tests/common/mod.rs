@@ -0,0 +1,151 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+use bitcoin_hashes::{hex::ToHex, sha256, Hash};
+use nostrd::protocol::Event;
+use secp256k1::{KeyPair, Message, Secp256k1, XOnlyPublicKey};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Create an empty, uniquely named directory for a test database.
+pub fn temp_db_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nostrd-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A port nothing is listening on.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Connect to `port` on localhost, waiting for the relay to start
+/// listening.
+pub fn connect(port: u16) -> TcpStream {
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => return stream,
+            Err(e) if started.elapsed() > Duration::from_secs(20) => {
+                panic!("relay did not start listening: {}", e)
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Start the relay in `dir`, with its database there, listening on
+/// `port` on localhost.  `config` is appended to the configuration
+/// file.
+pub fn spawn_relay(dir: &Path, port: u16, config: &str) -> Child {
+    spawn_relay_with_env(dir, port, config, &[])
+}
+
+/// Start the relay as [`spawn_relay`] does, with `env` set in its
+/// environment, for settings in sections the configuration file
+/// already starts.
+pub fn spawn_relay_with_env(dir: &Path, port: u16, config: &str, env: &[(&str, &str)]) -> Child {
+    let config = format!(
+        "[database]\ndata_directory = {:?}\n\n\
+         [network]\naddress = \"127.0.0.1\"\nport = {}\n{}",
+        dir.display().to_string(),
+        port,
+        config
+    );
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_nostrd"))
+        .current_dir(dir)
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// Start the relay in `dir` as [`spawn_relay`] does, but on any free
+/// port, returning the process and the port it listens on, read from
+/// its log.  Anything else it logs is discarded.
+pub fn spawn_relay_on_free_port(dir: &Path, config: &str) -> (Child, u16) {
+    let config = format!(
+        "[database]\ndata_directory = {:?}\n\n\
+         [network]\naddress = \"127.0.0.1\"\nport = 0\n{}",
+        dir.display().to_string(),
+        config
+    );
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    let mut relay = Command::new(env!("CARGO_BIN_EXE_nostrd"))
+        .current_dir(dir)
+        .env("RUST_LOG", "info")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut log = BufReader::new(relay.stderr.take().unwrap()).lines();
+    let port = loop {
+        let line = match log.next() {
+            Some(line) => line.unwrap(),
+            None => panic!("relay exited before listening"),
+        };
+        if let Some((_, addr)) = line.split_once("listening on: ") {
+            let addr: SocketAddr = addr.split_whitespace().next().unwrap().parse().unwrap();
+            break addr.port();
+        }
+    };
+    // keep reading, so the relay never blocks on a full pipe
+    std::thread::spawn(move || log.for_each(drop));
+    (relay, port)
+}
+
+/// The x-only public key for a test secret key made of a single repeated byte.
+pub fn test_pubkey(secret: u8) -> XOnlyPublicKey {
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::from_seckey_slice(&secp, &[secret; 32]).unwrap();
+    XOnlyPublicKey::from_keypair(&keypair)
+}
+
+/// A NIP-26 delegation tag, signed with the test secret key
+/// `delegator`, letting `delegatee` publish events meeting
+/// `conditions`.
+pub fn delegation_tag(delegator: u8, delegatee: &XOnlyPublicKey, conditions: &str) -> Value {
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::from_seckey_slice(&secp, &[delegator; 32]).unwrap();
+    let token = format!("nostr:delegation:{}:{}", delegatee.to_hex(), conditions);
+    let hash = sha256::Hash::hash(token.as_bytes());
+    let sig = secp.sign_schnorr(&Message::from_slice(hash.as_inner()).unwrap(), &keypair);
+    json!([
+        "delegation",
+        test_pubkey(delegator).to_hex(),
+        conditions,
+        sig.to_string()
+    ])
+}
+
+/// Build a valid, signed event from a test secret key made of a
+/// single repeated byte.
+pub fn signed_event(secret: u8, created_at: u64, kind: u64, tags: Value, content: &str) -> Event {
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::from_seckey_slice(&secp, &[secret; 32]).unwrap();
+    let pubkey = XOnlyPublicKey::from_keypair(&keypair).to_hex();
+    let canonical = json!([0, pubkey, created_at, kind, tags, content]).to_string();
+    let id = sha256::Hash::hash(canonical.as_bytes());
+    let msg = Message::from_slice(id.as_inner()).unwrap();
+    let sig = secp.sign_schnorr(&msg, &keypair);
+    let event = json!({
+        "id": id.to_hex(),
+        "pubkey": pubkey,
+        "created_at": created_at,
+        "kind": kind,
+        "tags": tags,
+        "content": content,
+        "sig": sig.to_string(),
+    });
+    Event::from_str(&event.to_string()).unwrap()
+}
@@ -0,0 +1,114 @@
+//! Connections refused once the relay is at its connection limit, or
+//! an address connects too often
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::WebSocket;
+
+const MAX_CONNECTIONS: usize = 3;
+
+/// Open a websocket to the relay.
+fn client(port: u16) -> tungstenite::Result<WebSocket<TcpStream>> {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client::client(url.as_str(), stream)
+        .map(|(socket, _)| socket)
+        .map_err(|e| match e {
+            tungstenite::HandshakeError::Failure(e) => e,
+            tungstenite::HandshakeError::Interrupted(_) => panic!("handshake interrupted"),
+        })
+}
+
+/// Fetch the relay information document, returning the response.
+fn relay_info(port: u16) -> String {
+    let mut stream = common::connect(port);
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\n\
+         Connection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn connection_beyond_limit_refused() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        &format!("\n[limits]\nmax_connections = {}\n", MAX_CONNECTIONS),
+    );
+
+    let mut open: Vec<_> = (0..MAX_CONNECTIONS)
+        .map(|_| client(port).unwrap())
+        .collect();
+    match client(port) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), 503);
+            assert!(response.headers().contains_key("retry-after"));
+        }
+        other => panic!("connection beyond the limit: {:?}", other.map(|_| ())),
+    }
+    // other routes are still served
+    assert!(relay_info(port).starts_with("HTTP/1.1 200"));
+
+    // closing a connection makes room for another
+    let mut closed = open.pop().unwrap();
+    closed.close(None).unwrap();
+    while closed.read_message().is_ok() {}
+    let started = Instant::now();
+    while client(port).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "slot not released"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn reconnecting_too_often_throttled() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        "\n[limits]\nconnections_per_minute = 1\nconnection_burst = 2\n",
+    );
+
+    // closed connections still count
+    for _ in 0..2 {
+        client(port).unwrap().close(None).unwrap();
+    }
+    match client(port) {
+        Err(tungstenite::Error::Http(response)) => {
+            assert_eq!(response.status(), 429);
+            let retry_after: u64 = response.headers()["retry-after"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=60).contains(&retry_after), "{}", retry_after);
+        }
+        other => panic!("connection over the rate: {:?}", other.map(|_| ())),
+    }
+    // other routes are still served
+    assert!(relay_info(port).starts_with("HTTP/1.1 200"));
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
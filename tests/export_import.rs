@@ -0,0 +1,56 @@
+//! Round trip of events through export and import
+mod common;
+
+use nostrd::db::{export_events, import_events, ExportFilter, SqliteStorage, Storage};
+use serde_json::json;
+
+#[tokio::test]
+async fn export_import_roundtrip() {
+    let src = common::temp_db_dir();
+    let storage = SqliteStorage::open(&src).unwrap();
+    storage.migrate().await.unwrap();
+    let events: Vec<_> = (0..50u64)
+        .map(|i| {
+            common::signed_event(
+                1 + (i % 3) as u8,
+                1_000 + i,
+                i % 3,
+                json!([]),
+                &format!("event {}", i),
+            )
+        })
+        .collect();
+    for e in events {
+        storage.write_event(e).await.unwrap();
+    }
+    storage.close().await.unwrap();
+
+    let mut dump = vec![];
+    let filter = ExportFilter {
+        include_hidden: true,
+        ..Default::default()
+    };
+    // all but the newest of the 17 metadata events were replaced
+    let exported = export_events(&src, &filter, &mut dump, |_| {}).unwrap();
+    assert_eq!(exported, 34);
+
+    let dest = common::temp_db_dir();
+    let report = import_events(&dest, dump.as_slice(), |_| {}).unwrap();
+    assert_eq!(report.imported, 34);
+    assert_eq!(report.invalid, 0);
+
+    // the same events are stored in both databases
+    for filter in [filter, ExportFilter::default()] {
+        let mut before = vec![];
+        let mut after = vec![];
+        export_events(&src, &filter, &mut before, |_| {}).unwrap();
+        export_events(&dest, &filter, &mut after, |_| {}).unwrap();
+        assert_eq!(before, after);
+    }
+
+    // importing again only finds duplicates
+    let report = import_events(&dest, dump.as_slice(), |_| {}).unwrap();
+    assert_eq!(report.duplicates, 34);
+    std::fs::remove_dir_all(src).ok();
+    std::fs::remove_dir_all(dest).ok();
+}
@@ -0,0 +1,107 @@
+//! Listening on the configured addresses
+mod common;
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+#[test]
+fn free_port_chosen_for_port_zero() {
+    let dir = common::temp_db_dir();
+    let (mut relay, port) = common::spawn_relay_on_free_port(&dir, "");
+    assert_ne!(port, 0);
+    let url = format!("ws://127.0.0.1:{}/", port);
+    let (mut socket, _) = tungstenite::client::client(url.as_str(), common::connect(port)).unwrap();
+    socket.close(None).unwrap();
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn address_in_use_explained() {
+    let dir = common::temp_db_dir();
+    let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = held.local_addr().unwrap().port();
+    let config = format!(
+        "[database]\ndata_directory = {:?}\n\n[network]\naddress = \"127.0.0.1\"\nport = {}\n",
+        dir.display().to_string(),
+        port
+    );
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nostrd"))
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("could not listen on 127.0.0.1:{}", port)),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("already in use"), "{}", stderr);
+
+    drop(held);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn listeners_announce_their_own_relay_url() {
+    let dir = common::temp_db_dir();
+    let (plain, public) = (common::free_port(), common::free_port());
+    let config = format!(
+        "listeners = [\"127.0.0.1:{}\", {{ address = \"127.0.0.1:{}\", relay_url = \"wss://relay.example.com/\" }}]\n\n\
+         [info]\nrelay_url = \"ws://localhost/\"\n",
+        plain, public
+    );
+    let mut relay = common::spawn_relay(&dir, plain, &config);
+    let relay_url = |port| {
+        let mut stream = common::connect(port);
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAccept: application/nostr+json\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let info: serde_json::Value = serde_json::from_str(body).unwrap();
+        info["id"].as_str().map(str::to_owned)
+    };
+    assert_eq!(relay_url(plain).as_deref(), Some("ws://localhost/"));
+    assert_eq!(
+        relay_url(public).as_deref(),
+        Some("wss://relay.example.com/")
+    );
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn tls_listener_without_key_refused() {
+    let dir = common::temp_db_dir();
+    let config = format!(
+        "[database]\ndata_directory = {:?}\n\n[network]\n\
+         listeners = [{{ address = \"127.0.0.1:0\", tls = {{ cert_path = \"relay.crt\" }} }}]\n",
+        dir.display().to_string(),
+    );
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nostrd"))
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("TLS listener 127.0.0.1:0 needs both tls.cert_path and tls.key_path"),
+        "{}",
+        stderr
+    );
+
+    std::fs::remove_dir_all(dir).ok();
+}
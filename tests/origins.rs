@@ -0,0 +1,51 @@
+//! Websockets refused to web pages not in network.allowed_origins
+mod common;
+
+use std::time::Duration;
+use tungstenite::client::IntoClientRequest;
+
+/// Open a websocket to the relay from a page at `origin`, returning
+/// the HTTP status of the handshake.
+fn connect_from(port: u16, origin: Option<&str>) -> u16 {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut request = format!("ws://127.0.0.1:{}/", port)
+        .into_client_request()
+        .unwrap();
+    if let Some(origin) = origin {
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+    }
+    match tungstenite::client::client(request, stream) {
+        Ok((_, response)) => response.status().as_u16(),
+        Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+            response.status().as_u16()
+        }
+        Err(e) => panic!("handshake failed: {}", e),
+    }
+}
+
+#[test]
+fn origins_checked_on_upgrade() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        "allowed_origins = [\"https://client.example.com\", \"https://*.example.org\"]\n\
+         allow_missing_origin = false\n",
+    );
+
+    assert_eq!(connect_from(port, Some("https://client.example.com")), 101);
+    assert_eq!(connect_from(port, Some("https://app.example.org")), 101);
+    assert_eq!(connect_from(port, Some("https://evil.example.net")), 403);
+    assert_eq!(connect_from(port, Some("https://example.org")), 403);
+    assert_eq!(connect_from(port, None), 403);
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
@@ -0,0 +1,61 @@
+//! Queries for a subscription that is replaced before they finish
+mod common;
+
+use bitcoin_hashes::{sha256, Hash};
+use nostrd::db::{db_query, QueryResult, RunningQueries, SqliteStorage, Storage};
+use nostrd::protocol::{Event, Subscription};
+use serde_json::json;
+use std::sync::Arc;
+
+#[tokio::test]
+async fn replaced_query_results_dropped() {
+    let dir = common::temp_db_dir();
+    let storage = Arc::new(SqliteStorage::open(&dir).unwrap());
+    storage.migrate().await.unwrap();
+    let events: Vec<Event> = (0..20u64)
+        .map(|i| common::signed_event(1, 1_000 + i, 1 + i % 2, json!([]), &format!("event {}", i)))
+        .collect();
+    for event in &events {
+        storage.write_event(event.clone()).await.unwrap();
+    }
+    // ids of the events the second request matches
+    let mut expected: Vec<_> = events
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .map(|e| e.get_event_id())
+        .collect();
+    let sub_id = sha256::Hash::hash(b"sub");
+    let req = |kind: u64| -> Subscription {
+        serde_json::from_str(&format!(r#"["REQ","{}",{{"kinds":[{}]}}]"#, sub_id, kind)).unwrap()
+    };
+
+    // the same subscription id is requested twice in a row
+    let mut running = RunningQueries::default();
+    let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(64);
+    let (first, abandon_rx) = running.start(&sub_id.to_string());
+    db_query(req(1), first, storage.clone(), query_tx.clone(), abandon_rx).await;
+    let (second, abandon_rx) = running.start(&sub_id.to_string());
+    db_query(req(2), second, storage.clone(), query_tx, abandon_rx).await;
+    assert_ne!(first, second);
+
+    let mut delivered = vec![];
+    while let Some(result) = query_rx.recv().await {
+        if running.is_current(&result) {
+            delivered.push(result.event.get_event_id());
+        }
+    }
+    delivered.sort();
+    expected.sort();
+    assert_eq!(delivered, expected);
+
+    // results still queued are dropped once the subscription is closed
+    running.cancel(&sub_id.to_string());
+    let stale = QueryResult {
+        sub_id: sub_id.to_string(),
+        generation: second,
+        event: events[1].clone(),
+    };
+    assert!(!running.is_current(&stale));
+    std::fs::remove_dir_all(dir).ok();
+}
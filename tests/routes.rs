@@ -0,0 +1,97 @@
+//! HTTP methods answered on each route
+mod common;
+
+use std::io::{Read, Write};
+
+/// Make a request of the relay on `port`, returning the response
+/// head and body.
+fn request(port: u16, method: &str, path: &str, headers: &str) -> (String, String) {
+    let mut stream = common::connect(port);
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+        method, path, headers
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.to_owned(), body.to_owned())
+}
+
+/// The value of `name` in a response head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[test]
+fn methods_checked_per_route() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(&dir, port, "");
+    let nip11 = "Accept: application/nostr+json\r\n";
+
+    // HEAD has the headers of GET, without the body
+    let (get_head, get_body) = request(port, "GET", "/", nip11);
+    assert!(get_head.starts_with("HTTP/1.1 200"), "{}", get_head);
+    let (head, body) = request(port, "HEAD", "/", nip11);
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(
+        header(&head, "content-length"),
+        Some(get_body.len().to_string().as_str())
+    );
+    assert_eq!(
+        header(&head, "content-type"),
+        header(&get_head, "content-type")
+    );
+    assert!(body.is_empty(), "{}", body);
+
+    let (head, _) = request(port, "POST", "/", nip11);
+    assert!(head.starts_with("HTTP/1.1 405"), "{}", head);
+    assert_eq!(header(&head, "allow"), Some("GET, HEAD, OPTIONS"));
+    let (head, _) = request(port, "PUT", "/stats", "");
+    assert!(head.starts_with("HTTP/1.1 405"), "{}", head);
+    assert_eq!(header(&head, "allow"), Some("GET, HEAD"));
+    let (head, _) = request(port, "HEAD", "/stats", "");
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    // unknown paths are not found, whatever the method
+    let (head, _) = request(port, "DELETE", "/nothing", "");
+    assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn build_info_served() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(&dir, port, "");
+
+    let (head, body) = request(port, "GET", "/version", "");
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let info: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(info["version"], nostrd::version::VERSION);
+    assert_eq!(info["commit"].as_str(), nostrd::version::COMMIT);
+    assert_eq!(
+        info["build_timestamp"].as_str(),
+        nostrd::version::BUILD_TIMESTAMP
+    );
+    assert_eq!(info["rustc"].as_str(), nostrd::version::RUSTC_VERSION);
+    assert_eq!(
+        info["features"],
+        serde_json::json!(nostrd::version::features())
+    );
+    // relay information carries the commit too
+    let (_, body) = request(port, "GET", "/", "Accept: application/nostr+json\r\n");
+    let nip11: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(nip11["version"], nostrd::version::full_version());
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}
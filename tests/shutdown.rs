@@ -0,0 +1,71 @@
+//! Shutting down the relay process with clients connected
+mod common;
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+
+/// Seconds the relay waits for connections to close
+const GRACE_SECS: u64 = 5;
+
+/// Start the relay, connect a client, and stop the relay with
+/// `signal`.
+fn clients_closed_on(signal: &str) {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        &format!("shutdown_grace_secs = {}\n", GRACE_SECS),
+    );
+
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    let (mut socket, _) = tungstenite::client::client(url.as_str(), stream).unwrap();
+
+    let interrupted = Instant::now();
+    let status = Command::new("kill")
+        .args([signal, &relay.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // a notice, then a close frame saying the relay is going away
+    let mut close = None;
+    while close.is_none() {
+        match socket.read_message().unwrap() {
+            Message::Text(text) => assert!(text.contains("shutting down"), "{}", text),
+            Message::Close(frame) => close = frame,
+            _ => {}
+        }
+    }
+    assert_eq!(close.unwrap().code, CloseCode::Away);
+    socket.close(None).ok();
+
+    let status = loop {
+        if let Some(status) = relay.try_wait().unwrap() {
+            break status;
+        }
+        if interrupted.elapsed() > Duration::from_secs(GRACE_SECS + 5) {
+            relay.kill().ok();
+            panic!("relay did not exit within the grace period");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn clients_closed_on_interrupt() {
+    clients_closed_on("-INT");
+}
+
+#[test]
+fn clients_closed_on_terminate() {
+    clients_closed_on("-TERM");
+}
@@ -0,0 +1,137 @@
+//! Subscriptions opened and answered over a websocket
+mod common;
+
+use nostrd::protocol::Event;
+use serde_json::{json, Value};
+use std::net::TcpStream;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+/// Open a websocket to the relay.
+fn client(port: u16) -> WebSocket<TcpStream> {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client::client(url.as_str(), stream).unwrap().0
+}
+
+/// Send a message made of `items`.
+fn send(socket: &mut WebSocket<TcpStream>, items: Value) {
+    socket
+        .write_message(Message::Text(items.to_string()))
+        .unwrap();
+}
+
+/// Read the next message.
+fn next(socket: &mut WebSocket<TcpStream>) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+/// Read messages until one of type `kind` arrives.
+fn expect(socket: &mut WebSocket<TcpStream>, kind: &str) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message[0] == kind {
+                return message;
+            }
+        }
+    }
+}
+
+/// Publish an event, waiting for the relay to accept it.
+fn publish(socket: &mut WebSocket<TcpStream>, event: &Event) {
+    send(socket, json!(["EVENT", event]));
+    assert_eq!(expect(socket, "OK")[2], true);
+}
+
+#[test]
+fn any_short_id_accepted() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(&dir, port, "\n[limits]\nmax_subid_length = 8\n");
+    let mut socket = client(port);
+    let event = common::signed_event(1, 1_000, 1, json!([]), "stored");
+    publish(&mut socket, &event);
+
+    // ids need not be hex, or a hash's length
+    send(&mut socket, json!(["REQ", "abc", {}]));
+    let stored = expect(&mut socket, "EVENT");
+    assert_eq!(stored[1], "abc");
+    assert_eq!(stored[2]["id"], event.get_event_id().to_string());
+    // and live events reach them too
+    let live = common::signed_event(1, 1_001, 1, json!([]), "live");
+    send(&mut socket, json!(["EVENT", live]));
+    let matched = expect(&mut socket, "EVENT");
+    assert_eq!(matched[1], "abc");
+    assert_eq!(matched[2]["id"], live.get_event_id().to_string());
+
+    // up to the configured length
+    send(&mut socket, json!(["REQ", "too-long!", {}]));
+    let closed = expect(&mut socket, "CLOSED");
+    assert_eq!(closed[1], "too-long!");
+    assert_eq!(closed[2], "error: invalid subscription id");
+
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn filterless_req_closed() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(&dir, port, "");
+    let mut socket = client(port);
+
+    send(&mut socket, json!(["REQ", "bare"]));
+    let closed = expect(&mut socket, "CLOSED");
+    assert_eq!(closed[1], "bare");
+    assert_eq!(closed[2], "error: subscription has no filters");
+    // the connection is still usable
+    let event = common::signed_event(1, 1_000, 1, json!([]), "after");
+    publish(&mut socket, &event);
+
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn identical_req_unanswered() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let mut relay = common::spawn_relay(&dir, port, "");
+    let mut socket = client(port);
+    let stored = common::signed_event(1, 1_000, 1, json!([]), "stored");
+    publish(&mut socket, &stored);
+
+    let req = json!(["REQ", "same", { "kinds": [1] }]);
+    send(&mut socket, req.clone());
+    assert_eq!(
+        expect(&mut socket, "EVENT")[2]["id"],
+        stored.get_event_id().to_string()
+    );
+    // the same subscription again gets no reply, not even the stored
+    // events again, so the next messages answer a later one
+    send(&mut socket, req);
+    let live = common::signed_event(1, 1_001, 1, json!([]), "live");
+    send(&mut socket, json!(["EVENT", live]));
+    let mut replies = vec![next(&mut socket), next(&mut socket)];
+    replies.sort_by_key(|reply| reply[0].to_string());
+    assert_eq!(replies[0][0], "EVENT");
+    assert_eq!(replies[0][1], "same");
+    assert_eq!(replies[0][2]["id"], live.get_event_id().to_string());
+    assert_eq!(replies[1][0], "OK");
+    assert_eq!(replies[1][1], live.get_event_id().to_string());
+
+    relay.kill().unwrap();
+    relay.wait().unwrap();
+    std::fs::remove_dir_all(dir).ok();
+}
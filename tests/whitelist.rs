@@ -0,0 +1,119 @@
+//! Publishing restricted to the authors in authorization.pubkey_whitelist
+mod common;
+
+use nostrd::protocol::Event;
+use serde_json::{json, Value};
+use std::net::TcpStream;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tungstenite::{Message, WebSocket};
+
+/// Open a websocket to the relay.
+fn client(port: u16) -> WebSocket<TcpStream> {
+    let stream = common::connect(port);
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client::client(url.as_str(), stream).unwrap().0
+}
+
+/// Read messages until one of type `kind` arrives.
+fn expect(socket: &mut WebSocket<TcpStream>, kind: &str) -> Value {
+    loop {
+        if let Message::Text(text) = socket.read_message().unwrap() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message[0] == kind {
+                return message;
+            }
+        }
+    }
+}
+
+/// Publish an event, returning whether it was accepted and the
+/// relay's message.
+fn publish(socket: &mut WebSocket<TcpStream>, event: &Event) -> (bool, String) {
+    let command = json!(["EVENT", event]).to_string();
+    socket.write_message(Message::Text(command)).unwrap();
+    let ok = expect(socket, "OK");
+    (ok[2].as_bool().unwrap(), ok[3].as_str().unwrap().to_owned())
+}
+
+#[test]
+fn only_whitelisted_authors_publish() {
+    let dir = common::temp_db_dir();
+    let port = common::free_port();
+    let file = dir.join("authors.txt");
+    std::fs::write(&file, "# more authors, read again on SIGHUP\n").unwrap();
+    let mut relay = common::spawn_relay(
+        &dir,
+        port,
+        &format!(
+            "\n[authorization]\npubkey_whitelist = [{:?}]\npubkey_whitelist_file = {:?}\n",
+            common::test_pubkey(1).to_string(),
+            file.display().to_string()
+        ),
+    );
+    let mut socket = client(port);
+
+    // a listed author
+    let listed = common::signed_event(1, 1_000, 1, json!([]), "listed");
+    assert_eq!(publish(&mut socket, &listed), (true, String::new()));
+
+    // anyone else
+    let unlisted = common::signed_event(2, 1_001, 1, json!([]), "unlisted");
+    assert_eq!(
+        publish(&mut socket, &unlisted),
+        (false, "restricted: not an accepted author".to_owned())
+    );
+
+    // someone the listed author delegated to, within the conditions
+    let delegatee = common::test_pubkey(3);
+    let conditions = "kind=1&created_at>500&created_at<2000";
+    let tags = json!([common::delegation_tag(1, &delegatee, conditions)]);
+    let delegated = common::signed_event(3, 1_002, 1, tags.clone(), "delegated");
+    assert!(publish(&mut socket, &delegated).0);
+    let outside = common::signed_event(3, 2_000, 1, tags, "too late");
+    assert!(!publish(&mut socket, &outside).0);
+    // a delegation from an unlisted author is no help
+    let tags = json!([common::delegation_tag(2, &delegatee, conditions)]);
+    let unlisted_delegation = common::signed_event(3, 1_003, 1, tags, "by proxy");
+    assert!(!publish(&mut socket, &unlisted_delegation).0);
+
+    // reads are open to everyone
+    let mut reader = client(port);
+    let ids = json!([
+        listed.get_event_id().to_string(),
+        delegated.get_event_id().to_string()
+    ]);
+    let req = json!(["REQ", "0".repeat(64), { "ids": ids }]).to_string();
+    reader.write_message(Message::Text(req)).unwrap();
+    assert_eq!(expect(&mut reader, "EVENT")[2]["content"], "listed");
+    assert_eq!(expect(&mut reader, "EVENT")[2]["content"], "delegated");
+
+    // authors added to the file may publish once it is read again
+    std::fs::write(&file, format!("{}\n", common::test_pubkey(2))).unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &relay.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let started = Instant::now();
+    let mut created_at = 1_100;
+    loop {
+        let event = common::signed_event(2, created_at, 1, json!([]), "added");
+        if publish(&mut socket, &event).0 {
+            break;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "whitelist file not reloaded"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+        created_at += 1;
+    }
+
+    relay.kill().ok();
+    relay.wait().ok();
+    std::fs::remove_dir_all(dir).ok();
+}